@@ -0,0 +1,67 @@
+//! Baselines shared by the benchmark suites.
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+
+
+/// The number of elements benchmarked operations work on.
+pub const SIZES: &[usize] = &[1_000, 100_000, 10_000_000];
+
+/// Benchmarks `Vec` with the global allocator of the running suite.
+pub fn bench_std_vec(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push");
+
+    for &size in SIZES {
+        group.throughput(Throughput::Elements(size as u64));
+        group.bench_with_input(BenchmarkId::new("Vec", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut vec = Vec::new();
+
+                for i in 0..size as u64 {
+                    vec.push(i);
+                }
+
+                vec
+            })
+        });
+    }
+
+    group.finish();
+
+    let mut group = c.benchmark_group("extend");
+
+    for &size in SIZES {
+        let values = (0..size as u64).collect::<Vec<_>>();
+
+        group.throughput(Throughput::Bytes(size as u64 * 8));
+        group.bench_with_input(BenchmarkId::new("Vec", size), &values, |b, values| {
+            b.iter(|| {
+                let mut vec = Vec::new();
+
+                vec.extend_from_slice(values);
+                vec
+            })
+        });
+    }
+
+    group.finish();
+
+    let mut group = c.benchmark_group("reserve");
+
+    for &size in SIZES {
+        group.bench_with_input(BenchmarkId::new("Vec", size), &size, |b, &size| {
+            b.iter(|| Vec::<u64>::with_capacity(size))
+        });
+    }
+
+    group.finish();
+
+    let mut group = c.benchmark_group("clear");
+
+    for &size in SIZES {
+        group.bench_with_input(BenchmarkId::new("Vec", size), &size, |b, &size| {
+            b.iter_batched_ref(|| vec![42u64; size], |vec| vec.clear(), BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
@@ -0,0 +1,15 @@
+//! The `Vec` baselines of the `vec` suite, with jemalloc as the global allocator.
+
+#[macro_use]
+extern crate criterion;
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+extern crate tikv_jemallocator;
+
+mod common;
+
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+criterion_group!(benches, common::bench_std_vec);
+criterion_main!(benches);
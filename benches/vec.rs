@@ -0,0 +1,169 @@
+//! Benchmarks of `VirtualVec` against `Vec` and a naive `mmap` buffer.
+//!
+//! Run with `cargo bench`. The `jemalloc` suite runs the `Vec` baselines again with
+//! jemalloc as the global allocator.
+
+#[macro_use]
+extern crate criterion;
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+extern crate libc;
+extern crate virtualalloc;
+
+use std::ptr::NonNull;
+
+use criterion::{BatchSize, BenchmarkId, Criterion, Throughput};
+use virtualalloc::{VirtualAlloc, VirtualVec};
+
+mod common;
+
+use common::{SIZES, bench_std_vec};
+
+
+/// A read-write buffer mapped at once, which lets the kernel commit pages on first touch.
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+struct NaiveMmap {
+    ptr: *mut u64,
+    len: usize,
+    max: usize
+}
+
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+impl NaiveMmap {
+    fn new(max: usize) -> Self {
+        let size = max * 8;
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANON;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), size, prot, flags, -1, 0) };
+
+        assert_ne!(ptr, libc::MAP_FAILED);
+
+        NaiveMmap { ptr: ptr as *mut u64, len: 0, max }
+    }
+
+    #[inline]
+    fn push(&mut self, value: u64) {
+        assert!(self.len < self.max);
+
+        unsafe { self.ptr.add(self.len).write(value) };
+
+        self.len += 1;
+    }
+}
+
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+impl Drop for NaiveMmap {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr as _, self.max * 8) };
+    }
+}
+
+fn push(c: &mut Criterion) {
+    let mut group = c.benchmark_group("push");
+
+    for &size in SIZES {
+        group.throughput(Throughput::Elements(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("VirtualVec", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut vec = VirtualVec::<u64>::new(size);
+
+                for i in 0..size as u64 {
+                    vec.push(i);
+                }
+
+                vec
+            })
+        });
+
+        #[cfg(not(any(windows, target_arch = "wasm32")))]
+        group.bench_with_input(BenchmarkId::new("mmap", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut buf = NaiveMmap::new(size);
+
+                for i in 0..size as u64 {
+                    buf.push(i);
+                }
+
+                buf
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn extend(c: &mut Criterion) {
+    let mut group = c.benchmark_group("extend");
+
+    for &size in SIZES {
+        let values = (0..size as u64).collect::<Vec<_>>();
+
+        group.throughput(Throughput::Bytes(size as u64 * 8));
+        group.bench_with_input(BenchmarkId::new("VirtualVec", size), &values, |b, values| {
+            b.iter(|| {
+                let mut vec = VirtualVec::<u64>::new(values.len());
+
+                vec.extend_from_slice(values);
+                vec
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn reserve(c: &mut Criterion) {
+    let mut group = c.benchmark_group("reserve");
+
+    for &size in SIZES {
+        group.bench_with_input(BenchmarkId::new("VirtualVec", size), &size, |b, &size| {
+            b.iter(|| {
+                let mut vec = VirtualVec::<u64>::new(size);
+
+                vec.reserve(size).unwrap();
+                vec
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn protect(c: &mut Criterion) {
+    let mut group = c.benchmark_group("protect");
+
+    for &size in SIZES {
+        let vec = VirtualVec::<u64>::with_capacity(size, size);
+        let ptr = NonNull::new(vec.as_ptr() as *mut u64).unwrap();
+        let len = vec.capacity() * 8;
+
+        group.bench_with_input(BenchmarkId::new("VirtualVec", size), &len, |b, &len| {
+            b.iter(|| {
+                VirtualAlloc::set_protection(ptr, len, true, false, false).unwrap();
+                VirtualAlloc::set_protection(ptr, len, true, true, false).unwrap();
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn clear(c: &mut Criterion) {
+    let mut group = c.benchmark_group("clear");
+
+    for &size in SIZES {
+        group.bench_with_input(BenchmarkId::new("VirtualVec", size), &size, |b, &size| {
+            b.iter_batched_ref(|| {
+                let mut vec = VirtualVec::<u64>::new(size);
+
+                vec.extend_with(size, 42);
+                vec
+            }, |vec| vec.clear(), BatchSize::LargeInput)
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, push, extend, reserve, protect, clear, bench_std_vec);
+criterion_main!(benches);
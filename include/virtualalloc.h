@@ -0,0 +1,53 @@
+/* C interface to virtualalloc, available when the crate is built with the `ffi` feature. */
+
+#ifndef VIRTUALALLOC_H
+#define VIRTUALALLOC_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define VIRTUALALLOC_OK                 0
+#define VIRTUALALLOC_RESERVATION_FAILED 1
+#define VIRTUALALLOC_COMMIT_FAILED      2
+#define VIRTUALALLOC_DECOMMIT_FAILED    3
+#define VIRTUALALLOC_EXCEEDS_MAX        4
+#define VIRTUALALLOC_PROTECT_FAILED     5
+#define VIRTUALALLOC_WX_VIOLATION       6
+#define VIRTUALALLOC_UNSUPPORTED        7
+#define VIRTUALALLOC_OTHER              8
+#define VIRTUALALLOC_INVALID_ARGUMENT   9
+
+/* A byte vector whose contents never move. */
+typedef struct VirtualAllocVec VirtualAllocVec;
+
+int32_t virtualalloc_vec_create(size_t max, VirtualAllocVec **out);
+int32_t virtualalloc_vec_reserve(VirtualAllocVec *vec, size_t additional);
+int32_t virtualalloc_vec_push_bytes(VirtualAllocVec *vec, const uint8_t *bytes, size_t len);
+uint8_t *virtualalloc_vec_data(VirtualAllocVec *vec);
+size_t virtualalloc_vec_len(const VirtualAllocVec *vec);
+int32_t virtualalloc_vec_protect(VirtualAllocVec *vec, bool read, bool write, bool exec);
+void virtualalloc_vec_destroy(VirtualAllocVec *vec);
+
+/* A raw reservation of address space. */
+typedef struct VirtualAllocRegion {
+    uint8_t *ptr;
+    size_t size;
+    size_t committed;
+} VirtualAllocRegion;
+
+int32_t virtualalloc_region_create(size_t size, VirtualAllocRegion *out);
+int32_t virtualalloc_region_commit(VirtualAllocRegion *region, size_t size);
+int32_t virtualalloc_region_protect(const VirtualAllocRegion *region, size_t offset, size_t size,
+                                    bool read, bool write, bool exec);
+void virtualalloc_region_destroy(VirtualAllocRegion *region);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,366 @@
+//! An append-only log of records stored in a memory-mapped file.
+
+use std::io;
+use std::path::Path;
+use std::slice;
+
+use super::file_mapping::FileMapping;
+use super::header::{self, FileHeader};
+
+
+/// The size of the header of each record, which holds its length and checksum.
+const HEADER_SIZE: usize = 8;
+
+/// The alignment of records in the log.
+const RECORD_ALIGN: usize = 8;
+
+/// The header at the start of the file of a log, after which records are stored.
+const FILE_HEADER: FileHeader = FileHeader {
+    kind: "append log",
+    magic: *b"VVLOGREC",
+    version: 1,
+    elem_size: 1,
+    elem_align: RECORD_ALIGN
+};
+
+/// An append-only log of records, stored in a file that is mapped in memory.
+///
+/// Records are appended with `append`, which copies them into the mapping, and are made
+/// durable with `sync`, which flushes the mapping up to the end of the log. Each record
+/// is preceded by its length and a checksum, so that reopening a log after a crash
+/// recovers every record up to the first one that was not completely written. The file
+/// starts with a header identifying it as a log, and opening any other file fails.
+///
+/// The file grows as records are appended, up to the maximum size given when the log is
+/// opened. On Unix, the whole maximum size is reserved upfront, and records never move;
+/// on Windows, the file is mapped again when it grows, and records may move.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::AppendLog;
+///
+/// let mut log = AppendLog::open("events.log", 1 << 30).unwrap();
+///
+/// log.append(b"first").unwrap();
+/// log.append(b"second").unwrap();
+/// log.sync().unwrap();
+///
+/// assert_eq!(log.iter().collect::<Vec<_>>(), [&b"first"[..], &b"second"[..]]);
+/// ```
+pub struct AppendLog {
+    mapping: FileMapping,
+    /// The number of bytes of valid records.
+    len: usize,
+    /// The number of bytes known to be durable.
+    synced: usize
+}
+
+// The mapping is only modified through `&mut self`.
+unsafe impl Send for AppendLog {}
+unsafe impl Sync for AppendLog {}
+
+/// An iterator over the records of an `AppendLog`, returned by `AppendLog::iter`.
+pub struct AppendLogIter<'a> {
+    log: &'a AppendLog,
+    offset: usize
+}
+
+/// Returns the FNV-1a hash of the length and contents of a record.
+fn checksum(record: &[u8]) -> u32 {
+    (record.len() as u32).to_le_bytes().iter().chain(record)
+        .fold(0x811c_9dc5, |hash, &b| (hash ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// Returns the space taken by a record of `len` bytes, including its header, or `None`
+/// if it overflows.
+#[inline]
+fn record_size(len: usize) -> Option<usize> {
+    len.checked_next_multiple_of(RECORD_ALIGN)?.checked_add(HEADER_SIZE)
+}
+
+impl AppendLog {
+    /// Opens the log stored in the file at the given path, creating it if it does not
+    /// exist, and recovers its valid records. The file can grow up to `max` bytes.
+    ///
+    /// Fails with `InvalidData` if the file is not a log, or was written by another version.
+    pub fn open<P: AsRef<Path>>(path: P, max: usize) -> io::Result<Self> {
+        let mut log = AppendLog { mapping: FileMapping::open(path, max)?, len: 0, synced: 0 };
+
+        if log.mapping.mapped() == 0 {
+            log.mapping.grow(header::SIZE)?;
+
+            unsafe {
+                FILE_HEADER.write(slice::from_raw_parts_mut(log.mapping.base(), header::SIZE));
+            }
+        }
+
+        let mapped = unsafe { slice::from_raw_parts(log.mapping.base(), log.mapping.mapped()) };
+
+        FILE_HEADER.validate(mapped)?;
+
+        log.len = log.recover();
+        log.synced = log.len;
+        log.terminate();
+
+        Ok(log)
+    }
+
+    /// Returns the number of bytes taken by the records of the log, including their headers.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the log has no records.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum size of the log, excluding the header of its file.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.mapping.max().saturating_sub(header::SIZE)
+    }
+
+    /// Appends a record to the log, and returns its offset.
+    ///
+    /// The record is not durable until `sync` is called. Fails with `InvalidInput` if the
+    /// record is 4GB or longer, since its header stores its length on 32 bits.
+    pub fn append(&mut self, record: &[u8]) -> io::Result<usize> {
+        let offset = self.len;
+        let too_large = || io::Error::new(io::ErrorKind::InvalidInput, "record too large");
+
+        if record.len() > u32::MAX as usize {
+            return Err(too_large())
+        }
+
+        let end = record_size(record.len())
+            .and_then(|size| offset.checked_add(size))
+            .ok_or_else(too_large)?;
+
+        // Records are always followed by an empty header, which marks the end of the log.
+        self.mapping.grow(header::SIZE + end + HEADER_SIZE)?;
+
+        unsafe {
+            let header = self.records().add(offset);
+
+            header.add(HEADER_SIZE).copy_from_nonoverlapping(record.as_ptr(), record.len());
+            header.copy_from_nonoverlapping((record.len() as u32).to_le_bytes().as_ptr(), 4);
+            header.add(4).copy_from_nonoverlapping(checksum(record).to_le_bytes().as_ptr(), 4);
+        }
+
+        self.len = end;
+        self.terminate();
+
+        Ok(offset)
+    }
+
+    /// Makes all records appended so far durable, by flushing the mapping to the file.
+    pub fn sync(&mut self) -> io::Result<()> {
+        self.mapping.flush(header::SIZE + self.synced, header::SIZE + self.len)?;
+        self.synced = self.len;
+
+        Ok(())
+    }
+
+    /// Returns the record at the given offset, or `None` if there is no valid record there.
+    #[inline]
+    pub fn get(&self, offset: usize) -> Option<&[u8]> {
+        self.read(offset, self.len).map(|(record, _)| record)
+    }
+
+    /// Returns an iterator over the records of the log.
+    #[inline]
+    pub fn iter(&self) -> AppendLogIter<'_> {
+        AppendLogIter { log: self, offset: 0 }
+    }
+
+    /// Returns the record at the given offset and the offset of the next one, if it is
+    /// valid and ends before `end`.
+    fn read(&self, offset: usize, end: usize) -> Option<(&[u8], usize)> {
+        if !offset.is_multiple_of(RECORD_ALIGN) || offset.saturating_add(HEADER_SIZE) > end {
+            return None
+        }
+
+        unsafe {
+            let base = self.records();
+            let header = slice::from_raw_parts(base.add(offset), HEADER_SIZE);
+            let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let sum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+            // An empty header marks the end of the log.
+            let next = match record_size(len).and_then(|size| offset.checked_add(size)) {
+                Some(next) if next <= end && (len != 0 || sum != 0) => next,
+                _ => return None
+            };
+
+            let record = slice::from_raw_parts(base.add(offset + HEADER_SIZE), len);
+
+            if checksum(record) == sum {
+                Some((record, next))
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Returns the end of the last valid record of the mapping, which is where the log
+    /// ends after a crash.
+    fn recover(&self) -> usize {
+        let mut offset = 0;
+
+        while let Some((_, next)) = self.read(offset, self.mapping.mapped() - header::SIZE) {
+            offset = next;
+        }
+
+        offset
+    }
+
+    /// Clears the header that follows the last record, if it is mapped.
+    fn terminate(&mut self) {
+        if header::SIZE + self.len + HEADER_SIZE <= self.mapping.mapped() {
+            unsafe {
+                self.records().add(self.len).write_bytes(0, HEADER_SIZE);
+            }
+        }
+    }
+
+    /// Returns a pointer to the first record, after the header of the file.
+    #[inline]
+    fn records(&self) -> *mut u8 {
+        unsafe { self.mapping.base().add(header::SIZE) }
+    }
+}
+
+impl<'a> Iterator for AppendLogIter<'a> {
+    type Item = &'a [u8];
+
+    fn next(&mut self) -> Option<&'a [u8]> {
+        let (record, next) = self.log.read(self.offset, self.log.len)?;
+
+        self.offset = next;
+
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+
+    use VirtualAlloc;
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("virtualalloc-{}-{}.log", name, std::process::id()))
+    }
+
+    #[test]
+    fn appends_and_reopens() {
+        let path = temp_path("reopen");
+        let mut log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        assert!(log.is_empty());
+
+        let first = log.append(b"first").unwrap();
+        let large = vec![7; VirtualAlloc::page_size() * 3];
+        let second = log.append(&large).unwrap();
+
+        log.append(b"").unwrap();
+        log.sync().unwrap();
+
+        assert_eq!(log.get(first), Some(&b"first"[..]));
+        assert_eq!(log.get(second), Some(&large[..]));
+        assert_eq!(log.get(first + 1), None);
+
+        let len = log.len();
+
+        drop(log);
+
+        let mut log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        assert_eq!(log.len(), len);
+        assert_eq!(log.iter().collect::<Vec<_>>(), [&b"first"[..], &large[..], &b""[..]]);
+
+        log.append(b"last").unwrap();
+
+        assert_eq!(log.iter().last(), Some(&b"last"[..]));
+        assert!(log.append(&vec![0; 1 << 20]).is_err());
+
+        drop(log);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn recovers_after_torn_writes() {
+        let path = temp_path("recover");
+        let mut log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        log.append(b"kept").unwrap();
+
+        let torn = log.append(b"torn record").unwrap();
+
+        log.append(b"lost").unwrap();
+        log.sync().unwrap();
+        drop(log);
+
+        // Corrupt the payload of the second record, as if it was only partially written.
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+
+        file.seek(SeekFrom::Start((header::SIZE + torn + HEADER_SIZE + 2) as u64)).unwrap();
+        file.write_all(b"?").unwrap();
+        drop(file);
+
+        let mut log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        assert_eq!(log.len(), torn);
+        assert_eq!(log.iter().collect::<Vec<_>>(), [&b"kept"[..]]);
+
+        // Records appended after recovery must not be followed by the stale ones.
+        log.append(b"new").unwrap();
+        log.sync().unwrap();
+        drop(log);
+
+        let log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        assert_eq!(log.iter().collect::<Vec<_>>(), [&b"kept"[..], &b"new"[..]]);
+
+        drop(log);
+
+        // Other files are not mistaken for logs.
+        fs::write(&path, [0; 128]).unwrap();
+
+        assert_eq!(AppendLog::open(&path, 1 << 20).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::InvalidData));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[cfg(all(any(target_os = "linux", target_os = "android"), target_pointer_width = "64"))]
+    #[test]
+    fn refuses_records_whose_length_does_not_fit_in_their_header() {
+        use VirtualBacking;
+
+        let path = temp_path("oversized");
+        let mut log = AppendLog::open(&path, 1 << 20).unwrap();
+
+        // Demand-paged memory is readable without being backed until it is touched.
+        let len = u32::MAX as usize + 1;
+        let alloc = VirtualAlloc::new(len).demand_paged();
+        let ptr = alloc.reserve(len).unwrap();
+        let record = unsafe { slice::from_raw_parts(ptr.as_ptr(), len) };
+
+        assert_eq!(log.append(record).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::InvalidInput));
+        assert!(log.is_empty());
+
+        unsafe { alloc.release(ptr, len) };
+        drop(log);
+        fs::remove_file(&path).unwrap();
+    }
+}
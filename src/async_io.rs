@@ -0,0 +1,92 @@
+//! Implementations of the `tokio` IO traits, enabled by the `tokio` and `std` features.
+//!
+//! `VirtualVec<u8>` implements `AsyncWrite`, and `VirtualCursor` implements `AsyncRead`.
+//! Neither ever waits for IO, but committing memory is a system call that may take a
+//! while for large sizes, so a single `poll_write` commits at most `MAX_COMMIT_PER_POLL`
+//! bytes, returning a short write and letting the runtime schedule other tasks before
+//! the rest of the buffer is written.
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::{VirtualBacking, VirtualCursor, VirtualVec};
+
+
+/// The maximum number of bytes committed by a single call to `poll_write`.
+pub const MAX_COMMIT_PER_POLL: usize = 1 << 20;
+
+impl<B: VirtualBacking + Unpin> AsyncWrite for VirtualVec<u8, B> {
+    fn poll_write(self: Pin<&mut Self>, _: &mut Context, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let vec = self.get_mut();
+        let spare = vec.capacity() - vec.len();
+        let len = if buf.len() <= spare {
+            buf.len()
+        } else {
+            // Writes that fit in committed memory never get split.
+            buf.len().min(spare.saturating_add(MAX_COMMIT_PER_POLL))
+        };
+
+        Poll::Ready(io::Write::write(vec, &buf[..len]))
+    }
+
+    #[inline]
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    #[inline]
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<'a, B: VirtualBacking> AsyncRead for VirtualCursor<'a, B> {
+    fn poll_read(self: Pin<&mut Self>, _: &mut Context, buf: &mut ReadBuf)
+        -> Poll<io::Result<()>> {
+        let cursor = self.get_mut();
+
+        buf.put_slice(cursor.read_slice(buf.remaining()));
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::task::Waker;
+
+    #[test]
+    fn splits_writes_that_commit_too_much_memory() {
+        let mut vec = VirtualVec::<u8>::new(MAX_COMMIT_PER_POLL * 4);
+        let mut cx = Context::from_waker(Waker::noop());
+        let buf = vec![1; MAX_COMMIT_PER_POLL * 2];
+
+        match Pin::new(&mut vec).poll_write(&mut cx, &buf) {
+            Poll::Ready(Ok(len)) => assert_eq!(len, MAX_COMMIT_PER_POLL),
+            _ => panic!("Expected write to succeed.")
+        }
+
+        assert_eq!(vec.len(), MAX_COMMIT_PER_POLL);
+    }
+
+    #[test]
+    fn reads_from_cursors() {
+        let mut vec = VirtualVec::<u8>::new(1024);
+
+        vec.extend_from_slice(b"hello");
+
+        let mut cursor = vec.cursor();
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut bytes = [0; 3];
+        let mut buf = ReadBuf::new(&mut bytes);
+
+        assert!(Pin::new(&mut cursor).poll_read(&mut cx, &mut buf).is_ready());
+        assert_eq!(buf.filled(), b"hel");
+        assert_eq!(cursor.position(), 3);
+    }
+}
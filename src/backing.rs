@@ -0,0 +1,218 @@
+//! The interface between containers and the memory they are built upon.
+//!
+//! Containers such as `VirtualVec` never call into the operating system directly, and
+//! instead go through a `VirtualBacking`. `VirtualAlloc` is the backing that reserves
+//! memory from the operating system, and is used by default; other backings can provide
+//! memory from elsewhere, such as the memory of a hypervisor guest, or a test double.
+
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ptr::NonNull;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))] use std::ptr;
+
+#[cfg(not(feature = "std"))] use core::ptr::NonNull;
+
+use super::{VirtualAlloc, VirtualMemError};
+use super::hooks;
+use super::trace::traced;
+
+
+/// A source of virtual memory that can be reserved upfront and committed incrementally.
+///
+/// # Safety
+/// Containers rely on the following guarantees, which implementations must uphold:
+///
+/// - A reservation never moves, and stays valid until it is released.
+/// - Once committed, memory is readable and writable (unless the protection of the
+///   backing states otherwise), and reads as zero until written to.
+/// - Committing memory that is already committed succeeds and preserves its contents.
+/// - Decommitted memory reads as zero once committed again.
+pub unsafe trait VirtualBacking {
+    /// Reserves `size` bytes of address space, without committing any of it.
+    fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError>;
+
+    /// Commits `size` bytes starting at `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` must lie within a reservation of this backing, and `size` may not exceed the
+    /// size of the reservation past `ptr`.
+    unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError>;
+
+    /// Returns `size` bytes of committed memory starting at `ptr` to the backing, keeping
+    /// their address space reserved.
+    ///
+    /// # Safety
+    /// `ptr` and `size` must be page-aligned, and lie within a reservation of this backing.
+    unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError>;
+
+    /// Sets the protection of `size` bytes of committed memory starting at `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` and `size` must lie within committed memory of this backing.
+    unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError>;
+
+    /// Releases a reservation of `size` bytes starting at `ptr`.
+    ///
+    /// # Safety
+    /// `ptr` and `size` must have been passed to and returned by `reserve` on this backing,
+    /// and the memory may not be accessed afterwards.
+    unsafe fn release(&self, ptr: NonNull<u8>, size: usize);
+
+    /// Returns the granularity at which memory can be committed and protected.
+    fn page_size(&self) -> usize;
+
+    /// Returns the label under which containers built upon the backing are listed by
+    /// `VirtualAlloc::live_regions`, unless they are given one of their own.
+    #[inline]
+    fn label(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+unsafe impl VirtualBacking for VirtualAlloc {
+    #[inline]
+    fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+        let result = traced("reserve", size, || {
+            self.reserve_bound(size)
+        });
+
+        #[cfg(feature = "std")]
+        let result = ::oom::check("reserve", size, result);
+
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if let (Ok(ptr), true) = (result, self.commits_on_fault()) {
+            // The protection to commit pages with is smuggled through the data pointer.
+            let prot = ptr::without_provenance(self.prot as usize);
+
+            // The range is unregistered when the reservation is released.
+            match unsafe { ::trap::register(ptr.as_ptr(), size, commit_page, prot) } {
+                Ok(trap) => mem::forget(trap),
+                Err(err) => {
+                    unsafe { VirtualAlloc::release(ptr.as_ptr(), size) };
+
+                    return Err(err)
+                }
+            }
+        }
+
+        result
+    }
+
+    #[inline]
+    unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if self.commits_on_fault() {
+            return Ok(())
+        }
+
+        let result = traced("commit", size, || self.grow(ptr.as_ptr(), size, self.prot as _));
+
+        #[cfg(feature = "std")]
+        let result = ::oom::check("commit", size, result);
+
+        result?;
+        hooks::committed(ptr.as_ptr(), size);
+
+        Ok(())
+    }
+
+    #[inline]
+    unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        let size = ::round_to_page(size);
+
+        traced("decommit", size, || {
+            // Decommitted demand-paged memory must remain accessible, since it is never
+            // committed again explicitly.
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            if self.is_demand_paged() {
+                return VirtualAlloc::discard(ptr.as_ptr(), size)
+            }
+
+            VirtualAlloc::decommit(ptr.as_ptr(), size, self.prot)
+        })?;
+        hooks::decommitted(ptr.as_ptr(), size);
+
+        Ok(())
+    }
+
+    #[inline]
+    unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        traced("protect", size, || VirtualAlloc::set_protection(ptr, size, read, write, exec))?;
+        hooks::protected(ptr.as_ptr(), size, read, write, exec);
+
+        Ok(())
+    }
+
+    #[inline]
+    unsafe fn release(&self, ptr: NonNull<u8>, size: usize) {
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if self.commits_on_fault() {
+            ::trap::unregister(ptr.as_ptr());
+        }
+
+        let _ = traced("release", size, || {
+            VirtualAlloc::release(ptr.as_ptr(), size);
+
+            Ok(())
+        });
+    }
+
+    #[inline]
+    fn page_size(&self) -> usize {
+        VirtualAlloc::page_size()
+    }
+
+    #[inline]
+    fn label(&self) -> Option<&'static str> {
+        self.label
+    }
+}
+
+/// Commits the page containing a faulting address of a reservation made by an allocator
+/// that commits memory on fault, with the protection given as data.
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+unsafe fn commit_page(prot: *const (), addr: *mut u8) -> bool {
+    let page = VirtualAlloc::page_size();
+    let start = addr.wrapping_sub(addr.addr() % page);
+
+    VirtualAlloc::commit(start as _, page, prot.addr() as _).is_ok()
+}
+
+unsafe impl<B: VirtualBacking + ?Sized> VirtualBacking for &B {
+    #[inline]
+    fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+        (**self).reserve(size)
+    }
+
+    #[inline]
+    unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        (**self).commit(ptr, size)
+    }
+
+    #[inline]
+    unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        (**self).decommit(ptr, size)
+    }
+
+    #[inline]
+    unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        (**self).protect(ptr, size, read, write, exec)
+    }
+
+    #[inline]
+    unsafe fn release(&self, ptr: NonNull<u8>, size: usize) {
+        (**self).release(ptr, size)
+    }
+
+    #[inline]
+    fn page_size(&self) -> usize {
+        (**self).page_size()
+    }
+
+    #[inline]
+    fn label(&self) -> Option<&'static str> {
+        (**self).label()
+    }
+}
@@ -0,0 +1,101 @@
+//! Implementations of the `bytes` traits, enabled by the `bytes` feature.
+//!
+//! `VirtualVec<u8>` implements `BufMut`, which lets networking code write straight into
+//! its spare capacity, and `VirtualCursor` implements `Buf` to read it back.
+
+#[cfg(feature = "std")] use std::cmp;
+
+#[cfg(not(feature = "std"))] use core::cmp;
+
+use bytes::{Buf, BufMut};
+use bytes::buf::UninitSlice;
+
+use super::{VirtualBacking, VirtualCursor, VirtualVec};
+
+
+/// The minimum number of bytes committed when `chunk_mut` runs out of capacity.
+const MIN_CHUNK: usize = 64;
+
+/// Writes go to the spare capacity of the vector, which is committed on demand by
+/// `chunk_mut`.
+///
+/// # Panics
+/// `chunk_mut` panics if memory could not be committed.
+unsafe impl<B: VirtualBacking> BufMut for VirtualVec<u8, B> {
+    #[inline]
+    fn remaining_mut(&self) -> usize {
+        self.max_capacity() - self.len()
+    }
+
+    #[inline]
+    unsafe fn advance_mut(&mut self, cnt: usize) {
+        assert!(cnt <= self.capacity() - self.len(), "cannot advance past the committed capacity");
+
+        self.set_len(self.len() + cnt);
+    }
+
+    fn chunk_mut(&mut self) -> &mut UninitSlice {
+        if self.capacity() == self.len() {
+            self.reserve_or_panic(cmp::min(MIN_CHUNK, self.remaining_mut()));
+        }
+
+        let spare = self.capacity() - self.len();
+
+        unsafe {
+            UninitSlice::from_raw_parts_mut(self.as_mut_ptr().add(self.len()), spare)
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> Buf for VirtualCursor<'a, B> {
+    #[inline]
+    fn remaining(&self) -> usize {
+        self.remaining_slice().len()
+    }
+
+    #[inline]
+    fn chunk(&self) -> &[u8] {
+        self.remaining_slice()
+    }
+
+    #[inline]
+    fn advance(&mut self, cnt: usize) {
+        assert!(cnt <= self.remaining(), "cannot advance past the end of the vector");
+
+        let pos = self.position();
+
+        self.set_position(pos + cnt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_write_and_read_back() {
+        let mut vec = VirtualVec::<u8>::new(1024);
+
+        vec.put_u32(0xdead_beef);
+        vec.put_slice(&[1; 100]);
+
+        assert_eq!(vec.len(), 104);
+
+        let mut cursor = vec.cursor();
+
+        assert_eq!(cursor.get_u32(), 0xdead_beef);
+        assert_eq!(cursor.remaining(), 100);
+
+        cursor.advance(99);
+
+        assert_eq!(cursor.get_u8(), 1);
+    }
+
+    #[test]
+    fn cannot_write_past_the_maximum_capacity() {
+        let mut vec = VirtualVec::<u8>::new(4);
+
+        assert_eq!(vec.remaining_mut(), 4);
+        assert_eq!(vec.chunk_mut().len(), 4);
+    }
+}
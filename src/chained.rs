@@ -0,0 +1,261 @@
+//! A vector spread over several reservations when a single one cannot be obtained.
+
+use std::mem;
+use std::ops::{Index, IndexMut};
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError, VirtualVec};
+
+
+/// The most segments a `ChainedVirtualVec` is split into before giving up.
+const MAX_SEGMENTS: usize = 1 << 16;
+
+/// A vector that reserves its maximum size in a single reservation if it can, and in
+/// several smaller ones otherwise, such as in a 32-bit process whose address space is
+/// fragmented.
+///
+/// The reservations, or segments, all hold the same number of elements, which is a power
+/// of two, so that finding the segment of an element is a shift and a mask, like looking
+/// up a page table. All segments are reserved upfront, so that the vector can always grow
+/// to its maximum length, and elements never move, like in a `VirtualVec`. Only elements
+/// in the same segment are contiguous, and `segments` returns them as slices.
+///
+/// # Example
+/// ```
+/// use virtualalloc::ChainedVirtualVec;
+///
+/// let mut vec = ChainedVirtualVec::<u64>::new(1 << 20);
+///
+/// vec.push(1);
+/// vec.push(2);
+///
+/// assert_eq!(vec[1], 2);
+/// assert_eq!(vec.iter().sum::<u64>(), 3);
+/// ```
+pub struct ChainedVirtualVec<T, B: VirtualBacking = VirtualAlloc> {
+    segments: Vec<VirtualVec<T, B>>,
+    /// The base 2 logarithm of the number of elements of each segment.
+    shift: u32,
+    len: usize,
+    max: usize
+}
+
+impl<T> ChainedVirtualVec<T> {
+    /// Returns a vector that can hold up to `max` elements.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved, even in several reservations.
+    pub fn new(max: usize) -> Self {
+        Self::try_new(max).unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a vector that can hold up to `max` elements, or an error if the memory could
+    /// not be reserved, even in several reservations.
+    pub fn try_new(max: usize) -> Result<Self, VirtualMemError> {
+        ChainedVirtualVec::reserve_segments(max, |len| {
+            VirtualVec::try_new(len)
+        })
+    }
+}
+
+impl<T, B: VirtualBacking + Clone> ChainedVirtualVec<T, B> {
+    /// Returns a vector that can hold up to `max` elements in memory provided by clones of
+    /// the given backing, one per segment, or an error if the memory could not be
+    /// reserved, even in several reservations.
+    pub fn try_with_backing(max: usize, backing: B) -> Result<Self, VirtualMemError> {
+        ChainedVirtualVec::reserve_segments(max, |len| {
+            VirtualVec::try_with_backing(len, backing.clone())
+        })
+    }
+}
+
+impl<T, B: VirtualBacking> ChainedVirtualVec<T, B> {
+    /// Reserves the segments of a vector of up to `max` elements, halving their length
+    /// every time one of them cannot be reserved.
+    fn reserve_segments<F>(max: usize, mut reserve: F) -> Result<Self, VirtualMemError>
+        where F: FnMut(usize) -> Result<VirtualVec<T, B>, VirtualMemError> {
+        let elem_size = mem::size_of::<T>().max(1);
+        let max_shift = max.max(1).next_power_of_two().trailing_zeros();
+        // Segments are never smaller than a page.
+        let min_shift = (VirtualAlloc::page_size() / elem_size).max(1).next_power_of_two()
+            .trailing_zeros().min(max_shift);
+        let mut shift = max_shift;
+
+        loop {
+            let count = max.div_ceil(1 << shift).max(1);
+            let segments = (0..count).map(|_| reserve(1 << shift)).collect();
+
+            match segments {
+                Ok(segments) => return Ok(ChainedVirtualVec { segments, shift, len: 0, max }),
+                Err(err) if shift == min_shift || count * 2 > MAX_SEGMENTS => return Err(err),
+                Err(_) => shift -= 1
+            }
+        }
+    }
+
+    /// Returns the number of elements of the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the vector has no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of elements of the vector.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.max
+    }
+
+    /// Returns the number of reservations the vector is spread over.
+    #[inline]
+    pub fn segment_count(&self) -> usize {
+        self.segments.len()
+    }
+
+    /// Returns the number of elements of each reservation.
+    #[inline]
+    pub fn segment_len(&self) -> usize {
+        1 << self.shift
+    }
+
+    /// Returns whether all elements are in a single reservation.
+    #[inline]
+    pub fn is_contiguous(&self) -> bool {
+        self.segments.len() == 1
+    }
+
+    /// Appends an element to the back of the vector.
+    ///
+    /// # Panics
+    /// Panics if the vector is full, or if memory could not be committed.
+    pub fn push(&mut self, value: T) {
+        assert!(self.len < self.max, "ChainedVirtualVec is full");
+
+        self.segments[self.len >> self.shift].push(value);
+        self.len += 1;
+    }
+
+    /// Removes the last element of the vector and returns it, or `None` if it is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None
+        }
+
+        self.len -= 1;
+        self.segments[self.len >> self.shift].pop()
+    }
+
+    /// Returns the element at the given index, or `None` if it is out of bounds.
+    #[inline]
+    pub fn get(&self, index: usize) -> Option<&T> {
+        if index >= self.len {
+            return None
+        }
+
+        self.segments[index >> self.shift].get(index & (self.segment_len() - 1))
+    }
+
+    /// Returns the element at the given index mutably, or `None` if it is out of bounds.
+    #[inline]
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        if index >= self.len {
+            return None
+        }
+
+        let mask = self.segment_len() - 1;
+
+        self.segments[index >> self.shift].get_mut(index & mask)
+    }
+
+    /// Returns the elements of the vector, as one slice per non-empty segment.
+    pub fn segments(&self) -> impl Iterator<Item = &[T]> {
+        self.segments.iter().map(|segment| segment.as_slice()).take_while(|s| !s.is_empty())
+    }
+
+    /// Returns an iterator over the elements of the vector.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.segments().flatten()
+    }
+
+    /// Removes all elements from the vector, keeping the memory they used committed.
+    pub fn clear(&mut self) {
+        for segment in &mut self.segments {
+            segment.clear();
+        }
+
+        self.len = 0;
+    }
+}
+
+impl<T, B: VirtualBacking> Index<usize> for ChainedVirtualVec<T, B> {
+    type Output = T;
+
+    #[inline]
+    fn index(&self, index: usize) -> &T {
+        match self.get(index) {
+            Some(value) => value,
+            None => panic!("index {} out of bounds for length {}", index, self.len)
+        }
+    }
+}
+
+impl<T, B: VirtualBacking> IndexMut<usize> for ChainedVirtualVec<T, B> {
+    #[inline]
+    fn index_mut(&mut self, index: usize) -> &mut T {
+        let len = self.len;
+
+        match self.get_mut(index) {
+            Some(value) => value,
+            None => panic!("index {} out of bounds for length {}", index, len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mock::{MockBacking, Op};
+
+    #[test]
+    fn falls_back_to_several_reservations() {
+        let page = VirtualAlloc::page_size();
+        let backing = MockBacking::new();
+        let err = VirtualMemError::ReservationFailed { os_err: 12 };
+
+        // Neither a single reservation, nor the first of two, can be obtained.
+        backing.fail_nth(Op::Reserve, 0, err);
+        backing.fail_nth(Op::Reserve, 1, err);
+
+        let mut vec = ChainedVirtualVec::<u8, _>::try_with_backing(page * 4, &backing).unwrap();
+
+        assert_eq!((vec.segment_count(), vec.segment_len()), (4, page));
+        assert!(!vec.is_contiguous());
+
+        for i in 0..page * 4 {
+            vec.push(i as u8);
+        }
+
+        let first = &vec[0] as *const u8;
+
+        assert_eq!(vec.get(page * 3 + 1), Some(&1));
+        assert_eq!(vec.segments().map(|segment| segment.len()).collect::<Vec<_>>(), [page; 4]);
+        assert_eq!(vec.pop(), Some((page * 4 - 1) as u8));
+
+        vec[page] = 42;
+        vec.push(7);
+
+        assert_eq!(vec.iter().filter(|&&b| b == 42).count(), page * 4 / 256 + 1);
+        assert_eq!(&vec[0] as *const u8, first);
+        assert!(vec.get(page * 4).is_none());
+
+        vec.clear();
+
+        assert!(vec.is_empty());
+        assert!(ChainedVirtualVec::<u32>::new(1000).is_contiguous());
+    }
+}
@@ -0,0 +1,602 @@
+//! A single-producer single-consumer channel between processes, over named shared memory,
+//! on Linux.
+//!
+//! The channel is a named shared memory object, which holds a page of header with the
+//! positions of both sides, followed by the bytes of a ring. Like the memory of a
+//! `SpscRing`, the ring is mapped twice in a row in each process, so that the producer
+//! writes payloads in place and the consumer reads them in place, as contiguous slices,
+//! without copying them through a pipe or a socket.
+//!
+//! Either side can sleep until the other side makes progress. Sleeping is done on a futex
+//! in the header, which the other side only wakes when someone is asleep, so that
+//! producing and consuming do not make system calls while both sides keep up.
+//!
+//! # Example
+//! ```no_run
+//! use virtualalloc::channel::SharedChannel;
+//!
+//! // In the producing process.
+//! let mut channel = SharedChannel::create("/frames", 1 << 20).unwrap();
+//! let mut producer = channel.producer();
+//!
+//! producer.write()[..5].copy_from_slice(b"hello");
+//! producer.produce(5);
+//!
+//! // In the consuming process.
+//! let mut channel = SharedChannel::open("/frames").unwrap();
+//! let mut consumer = channel.consumer();
+//!
+//! assert!(consumer.wait_readable(5, None));
+//! assert_eq!(&consumer.read()[..5], b"hello");
+//!
+//! consumer.consume(5);
+//! ```
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::{self, c_void};
+
+use super::VirtualAlloc;
+use super::ring::map_file_mirrored;
+
+
+/// The value of the first field of the header once it is initialized.
+const MAGIC: u64 = u64::from_le_bytes(*b"VVCHANL1");
+
+/// The header at the start of the shared memory object of a channel.
+#[repr(C)]
+struct Header {
+    /// `MAGIC` once the rest of the header is initialized.
+    magic: AtomicU64,
+    /// The size of the ring, in bytes.
+    size: AtomicU64,
+    /// The number of bytes consumed so far, wrapping around.
+    head: AtomicUsize,
+    /// The number of bytes produced so far, wrapping around.
+    tail: AtomicUsize,
+    /// Signaled when bytes are produced.
+    readable: Event,
+    /// Signaled when bytes are consumed.
+    writable: Event
+}
+
+/// A futex word that one side of the channel sleeps on, until the other side signals it.
+#[repr(C)]
+struct Event {
+    /// The number of times the event was signaled, wrapping around.
+    seq: AtomicU32,
+    /// The number of threads sleeping on the event.
+    sleepers: AtomicU32
+}
+
+/// A byte ring buffer shared by a producer and a consumer in different processes, which
+/// hands out contiguous slices.
+///
+/// A channel is created under a name by one process with `create`, and opened by another
+/// with `open`. Each process then uses one side of the channel, which it gets with
+/// `producer` or `consumer`. There must be at most one producer and one consumer at once,
+/// across all processes. Both processes must have the same pointer width.
+///
+/// The name is removed when the channel returned by `create` is dropped, while processes
+/// that opened it keep using the shared memory until they drop their own channel.
+pub struct SharedChannel {
+    header: NonNull<Header>,
+    ptr: NonNull<u8>,
+    size: usize,
+    /// The name to remove when the channel is dropped, if it was created by this process.
+    name: Option<CString>
+}
+
+// The producer and the consumer only write to the positions and bytes they own.
+unsafe impl Send for SharedChannel {}
+unsafe impl Sync for SharedChannel {}
+
+/// The producing side of a `SharedChannel`.
+pub struct ChannelProducer<'a> {
+    channel: &'a SharedChannel
+}
+
+/// The consuming side of a `SharedChannel`.
+pub struct ChannelConsumer<'a> {
+    channel: &'a SharedChannel
+}
+
+/// Returns an `InvalidData` error with the given message.
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Returns the given name as a C string, with the leading slash `shm_open` expects.
+fn c_name(name: &str) -> io::Result<CString> {
+    let name = match name.starts_with('/') {
+        true => name.to_owned(),
+        false => format!("/{}", name)
+    };
+
+    CString::new(name).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid name"))
+}
+
+impl SharedChannel {
+    /// Creates a channel under the given name, with a ring of at least `min_size` bytes,
+    /// rounded up to whole pages.
+    ///
+    /// Fails with `AlreadyExists` if another channel or shared memory object has the name.
+    pub fn create(name: &str, min_size: usize) -> io::Result<Self> {
+        let name = c_name(name)?;
+        let page = VirtualAlloc::page_size();
+        let size = ::round_to_page(min_size.max(1));
+
+        if size > isize::MAX as usize / 2 - page {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "channel too large"))
+        }
+
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(),
+                                    libc::O_RDWR | libc::O_CREAT | libc::O_EXCL | libc::O_CLOEXEC,
+                                    0o600);
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error())
+            }
+
+            let channel = match libc::ftruncate(fd, (page + size) as libc::off_t) {
+                0 => SharedChannel::map(fd, size),
+                _ => Err(io::Error::last_os_error())
+            };
+
+            // The mappings keep the object alive.
+            libc::close(fd);
+
+            match channel {
+                Ok(mut channel) => {
+                    let header = channel.header();
+
+                    header.size.store(size as u64, Ordering::Relaxed);
+                    header.magic.store(MAGIC, Ordering::Release);
+
+                    channel.name = Some(name);
+                    Ok(channel)
+                },
+                Err(err) => {
+                    libc::shm_unlink(name.as_ptr());
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Opens the channel with the given name, created by another process.
+    ///
+    /// Fails with `NotFound` if there is no such channel, and with `InvalidData` if the
+    /// shared memory object with the name is not a channel, or is not initialized yet.
+    pub fn open(name: &str) -> io::Result<Self> {
+        let name = c_name(name)?;
+        let page = VirtualAlloc::page_size();
+
+        unsafe {
+            let fd = libc::shm_open(name.as_ptr(), libc::O_RDWR | libc::O_CLOEXEC, 0);
+
+            if fd < 0 {
+                return Err(io::Error::last_os_error())
+            }
+
+            let mut stat: libc::stat = mem::zeroed();
+
+            let channel = match libc::fstat(fd, &mut stat) {
+                0 if (stat.st_size as u64) < 2 * page as u64 => {
+                    Err(invalid("not a shared channel"))
+                },
+                0 => SharedChannel::map(fd, stat.st_size as usize - page),
+                _ => Err(io::Error::last_os_error())
+            };
+
+            libc::close(fd);
+
+            let channel = channel?;
+            let header = channel.header();
+
+            if header.magic.load(Ordering::Acquire) != MAGIC ||
+                header.size.load(Ordering::Relaxed) != channel.size as u64 {
+                return Err(invalid("not a shared channel"))
+            }
+
+            Ok(channel)
+        }
+    }
+
+    /// Returns the number of bytes the channel can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of bytes that were produced but not consumed yet.
+    ///
+    /// The positions are stored in memory shared with another process, which may corrupt
+    /// them. The length is therefore capped to the capacity of the channel, so that the
+    /// slices returned by `read` and `write` never extend past the ring.
+    #[inline]
+    pub fn len(&self) -> usize {
+        let header = self.header();
+        let len = header.tail.load(Ordering::Acquire)
+            .wrapping_sub(header.head.load(Ordering::Acquire));
+
+        len.min(self.size)
+    }
+
+    /// Returns whether all produced bytes were consumed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the producing side of the channel.
+    #[inline]
+    pub fn producer(&mut self) -> ChannelProducer<'_> {
+        ChannelProducer { channel: self }
+    }
+
+    /// Returns the consuming side of the channel.
+    #[inline]
+    pub fn consumer(&mut self) -> ChannelConsumer<'_> {
+        ChannelConsumer { channel: self }
+    }
+
+    /// Splits the channel into its producer and its consumer, to use both sides in this
+    /// process.
+    #[inline]
+    pub fn split(&mut self) -> (ChannelProducer<'_>, ChannelConsumer<'_>) {
+        (ChannelProducer { channel: self }, ChannelConsumer { channel: self })
+    }
+
+    /// Maps the header and the ring of `size` bytes of the given shared memory object.
+    unsafe fn map(fd: libc::c_int, size: usize) -> io::Result<Self> {
+        let page = VirtualAlloc::page_size();
+        let header = libc::mmap(ptr::null_mut(), page, libc::PROT_READ | libc::PROT_WRITE,
+                                libc::MAP_SHARED, fd, 0);
+
+        if header == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+
+        match map_file_mirrored(fd, page, size) {
+            Ok(ptr) => Ok(SharedChannel {
+                header: NonNull::new_unchecked(header as *mut Header),
+                ptr,
+                size,
+                name: None
+            }),
+            Err(os_err) => {
+                libc::munmap(header, page);
+                Err(io::Error::from_raw_os_error(os_err))
+            }
+        }
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { self.header.as_ref() }
+    }
+
+    /// Returns a pointer to the byte at the given position in the ring.
+    #[inline]
+    fn at(&self, pos: usize) -> *mut u8 {
+        unsafe { self.ptr.as_ptr().add(pos % self.size) }
+    }
+
+    #[inline]
+    fn free(&self) -> usize {
+        self.size - self.len()
+    }
+}
+
+impl Drop for SharedChannel {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut c_void, self.size * 2);
+            libc::munmap(self.header.as_ptr() as *mut c_void, VirtualAlloc::page_size());
+
+            if let Some(ref name) = self.name {
+                libc::shm_unlink(name.as_ptr());
+            }
+        }
+    }
+}
+
+impl<'a> ChannelProducer<'a> {
+    /// Returns the free space of the channel, which can be written to before being
+    /// produced.
+    #[inline]
+    pub fn write(&mut self) -> &mut [u8] {
+        let channel = self.channel;
+        let tail = channel.header().tail.load(Ordering::Relaxed);
+
+        unsafe { slice::from_raw_parts_mut(channel.at(tail), channel.free()) }
+    }
+
+    /// Makes the first `len` bytes of the free space available to the consumer, and wakes
+    /// it if it waits for them.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds the free space of the channel.
+    #[inline]
+    pub fn produce(&mut self, len: usize) {
+        let channel = self.channel;
+        let header = channel.header();
+
+        assert!(len <= channel.free(), "produced more bytes than the channel can hold");
+
+        header.tail.store(header.tail.load(Ordering::Relaxed).wrapping_add(len),
+                          Ordering::Release);
+        header.readable.signal();
+    }
+
+    /// Waits until at least `min` bytes are free, or the timeout expires, and returns
+    /// whether they are.
+    ///
+    /// # Panics
+    /// Panics if `min` exceeds the capacity of the channel.
+    pub fn wait_writable(&mut self, min: usize, timeout: Option<Duration>) -> bool {
+        let channel = self.channel;
+
+        assert!(min <= channel.size, "waited for more bytes than the channel can hold");
+
+        channel.header().writable.wait(|| channel.free() >= min, timeout)
+    }
+}
+
+impl<'a> ChannelConsumer<'a> {
+    /// Returns the bytes that were produced but not consumed yet.
+    #[inline]
+    pub fn read(&mut self) -> &[u8] {
+        let channel = self.channel;
+        let head = channel.header().head.load(Ordering::Relaxed);
+
+        unsafe { slice::from_raw_parts(channel.at(head), channel.len()) }
+    }
+
+    /// Gives the first `len` readable bytes back to the producer, and wakes it if it waits
+    /// for free space.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds the number of readable bytes.
+    #[inline]
+    pub fn consume(&mut self, len: usize) {
+        let channel = self.channel;
+        let header = channel.header();
+
+        assert!(len <= channel.len(), "consumed more bytes than were produced");
+
+        header.head.store(header.head.load(Ordering::Relaxed).wrapping_add(len),
+                          Ordering::Release);
+        header.writable.signal();
+    }
+
+    /// Waits until at least `min` bytes are readable, or the timeout expires, and returns
+    /// whether they are.
+    ///
+    /// # Panics
+    /// Panics if `min` exceeds the capacity of the channel.
+    pub fn wait_readable(&mut self, min: usize, timeout: Option<Duration>) -> bool {
+        let channel = self.channel;
+
+        assert!(min <= channel.size, "waited for more bytes than the channel can hold");
+
+        channel.header().readable.wait(|| channel.len() >= min, timeout)
+    }
+}
+
+impl Event {
+    /// Signals the event, waking the threads sleeping on it, if any.
+    #[inline]
+    fn signal(&self) {
+        // Sleepers read the sequence number before checking their condition, and the
+        // kernel only puts them to sleep if it did not change since then, so that a signal
+        // sent in between is never lost.
+        self.seq.fetch_add(1, Ordering::SeqCst);
+
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            unsafe {
+                libc::syscall(libc::SYS_futex, &self.seq as *const AtomicU32, libc::FUTEX_WAKE,
+                              i32::MAX);
+            }
+        }
+    }
+
+    /// Sleeps until `ready` returns true or the timeout expires, and returns the last
+    /// result of `ready`.
+    fn wait<F: Fn() -> bool>(&self, ready: F, timeout: Option<Duration>) -> bool {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let seq = self.seq.load(Ordering::SeqCst);
+
+            if ready() {
+                return true
+            }
+
+            let timespec = match deadline {
+                Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                    Some(left) if left > Duration::ZERO => Some(libc::timespec {
+                        tv_sec: left.as_secs().min(libc::time_t::MAX as u64) as libc::time_t,
+                        tv_nsec: left.subsec_nanos() as _
+                    }),
+                    _ => return false
+                },
+                None => None
+            };
+
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+
+            unsafe {
+                libc::syscall(libc::SYS_futex, &self.seq as *const AtomicU32, libc::FUTEX_WAIT,
+                              seq, timespec.as_ref().map_or(ptr::null(), |t| t as *const _));
+            }
+
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    /// Returns a name for a channel that no other test uses.
+    fn name(test: &str) -> String {
+        format!("/virtualalloc-channel-{}-{}", test, std::process::id())
+    }
+
+    #[test]
+    fn transfers_bytes_between_handles() {
+        let name = name("transfer");
+        let mut created = SharedChannel::create(&name, 4096).unwrap();
+        let mut opened = SharedChannel::open(&name).unwrap();
+        let total = 1_000_000usize;
+
+        assert_eq!(opened.capacity(), created.capacity());
+        assert_eq!(SharedChannel::create(&name, 4096).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::AlreadyExists));
+
+        thread::scope(|s| {
+            let mut producer = created.producer();
+
+            s.spawn(move || {
+                let mut sent = 0;
+
+                while sent < total {
+                    assert!(producer.wait_writable(1, None));
+
+                    let free = producer.write();
+                    let len = free.len().min(total - sent).min(1000);
+
+                    for (i, byte) in free[..len].iter_mut().enumerate() {
+                        *byte = (sent + i) as u8;
+                    }
+
+                    producer.produce(len);
+                    sent += len;
+                }
+            });
+
+            let mut consumer = opened.consumer();
+            let mut received = 0;
+
+            while received < total {
+                assert!(consumer.wait_readable(1, None));
+
+                let bytes = consumer.read();
+
+                for (i, &byte) in bytes.iter().enumerate() {
+                    assert_eq!(byte, (received + i) as u8);
+                }
+
+                let len = bytes.len();
+
+                consumer.consume(len);
+                received += len;
+            }
+        });
+
+        assert!(opened.is_empty());
+        assert!(!opened.consumer().wait_readable(1, Some(Duration::from_millis(10))));
+
+        drop(created);
+
+        // The name is gone, but the memory lives on in the opened channel.
+        assert_eq!(SharedChannel::open(&name).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::NotFound));
+        assert!(opened.producer().wait_writable(4096, Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn hands_out_contiguous_slices_across_the_end_of_the_ring() {
+        let mut channel = SharedChannel::create(&name("wrap"), 1).unwrap();
+        let size = channel.capacity();
+        let (mut producer, mut consumer) = channel.split();
+
+        producer.write()[..size - 10].fill(1);
+        producer.produce(size - 10);
+        consumer.consume(size - 10);
+
+        let free = producer.write();
+
+        assert_eq!(free.len(), size);
+
+        for (i, byte) in free.iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        producer.produce(size);
+
+        assert!(producer.write().is_empty());
+        assert!(!producer.wait_writable(1, Some(Duration::from_millis(10))));
+        assert!(consumer.read().iter().enumerate().all(|(i, &byte)| byte == i as u8));
+    }
+
+    #[test]
+    #[should_panic(expected = "produced more bytes than the channel can hold")]
+    fn refuses_to_produce_more_than_the_free_space() {
+        let mut channel = SharedChannel::create(&name("overflow"), 1).unwrap();
+        let size = channel.capacity();
+
+        channel.producer().produce(size + 1);
+    }
+
+    #[test]
+    fn caps_positions_corrupted_by_the_other_side() {
+        let name = name("corrupt");
+        let mut created = SharedChannel::create(&name, 1).unwrap();
+        let opened = SharedChannel::open(&name).unwrap();
+        let size = created.capacity();
+
+        for tail in [size * 3, usize::MAX, size + 1] {
+            // The other process writes garbage over the position of the producer.
+            opened.header().tail.store(tail, Ordering::Release);
+
+            assert_eq!(created.len(), size);
+            assert_eq!(created.consumer().read().len(), size);
+            assert!(created.producer().write().is_empty());
+            assert!(created.consumer().wait_readable(size, Some(Duration::ZERO)));
+        }
+
+        opened.header().tail.store(0, Ordering::Release);
+
+        assert!(created.is_empty());
+        assert_eq!(created.producer().write().len(), size);
+    }
+
+    #[test]
+    fn refuses_to_open_other_objects() {
+        let name = name("foreign");
+        let c_name = c_name(&name).unwrap();
+        let page = VirtualAlloc::page_size();
+
+        assert_eq!(SharedChannel::open(&name).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::NotFound));
+
+        unsafe {
+            let fd = libc::shm_open(c_name.as_ptr(), libc::O_RDWR | libc::O_CREAT, 0o600);
+
+            assert!(fd >= 0);
+
+            // Too small to hold a header and a ring, then large enough but without a header.
+            for size in [page, page * 2] {
+                assert_eq!(libc::ftruncate(fd, size as libc::off_t), 0);
+                assert_eq!(SharedChannel::open(&name).err().map(|err| err.kind()),
+                           Some(io::ErrorKind::InvalidData));
+            }
+
+            libc::close(fd);
+            libc::shm_unlink(c_name.as_ptr());
+        }
+    }
+}
@@ -0,0 +1,195 @@
+//! A cursor that reads from a `VirtualVec<u8>`.
+
+#[cfg(feature = "std")] use std::cmp;
+#[cfg(feature = "std")] use std::io::{self, BufRead, Read, Seek, SeekFrom};
+
+#[cfg(not(feature = "std"))] use core::cmp;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualVec};
+
+
+/// A cursor over the bytes of a `VirtualVec<u8>`, which implements `Read`, `BufRead` and
+/// `Seek`.
+///
+/// `BufRead` reads directly from the vector, so wrapping the cursor in a `BufReader` is
+/// unnecessary and only adds copies. Since elements of a `VirtualVec` never move, slices returned by `read_slice` borrow
+/// the vector rather than the cursor, and therefore remain valid as reading continues.
+///
+/// # Example
+/// ```
+/// use virtualalloc::VirtualVec;
+///
+/// let mut vec = VirtualVec::<u8>::new(1024);
+///
+/// vec.extend_from_slice(b"header:body");
+///
+/// let mut cursor = vec.cursor();
+/// let header = cursor.read_slice(6);
+///
+/// cursor.set_position(7);
+///
+/// assert_eq!(header, b"header");
+/// assert_eq!(cursor.read_slice(100), b"body");
+/// ```
+pub struct VirtualCursor<'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    vec: &'a VirtualVec<u8, B>,
+    pos: usize
+}
+
+impl<'a, B: VirtualBacking> VirtualCursor<'a, B> {
+    /// Returns a cursor that starts reading at the beginning of the given vector.
+    #[inline]
+    pub fn new(vec: &'a VirtualVec<u8, B>) -> Self {
+        VirtualCursor { vec, pos: 0 }
+    }
+
+    /// Returns the position of the cursor in the vector.
+    #[inline]
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Sets the position of the cursor in the vector. The position may be past the end of
+    /// the vector, in which case reading returns no bytes.
+    #[inline]
+    pub fn set_position(&mut self, pos: usize) {
+        self.pos = pos;
+    }
+
+    /// Returns the bytes that have not been read yet.
+    #[inline]
+    pub fn remaining_slice(&self) -> &'a [u8] {
+        let vec: &'a [u8] = self.vec.as_slice();
+
+        &vec[cmp::min(self.pos, vec.len())..]
+    }
+
+    /// Reads up to `len` bytes, and returns them without copying.
+    pub fn read_slice(&mut self, len: usize) -> &'a [u8] {
+        let remaining = self.remaining_slice();
+        let slice = &remaining[..cmp::min(len, remaining.len())];
+
+        self.pos += slice.len();
+
+        slice
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, B: VirtualBacking> Read for VirtualCursor<'a, B> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let slice = self.read_slice(buf.len());
+
+        buf[..slice.len()].copy_from_slice(slice);
+
+        Ok(slice.len())
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        if self.remaining_slice().len() < buf.len() {
+            return Err(io::ErrorKind::UnexpectedEof.into())
+        }
+
+        buf.copy_from_slice(self.read_slice(buf.len()));
+
+        Ok(())
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, B: VirtualBacking> BufRead for VirtualCursor<'a, B> {
+    #[inline]
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        Ok(self.remaining_slice())
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: usize) {
+        self.pos = self.pos.saturating_add(amt);
+    }
+}
+
+#[cfg(feature = "std")]
+impl<'a, B: VirtualBacking> Seek for VirtualCursor<'a, B> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.vec.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.pos as u64).checked_add_signed(offset)
+        };
+
+        match pos {
+            Some(pos) if pos <= usize::MAX as u64 => {
+                self.pos = pos as usize;
+
+                Ok(pos)
+            },
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                    "invalid seek to a negative or overflowing position"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec() -> VirtualVec<u8> {
+        let mut vec = VirtualVec::new(1024);
+
+        vec.extend_from_slice(b"0123456789");
+        vec
+    }
+
+    #[test]
+    fn returned_slices_outlive_reads() {
+        let vec = vec();
+        let mut cursor = vec.cursor();
+
+        let first = cursor.read_slice(3);
+        let second = cursor.read_slice(3);
+
+        assert_eq!(first, b"012");
+        assert_eq!(second, b"345");
+        assert_eq!(cursor.read_slice(10), b"6789");
+        assert!(cursor.read_slice(1).is_empty());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn can_read_and_seek() {
+        let vec = vec();
+        let mut cursor = vec.cursor();
+        let mut buf = [0; 4];
+
+        assert_eq!(cursor.seek(SeekFrom::End(-4)).unwrap(), 6);
+        assert_eq!(cursor.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"6789");
+
+        assert_eq!(cursor.seek(SeekFrom::Current(-8)).unwrap(), 2);
+        cursor.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"2345");
+
+        assert!(cursor.seek(SeekFrom::Current(-10)).is_err());
+        assert_eq!(cursor.seek(SeekFrom::Start(100)).unwrap(), 100);
+        assert_eq!(cursor.read(&mut buf).unwrap(), 0);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn can_read_lines_without_copying() {
+        let mut vec = VirtualVec::new(1024);
+
+        vec.extend_from_slice(b"first\nsecond\nthird");
+
+        let mut cursor = vec.cursor();
+        let mut line = Vec::new();
+
+        assert_eq!(cursor.read_until(b'\n', &mut line).unwrap(), 6);
+        assert_eq!(cursor.fill_buf().unwrap().as_ptr(), vec[6..].as_ptr());
+
+        let lines = cursor.lines().collect::<io::Result<Vec<_>>>().unwrap();
+
+        assert_eq!(lines, ["second", "third"]);
+    }
+}
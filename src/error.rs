@@ -0,0 +1,141 @@
+//! Errors returned by virtual memory operations.
+
+#[cfg(feature = "std")] use std::error::Error;
+#[cfg(feature = "std")] use std::fmt;
+#[cfg(feature = "std")] use std::io;
+
+#[cfg(feature = "std")] use super::OomReport;
+
+#[cfg(not(feature = "std"))] use core::fmt;
+
+
+/// An error encountered while reserving, committing or protecting virtual memory.
+///
+/// Errors that originate from the operating system carry the OS error code (`errno` on
+/// Unix, `GetLastError()` on Windows) that was reported at the time of the failure.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VirtualMemError {
+    /// Address space could not be reserved.
+    ReservationFailed { os_err: i32 },
+    /// Reserved memory could not be committed.
+    CommitFailed { os_err: i32 },
+    /// Committed memory could not be decommitted.
+    DecommitFailed { os_err: i32 },
+    /// The requested size exceeds the maximum size of the reservation.
+    ExceedsMax { requested: usize, max: usize },
+    /// The size in bytes of the requested number of elements does not fit in a `usize`.
+    CapacityOverflow,
+    /// The requested alignment is not a power of two.
+    InvalidAlignment,
+    /// The protection of memory could not be changed.
+    ProtectFailed { os_err: i32 },
+    /// Memory could not be sealed.
+    SealFailed { os_err: i32 },
+    /// Advice about the usage of memory could not be given to the operating system.
+    AdviseFailed { os_err: i32 },
+    /// Memory could not be locked in physical memory.
+    LockFailed { os_err: i32 },
+    /// Memory was requested to be both writable and executable in strict W^X mode.
+    WxViolation,
+    /// The operation is not supported on this platform.
+    Unsupported
+}
+
+impl fmt::Display for VirtualMemError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VirtualMemError::ReservationFailed { os_err } =>
+                write!(f, "could not reserve memory (os error {})", os_err),
+            VirtualMemError::CommitFailed { os_err } =>
+                write!(f, "could not commit memory (os error {})", os_err),
+            VirtualMemError::DecommitFailed { os_err } =>
+                write!(f, "could not decommit memory (os error {})", os_err),
+            VirtualMemError::ExceedsMax { requested, max } =>
+                write!(f, "requested {} bytes, but at most {} can be reserved", requested, max),
+            VirtualMemError::CapacityOverflow =>
+                write!(f, "capacity overflow"),
+            VirtualMemError::InvalidAlignment =>
+                write!(f, "alignment is not a power of two"),
+            VirtualMemError::ProtectFailed { os_err } =>
+                write!(f, "could not change memory protection (os error {})", os_err),
+            VirtualMemError::SealFailed { os_err } =>
+                write!(f, "could not seal memory (os error {})", os_err),
+            VirtualMemError::AdviseFailed { os_err } =>
+                write!(f, "could not advise memory usage (os error {})", os_err),
+            VirtualMemError::LockFailed { os_err } =>
+                write!(f, "could not lock memory (os error {})", os_err),
+            VirtualMemError::WxViolation =>
+                write!(f, "memory cannot be both writable and executable in strict W^X mode"),
+            VirtualMemError::Unsupported =>
+                write!(f, "operation not supported on this platform")
+        }
+    }
+}
+
+impl VirtualMemError {
+    /// Returns the state of the system when this error occurred, if it was a failure to
+    /// reserve or commit memory seen by the current thread while diagnostics were enabled
+    /// with `VirtualAlloc::set_oom_diagnostics`.
+    ///
+    /// Only the last failure of each thread is kept.
+    #[cfg(feature = "std")]
+    pub fn diagnostics(&self) -> Option<OomReport> {
+        ::oom::last_report(self)
+    }
+}
+
+#[cfg(feature = "std")]
+impl Error for VirtualMemError {}
+
+#[cfg(feature = "std")]
+impl From<VirtualMemError> for io::Error {
+    fn from(err: VirtualMemError) -> io::Error {
+        let kind = match err {
+            VirtualMemError::ReservationFailed { .. } |
+            VirtualMemError::CommitFailed { .. } => io::ErrorKind::OutOfMemory,
+            VirtualMemError::ExceedsMax { .. } => io::ErrorKind::WriteZero,
+            VirtualMemError::CapacityOverflow |
+            VirtualMemError::InvalidAlignment => io::ErrorKind::InvalidInput,
+            VirtualMemError::Unsupported => io::ErrorKind::Unsupported,
+            _ => io::ErrorKind::Other
+        };
+
+        io::Error::new(kind, err)
+    }
+}
+
+/// Returns the last error reported by the operating system on the current thread.
+#[cfg(windows)]
+#[inline]
+pub(crate) fn last_os_error() -> i32 {
+    unsafe {
+        windows_sys::Win32::Foundation::GetLastError() as _
+    }
+}
+
+/// Returns the last error reported by the operating system on the current thread.
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+#[inline]
+pub(crate) fn last_os_error() -> i32 {
+    #[cfg(any(target_os = "linux", target_os = "emscripten", target_os = "fuchsia"))]
+    use libc::__errno_location as errno_location;
+    #[cfg(any(target_os = "android", target_os = "netbsd", target_os = "openbsd"))]
+    use libc::__errno as errno_location;
+    #[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+              target_os = "dragonfly"))]
+    use libc::__error as errno_location;
+    #[cfg(any(target_os = "solaris", target_os = "illumos"))]
+    use libc::___errno as errno_location;
+
+    unsafe {
+        *errno_location()
+    }
+}
+
+/// Returns the last error reported by the operating system on the current thread, which
+/// is always 0 on WebAssembly since memory is managed through instructions.
+#[cfg(target_arch = "wasm32")]
+#[inline]
+pub(crate) fn last_os_error() -> i32 {
+    0
+}
@@ -0,0 +1,186 @@
+//! Fault injection for testing how out-of-memory conditions are handled.
+//!
+//! When the `fault-injection` feature is enabled, every commit made through the operating
+//! system backing first consults the injector of the current thread, which can be set up to
+//! fail the Nth commit, or all commits larger than a given size. Since the injector sits
+//! in front of the real backing, the error paths of `VirtualVec`, of the allocator and of
+//! every other container are exercised exactly as they would be on a machine that ran
+//! out of memory.
+//!
+//! Injectors are thread-local, so that tests running in parallel do not interfere with
+//! each other.
+//!
+//! # Example
+//! ```
+//! use virtualalloc::{fault, VirtualMemError, VirtualVec};
+//!
+//! let mut vec = VirtualVec::<u8>::new(1_000_000);
+//!
+//! fault::fail_commits_larger_than(Some(4096));
+//!
+//! assert!(vec.reserve(4096).is_ok());
+//! assert_eq!(vec.reserve(16384),
+//!            Err(VirtualMemError::CommitFailed { os_err: fault::INJECTED_OS_ERR }));
+//!
+//! fault::reset();
+//! ```
+
+use std::cell::Cell;
+
+use super::VirtualMemError;
+
+
+/// The OS error code carried by `CommitFailed` errors caused by the injector.
+pub const INJECTED_OS_ERR: i32 = -1;
+
+thread_local! {
+    static COMMITS: Cell<usize> = const { Cell::new(0) };
+    static FAIL_NTH: Cell<Option<usize>> = const { Cell::new(None) };
+    static FAIL_LARGER_THAN: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Makes the `n`th commit made on the current thread from now on fail, counting from zero.
+pub fn fail_nth_commit(n: usize) {
+    FAIL_NTH.with(|nth| nth.set(Some(COMMITS.with(Cell::get).saturating_add(n))));
+}
+
+/// Makes all commits of more than `size` bytes made on the current thread fail, or stops
+/// doing so if `size` is `None`.
+///
+/// Containers only commit the memory past what they already committed, so this limits how
+/// much a container grows at once, rather than its total size.
+pub fn fail_commits_larger_than(size: Option<usize>) {
+    FAIL_LARGER_THAN.with(|max| max.set(size));
+}
+
+/// Returns the number of commits made on the current thread, including failed ones.
+pub fn commits() -> usize {
+    COMMITS.with(Cell::get)
+}
+
+/// Removes all faults scheduled on the current thread, and resets its commit count.
+pub fn reset() {
+    COMMITS.with(|commits| commits.set(0));
+    FAIL_NTH.with(|nth| nth.set(None));
+    FAIL_LARGER_THAN.with(|max| max.set(None));
+}
+
+/// Returns an error if a commit of `size` bytes must fail.
+pub(crate) fn check_commit(size: usize) -> Result<(), VirtualMemError> {
+    let index = COMMITS.with(|commits| commits.replace(commits.get() + 1));
+    let fail_nth = FAIL_NTH.with(|nth| {
+        if nth.get() == Some(index) {
+            nth.set(None);
+            true
+        } else {
+            false
+        }
+    });
+
+    if fail_nth || FAIL_LARGER_THAN.with(Cell::get).is_some_and(|max| size > max) {
+        return Err(VirtualMemError::CommitFailed { os_err: INJECTED_OS_ERR })
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use {VirtualAlloc, VirtualVec};
+
+    const INJECTED: VirtualMemError = VirtualMemError::CommitFailed { os_err: INJECTED_OS_ERR };
+
+    #[test]
+    fn fails_the_nth_commit_once() {
+        reset();
+
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 16);
+
+        fail_nth_commit(1);
+
+        assert_eq!(vec.reserve(page), Ok(()));
+        assert_eq!(vec.reserve(page * 3), Err(INJECTED));
+        assert_eq!(vec.reserve(page * 3), Ok(()));
+        assert_eq!(commits(), 3);
+        assert_eq!(vec.capacity(), page * 3);
+    }
+
+    #[test]
+    fn fails_commits_over_a_size() {
+        reset();
+
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 16);
+
+        fail_commits_larger_than(Some(page * 2));
+
+        assert_eq!(vec.reserve(page * 2), Ok(()));
+        assert_eq!(vec.reserve(page * 4 + 1), Err(INJECTED));
+        assert_eq!(vec.capacity(), page * 2);
+        assert_eq!(vec.reserve(page * 4), Ok(()));
+
+        reset();
+
+        assert_eq!(vec.reserve(page * 4 + 1), Ok(()));
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn fails_allocations() {
+        use std::alloc::{Allocator, Layout};
+        use VirtualAlloc;
+
+        reset();
+        fail_nth_commit(0);
+
+        assert!(VirtualAlloc::new(1 << 20).allocate(Layout::new::<u64>()).is_err());
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn skips_commits_within_the_same_page() {
+        use std::alloc::{Allocator, Layout};
+        use VirtualAlloc;
+
+        reset();
+
+        let alloc = VirtualAlloc::new(1 << 20);
+        let old = Layout::array::<u8>(10).unwrap();
+        let new = Layout::array::<u8>(VirtualAlloc::page_size()).unwrap();
+
+        unsafe {
+            let ptr = alloc.allocate(old).unwrap().cast();
+
+            Allocator::grow(&alloc, ptr, old, new).unwrap();
+            assert_eq!(commits(), 1);
+
+            alloc.deallocate(ptr, new);
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    #[test]
+    fn skips_commits_below_the_high_water_mark() {
+        use std::alloc::{Allocator, Layout};
+        use VirtualAlloc;
+
+        reset();
+
+        let alloc = VirtualAlloc::new(1 << 20);
+        let large = Layout::array::<u8>(VirtualAlloc::page_size() * 4).unwrap();
+        let small = Layout::array::<u8>(10).unwrap();
+
+        unsafe {
+            let ptr = alloc.allocate(large).unwrap().cast();
+
+            alloc.shrink(ptr, large, small).unwrap();
+            Allocator::grow(&alloc, ptr, small, large).unwrap();
+            assert_eq!(commits(), 1);
+
+            alloc.deallocate(ptr, large);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+//! A C interface to vectors and raw regions, enabled by the `ffi` feature.
+//!
+//! All functions return `VIRTUALALLOC_OK` (zero) on success, and one of the other
+//! `VIRTUALALLOC_*` codes on failure. The declarations matching these functions can be
+//! found in `include/virtualalloc.h`.
+//!
+//! # Safety
+//! Pointers to vectors and regions passed to these functions must be null, or point to
+//! objects created by the matching `_create` function and not destroyed since. Other
+//! pointers must be valid for the number of bytes that accompanies them.
+
+// Every function shares the safety requirements stated above.
+#![allow(clippy::missing_safety_doc)]
+
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError, VirtualVec};
+
+
+pub const VIRTUALALLOC_OK: i32 = 0;
+pub const VIRTUALALLOC_RESERVATION_FAILED: i32 = 1;
+pub const VIRTUALALLOC_COMMIT_FAILED: i32 = 2;
+pub const VIRTUALALLOC_DECOMMIT_FAILED: i32 = 3;
+pub const VIRTUALALLOC_EXCEEDS_MAX: i32 = 4;
+pub const VIRTUALALLOC_PROTECT_FAILED: i32 = 5;
+pub const VIRTUALALLOC_WX_VIOLATION: i32 = 6;
+pub const VIRTUALALLOC_UNSUPPORTED: i32 = 7;
+pub const VIRTUALALLOC_OTHER: i32 = 8;
+pub const VIRTUALALLOC_INVALID_ARGUMENT: i32 = 9;
+
+fn error_code(err: VirtualMemError) -> i32 {
+    match err {
+        VirtualMemError::ReservationFailed { .. } => VIRTUALALLOC_RESERVATION_FAILED,
+        VirtualMemError::CommitFailed { .. } => VIRTUALALLOC_COMMIT_FAILED,
+        VirtualMemError::DecommitFailed { .. } => VIRTUALALLOC_DECOMMIT_FAILED,
+        VirtualMemError::ExceedsMax { .. } => VIRTUALALLOC_EXCEEDS_MAX,
+        VirtualMemError::ProtectFailed { .. } => VIRTUALALLOC_PROTECT_FAILED,
+        VirtualMemError::WxViolation => VIRTUALALLOC_WX_VIOLATION,
+        VirtualMemError::InvalidAlignment => VIRTUALALLOC_INVALID_ARGUMENT,
+        VirtualMemError::Unsupported => VIRTUALALLOC_UNSUPPORTED,
+        _ => VIRTUALALLOC_OTHER
+    }
+}
+
+fn result_code(result: Result<(), VirtualMemError>) -> i32 {
+    result.err().map_or(VIRTUALALLOC_OK, error_code)
+}
+
+
+/// An opaque byte vector whose contents never move.
+pub type VirtualAllocVec = VirtualVec<u8>;
+
+/// Creates a byte vector that can hold up to `max` bytes, and stores it in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_create(max: usize,
+                                                 out: *mut *mut VirtualAllocVec) -> i32 {
+    if out.is_null() {
+        return VIRTUALALLOC_INVALID_ARGUMENT
+    }
+
+    match VirtualVec::try_new(max) {
+        Ok(vec) => {
+            *out = Box::into_raw(Box::new(vec));
+
+            VIRTUALALLOC_OK
+        },
+        Err(err) => error_code(err)
+    }
+}
+
+/// Ensures that the vector can hold `additional` more bytes without committing memory.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_reserve(vec: *mut VirtualAllocVec,
+                                                  additional: usize) -> i32 {
+    match vec.as_mut() {
+        Some(vec) => result_code(vec.reserve(additional)),
+        None => VIRTUALALLOC_INVALID_ARGUMENT
+    }
+}
+
+/// Appends `len` bytes starting at `bytes` to the back of the vector.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_push_bytes(vec: *mut VirtualAllocVec,
+                                                     bytes: *const u8, len: usize) -> i32 {
+    let vec = match vec.as_mut() {
+        Some(vec) if !bytes.is_null() || len == 0 => vec,
+        _ => return VIRTUALALLOC_INVALID_ARGUMENT
+    };
+
+    if let Err(err) = vec.reserve(len) {
+        return error_code(err)
+    }
+
+    if len > 0 {
+        vec.extend_from_slice(slice::from_raw_parts(bytes, len));
+    }
+
+    VIRTUALALLOC_OK
+}
+
+/// Returns a pointer to the bytes of the vector, which never changes.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_data(vec: *mut VirtualAllocVec) -> *mut u8 {
+    vec.as_mut().map_or(ptr::null_mut(), |vec| vec.as_mut_ptr())
+}
+
+/// Returns the number of bytes in the vector.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_len(vec: *const VirtualAllocVec) -> usize {
+    vec.as_ref().map_or(0, |vec| vec.len())
+}
+
+/// Sets the protection of the committed memory of the vector.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_protect(vec: *mut VirtualAllocVec,
+                                                  read: bool, write: bool, exec: bool) -> i32 {
+    let vec = match vec.as_mut() {
+        Some(vec) => vec,
+        None => return VIRTUALALLOC_INVALID_ARGUMENT
+    };
+
+    match NonNull::new(vec.as_mut_ptr()) {
+        Some(ptr) if vec.capacity() > 0 =>
+            result_code(vec.backing().protect(ptr, vec.capacity(), read, write, exec)),
+        _ => VIRTUALALLOC_OK
+    }
+}
+
+/// Destroys a vector created by `virtualalloc_vec_create`.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_vec_destroy(vec: *mut VirtualAllocVec) {
+    if !vec.is_null() {
+        drop(Box::from_raw(vec));
+    }
+}
+
+
+/// A raw reservation of address space.
+#[repr(C)]
+pub struct VirtualAllocRegion {
+    /// The start of the region, which never changes.
+    pub ptr: *mut u8,
+    /// The size of the region, in bytes.
+    pub size: usize,
+    /// The number of bytes committed from the start of the region.
+    pub committed: usize
+}
+
+/// Reserves `size` bytes of read-write memory, and describes them in `out`.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_region_create(size: usize,
+                                                    out: *mut VirtualAllocRegion) -> i32 {
+    if out.is_null() {
+        return VIRTUALALLOC_INVALID_ARGUMENT
+    }
+
+    match VirtualAlloc::new(size).reserve(size) {
+        Ok(ptr) => {
+            *out = VirtualAllocRegion { ptr: ptr.as_ptr(), size, committed: 0 };
+
+            VIRTUALALLOC_OK
+        },
+        Err(err) => error_code(err)
+    }
+}
+
+/// Commits the first `size` bytes of the region.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_region_commit(region: *mut VirtualAllocRegion,
+                                                    size: usize) -> i32 {
+    let region = match region.as_mut() {
+        Some(region) if !region.ptr.is_null() => region,
+        _ => return VIRTUALALLOC_INVALID_ARGUMENT
+    };
+
+    if size > region.size {
+        return VIRTUALALLOC_EXCEEDS_MAX
+    }
+
+    if size <= region.committed {
+        return VIRTUALALLOC_OK
+    }
+
+    let ptr = NonNull::new_unchecked(region.ptr);
+    let result = VirtualAlloc::new(region.size).commit(ptr, size);
+
+    if result.is_ok() {
+        region.committed = size;
+    }
+
+    result_code(result)
+}
+
+/// Sets the protection of `size` committed bytes starting `offset` bytes into the region.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_region_protect(region: *const VirtualAllocRegion,
+                                                     offset: usize, size: usize,
+                                                     read: bool, write: bool, exec: bool) -> i32 {
+    let region = match region.as_ref() {
+        Some(region) if !region.ptr.is_null() => region,
+        _ => return VIRTUALALLOC_INVALID_ARGUMENT
+    };
+
+    if offset.checked_add(size).is_none_or(|end| end > region.committed) {
+        return VIRTUALALLOC_INVALID_ARGUMENT
+    }
+
+    let ptr = NonNull::new_unchecked(region.ptr.add(offset));
+
+    result_code(VirtualAlloc::set_protection(ptr, size, read, write, exec))
+}
+
+/// Releases a region created by `virtualalloc_region_create`.
+#[no_mangle]
+pub unsafe extern "C" fn virtualalloc_region_destroy(region: *mut VirtualAllocRegion) {
+    if let Some(region) = region.as_mut() {
+        if let Some(ptr) = NonNull::new(region.ptr) {
+            VirtualAlloc::new(region.size).release(ptr, region.size);
+        }
+
+        region.ptr = ptr::null_mut();
+        region.committed = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn vectors_can_be_used_through_ffi() {
+        unsafe {
+            let mut vec = ptr::null_mut();
+
+            assert_eq!(virtualalloc_vec_create(16, &mut vec), VIRTUALALLOC_OK);
+            assert_eq!(virtualalloc_vec_push_bytes(vec, b"hello".as_ptr(), 5), VIRTUALALLOC_OK);
+            assert_eq!(virtualalloc_vec_push_bytes(vec, [0; 12].as_ptr(), 12),
+                       VIRTUALALLOC_EXCEEDS_MAX);
+
+            assert_eq!(slice::from_raw_parts(virtualalloc_vec_data(vec), virtualalloc_vec_len(vec)),
+                       b"hello");
+
+            virtualalloc_vec_destroy(vec);
+        }
+    }
+
+    #[test]
+    fn regions_can_be_used_through_ffi() {
+        unsafe {
+            let mut region = VirtualAllocRegion { ptr: ptr::null_mut(), size: 0, committed: 0 };
+
+            assert_eq!(virtualalloc_region_create(1 << 20, &mut region), VIRTUALALLOC_OK);
+            assert_eq!(virtualalloc_region_commit(&mut region, 4096), VIRTUALALLOC_OK);
+
+            *region.ptr.add(4095) = 42;
+
+            assert_eq!(virtualalloc_region_protect(&region, 0, 4096, true, false, false),
+                       VIRTUALALLOC_OK);
+            assert_eq!(virtualalloc_region_protect(&region, 0, 8192, true, false, false),
+                       VIRTUALALLOC_INVALID_ARGUMENT);
+            assert_eq!(*region.ptr.add(4095), 42);
+
+            virtualalloc_region_destroy(&mut region);
+
+            assert!(region.ptr.is_null());
+        }
+    }
+}
@@ -0,0 +1,227 @@
+//! Files mapped in memory, which grow within a reservation of their maximum size.
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::path::Path;
+
+use super::{VirtualAlloc, VirtualMemError};
+
+
+/// A file mapped in memory, which can grow up to a maximum size.
+///
+/// On Unix, the maximum size is reserved upfront and the file is mapped over the
+/// reservation, so its base never changes. On Windows, the file is mapped in a new view
+/// when it grows, so its base may change, which users must account for.
+pub(crate) struct FileMapping {
+    file: File,
+    base: *mut u8,
+    max: usize,
+    /// The number of bytes of the file that are mapped.
+    mapped: usize,
+    /// Whether the file grew since it was last flushed.
+    resized: bool
+}
+
+impl FileMapping {
+    /// Opens the file at the given path, creating it if it does not exist, and maps all of
+    /// it. Fails if it is larger than `max` bytes, rounded up to whole pages.
+    pub(crate) fn open<P: AsRef<Path>>(path: P, max: usize) -> io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+        let size = file.metadata()?.len();
+        let max = ::round_to_page(max);
+
+        if size > max as u64 {
+            return Err(VirtualMemError::ExceedsMax { requested: size as usize, max }.into())
+        }
+
+        let mut mapping = FileMapping {
+            file,
+            base: unsafe { imp::reserve(max)? },
+            max,
+            mapped: 0,
+            resized: false
+        };
+
+        mapping.map(::round_to_page(size as usize))?;
+
+        Ok(mapping)
+    }
+
+    /// Returns a pointer to the start of the mapping, which is null while it is empty on
+    /// Windows.
+    #[inline]
+    pub(crate) fn base(&self) -> *mut u8 {
+        self.base
+    }
+
+    /// Returns the number of bytes that are mapped.
+    #[inline]
+    pub(crate) fn mapped(&self) -> usize {
+        self.mapped
+    }
+
+    /// Returns the most bytes that can be mapped.
+    #[inline]
+    pub(crate) fn max(&self) -> usize {
+        self.max
+    }
+
+    /// Ensures that at least `size` bytes are mapped, growing the file geometrically.
+    ///
+    /// Fails with `ExceedsMax` if `size` exceeds the maximum size of the mapping.
+    pub(crate) fn grow(&mut self, size: usize) -> io::Result<()> {
+        if size <= self.mapped {
+            return Ok(())
+        }
+        if size > self.max {
+            return Err(VirtualMemError::ExceedsMax { requested: size, max: self.max }.into())
+        }
+
+        self.map(::commit_size(self.mapped, size, self.max, VirtualAlloc::page_size()))
+    }
+
+    /// Writes the mapped bytes in the given range back to the file, and waits until they,
+    /// and the size of the file, are durable.
+    pub(crate) fn flush(&mut self, start: usize, end: usize) -> io::Result<()> {
+        let start = start / VirtualAlloc::page_size() * VirtualAlloc::page_size();
+
+        if end > start {
+            unsafe {
+                imp::flush(self.base.add(start), end - start)?;
+            }
+        }
+
+        // Flushing the mapping does not persist the size of the file.
+        if self.resized || cfg!(windows) {
+            self.file.sync_data()?;
+            self.resized = false;
+        }
+
+        Ok(())
+    }
+
+    /// Maps the first `size` bytes of the file, growing it if needed.
+    fn map(&mut self, size: usize) -> io::Result<()> {
+        if size == 0 || size <= self.mapped {
+            return Ok(())
+        }
+
+        if self.file.metadata()?.len() < size as u64 {
+            self.file.set_len(size as u64)?;
+            self.resized = true;
+        }
+
+        self.base = unsafe { imp::map(&self.file, self.base, self.mapped, size)? };
+        self.mapped = size;
+
+        Ok(())
+    }
+}
+
+impl Drop for FileMapping {
+    fn drop(&mut self) {
+        unsafe {
+            imp::release(self.base, self.max, self.mapped);
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::fs::File;
+    use std::ptr;
+
+    /// Reserves the address space of the log, which is then mapped from the file.
+    pub(super) unsafe fn reserve(max: usize) -> io::Result<*mut u8> {
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS;
+        let ptr = libc::mmap(ptr::null_mut(), max, libc::PROT_NONE, flags, -1, 0);
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(ptr.cast())
+    }
+
+    /// Maps the first `size` bytes of the file over the reservation.
+    pub(super) unsafe fn map(file: &File, base: *mut u8, _: usize, size: usize)
+        -> io::Result<*mut u8> {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        let flags = libc::MAP_SHARED | libc::MAP_FIXED;
+
+        if libc::mmap(base.cast(), size, prot, flags, file.as_raw_fd(), 0) == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(base)
+    }
+
+    pub(super) unsafe fn flush(ptr: *mut u8, len: usize) -> io::Result<()> {
+        if libc::msync(ptr.cast(), len, libc::MS_SYNC) != 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    pub(super) unsafe fn release(base: *mut u8, max: usize, _: usize) {
+        libc::munmap(base.cast(), max);
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::fs::File;
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Memory::*;
+
+    /// Returns no reservation, since files are mapped in their own views.
+    pub(super) unsafe fn reserve(_: usize) -> io::Result<*mut u8> {
+        Ok(ptr::null_mut())
+    }
+
+    /// Maps the first `size` bytes of the file in a new view, replacing the previous one.
+    pub(super) unsafe fn map(file: &File, base: *mut u8, mapped: usize, size: usize)
+        -> io::Result<*mut u8> {
+        let mapping = CreateFileMappingW(file.as_raw_handle() as _, ptr::null(), PAGE_READWRITE,
+                                         (size as u64 >> 32) as u32, size as u32, ptr::null());
+
+        if mapping.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+
+        let view = MapViewOfFile(mapping, FILE_MAP_READ | FILE_MAP_WRITE, 0, 0, size);
+
+        // The view keeps the mapping alive.
+        CloseHandle(mapping);
+
+        if view.Value.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+
+        release(base, 0, mapped);
+
+        Ok(view.Value.cast())
+    }
+
+    pub(super) unsafe fn flush(ptr: *mut u8, len: usize) -> io::Result<()> {
+        if FlushViewOfFile(ptr.cast(), len) == 0 {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(())
+    }
+
+    pub(super) unsafe fn release(base: *mut u8, _: usize, _: usize) {
+        if !base.is_null() {
+            UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: base.cast() });
+        }
+    }
+}
@@ -0,0 +1,279 @@
+//! A bump arena reset at the end of each frame, for game loops.
+
+#[cfg(feature = "std")] use std::alloc::Layout;
+#[cfg(feature = "std")] use std::cell::Cell;
+#[cfg(feature = "std")] use std::ptr::NonNull;
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::alloc::Layout;
+#[cfg(not(feature = "std"))] use core::cell::Cell;
+#[cfg(not(feature = "std"))] use core::ptr::NonNull;
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// A bump arena for data that only lives for one frame, which is reset at the end of
+/// each frame without returning its memory to the operating system.
+///
+/// Allocating only moves a cursor forward, committing memory the first time the arena
+/// grows past what it ever used, and `end_frame` moves the cursor back to the start in
+/// constant time. Memory therefore stays committed from one frame to the next, so that
+/// steady-state frames never make a system call.
+///
+/// After a spike, such as a loading screen, the arena keeps the memory it committed. With
+/// `trim_after`, the arena instead decommits the memory that none of the last frames used
+/// once enough frames went by.
+///
+/// Values allocated from the arena are never dropped.
+///
+/// # Example
+/// ```
+/// use virtualalloc::FrameArena;
+///
+/// let mut arena = FrameArena::new(1 << 20).unwrap().trim_after(60);
+///
+/// for frame in 0..3 {
+///     let positions = arena.alloc_slice(&[[0f32; 3]; 100]).unwrap();
+///     let count = arena.alloc_value(frame).unwrap();
+///
+///     positions[0][0] = *count as f32;
+///
+///     arena.end_frame();
+/// }
+///
+/// assert_eq!(arena.used(), 0);
+/// ```
+pub struct FrameArena<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    size: usize,
+    cursor: Cell<usize>,
+    committed: Cell<usize>,
+    trim_after: Option<usize>,
+    /// The most memory used by a frame since the last trim.
+    peak: usize,
+    /// The number of frames ended since the last trim.
+    frames: usize
+}
+
+// Allocations are handed out through `&self`, and therefore only from one thread.
+unsafe impl<B: VirtualBacking + Send> Send for FrameArena<B> {}
+
+impl FrameArena {
+    /// Returns an arena that can hold up to `size` bytes per frame, rounded up to whole
+    /// pages.
+    pub fn new(size: usize) -> Result<Self, VirtualMemError> {
+        FrameArena::with_backing(size, VirtualAlloc::new(::round_to_page(size)))
+    }
+}
+
+impl<B: VirtualBacking> FrameArena<B> {
+    /// Returns an arena that can hold up to `size` bytes per frame, rounded up to whole
+    /// pages, in memory provided by the given backing.
+    pub fn with_backing(size: usize, backing: B) -> Result<Self, VirtualMemError> {
+        let page = backing.page_size();
+        let size = size.div_ceil(page).saturating_mul(page);
+        let ptr = backing.reserve(size)?;
+
+        Ok(FrameArena {
+            backing,
+            ptr,
+            size,
+            cursor: Cell::new(0),
+            committed: Cell::new(0),
+            trim_after: None,
+            peak: 0,
+            frames: 0
+        })
+    }
+
+    /// Makes the arena decommit the memory that none of the last `frames` frames used,
+    /// every `frames` frames, so that memory committed during a spike is eventually
+    /// returned to the operating system.
+    ///
+    /// Giving zero frames disables trimming, which is the default.
+    #[inline]
+    pub fn trim_after(mut self, frames: usize) -> Self {
+        self.trim_after = Some(frames).filter(|&frames| frames > 0);
+        self
+    }
+
+    /// Returns the number of bytes allocated during the current frame.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.cursor.get()
+    }
+
+    /// Returns the number of bytes committed by the arena.
+    #[inline]
+    pub fn committed(&self) -> usize {
+        self.committed.get()
+    }
+
+    /// Returns the most bytes that can be allocated during a frame.
+    #[inline]
+    pub fn max_size(&self) -> usize {
+        self.size
+    }
+
+    /// Allocates memory for the given layout, committing memory as needed.
+    ///
+    /// The memory remains valid until the end of the frame.
+    pub fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, VirtualMemError> {
+        let cursor = self.cursor.get();
+        let start = cursor + unsafe { self.ptr.as_ptr().add(cursor) }.align_offset(layout.align());
+
+        let end = match start.checked_add(layout.size()) {
+            Some(end) if end <= self.size => end,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: start.saturating_add(layout.size()), max: self.size
+            })
+        };
+
+        if end > self.committed.get() {
+            self.commit(end)?;
+        }
+
+        self.cursor.set(end);
+
+        Ok(unsafe { NonNull::new_unchecked(self.ptr.as_ptr().add(start)) })
+    }
+
+    /// Allocates a copy of the given slice.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_slice<T: Copy>(&self, values: &[T]) -> Result<&mut [T], VirtualMemError> {
+        let ptr = self.alloc(Layout::for_value(values))?.cast::<T>().as_ptr();
+
+        unsafe {
+            ptr.copy_from_nonoverlapping(values.as_ptr(), values.len());
+
+            Ok(slice::from_raw_parts_mut(ptr, values.len()))
+        }
+    }
+
+    /// Allocates the given value. The value is never dropped.
+    #[allow(clippy::mut_from_ref)]
+    pub fn alloc_value<T>(&self, value: T) -> Result<&mut T, VirtualMemError> {
+        let ptr = self.alloc(Layout::new::<T>())?.cast::<T>().as_ptr();
+
+        unsafe {
+            ptr.write(value);
+
+            Ok(&mut *ptr)
+        }
+    }
+
+    /// Ends the current frame, which frees everything allocated during it at once.
+    ///
+    /// Memory stays committed for the next frames, unless trimming is enabled with
+    /// `trim_after` and none of the last frames used it.
+    pub fn end_frame(&mut self) {
+        self.peak = self.peak.max(self.cursor.get());
+        self.cursor.set(0);
+
+        let frames = match self.trim_after {
+            Some(frames) => frames,
+            None => return
+        };
+
+        self.frames += 1;
+
+        if self.frames >= frames {
+            let page = self.backing.page_size();
+            let keep = self.peak.div_ceil(page) * page;
+
+            self.frames = 0;
+            self.peak = 0;
+
+            if keep < self.committed.get() {
+                self.decommit_from(keep);
+            }
+        }
+    }
+
+    #[cold]
+    fn commit(&self, end: usize) -> Result<(), VirtualMemError> {
+        let committed = self.committed.get();
+        let size = ::commit_size(committed, end, self.size, self.backing.page_size());
+
+        unsafe {
+            ::commit_delta(&self.backing, self.ptr, committed, size)?;
+        }
+
+        self.committed.set(size);
+
+        Ok(())
+    }
+
+    fn decommit_from(&mut self, keep: usize) {
+        unsafe {
+            let start = NonNull::new_unchecked(self.ptr.as_ptr().add(keep));
+
+            if self.backing.decommit(start, self.committed.get() - keep).is_ok() {
+                self.committed.set(keep);
+            }
+        }
+    }
+}
+
+impl<B: VirtualBacking> Drop for FrameArena<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_memory_committed_across_frames() {
+        let page = VirtualAlloc::page_size();
+        let mut arena = FrameArena::new(page * 16).unwrap();
+        let first = arena.alloc_value(1u64).unwrap() as *mut u64;
+
+        arena.alloc_slice(&[1u8; 3]).unwrap();
+
+        let aligned = arena.alloc(Layout::from_size_align(8, 8).unwrap()).unwrap();
+
+        assert_eq!(aligned.as_ptr().align_offset(8), 0);
+        assert_eq!(arena.used(), 24);
+
+        let committed = arena.committed();
+
+        arena.end_frame();
+
+        assert_eq!(arena.used(), 0);
+        assert_eq!(arena.committed(), committed);
+        assert_eq!(arena.alloc_value(2u64).unwrap() as *mut u64, first);
+        assert!(arena.alloc_slice(&[0u8; 1]).is_ok());
+        assert!(arena.alloc(Layout::from_size_align(page * 16, 1).unwrap()).is_err());
+    }
+
+    #[test]
+    fn trims_after_quiet_frames() {
+        let page = VirtualAlloc::page_size();
+        let mut arena = FrameArena::new(page * 64).unwrap().trim_after(3);
+
+        arena.alloc(Layout::from_size_align(page * 32, 1).unwrap()).unwrap();
+        arena.end_frame();
+
+        let spike = arena.committed();
+
+        // The spike is part of the first window of frames, so nothing is trimmed.
+        arena.end_frame();
+        arena.end_frame();
+
+        assert_eq!(arena.committed(), spike);
+
+        for _ in 0..3 {
+            arena.alloc(Layout::from_size_align(page + 1, 1).unwrap()).unwrap();
+            arena.end_frame();
+        }
+
+        assert_eq!(arena.committed(), page * 2);
+    }
+}
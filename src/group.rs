@@ -0,0 +1,353 @@
+//! A single reservation carved into named sub-regions, for the heaps of language runtimes.
+
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// A reservation split into named sub-regions of fixed sizes, laid out one after the other
+/// in the order they were given, such as the code, data and stack of a runtime.
+///
+/// Each sub-region has its own protection and commit watermark: committing memory in a
+/// sub-region only commits its own pages, with its own protection, and resetting it
+/// decommits them without touching its neighbours. Since all sub-regions live in the same
+/// reservation, the whole heap remains in a single contiguous range of addresses, so that
+/// a runtime can tell which sub-region a pointer belongs to with `region_of`, or encode
+/// pointers as offsets from the start of the group.
+///
+/// # Example
+/// ```
+/// use virtualalloc::RegionGroup;
+///
+/// let mut heap = RegionGroup::new(&[("code", 1 << 20), ("data", 16 << 20), ("stack", 1 << 20)])
+///     .unwrap();
+///
+/// let mut data = heap.region("data").unwrap();
+///
+/// data.commit(4096).unwrap();
+/// data.as_mut_slice()[0] = 42;
+///
+/// let mut code = heap.region("code").unwrap();
+///
+/// code.commit(4096).unwrap();
+/// code.protect(true, false, false).unwrap();
+///
+/// assert_eq!(heap.region_of(heap.as_ptr().wrapping_add(1 << 20)), Some("data"));
+/// ```
+pub struct RegionGroup<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    size: usize,
+    regions: Vec<SubRegion>
+}
+
+struct SubRegion {
+    name: String,
+    offset: usize,
+    size: usize,
+    committed: usize,
+    /// The protection of the committed pages, as `(read, write, exec)`.
+    protection: (bool, bool, bool)
+}
+
+// Sub-regions are only modified through `&mut self`.
+unsafe impl<B: VirtualBacking + Send> Send for RegionGroup<B> {}
+unsafe impl<B: VirtualBacking + Sync> Sync for RegionGroup<B> {}
+
+/// A sub-region of a `RegionGroup`, returned by `RegionGroup::region`.
+pub struct GroupRegion<'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    group: &'a mut RegionGroup<B>,
+    index: usize
+}
+
+impl RegionGroup {
+    /// Returns a group of sub-regions with the given names and sizes, each rounded up to
+    /// whole pages.
+    ///
+    /// # Panics
+    /// Panics if two sub-regions have the same name.
+    pub fn new(regions: &[(&str, usize)]) -> Result<Self, VirtualMemError> {
+        let size = regions.iter()
+            .fold(0usize, |size, &(_, region)| size.saturating_add(::round_to_page(region)));
+
+        RegionGroup::with_backing(regions, VirtualAlloc::new(size))
+    }
+}
+
+impl<B: VirtualBacking> RegionGroup<B> {
+    /// Returns a group of sub-regions with the given names and sizes, each rounded up to
+    /// whole pages, in memory provided by the given backing.
+    ///
+    /// # Panics
+    /// Panics if two sub-regions have the same name.
+    pub fn with_backing(regions: &[(&str, usize)], backing: B)
+        -> Result<Self, VirtualMemError> {
+        let page = backing.page_size();
+        let mut size = 0usize;
+        let mut subregions: Vec<SubRegion> = Vec::with_capacity(regions.len());
+
+        for &(name, region) in regions {
+            assert!(subregions.iter().all(|other| other.name != name),
+                    "duplicate region name {:?}", name);
+
+            let region = region.div_ceil(page).saturating_mul(page);
+
+            subregions.push(SubRegion {
+                name: name.to_owned(),
+                offset: size,
+                size: region,
+                committed: 0,
+                protection: (true, true, false)
+            });
+
+            size = size.saturating_add(region);
+        }
+
+        if size > isize::MAX as usize {
+            return Err(VirtualMemError::ExceedsMax { requested: size, max: isize::MAX as usize })
+        }
+
+        let ptr = backing.reserve(size)?;
+
+        Ok(RegionGroup { backing, ptr, size, regions: subregions })
+    }
+
+    /// Returns a pointer to the start of the group, which never changes.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns the size of the whole group, in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the names of the sub-regions, in the order of their addresses.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.regions.iter().map(|region| &region.name[..])
+    }
+
+    /// Returns the sub-region with the given name, or `None` if there is none.
+    pub fn region(&mut self, name: &str) -> Option<GroupRegion<'_, B>> {
+        let index = self.regions.iter().position(|region| region.name == name)?;
+
+        Some(GroupRegion { group: self, index })
+    }
+
+    /// Returns the name of the sub-region that contains the given address, or `None` if it
+    /// is outside of the group.
+    pub fn region_of(&self, ptr: *const u8) -> Option<&str> {
+        let offset = ptr.addr().wrapping_sub(self.ptr.as_ptr().addr());
+
+        self.regions.iter()
+            .find(|region| offset >= region.offset && offset - region.offset < region.size)
+            .map(|region| &region.name[..])
+    }
+}
+
+impl<B: VirtualBacking> Drop for RegionGroup<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.size);
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> GroupRegion<'a, B> {
+    #[inline]
+    fn state(&self) -> &SubRegion {
+        &self.group.regions[self.index]
+    }
+
+    /// Returns a pointer to the byte at the given offset in the sub-region.
+    #[inline]
+    fn at(&self, offset: usize) -> NonNull<u8> {
+        unsafe { NonNull::new_unchecked(self.group.ptr.as_ptr().add(self.state().offset + offset)) }
+    }
+
+    /// Returns the name of the sub-region.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.state().name
+    }
+
+    /// Returns a pointer to the start of the sub-region, which never changes.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.at(0).as_ptr()
+    }
+
+    /// Returns the offset of the sub-region from the start of the group.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.state().offset
+    }
+
+    /// Returns the size of the sub-region, in bytes.
+    #[inline]
+    pub fn size(&self) -> usize {
+        self.state().size
+    }
+
+    /// Returns the number of bytes committed at the start of the sub-region.
+    #[inline]
+    pub fn committed(&self) -> usize {
+        self.state().committed
+    }
+
+    /// Returns the committed bytes of the sub-region.
+    ///
+    /// # Panics
+    /// Panics if the sub-region is not readable.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        assert!(self.state().protection.0, "region is not readable");
+
+        unsafe { slice::from_raw_parts(self.as_ptr(), self.committed()) }
+    }
+
+    /// Returns the committed bytes of the sub-region, mutably.
+    ///
+    /// # Panics
+    /// Panics if the sub-region is not writable.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        assert!(self.state().protection.1, "region is not writable");
+
+        unsafe { slice::from_raw_parts_mut(self.as_ptr(), self.committed()) }
+    }
+
+    /// Raises the commit watermark of the sub-region to at least `len` bytes, rounded up to
+    /// whole pages. The new pages are zeroed, and get the protection of the sub-region.
+    pub fn commit(&mut self, len: usize) -> Result<(), VirtualMemError> {
+        let (size, committed, (read, write, exec)) = {
+            let state = self.state();
+
+            (state.size, state.committed, state.protection)
+        };
+
+        if len > size {
+            return Err(VirtualMemError::ExceedsMax { requested: len, max: size })
+        }
+        if len <= committed {
+            return Ok(())
+        }
+
+        let page = self.group.backing.page_size();
+        let end = len.div_ceil(page) * page;
+        let start = self.at(committed);
+
+        unsafe {
+            self.group.backing.commit(start, end - committed)?;
+
+            if (read, write, exec) != (true, true, false) {
+                if let Err(err) = self.group.backing.protect(start, end - committed,
+                                                             read, write, exec) {
+                    let _ = self.group.backing.decommit(start, end - committed);
+
+                    return Err(err)
+                }
+            }
+        }
+
+        self.group.regions[self.index].committed = end;
+
+        Ok(())
+    }
+
+    /// Sets the protection of the committed pages of the sub-region, and of the pages it
+    /// commits from now on.
+    ///
+    /// Fails with `WxViolation` if the sub-region was requested to be both writable and
+    /// executable in strict W^X mode.
+    pub fn protect(&mut self, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        let committed = self.committed();
+
+        if committed > 0 {
+            unsafe {
+                self.group.backing.protect(self.at(0), committed, read, write, exec)?;
+            }
+        } else if ::is_wx_violation(write, exec) {
+            return Err(VirtualMemError::WxViolation)
+        }
+
+        self.group.regions[self.index].protection = (read, write, exec);
+
+        Ok(())
+    }
+
+    /// Decommits all pages of the sub-region, which are zeroed the next time they are
+    /// committed.
+    pub fn reset(&mut self) -> Result<(), VirtualMemError> {
+        let committed = self.committed();
+
+        if committed > 0 {
+            unsafe {
+                self.group.backing.decommit(self.at(0), committed)?;
+            }
+
+            self.group.regions[self.index].committed = 0;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commits_and_resets_regions_independently() {
+        let page = VirtualAlloc::page_size();
+        let mut group = RegionGroup::new(&[("code", 1), ("data", page * 4), ("stack", page)])
+            .unwrap();
+
+        assert_eq!(group.size(), page * 6);
+        assert_eq!(group.names().collect::<Vec<_>>(), ["code", "data", "stack"]);
+
+        {
+            let mut data = group.region("data").unwrap();
+
+            assert_eq!(data.offset(), page);
+
+            data.commit(page + 1).unwrap();
+            data.as_mut_slice()[page] = 7;
+
+            assert_eq!(data.committed(), page * 2);
+            assert!(data.commit(page * 5).is_err());
+        }
+
+        {
+            let mut code = group.region("code").unwrap();
+
+            code.commit(1).unwrap();
+            code.as_mut_slice()[0] = 0xc3;
+            code.protect(true, false, false).unwrap();
+
+            assert_eq!(code.as_slice()[0], 0xc3);
+        }
+
+        let mut stack = group.region("stack").unwrap();
+
+        stack.protect(true, false, false).unwrap();
+        stack.commit(page).unwrap();
+
+        assert_eq!(stack.as_slice(), &vec![0; page][..]);
+
+        stack.protect(true, true, false).unwrap();
+        stack.as_mut_slice()[0] = 1;
+        stack.reset().unwrap();
+        stack.commit(1).unwrap();
+
+        assert_eq!(stack.as_slice()[0], 0);
+        assert_eq!(group.region("data").unwrap().as_slice()[page], 7);
+        assert_eq!(group.region_of(group.as_ptr().wrapping_add(page * 5)), Some("stack"));
+        assert_eq!(group.region_of(group.as_ptr().wrapping_add(page * 6)), None);
+        assert!(group.region("heap").is_none());
+    }
+}
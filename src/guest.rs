@@ -0,0 +1,261 @@
+//! Guest memory for emulators and virtual machine monitors, whose accesses can be trapped.
+
+use std::ops::Range;
+use std::ptr::NonNull;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+use super::trap::{self, Trap};
+
+
+/// The handler of the faults of a `GuestMemory`.
+type FaultHandler = Box<dyn Fn(&GuestFault) -> bool + Send + Sync>;
+
+/// The memory of an emulated machine, in which ranges can be made inaccessible so that
+/// accesses to them are routed to a callback.
+///
+/// The whole memory is committed and accessible when created. Ranges made inaccessible
+/// with `set_no_access` then fault when accessed by the host, and the faults are given to
+/// the handler of the memory, which can emulate the access (to implement memory-mapped
+/// I/O) or record it (to track dirty pages), and restore access to the page with
+/// `GuestFault::restore_access` before execution resumes at the faulting instruction.
+///
+/// Faults are intercepted with a `SIGSEGV` handler on Unix and a vectored exception
+/// handler on Windows, which are installed once for the whole process, and forward
+/// faults outside guest memories to the handlers that were installed before them.
+///
+/// # Handlers
+/// Handlers run in the context of a fault, and must therefore only perform operations
+/// that are safe in a signal handler: they must not allocate, lock, or panic. Returning
+/// `false` reports the fault as unhandled, which usually terminates the process.
+///
+/// # Example
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicUsize, Ordering};
+/// use virtualalloc::{GuestMemory, VirtualAlloc};
+///
+/// let page = VirtualAlloc::page_size();
+/// let dirty = Arc::new(AtomicUsize::new(0));
+/// let memory = {
+///     let dirty = dirty.clone();
+///
+///     GuestMemory::new(page * 4, move |fault| {
+///         dirty.fetch_or(1 << (fault.offset() / VirtualAlloc::page_size()), Ordering::Relaxed);
+///         fault.restore_access().is_ok()
+///     }).unwrap()
+/// };
+///
+/// memory.set_no_access(0..page * 4).unwrap();
+///
+/// unsafe {
+///     memory.as_ptr().add(page * 2).write_volatile(1);
+/// }
+///
+/// assert_eq!(dirty.load(Ordering::Relaxed), 0b100);
+/// ```
+pub struct GuestMemory {
+    alloc: VirtualAlloc,
+    state: Box<GuestState>,
+    trap: Option<Trap>
+}
+
+struct GuestState {
+    base: NonNull<u8>,
+    size: usize,
+    handler: FaultHandler
+}
+
+// Guest memory is only accessed through raw pointers, which are synchronized by the emulator.
+unsafe impl Send for GuestMemory {}
+unsafe impl Sync for GuestMemory {}
+
+/// A fault in a `GuestMemory`, given to its handler.
+pub struct GuestFault<'a> {
+    state: &'a GuestState,
+    offset: usize
+}
+
+impl GuestMemory {
+    /// Returns `size` bytes of guest memory, rounded up to whole pages, whose faults are
+    /// given to `handler`.
+    ///
+    /// Fails with `Unsupported` if too many guest memories are alive at once.
+    pub fn new<F>(size: usize, handler: F) -> Result<Self, VirtualMemError>
+        where F: Fn(&GuestFault) -> bool + Send + Sync + 'static {
+        let size = ::round_to_page(size);
+        let alloc = VirtualAlloc::new(size);
+        let base = alloc.reserve(size)?;
+        let state = Box::new(GuestState { base, size, handler: Box::new(handler) });
+
+        unsafe {
+            if let Err(err) = alloc.commit(base, size) {
+                alloc.release(base, size);
+
+                return Err(err)
+            }
+
+            let data = &*state as *const GuestState as *const ();
+
+            match trap::register(base.as_ptr(), size, on_fault, data) {
+                Ok(trap) => Ok(GuestMemory { alloc, state, trap: Some(trap) }),
+                Err(err) => {
+                    alloc.release(base, size);
+
+                    Err(err)
+                }
+            }
+        }
+    }
+
+    /// Returns a pointer to the start of the memory.
+    #[inline]
+    pub fn as_ptr(&self) -> *mut u8 {
+        self.state.base.as_ptr()
+    }
+
+    /// Returns the size of the memory.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.state.size
+    }
+
+    /// Returns whether the memory is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.state.size == 0
+    }
+
+    /// Makes the pages overlapping the given range of offsets inaccessible, so that
+    /// accessing them calls the handler of the memory.
+    pub fn set_no_access(&self, range: Range<usize>) -> Result<(), VirtualMemError> {
+        self.state.protect(range, false, false)
+    }
+
+    /// Makes the pages overlapping the given range of offsets readable, and writable if
+    /// `write` is true. Writes to read-only pages call the handler of the memory.
+    pub fn set_access(&self, range: Range<usize>, write: bool) -> Result<(), VirtualMemError> {
+        self.state.protect(range, true, write)
+    }
+}
+
+impl Drop for GuestMemory {
+    fn drop(&mut self) {
+        // Faults must stop being routed to the handler before the memory can be reused.
+        drop(self.trap.take());
+
+        unsafe {
+            self.alloc.release(self.state.base, self.state.size);
+        }
+    }
+}
+
+impl GuestState {
+    fn protect(&self, range: Range<usize>, read: bool, write: bool)
+        -> Result<(), VirtualMemError> {
+        let page = VirtualAlloc::page_size();
+        let start = range.start / page * page;
+        let end = range.end.min(self.size);
+
+        if start >= end {
+            return Ok(())
+        }
+
+        unsafe {
+            let ptr = NonNull::new_unchecked(self.base.as_ptr().add(start));
+
+            VirtualAlloc::set_protection(ptr, end - start, read, write, false)
+        }
+    }
+}
+
+impl<'a> GuestFault<'a> {
+    /// Returns the offset of the faulting address in the guest memory.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the faulting address.
+    #[inline]
+    pub fn address(&self) -> *mut u8 {
+        self.state.base.as_ptr().wrapping_add(self.offset)
+    }
+
+    /// Makes the faulting page readable and writable, so that the faulting access
+    /// succeeds once execution resumes.
+    #[inline]
+    pub fn restore_access(&self) -> Result<(), VirtualMemError> {
+        self.state.protect(self.offset..self.offset + 1, true, true)
+    }
+
+    /// Changes the protection of the pages overlapping the given range of offsets, like
+    /// `GuestMemory::set_access` and `GuestMemory::set_no_access`.
+    #[inline]
+    pub fn protect(&self, range: Range<usize>, read: bool, write: bool)
+        -> Result<(), VirtualMemError> {
+        self.state.protect(range, read, write)
+    }
+}
+
+/// Gives a fault in guest memory to its handler.
+unsafe fn on_fault(data: *const (), addr: *mut u8) -> bool {
+    let state = &*(data as *const GuestState);
+    let fault = GuestFault { state, offset: addr.addr() - state.base.as_ptr().addr() };
+
+    (state.handler)(&fault)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn routes_faults_to_handlers() {
+        let page = VirtualAlloc::page_size();
+        let faults = Arc::new(AtomicUsize::new(0));
+        let last = Arc::new(AtomicUsize::new(usize::MAX));
+        let memory = {
+            let (faults, last) = (faults.clone(), last.clone());
+
+            GuestMemory::new(page * 4, move |fault| {
+                faults.fetch_add(1, Ordering::Relaxed);
+                last.store(fault.offset(), Ordering::Relaxed);
+                fault.restore_access().is_ok()
+            }).unwrap()
+        };
+
+        unsafe {
+            memory.as_ptr().write_volatile(1);
+        }
+
+        assert_eq!(faults.load(Ordering::Relaxed), 0);
+
+        memory.set_no_access(page..page * 3).unwrap();
+
+        unsafe {
+            assert_eq!(memory.as_ptr().read_volatile(), 1);
+
+            memory.as_ptr().add(page * 2 + 5).write_volatile(7);
+            memory.as_ptr().add(page * 2 + 6).write_volatile(8);
+        }
+
+        assert_eq!(faults.load(Ordering::Relaxed), 1);
+        assert_eq!(last.load(Ordering::Relaxed), page * 2 + 5);
+
+        memory.set_access(0..page * 4, false).unwrap();
+
+        unsafe {
+            assert_eq!(memory.as_ptr().add(page * 2 + 6).read_volatile(), 8);
+
+            memory.as_ptr().add(page).write_volatile(3);
+
+            assert_eq!(memory.as_ptr().add(page).read_volatile(), 3);
+        }
+
+        assert_eq!(faults.load(Ordering::Relaxed), 2);
+        assert_eq!(last.load(Ordering::Relaxed), page);
+    }
+}
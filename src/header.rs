@@ -0,0 +1,132 @@
+//! The header at the start of the files of the persistent types.
+//!
+//! Every file starts with 64 bytes laid out as follows, in little-endian order:
+//!
+//! | Offset | Size | Field                                                           |
+//! |--------|------|-----------------------------------------------------------------|
+//! | 0      | 8    | Magic number, which identifies the type that wrote the file     |
+//! | 8      | 4    | Version of the format of the type                               |
+//! | 12     | 4    | `0x01020304` in the byte order of the machine that wrote it     |
+//! | 16     | 8    | Size of the elements                                            |
+//! | 24     | 8    | Alignment of the elements                                       |
+//! | 32     | 8    | Number of elements, or zero for logs, which scan their records  |
+//! | 40     | 8    | FNV-1a hash of the first 32 bytes                               |
+//!
+//! The remaining bytes are zero. The hash only covers the fields that never change, so
+//! that a crash while the length is updated never makes the header invalid.
+
+use std::io;
+use std::ops::Range;
+
+
+/// The size of the header, after which the contents of the file start.
+pub(crate) const SIZE: usize = 64;
+
+const VERSION: Range<usize> = 8..12;
+const BYTE_ORDER: Range<usize> = 12..16;
+const ELEM_SIZE: Range<usize> = 16..24;
+const ELEM_ALIGN: Range<usize> = 24..32;
+const LEN: Range<usize> = 32..40;
+const CHECKSUM: Range<usize> = 40..48;
+
+/// The value of the byte order field, written in native order.
+const BYTE_ORDER_MARK: u32 = 0x0102_0304;
+
+/// What the header of a file must describe for a type to open it.
+pub(crate) struct FileHeader {
+    /// The name of the type, for error messages.
+    pub(crate) kind: &'static str,
+    pub(crate) magic: [u8; 8],
+    pub(crate) version: u32,
+    pub(crate) elem_size: usize,
+    pub(crate) elem_align: usize
+}
+
+/// Returns the FNV-1a hash of the given bytes.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+#[inline]
+fn read_u64(bytes: &[u8], range: Range<usize>) -> u64 {
+    let mut field = [0; 8];
+
+    field.copy_from_slice(&bytes[range]);
+
+    u64::from_le_bytes(field)
+}
+
+#[inline]
+fn read_u32(bytes: &[u8], range: Range<usize>) -> u32 {
+    let mut field = [0; 4];
+
+    field.copy_from_slice(&bytes[range]);
+
+    u32::from_le_bytes(field)
+}
+
+impl FileHeader {
+    /// Writes the header, with a length of zero, to the first `SIZE` bytes.
+    pub(crate) fn write(&self, bytes: &mut [u8]) {
+        let bytes = &mut bytes[..SIZE];
+
+        bytes.fill(0);
+        bytes[..8].copy_from_slice(&self.magic);
+        bytes[VERSION].copy_from_slice(&self.version.to_le_bytes());
+        bytes[BYTE_ORDER].copy_from_slice(&BYTE_ORDER_MARK.to_ne_bytes());
+        bytes[ELEM_SIZE].copy_from_slice(&(self.elem_size as u64).to_le_bytes());
+        bytes[ELEM_ALIGN].copy_from_slice(&(self.elem_align as u64).to_le_bytes());
+
+        let sum = checksum(&bytes[..LEN.start]);
+
+        bytes[CHECKSUM].copy_from_slice(&sum.to_le_bytes());
+    }
+
+    /// Checks that the first `SIZE` bytes are a header written by the same type, with the
+    /// same version and element layout, on a machine of the same byte order.
+    ///
+    /// Fails with `InvalidData` describing the first mismatch otherwise.
+    pub(crate) fn validate(&self, bytes: &[u8]) -> io::Result<()> {
+        let invalid = |msg: String| Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+
+        if bytes.len() < SIZE || bytes[..8] != self.magic {
+            return invalid(format!("not a {}", self.kind))
+        }
+        if checksum(&bytes[..LEN.start]) != read_u64(bytes, CHECKSUM) {
+            return invalid(format!("corrupted {} header", self.kind))
+        }
+
+        let version = read_u32(bytes, VERSION);
+
+        if version != self.version {
+            return invalid(format!("{} of version {}, expected version {}",
+                                   self.kind, version, self.version))
+        }
+        if read_u32(bytes, BYTE_ORDER) != u32::from_le_bytes(BYTE_ORDER_MARK.to_ne_bytes()) {
+            return invalid(format!("{} written on a machine of another byte order", self.kind))
+        }
+
+        let (size, align) = (read_u64(bytes, ELEM_SIZE), read_u64(bytes, ELEM_ALIGN));
+
+        if (size, align) != (self.elem_size as u64, self.elem_align as u64) {
+            return invalid(format!("{} of elements of size {} and alignment {}, expected size \
+                                    {} and alignment {}", self.kind, size, align,
+                                   self.elem_size, self.elem_align))
+        }
+
+        Ok(())
+    }
+}
+
+/// Returns the length stored in the header.
+#[inline]
+pub(crate) fn len(bytes: &[u8]) -> u64 {
+    read_u64(bytes, LEN)
+}
+
+/// Stores the given length in the header.
+#[inline]
+pub(crate) fn set_len(bytes: &mut [u8], len: u64) {
+    bytes[LEN].copy_from_slice(&len.to_le_bytes());
+}
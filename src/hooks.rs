@@ -0,0 +1,103 @@
+//! Process-wide callbacks invoked when containers change their memory.
+
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ptr;
+#[cfg(feature = "std")] use std::sync::atomic::{AtomicPtr, Ordering};
+
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::ptr;
+#[cfg(not(feature = "std"))] use core::sync::atomic::{AtomicPtr, Ordering};
+
+
+/// A callback invoked with the start and size of memory that was committed or decommitted.
+pub type RangeHook = fn(*mut u8, usize);
+
+/// A callback invoked with the start and size of memory whose protection changed, and with
+/// its new protection.
+pub type ProtectHook = fn(*mut u8, usize, bool, bool, bool);
+
+pub(crate) static COMMIT: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+pub(crate) static DECOMMIT: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+pub(crate) static PROTECT: AtomicPtr<()> = AtomicPtr::new(ptr::null_mut());
+
+/// Returns the callback stored in the given slot, if any.
+///
+/// # Safety
+/// The slot must only ever hold null or callbacks of type `F`.
+#[inline]
+unsafe fn load<F: Copy>(slot: &AtomicPtr<()>) -> Option<F> {
+    let hook = slot.load(Ordering::Acquire);
+
+    if hook.is_null() {
+        None
+    } else {
+        Some(mem::transmute_copy(&hook))
+    }
+}
+
+/// Reports that `size` bytes starting at `ptr` were committed.
+#[inline]
+pub(crate) fn committed(ptr: *mut u8, size: usize) {
+    if let Some(hook) = unsafe { load::<RangeHook>(&COMMIT) } {
+        hook(ptr, size)
+    }
+}
+
+/// Reports that `size` bytes starting at `ptr` were decommitted.
+#[inline]
+pub(crate) fn decommitted(ptr: *mut u8, size: usize) {
+    if let Some(hook) = unsafe { load::<RangeHook>(&DECOMMIT) } {
+        hook(ptr, size)
+    }
+}
+
+/// Reports that the protection of `size` bytes starting at `ptr` changed.
+#[inline]
+pub(crate) fn protected(ptr: *mut u8, size: usize, read: bool, write: bool, exec: bool) {
+    if let Some(hook) = unsafe { load::<ProtectHook>(&PROTECT) } {
+        hook(ptr, size, read, write, exec)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]      use std::sync::atomic::AtomicUsize;
+    #[cfg(not(feature = "std"))] use core::sync::atomic::AtomicUsize;
+
+    use {VirtualAlloc, VirtualVec};
+
+    // Hooks are process-wide, so only calls about the vector of the test are counted.
+    static TARGET: AtomicPtr<u8> = AtomicPtr::new(ptr::null_mut());
+    static COMMITS: AtomicUsize = AtomicUsize::new(0);
+    static DECOMMITS: AtomicUsize = AtomicUsize::new(0);
+
+    fn count(counter: &AtomicUsize, ptr: *mut u8) {
+        if ptr == TARGET.load(Ordering::Relaxed) {
+            counter.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn calls_hooks_after_changes() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 4);
+
+        TARGET.store(vec.as_mut_ptr(), Ordering::Relaxed);
+
+        VirtualAlloc::on_commit(Some(|ptr, _| count(&COMMITS, ptr)));
+        VirtualAlloc::on_decommit(Some(|ptr, _| count(&DECOMMITS, ptr)));
+
+        vec.reserve(page).unwrap();
+        vec.shrink_to_fit().unwrap();
+
+        VirtualAlloc::on_commit(None);
+        VirtualAlloc::on_decommit(None);
+
+        vec.reserve(page).unwrap();
+
+        assert_eq!(COMMITS.load(Ordering::Relaxed), 1);
+        assert_eq!(DECOMMITS.load(Ordering::Relaxed), 1);
+    }
+}
@@ -0,0 +1,883 @@
+//! Utilities for generating and executing machine code at runtime.
+
+#[cfg(feature = "std")] use std::marker::PhantomData;
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ptr::{self, NonNull};
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::marker::PhantomData;
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{Opaque, VirtualAlloc};
+
+
+/// Whether executable memory must be mapped as such from the start, in which case
+/// writes are enabled and disabled per thread using `set_jit_write_protection`.
+const EXEC_ON_MAP: bool = cfg!(target_os = "macos");
+
+/// A buffer in which machine code can be written, and then executed.
+///
+/// Code is appended to the buffer using `write`, which commits memory as needed. Once
+/// all code has been written, `finalize` makes the buffer executable (and no longer
+/// writable), after which functions can be obtained using `get`.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::jit::JitBuffer;
+///
+/// let mut buf = JitBuffer::new(4096).unwrap();
+/// let offset = buf.write(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]).unwrap(); // mov eax, 42; ret
+///
+/// assert!(buf.finalize());
+///
+/// let f = unsafe { buf.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+///
+/// assert_eq!(f.call(), 42);
+/// ```
+pub struct JitBuffer {
+    alloc: VirtualAlloc,
+    ptr: NonNull<Opaque>,
+    len: usize,
+    committed: usize,
+    finalized: bool
+}
+
+impl JitBuffer {
+    /// Returns a `JitBuffer` that can hold up to `max` bytes of code.
+    ///
+    /// Returns `None` if the memory could not be reserved.
+    #[inline]
+    pub fn new(max: usize) -> Option<Self> {
+        Self::near(ptr::null(), max)
+    }
+
+    /// Returns a `JitBuffer` that can hold up to `max` bytes of code, and that is
+    /// reserved as close to `hint` as possible.
+    ///
+    /// Placing generated code near existing code allows it to use relative calls and
+    /// jumps. However, the hint is only a hint, and the position of the returned buffer
+    /// must still be checked if it matters.
+    ///
+    /// Returns `None` if the memory could not be reserved.
+    pub fn near(hint: *const u8, max: usize) -> Option<Self> {
+        // Executable memory mapped with MAP_JIT is only ever writable or executable for a
+        // given thread, which strict W^X mode allows.
+        let alloc = VirtualAlloc::with_raw_protection(max, ::get_protection(true, true,
+                                                                            EXEC_ON_MAP));
+        let ptr = VirtualAlloc::init(hint as _, max, alloc.prot).ok()?;
+
+        Some(JitBuffer { alloc, ptr, len: 0, committed: 0, finalized: false })
+    }
+
+    /// Returns a pointer to the start of the buffer.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns the number of bytes of code written to the buffer.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no code was written to the buffer.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of bytes of code that the buffer can hold.
+    #[inline]
+    pub fn max_capacity(&self) -> usize {
+        self.alloc.max
+    }
+
+    /// Returns whether the buffer was finalized, and is therefore executable.
+    #[inline]
+    pub fn is_finalized(&self) -> bool {
+        self.finalized
+    }
+
+    /// Appends the given code to the buffer, returning the offset at which it was written.
+    ///
+    /// Returns `None` if the buffer was already finalized, or if it cannot hold the
+    /// given code.
+    pub fn write(&mut self, code: &[u8]) -> Option<usize> {
+        let offset = self.len;
+
+        self.code_mut(code.len())?.copy_from_slice(code);
+
+        Some(offset)
+    }
+
+    /// Extends the buffer by `len` bytes and returns a writable view over them, in which
+    /// code can be written directly.
+    ///
+    /// Returns `None` if the buffer was already finalized, or if it cannot grow by
+    /// `len` bytes.
+    pub fn code_mut(&mut self, len: usize) -> Option<&mut [u8]> {
+        if self.finalized {
+            return None
+        }
+
+        let offset = self.len;
+        let new_len = offset.checked_add(len)?;
+
+        if new_len > self.committed {
+            let page = VirtualAlloc::page_size();
+            let size = ::commit_size(self.committed, new_len, self.alloc.max, page);
+
+            let ptr = self.ptr.as_ptr();
+
+            if unsafe { self.alloc.reserve_internal(ptr, self.committed, size) }.is_err() {
+                return None
+            }
+
+            self.committed = size;
+        }
+
+        VirtualAlloc::set_jit_write_protection(false);
+
+        self.len = new_len;
+
+        unsafe {
+            Some(slice::from_raw_parts_mut(self.ptr.as_ptr().add(offset), len))
+        }
+    }
+
+    /// Makes the buffer executable, and no longer writable.
+    ///
+    /// Returns `false` if the protection of the buffer could not be changed, in which
+    /// case it remains writable.
+    pub fn finalize(&mut self) -> bool {
+        if self.finalized {
+            return true
+        }
+
+        if self.len != 0 && VirtualAlloc::make_executable(self.ptr, self.len).is_err() {
+            return false
+        }
+
+        VirtualAlloc::set_jit_write_protection(true);
+
+        self.finalized = true;
+        true
+    }
+
+    /// Makes the buffer executable, and marks the functions at the given offsets as valid
+    /// targets of indirect calls.
+    ///
+    /// On Windows, processes protected by Control Flow Guard terminate when an indirect
+    /// call targets generated code that was not registered this way. Elsewhere, this is
+    /// equivalent to `finalize`.
+    ///
+    /// Returns `false` if the buffer could not be made executable, or if the entry points
+    /// could not be registered.
+    pub fn finalize_with_entry_points(&mut self, offsets: &[usize]) -> bool {
+        if offsets.iter().any(|&offset| offset >= self.len) {
+            return false
+        }
+
+        self.finalize() && unsafe { mark_call_targets(self.ptr.as_ptr(), self.alloc.max, offsets) }
+    }
+
+    /// Returns the function starting at the given offset in the buffer.
+    ///
+    /// Returns `None` if the buffer has not been finalized yet, or if the offset is
+    /// out of bounds.
+    ///
+    /// # Safety
+    /// `F` must be a function pointer type (such as `extern "C" fn(u32) -> u32`) whose
+    /// signature and calling convention match the code at the given offset.
+    ///
+    /// # Panics
+    /// Panics if `F` does not have the size of a pointer.
+    pub unsafe fn get<F: Copy>(&self, offset: usize) -> Option<JitFn<'_, F>> {
+        assert_eq!(mem::size_of::<F>(), mem::size_of::<*const u8>(),
+                   "Function type must be a function pointer.");
+
+        if !self.finalized || offset >= self.len {
+            return None
+        }
+
+        let ptr = self.ptr.as_ptr().add(offset) as *const u8;
+
+        Some(JitFn { f: mem::transmute_copy(&ptr), _buffer: PhantomData })
+    }
+
+    /// Registers a table of `RUNTIME_FUNCTION` entries describing how to unwind through
+    /// the code in the buffer, so that exceptions and panics can propagate through it.
+    ///
+    /// Addresses in the table are relative to the start of the buffer. The table itself
+    /// is typically written at the end of the buffer, and must remain valid until the
+    /// returned registration is dropped, at which point it is deregistered.
+    ///
+    /// Returns `None` if the table could not be registered.
+    ///
+    /// # Safety
+    /// `table` must point to `count` valid `RUNTIME_FUNCTION` entries.
+    #[cfg(windows)]
+    pub unsafe fn register_function_table(&self, table: *const u8, count: u32)
+        -> Option<UnwindRegistration<'_>> {
+        // Declared here since windows-sys uses a different entry type on each architecture.
+        extern "system" {
+            fn RtlAddFunctionTable(table: *const Opaque, count: u32, base: usize) -> u8;
+        }
+
+        if RtlAddFunctionTable(table, count, self.ptr.as_ptr().expose_provenance()) == 0 {
+            return None
+        }
+
+        Some(UnwindRegistration { table, _buffer: PhantomData })
+    }
+
+    /// Registers an `.eh_frame` section describing how to unwind through the code in the
+    /// buffer, so that exceptions and panics can propagate through it.
+    ///
+    /// The section is typically written at the end of the buffer, must be terminated by
+    /// a zero-length entry, and must remain valid until the returned registration is
+    /// dropped, at which point it is deregistered.
+    ///
+    /// Returns `None` if the section could not be registered.
+    ///
+    /// # Safety
+    /// `eh_frame` must point to a valid `.eh_frame` section whose addresses refer to the
+    /// code in this buffer.
+    #[cfg(not(windows))]
+    pub unsafe fn register_eh_frame(&self, eh_frame: *const u8)
+        -> Option<UnwindRegistration<'_>> {
+        if eh_frame.is_null() {
+            return None
+        }
+
+        for_each_fde(eh_frame, |entry| __register_frame(entry));
+
+        Some(UnwindRegistration { table: eh_frame, _buffer: PhantomData })
+    }
+}
+
+#[cfg(not(windows))]
+extern "C" {
+    fn __register_frame(begin: *const Opaque);
+    fn __deregister_frame(begin: *const Opaque);
+}
+
+/// Invokes the given function on the entries that must be given to `__register_frame`.
+///
+/// libgcc expects the whole `.eh_frame` section, but libunwind (which is used on Apple
+/// platforms) expects each FDE to be registered on its own.
+#[cfg(all(not(windows), not(any(target_os = "macos", target_os = "ios"))))]
+unsafe fn for_each_fde<F: FnMut(*const Opaque)>(eh_frame: *const Opaque, mut f: F) {
+    f(eh_frame)
+}
+
+/// Invokes the given function on the entries that must be given to `__register_frame`.
+///
+/// libgcc expects the whole `.eh_frame` section, but libunwind (which is used on Apple
+/// platforms) expects each FDE to be registered on its own.
+#[cfg(any(target_os = "macos", target_os = "ios"))]
+unsafe fn for_each_fde<F: FnMut(*const Opaque)>(eh_frame: *const Opaque, mut f: F) {
+    let mut entry = eh_frame;
+
+    loop {
+        let len = ptr::read_unaligned(entry as *const u32);
+
+        if len == 0 {
+            break
+        }
+
+        // Extended lengths are not emitted by JIT compilers in practice.
+        let id = ptr::read_unaligned(entry.add(4) as *const u32);
+
+        // CIEs have an id of 0, and must not be registered.
+        if id != 0 {
+            f(entry)
+        }
+
+        entry = entry.add(4 + len as usize);
+    }
+}
+
+/// Unwind information registered for the code in a `JitBuffer`, which is deregistered
+/// when dropped.
+pub struct UnwindRegistration<'a> {
+    table: *const Opaque,
+    _buffer: PhantomData<&'a JitBuffer>
+}
+
+#[cfg(windows)]
+impl<'a> Drop for UnwindRegistration<'a> {
+    fn drop(&mut self) {
+        extern "system" {
+            fn RtlDeleteFunctionTable(table: *const Opaque) -> u8;
+        }
+
+        unsafe {
+            RtlDeleteFunctionTable(self.table);
+        }
+    }
+}
+
+#[cfg(not(windows))]
+impl<'a> Drop for UnwindRegistration<'a> {
+    fn drop(&mut self) {
+        unsafe {
+            for_each_fde(self.table, |entry| __deregister_frame(entry));
+        }
+    }
+}
+
+impl Drop for JitBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            VirtualAlloc::release(self.ptr.as_ptr(), self.alloc.max);
+        }
+    }
+}
+
+/// A function generated in a `JitBuffer`, which cannot outlive it.
+///
+/// Functions of up to six arguments are called with `call`. The function pointer itself
+/// is only handed out by the unsafe `as_raw`, since it could otherwise be called after
+/// the buffer is dropped:
+///
+/// ```compile_fail
+/// use virtualalloc::jit::JitBuffer;
+///
+/// let mut buf = JitBuffer::new(4096).unwrap();
+/// let offset = buf.write(&[0xC3]).unwrap(); // ret
+///
+/// buf.finalize();
+///
+/// let f = unsafe { buf.get::<extern "C" fn()>(offset) }.unwrap();
+/// let g: extern "C" fn() = *f;
+///
+/// drop(buf);
+/// g();
+/// ```
+#[derive(Clone, Copy)]
+pub struct JitFn<'a, F: Copy> {
+    f: F,
+    _buffer: PhantomData<&'a JitBuffer>
+}
+
+impl<'a, F: Copy> JitFn<'a, F> {
+    /// Returns the function pointer, for signatures that `call` does not support.
+    ///
+    /// # Safety
+    /// The function may not be called once the buffer is dropped.
+    #[inline]
+    pub unsafe fn as_raw(&self) -> F {
+        self.f
+    }
+}
+
+/// A buffer in which machine code is written through a writable view, and executed
+/// through a separate executable view of the same memory.
+///
+/// Unlike a `JitBuffer`, a `DualMapJit` never changes the protection of its pages: the
+/// writable view is never executable, and the executable view is never writable. Code
+/// can therefore be appended or patched at any time, even while other code in the buffer
+/// is being executed, without any `mprotect` call.
+///
+/// # Implementation
+/// - On Windows, two views of an anonymous file mapping are used.
+/// - On Linux and Android, two mappings of a `memfd_create` file are used.
+/// - On FreeBSD, two mappings of an anonymous `shm_open` object are used.
+/// - Dual mappings are not supported anywhere else, where `new` returns `None`.
+pub struct DualMapJit {
+    rw: NonNull<Opaque>,
+    rx: NonNull<Opaque>,
+    len: usize,
+    committed: usize,
+    max: usize
+}
+
+impl DualMapJit {
+    /// Returns a `DualMapJit` that can hold up to `max` bytes of code.
+    ///
+    /// Returns `None` if the memory could not be reserved, or if dual mappings are not
+    /// supported on this platform.
+    pub fn new(max: usize) -> Option<Self> {
+        let (rw, rx) = unsafe { map_dual(max)? };
+
+        Some(DualMapJit { rw, rx, len: 0, committed: 0, max })
+    }
+
+    /// Returns a pointer to the start of the writable view.
+    #[inline]
+    pub fn writable_ptr(&self) -> *mut u8 {
+        self.rw.as_ptr()
+    }
+
+    /// Returns a pointer to the start of the executable view.
+    #[inline]
+    pub fn executable_ptr(&self) -> *const u8 {
+        self.rx.as_ptr()
+    }
+
+    /// Returns the number of bytes of code written to the buffer.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no code was written to the buffer.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the maximum number of bytes of code that the buffer can hold.
+    #[inline]
+    pub fn max_capacity(&self) -> usize {
+        self.max
+    }
+
+    /// Appends the given code to the buffer, returning the offset at which it was written.
+    ///
+    /// The code can be executed as soon as this function returns. Returns `None` if the
+    /// buffer cannot hold the given code.
+    pub fn write(&mut self, code: &[u8]) -> Option<usize> {
+        let offset = self.len;
+        let new_len = offset.checked_add(code.len())?;
+
+        if new_len > self.committed {
+            let size = ::commit_size(self.committed, new_len, self.max, VirtualAlloc::page_size());
+
+            if new_len > self.max || !unsafe { commit_dual(self.rw, size) } {
+                return None
+            }
+
+            self.committed = size;
+        }
+
+        self.len = new_len;
+
+        unsafe {
+            self.copy_and_flush(offset, code);
+        }
+
+        Some(offset)
+    }
+
+    /// Overwrites code previously written at the given offset.
+    ///
+    /// Returns `false` if the given range was not written to before.
+    ///
+    /// # Safety
+    /// The patched code must not be executing, unless the patch is known to be atomic
+    /// with respect to instruction fetching on the current architecture.
+    pub unsafe fn patch(&mut self, offset: usize, code: &[u8]) -> bool {
+        match offset.checked_add(code.len()) {
+            Some(end) if end <= self.len => (),
+            _ => return false
+        }
+
+        self.copy_and_flush(offset, code);
+
+        true
+    }
+
+    /// Returns the function starting at the given offset in the executable view.
+    ///
+    /// Returns `None` if the offset is out of bounds.
+    ///
+    /// # Safety
+    /// `F` must be a function pointer type (such as `extern "C" fn(u32) -> u32`) whose
+    /// signature and calling convention match the code at the given offset.
+    ///
+    /// # Panics
+    /// Panics if `F` does not have the size of a pointer.
+    pub unsafe fn get<F: Copy>(&self, offset: usize) -> Option<DualMapFn<'_, F>> {
+        assert_eq!(mem::size_of::<F>(), mem::size_of::<*const u8>(),
+                   "Function type must be a function pointer.");
+
+        if offset >= self.len {
+            return None
+        }
+
+        let ptr = self.rx.as_ptr().add(offset) as *const u8;
+
+        Some(DualMapFn { f: mem::transmute_copy(&ptr), _buffer: PhantomData })
+    }
+
+    #[inline]
+    unsafe fn copy_and_flush(&mut self, offset: usize, code: &[u8]) {
+        ptr::copy_nonoverlapping(code.as_ptr(), self.rw.as_ptr().add(offset), code.len());
+
+        ::flush_icache(self.rx.as_ptr().add(offset), code.len());
+    }
+}
+
+impl Drop for DualMapJit {
+    fn drop(&mut self) {
+        unsafe {
+            unmap_dual(self.rw, self.rx, self.max);
+        }
+    }
+}
+
+/// A function generated in a `DualMapJit`, which cannot outlive it.
+///
+/// Like a `JitFn`, it is called with `call`, and only hands out its function pointer
+/// through the unsafe `as_raw`.
+#[derive(Clone, Copy)]
+pub struct DualMapFn<'a, F: Copy> {
+    f: F,
+    _buffer: PhantomData<&'a DualMapJit>
+}
+
+impl<'a, F: Copy> DualMapFn<'a, F> {
+    /// Returns the function pointer, for signatures that `call` does not support.
+    ///
+    /// # Safety
+    /// The function may not be called once the buffer is dropped.
+    #[inline]
+    pub unsafe fn as_raw(&self) -> F {
+        self.f
+    }
+}
+
+/// Implements `call` on the wrappers of generated functions, for function pointers with
+/// the given arguments.
+macro_rules! impl_call {
+    ($($arg:ident: $ty:ident),*) => {
+        impl_call!(JitFn; $($arg: $ty),*);
+        impl_call!(DualMapFn; $($arg: $ty),*);
+    };
+    ($wrapper:ident; $($arg:ident: $ty:ident),*) => {
+        impl<'a, R, $($ty),*> $wrapper<'a, extern "C" fn($($ty),*) -> R> {
+            /// Calls the function.
+            #[inline]
+            pub fn call(&self, $($arg: $ty),*) -> R {
+                (self.f)($($arg),*)
+            }
+        }
+
+        impl<'a, R, $($ty),*> $wrapper<'a, fn($($ty),*) -> R> {
+            /// Calls the function.
+            #[inline]
+            pub fn call(&self, $($arg: $ty),*) -> R {
+                (self.f)($($arg),*)
+            }
+        }
+
+        impl<'a, R, $($ty),*> $wrapper<'a, unsafe extern "C" fn($($ty),*) -> R> {
+            /// Calls the function.
+            ///
+            /// # Safety
+            /// The function must be safe to call with the given arguments.
+            #[inline]
+            pub unsafe fn call(&self, $($arg: $ty),*) -> R {
+                (self.f)($($arg),*)
+            }
+        }
+    };
+}
+
+impl_call!();
+impl_call!(a: A);
+impl_call!(a: A, b: B);
+impl_call!(a: A, b: B, c: C);
+impl_call!(a: A, b: B, c: C, d: D);
+impl_call!(a: A, b: B, c: C, d: D, e: E);
+impl_call!(a: A, b: B, c: C, d: D, e: E, f: G);
+
+/// Marks the functions at the given offsets of a region as valid targets of indirect calls.
+///
+/// On Windows, processes protected by Control Flow Guard terminate when an indirect call
+/// targets generated code that was not registered this way, and `SetProcessValidCallTargets`
+/// is used. This function does nothing on other platforms.
+///
+/// # Safety
+/// `base` must be the start of a region allocated by this crate, and `len` its size.
+/// Offsets must be aligned on 16 bytes.
+#[cfg(windows)]
+pub unsafe fn mark_call_targets(base: *const u8, len: usize, offsets: &[usize]) -> bool {
+    use windows_sys::Win32::System::Memory::{SetProcessValidCallTargets, CFG_CALL_TARGET_INFO};
+    use windows_sys::Win32::System::SystemServices::CFG_CALL_TARGET_VALID;
+    use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+    // Register targets one by one in order not to allocate.
+    offsets.iter().all(|&offset| {
+        let mut info = CFG_CALL_TARGET_INFO { Offset: offset, Flags: CFG_CALL_TARGET_VALID as _ };
+
+        SetProcessValidCallTargets(GetCurrentProcess(), base as _, len, 1, &mut info) != 0
+    })
+}
+
+/// Marks the functions at the given offsets of a region as valid targets of indirect calls.
+///
+/// On Windows, processes protected by Control Flow Guard terminate when an indirect call
+/// targets generated code that was not registered this way, and `SetProcessValidCallTargets`
+/// is used. This function does nothing on other platforms.
+///
+/// # Safety
+/// `base` must be the start of a region allocated by this crate, and `len` its size.
+/// Offsets must be aligned on 16 bytes.
+#[cfg(not(windows))]
+#[inline]
+pub unsafe fn mark_call_targets(_: *const u8, _: usize, _: &[usize]) -> bool {
+    true
+}
+
+#[cfg(windows)]
+unsafe fn map_dual(max: usize) -> Option<(NonNull<Opaque>, NonNull<Opaque>)> {
+    use windows_sys::Win32::Foundation::{CloseHandle, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::System::Memory::*;
+
+    // SEC_RESERVE, so that pages are committed on demand.
+    let handle = CreateFileMappingW(INVALID_HANDLE_VALUE, ptr::null(),
+                                    PAGE_EXECUTE_READWRITE | SEC_RESERVE,
+                                    (max as u64 >> 32) as _, max as u32, ptr::null());
+
+    if handle.is_null() {
+        return None
+    }
+
+    let rw = MapViewOfFile(handle, FILE_MAP_WRITE, 0, 0, max);
+    let rx = MapViewOfFile(handle, FILE_MAP_READ | FILE_MAP_EXECUTE, 0, 0, max);
+
+    // Views keep the mapping alive on their own.
+    CloseHandle(handle);
+
+    match (NonNull::new(rw.Value as *mut Opaque), NonNull::new(rx.Value as *mut Opaque)) {
+        (Some(rw), Some(rx)) => Some((rw, rx)),
+        _ => {
+            if !rw.Value.is_null() { UnmapViewOfFile(rw); }
+            if !rx.Value.is_null() { UnmapViewOfFile(rx); }
+
+            None
+        }
+    }
+}
+
+#[cfg(windows)]
+unsafe fn commit_dual(rw: NonNull<Opaque>, needed: usize) -> bool {
+    use windows_sys::Win32::System::Memory::{VirtualAlloc, MEM_COMMIT, PAGE_READWRITE};
+
+    // Committing pages through one view makes them available in all views.
+    !VirtualAlloc(rw.as_ptr() as _, needed, MEM_COMMIT, PAGE_READWRITE).is_null()
+}
+
+#[cfg(windows)]
+unsafe fn unmap_dual(rw: NonNull<Opaque>, rx: NonNull<Opaque>, _: usize) {
+    use windows_sys::Win32::System::Memory::{UnmapViewOfFile, MEMORY_MAPPED_VIEW_ADDRESS};
+
+    UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: rw.as_ptr() as _ });
+    UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: rx.as_ptr() as _ });
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn open_dual() -> libc::c_int {
+    libc::memfd_create(b"virtualalloc-jit\0".as_ptr() as _, libc::MFD_CLOEXEC)
+}
+
+#[cfg(target_os = "freebsd")]
+unsafe fn open_dual() -> libc::c_int {
+    libc::shm_open(libc::SHM_ANON, libc::O_RDWR | libc::O_CLOEXEC, 0o600)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+unsafe fn map_dual(max: usize) -> Option<(NonNull<Opaque>, NonNull<Opaque>)> {
+    let fd = open_dual();
+
+    if fd == -1 {
+        return None
+    }
+
+    if libc::ftruncate(fd, max as _) != 0 {
+        libc::close(fd);
+
+        return None
+    }
+
+    let rw = libc::mmap(ptr::null_mut(), max, libc::PROT_READ | libc::PROT_WRITE,
+                        libc::MAP_SHARED, fd, 0);
+    let rx = libc::mmap(ptr::null_mut(), max, libc::PROT_READ | libc::PROT_EXEC,
+                        libc::MAP_SHARED, fd, 0);
+
+    // Mappings keep the file alive on their own.
+    libc::close(fd);
+
+    if rw != libc::MAP_FAILED && rx != libc::MAP_FAILED {
+        return Some((NonNull::new_unchecked(rw as _), NonNull::new_unchecked(rx as _)))
+    }
+
+    if rw != libc::MAP_FAILED { libc::munmap(rw, max); }
+    if rx != libc::MAP_FAILED { libc::munmap(rx, max); }
+
+    None
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+unsafe fn commit_dual(_: NonNull<Opaque>, _: usize) -> bool {
+    // Pages of the shared file are allocated when they are first touched.
+    true
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+unsafe fn unmap_dual(rw: NonNull<Opaque>, rx: NonNull<Opaque>, max: usize) {
+    libc::munmap(rw.as_ptr() as _, max);
+    libc::munmap(rx.as_ptr() as _, max);
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android", target_os = "freebsd")))]
+unsafe fn map_dual(_: usize) -> Option<(NonNull<Opaque>, NonNull<Opaque>)> {
+    None
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android", target_os = "freebsd")))]
+unsafe fn commit_dual(_: NonNull<Opaque>, _: usize) -> bool {
+    false
+}
+
+#[cfg(not(any(windows, target_os = "linux", target_os = "android", target_os = "freebsd")))]
+unsafe fn unmap_dual(_: NonNull<Opaque>, _: NonNull<Opaque>, _: usize) {}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    mod jit_buffer {
+        use super::*;
+
+        fn buf() -> JitBuffer {
+            JitBuffer::new(1_000_000).unwrap()
+        }
+
+        #[test]
+        fn can_execute_written_code() {
+            let mut buf = buf();
+
+            // mov eax, 42; ret
+            let offset = buf.write(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]).unwrap();
+
+            assert!(buf.finalize());
+
+            let f = unsafe { buf.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+
+            assert_eq!(f.call(), 42);
+        }
+
+        #[test]
+        fn cant_write_after_being_finalized() {
+            let mut buf = buf();
+
+            assert!(buf.finalize());
+            assert!(buf.write(&[0xC3]).is_none());
+        }
+
+        #[test]
+        fn can_be_finalized_with_entry_points() {
+            let mut buf = buf();
+
+            buf.write(&[0xC3]).unwrap();
+
+            assert!(buf.finalize_with_entry_points(&[0]));
+            assert!(buf.is_finalized());
+        }
+
+        #[test]
+        fn cant_be_finalized_with_out_of_bounds_entry_points() {
+            assert!(!buf().finalize_with_entry_points(&[0]));
+        }
+
+        #[test]
+        fn cant_return_functions_before_being_finalized() {
+            let mut buf = buf();
+
+            buf.write(&[0xC3]).unwrap();
+
+            assert!(unsafe { buf.get::<extern "C" fn()>(0) }.is_none());
+        }
+
+        #[test]
+        fn cant_grow_over_maximum() {
+            assert!(buf().code_mut(2_000_000).is_none());
+        }
+    }
+
+    #[cfg(any(windows, target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    mod dual_mapped_jit {
+        use super::*;
+
+        fn jit() -> DualMapJit {
+            DualMapJit::new(1_000_000).unwrap()
+        }
+
+        #[test]
+        fn can_execute_code_as_soon_as_it_is_written() {
+            let mut jit = jit();
+
+            // mov eax, 42; ret
+            let offset = jit.write(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]).unwrap();
+            let f = unsafe { jit.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+
+            assert_eq!(f.call(), 42);
+        }
+
+        #[test]
+        fn can_patch_written_code() {
+            let mut jit = jit();
+            let offset = jit.write(&[0xB8, 0x2A, 0x00, 0x00, 0x00, 0xC3]).unwrap();
+
+            assert!(unsafe { jit.patch(offset + 1, &[0x2B]) });
+
+            let f = unsafe { jit.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+
+            assert_eq!(f.call(), 43);
+        }
+
+        #[test]
+        fn cant_patch_code_that_wasnt_written() {
+            assert!(!unsafe { jit().patch(0, &[0xC3]) });
+        }
+    }
+}
+
+#[cfg(all(test, target_arch = "aarch64"))]
+mod tests {
+    use super::*;
+
+    fn buf() -> JitBuffer {
+        JitBuffer::new(1_000_000).unwrap()
+    }
+
+    #[test]
+    fn can_execute_written_code() {
+        let mut buf = buf();
+
+        // mov w0, #42; ret
+        let offset = buf.write(&[0x40, 0x05, 0x80, 0x52, 0xC0, 0x03, 0x5F, 0xD6]).unwrap();
+
+        assert!(buf.finalize());
+
+        let f = unsafe { buf.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+
+        assert_eq!(f.call(), 42);
+    }
+
+    #[test]
+    fn executes_code_patched_after_a_protection_flip() {
+        let mut buf = buf();
+        let offset = buf.write(&[0x40, 0x05, 0x80, 0x52, 0xC0, 0x03, 0x5F, 0xD6]).unwrap();
+        let ptr = NonNull::new(buf.as_ptr() as *mut u8).unwrap();
+
+        assert!(buf.finalize());
+        assert!(VirtualAlloc::set_protection(ptr, buf.len(), true, true, false).is_ok());
+
+        // mov w0, #43
+        unsafe { *ptr.as_ptr() = 0x60 };
+
+        assert!(VirtualAlloc::set_protection(ptr, buf.len(), true, false, true).is_ok());
+
+        let f = unsafe { buf.get::<extern "C" fn() -> u32>(offset) }.unwrap();
+
+        assert_eq!(f.call(), 43);
+    }
+}
@@ -1,62 +1,486 @@
-#![feature(alloc, allocator_api, core_intrinsics)]
+#![cfg_attr(feature = "nightly", feature(allocator_api, core_intrinsics))]
+#![cfg_attr(feature = "nightly", feature(strict_provenance_lints))]
+#![cfg_attr(feature = "nightly", allow(internal_features))]
+#![cfg_attr(feature = "nightly", deny(fuzzy_provenance_casts, lossy_provenance_casts))]
 
 #![cfg_attr(not(feature = "std"), no_std)]
-#![cfg_attr(not(windows), feature(libc))]
-
-#![cfg_attr(test, feature(plugin))]
-#![cfg_attr(test, plugin(speculate))]
 
 #[cfg(windows)]
-extern crate kernel32;
-#[cfg(not(windows))]
+extern crate windows_sys;
+#[cfg(not(any(windows, target_arch = "wasm32")))]
 extern crate libc;
+#[cfg(feature = "bytes")]
+extern crate bytes;
+#[cfg(loom)]
+extern crate loom;
+#[cfg(feature = "metrics")]
+extern crate metrics;
+#[cfg(feature = "portable")]
+extern crate region;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "stable_deref_trait")]
+extern crate stable_deref_trait;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
 
-#[cfg(not(feature = "std"))]
-extern crate core;
-
-#[cfg(test)]
-extern crate alloc;
+#[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
+compile_error!("virtualalloc only supports 32-bit and 64-bit targets.");
 
 
-#[cfg(feature = "std")] use std::alloc::*;
-#[cfg(feature = "std")] use std::intrinsics;
+#[cfg(all(feature = "std", feature = "nightly"))] use std::alloc::{AllocError, Allocator, Layout};
+#[cfg(feature = "std")] use std::fmt;
 #[cfg(feature = "std")] use std::ptr::{self, NonNull};
+#[cfg(feature = "std")] use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(all(feature = "std", target_os = "macos"))] use std::sync::atomic::AtomicU8;
 
-#[cfg(not(feature = "std"))] use core::alloc::*;
-#[cfg(not(feature = "std"))] use core::intrinsics;
+#[cfg(all(not(feature = "std"), feature = "nightly"))] use core::alloc::{AllocError, Allocator, Layout};
+#[cfg(not(feature = "std"))] use core::fmt;
 #[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+#[cfg(not(feature = "std"))] use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(all(not(feature = "std"), target_os = "macos"))] use core::sync::atomic::AtomicU8;
 
+#[cfg(all(feature = "std", feature = "tokio"))]
+pub mod async_io;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod append_log;
+mod backing;
+#[cfg(feature = "bytes")]
+mod buf;
+#[cfg(feature = "std")]
+mod chained;
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+pub mod channel;
+mod cursor;
+mod error;
+#[cfg(feature = "fault-injection")]
+pub mod fault;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod file_mapping;
+mod frame;
+#[cfg(feature = "std")]
+mod group;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod guest;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod header;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod jit;
+mod log;
+mod hooks;
+#[cfg(target_os = "macos")]
+mod mach;
+#[cfg(feature = "std")]
+pub mod mock;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub mod mte;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod near;
+mod offset;
+#[cfg(feature = "std")]
+mod oom;
+mod options;
+#[cfg(feature = "std")]
+mod pagemap;
+#[cfg(feature = "std")]
+mod pool;
+#[cfg(feature = "std")]
+mod registry;
+#[cfg(feature = "portable")]
+mod portable;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod persistent;
+mod pod;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+pub mod prefault;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod ring;
+#[cfg(not(target_arch = "wasm32"))]
+pub mod secret;
+#[cfg(feature = "serde")]
+pub mod serde_bytes;
+mod shared;
+#[cfg(feature = "std")]
+mod sharded;
+mod stats;
+#[cfg(feature = "std")]
+mod streaming;
+mod sync;
+mod trace;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod trap;
+#[cfg(all(feature = "std", unix, not(target_os = "fuchsia")))]
+mod transaction;
+#[cfg(all(feature = "std", target_os = "linux"))]
+pub mod uring;
+mod vec;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod virtual_slice;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+mod watch;
+#[cfg(all(target_pointer_width = "64", not(target_arch = "wasm32")))]
+mod wasm_memory;
+#[cfg(target_os = "fuchsia")]
+mod zircon;
 
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+pub use append_log::{AppendLog, AppendLogIter};
+pub use backing::VirtualBacking;
+#[cfg(feature = "std")]
+pub use chained::ChainedVirtualVec;
+pub use cursor::VirtualCursor;
+pub use error::VirtualMemError;
+pub use frame::FrameArena;
+#[cfg(feature = "std")]
+pub use group::{GroupRegion, RegionGroup};
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+pub use guest::{GuestFault, GuestMemory};
+pub use hooks::{ProtectHook, RangeHook};
+pub use log::{LogEntry, VirtualLog};
+pub use offset::{Offset, OffsetSlab, OffsetVec, OffsetWidth};
+#[cfg(feature = "std")]
+pub use oom::OomReport;
+pub use options::VirtualAllocOptions;
+#[cfg(feature = "std")]
+pub use pool::BufferPool;
+#[cfg(feature = "backtrace")]
+pub use registry::CapturedBacktrace;
+#[cfg(feature = "std")]
+pub use registry::RegionInfo;
+#[cfg(feature = "portable")]
+pub use portable::RegionBacking;
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+pub use persistent::PersistentVirtualVec;
+pub use pod::Pod;
+#[cfg(feature = "std")]
+pub use sharded::{ArenaShard, ShardedArena};
+pub use shared::{SharedVirtualVec, SharedWriter, Snapshot};
+pub use stats::{StatsSnapshot, VirtualStats};
+#[cfg(feature = "std")]
+pub use streaming::StreamingBuffer;
+#[cfg(all(feature = "std", unix, not(target_os = "fuchsia")))]
+pub use transaction::{Transaction, TransactionalRegion};
+pub use vec::{DecommitSlack, GrowthPolicy, PinnedRange, VirtualVec};
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+pub use virtual_slice::{AccessPattern, VirtualSlice};
+#[cfg(all(target_pointer_width = "64", not(target_arch = "wasm32")))]
+pub use wasm_memory::WasmLinearMemory;
+
+use error::last_os_error;
+
+
+/// Whether memory that is both writable and executable can be requested.
+///
+/// Platforms that enforce W^X themselves start in strict mode.
+static STRICT_WX: AtomicBool = AtomicBool::new(
+    cfg!(any(target_os = "openbsd", target_os = "ios", target_os = "tvos", target_os = "watchos"))
+);
 
-#[cfg(windows)]
 #[inline]
-fn get_protection(r: bool, w: bool, x: bool) -> u8 {
-    match (r, w, x) {
-        (true, true, true)   => 0x40,
-        (true, false, true)  => 0x20,
-        (false, false, true) => 0x10,
-        (true, true, false)  => 0x04,
-        (true, false, false) => 0x02,
+fn is_wx_violation(w: bool, x: bool) -> bool {
+    w && x && STRICT_WX.load(Ordering::Relaxed)
+}
 
-        _ => panic!("Invalid protection requested.")
+/// The size from which commits are backed by transparent huge pages, or 0 if they never are.
+static HUGE_PAGE_THRESHOLD: AtomicUsize = AtomicUsize::new(2 << 20);
+
+/// The tag given to memory reserved on macOS.
+#[cfg(target_os = "macos")]
+static VM_TAG: AtomicU8 = AtomicU8::new(mach::DEFAULT_VM_TAG);
+
+/// The maximum size of default allocators and vectors, or 0 if it must be computed
+/// from the platform.
+static DEFAULT_MAX_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The default maximum size on platforms with a large address space and no limit.
+const LARGE_DEFAULT_MAX_SIZE: usize = 500_000_000_000;
+
+/// Returns the maximum size (in bytes) of allocators and vectors created using `Default`.
+///
+/// Unless overridden by `set_default_max_size`, this is computed from the platform: it
+/// is 500GB on 64-bit platforms, but is lowered to an eighth of the available address
+/// space when it is limited (on 32-bit platforms, or by `ulimit -v`).
+pub fn default_max_size() -> usize {
+    match DEFAULT_MAX_SIZE.load(Ordering::Relaxed) {
+        0 => platform_default_max_size(),
+        max => max
     }
 }
 
-#[cfg(not(windows))]
+/// Overrides the maximum size (in bytes) of allocators and vectors created using
+/// `Default`, or restores the platform default if `None` is given.
+pub fn set_default_max_size(max: Option<usize>) {
+    DEFAULT_MAX_SIZE.store(max.unwrap_or(0), Ordering::Relaxed)
+}
+
+/// The cached page size, or 0 if it has not been queried yet.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// Rounds the given size up to a whole number of pages, saturating instead of wrapping.
+#[inline]
+fn round_to_page(size: usize) -> usize {
+    let page = VirtualAlloc::page_size();
+
+    size.saturating_add(page - 1) / page * page
+}
+
+/// The most memory committed ahead of what was requested by a single commit.
+const MAX_COMMIT_AHEAD: usize = 64 << 20;
+
+/// Returns how many bytes to commit to satisfy a request for `requested` bytes when
+/// `committed` bytes already are.
+///
+/// Commits are rounded to whole pages, and double the committed memory until committing
+/// `MAX_COMMIT_AHEAD` bytes at a time, so that many small requests only translate into a
+/// logarithmic number of system calls. They never exceed `max`, unless `requested` does.
+#[inline]
+fn commit_size(committed: usize, requested: usize, max: usize, page: usize) -> usize {
+    commit_size_ahead(committed, requested, max, page, MAX_COMMIT_AHEAD)
+}
+
+/// Same as `commit_size`, but commits at most `max_ahead` bytes ahead of `committed`.
+#[inline]
+fn commit_size_ahead(committed: usize, requested: usize, max: usize, page: usize,
+                     max_ahead: usize) -> usize {
+    let ahead = committed.min(max_ahead);
+    let target = requested.max(committed.saturating_add(ahead));
+
+    target.div_ceil(page).saturating_mul(page).min(max).max(requested)
+}
+
+/// Commits the first `size` bytes of the reservation at `base`, of which the first
+/// `committed` bytes already are, by only committing the pages past them.
+///
+/// This keeps the cost of growing proportional to the growth, and leaves the protection
+/// of memory that was already committed untouched.
+///
+/// # Safety
+/// `size` may not exceed the size of the reservation, which must belong to `backing`.
 #[inline]
-fn get_protection(r: bool, w: bool, x: bool) -> u8 {
+unsafe fn commit_delta<B: VirtualBacking>(backing: &B, base: NonNull<u8>, committed: usize,
+                                          size: usize) -> Result<(), VirtualMemError> {
+    let start = committed / backing.page_size() * backing.page_size();
+
+    if size <= start {
+        return Ok(())
+    }
+
+    backing.commit(NonNull::new_unchecked(base.as_ptr().add(start)), size - start)
+}
+
+#[cfg(windows)]
+fn platform_default_max_size() -> usize {
+    #[cfg(feature = "std")]      use std::mem;
+    #[cfg(not(feature = "std"))] use core::mem;
+
+    use windows_sys::Win32::System::SystemInformation::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+    unsafe {
+        let mut status: MEMORYSTATUSEX = mem::zeroed();
+
+        status.dwLength = mem::size_of::<MEMORYSTATUSEX>() as _;
+
+        if GlobalMemoryStatusEx(&mut status) == 0 {
+            return LARGE_DEFAULT_MAX_SIZE.min(usize::MAX / 8)
+        }
+
+        LARGE_DEFAULT_MAX_SIZE.min((status.ullAvailVirtual / 8) as usize)
+    }
+}
+
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+fn platform_default_max_size() -> usize {
+    // Assume that user space gets half of the address space on 32-bit platforms.
+    let mut available = if cfg!(target_pointer_width = "32") {
+        usize::MAX / 2
+    } else {
+        LARGE_DEFAULT_MAX_SIZE * 8
+    };
+
+    match address_space_limit() {
+        Some(limit) if limit < available as u64 => available = limit as usize,
+        _ => ()
+    }
+
+    available / 8
+}
+
+/// Returns the limit of the address space of the process, if any.
+///
+/// OpenBSD has no such limit, but counts anonymous mappings against the size of the data
+/// segment, whose limit is returned instead.
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+pub(crate) fn address_space_limit() -> Option<u64> {
     #[cfg(feature = "std")]      use std::mem;
     #[cfg(not(feature = "std"))] use core::mem;
 
+    #[cfg(not(target_os = "openbsd"))]
+    let resource = libc::RLIMIT_AS;
+    #[cfg(target_os = "openbsd")]
+    let resource = libc::RLIMIT_DATA;
+
     unsafe {
-        mem::transmute::<_, u8>(r)      |
-        mem::transmute::<_, u8>(w) << 1 |
-        mem::transmute::<_, u8>(x) << 2
+        let mut limit: libc::rlimit = mem::zeroed();
+
+        if libc::getrlimit(resource, &mut limit) == 0 && limit.rlim_cur != libc::RLIM_INFINITY {
+            Some(limit.rlim_cur as u64)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn platform_default_max_size() -> usize {
+    // Reservations are backed by linear memory as soon as they are made, and all of them
+    // must share its 4GB.
+    WASM_DEFAULT_MAX_SIZE
+}
+
+/// The default maximum size on WebAssembly.
+#[cfg(target_arch = "wasm32")]
+const WASM_DEFAULT_MAX_SIZE: usize = 64 * 1024 * 1024;
+
+/// The size of a page of WebAssembly linear memory.
+#[cfg(target_arch = "wasm32")]
+const WASM_PAGE_SIZE: usize = 0x10000;
+
+#[cfg(windows)]
+type Protection = windows_sys::Win32::System::Memory::PAGE_PROTECTION_FLAGS;
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+type Protection = libc::c_int;
+#[cfg(target_arch = "wasm32")]
+type Protection = u32;
+
+/// A protection flag that maps reservations with their final protection and
+/// `MAP_NORESERVE`, leaving commits to demand paging.
+///
+/// It is never passed to the kernel, and sits well above the flags the kernel defines.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const PROT_NORESERVE: Protection = 1 << 30;
+
+#[cfg(windows)]
+#[inline]
+fn get_protection(r: bool, w: bool, x: bool) -> Protection {
+    use windows_sys::Win32::System::Memory::*;
+
+    match (r, w, x) {
+        (true, true, true)   => PAGE_EXECUTE_READWRITE,
+        (true, false, true)  => PAGE_EXECUTE_READ,
+        (false, false, true) => PAGE_EXECUTE,
+        (true, true, false)  => PAGE_READWRITE,
+        (true, false, false) => PAGE_READONLY,
+
+        _ => panic!("Invalid protection requested.")
+    }
+}
+
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+#[inline]
+fn get_protection(r: bool, w: bool, x: bool) -> Protection {
+    let mut prot = libc::PROT_NONE;
+
+    if r { prot |= libc::PROT_READ }
+    if w { prot |= libc::PROT_WRITE }
+    if x { prot |= libc::PROT_EXEC }
+
+    prot
+}
+
+#[cfg(target_arch = "wasm32")]
+#[inline]
+fn get_protection(r: bool, w: bool, x: bool) -> Protection {
+    r as Protection | (w as Protection) << 1 | (x as Protection) << 2
+}
+
+/// Flushes the instruction cache for the given range, ensuring that code written to
+/// it will be seen by the processor when it is executed.
+///
+/// This must be called after patching memory that is already executable; buffers made
+/// executable using `VirtualAlloc::make_executable` are flushed automatically. On x86,
+/// the instruction cache is coherent and this function does nothing.
+///
+/// # Safety
+/// The given range must be mapped.
+///
+/// # Implementation
+/// - On Windows, `FlushInstructionCache` is used.
+/// - On macOS and iOS, `sys_icache_invalidate` is used.
+/// - On other ARM platforms, `__clear_cache` is used.
+#[inline]
+pub unsafe fn flush_icache(ptr: *const u8, len: usize) {
+    #[cfg(windows)]
+    {
+        use windows_sys::Win32::System::Diagnostics::Debug::FlushInstructionCache;
+        use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+        FlushInstructionCache(GetCurrentProcess(), ptr as _, len);
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        extern "C" {
+            fn sys_icache_invalidate(start: *mut libc::c_void, len: libc::size_t);
+        }
+
+        sys_icache_invalidate(ptr as _, len);
+    }
+
+    #[cfg(all(not(windows), not(any(target_os = "macos", target_os = "ios")),
+              any(target_arch = "arm", target_arch = "aarch64")))]
+    {
+        extern "C" {
+            fn __clear_cache(start: *mut libc::c_char, end: *mut libc::c_char);
+        }
+
+        __clear_cache(ptr as _, ptr.add(len) as _);
     }
+
+    // The instruction cache is coherent with the data cache on x86.
+    #[cfg(all(not(windows), not(any(target_os = "macos", target_os = "ios")),
+              not(any(target_arch = "arm", target_arch = "aarch64"))))]
+    let _ = (ptr, len);
 }
 
 type Opaque = u8;
 
+/// Overwrites the given range with zeroes in a way that cannot be optimized away, even
+/// if the memory is never read again.
+#[inline(never)]
+unsafe fn secure_zero(ptr: *mut Opaque, len: usize) {
+    #[cfg(feature = "std")]      use std::sync::atomic::{compiler_fence, Ordering};
+    #[cfg(not(feature = "std"))] use core::sync::atomic::{compiler_fence, Ordering};
+
+    for i in 0..len {
+        ptr::write_volatile(ptr.add(i), 0);
+    }
+
+    compiler_fence(Ordering::SeqCst);
+}
+
+/// Faults in the committed pages of the given range for writing, without changing their
+/// contents.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+unsafe fn populate(ptr: *mut Opaque, len: usize) {
+    // MADV_POPULATE_WRITE requires Linux 5.14, and is merely a read-ahead hint before.
+    if libc::madvise(ptr as _, len, libc::MADV_POPULATE_WRITE) != 0 {
+        libc::madvise(ptr as _, len, libc::MADV_WILLNEED);
+    }
+}
+
+/// Faults in the committed pages of the given range for writing, without changing their
+/// contents.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+unsafe fn populate(ptr: *mut Opaque, len: usize) {
+    if let Some(ptr) = NonNull::new(ptr) {
+        let _ = VirtualAlloc::prefetch(ptr, len);
+    }
+}
+
 /// An allocator that allocates memory in large uncommited pools of memory,
 /// which has the added benefit of preserving pointers when reallocating.
 /// 
@@ -70,180 +494,2277 @@ type Opaque = u8;
 /// # Implementation
 /// - On Windows, `VirtualAlloc`, `VirtualProtect` and `VirtualFree` are used.
 /// - On Unix, `mmap`, `mprotect` and `munmap` are used.
+/// - On macOS, executable memory is additionally mapped with `MAP_JIT`.
 pub struct VirtualAlloc {
     max: usize,
-    prot: u8
+    prot: Protection,
+    guard: GuardPages,
+    quantum: Option<usize>,
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    commit_on_fault: bool,
+    /// Whether commits use huge pages, or `None` to follow `huge_page_threshold`.
+    huge_pages: Option<bool>,
+    populate: bool,
+    numa_node: Option<u32>,
+    growth: GrowthPolicy,
+    label: Option<&'static str>,
+    #[cfg(feature = "nightly")]
+    committed: HighWaterMark
+}
+
+/// The number of bytes committed in the last reservation grown by an allocator, which lets
+/// growing allocations skip system calls when their pages are already committed.
+///
+/// Allocators are usually dedicated to a single allocation, so remembering one reservation
+/// is enough. Updates are guarded by a spin lock, which is only held for a few loads and
+/// stores.
+#[cfg(feature = "nightly")]
+struct HighWaterMark {
+    lock: AtomicBool,
+    base: AtomicUsize,
+    committed: AtomicUsize
+}
+
+#[cfg(feature = "nightly")]
+impl HighWaterMark {
+    const fn new() -> Self {
+        HighWaterMark { lock: AtomicBool::new(false), base: AtomicUsize::new(0),
+                        committed: AtomicUsize::new(0) }
+    }
+
+    fn with_lock<R>(&self, f: impl FnOnce(&AtomicUsize, &AtomicUsize) -> R) -> R {
+        #[cfg(feature = "std")]      use std::hint;
+        #[cfg(not(feature = "std"))] use core::hint;
+
+        while self.lock.compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+                       .is_err() {
+            hint::spin_loop();
+        }
+
+        let result = f(&self.base, &self.committed);
+
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+
+    /// Returns the number of bytes known to be committed in the reservation at `base`.
+    fn get(&self, base: *mut Opaque) -> usize {
+        self.with_lock(|b, committed| match b.load(Ordering::Relaxed) == base.addr() {
+            true => committed.load(Ordering::Relaxed),
+            false => 0
+        })
+    }
+
+    /// Records that `size` bytes are committed in the reservation at `base`.
+    fn set(&self, base: *mut Opaque, size: usize) {
+        self.with_lock(|b, committed| {
+            b.store(base.addr(), Ordering::Relaxed);
+            committed.store(size, Ordering::Relaxed);
+        })
+    }
+
+    /// Forgets the reservation at `base`, which is about to be released.
+    fn forget(&self, base: *mut Opaque) {
+        self.with_lock(|b, _| {
+            if b.load(Ordering::Relaxed) == base.addr() {
+                b.store(0, Ordering::Relaxed);
+            }
+        })
+    }
+}
+
+/// Where inaccessible guard pages are placed around the allocations of a `VirtualAlloc`,
+/// in order to detect out-of-bounds accesses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GuardPages {
+    /// Allocations are not guarded.
+    None,
+    /// Allocations are placed so that their end abuts a guard page, which turns
+    /// overflows (including off-by-one writes) into immediate faults.
+    End,
+    /// Allocations are placed between two guard pages, which additionally turns
+    /// underflows into immediate faults.
+    Both
 }
 
 impl Default for VirtualAlloc {
-    /// Returns a `VirtualAlloc` that can allocate up to 500GB of read-write memory.
+    /// Returns a `VirtualAlloc` that can allocate up to `default_max_size()` bytes of
+    /// read-write memory.
     fn default() -> Self {
-        VirtualAlloc { max: 500_000_000_000, prot: get_protection(true, true, false) }
+        VirtualAlloc::new(default_max_size())
+    }
+}
+
+impl Clone for VirtualAlloc {
+    /// Returns an allocator with the same configuration, which can therefore grow and
+    /// release the allocations of this one.
+    ///
+    /// The committed size remembered for the last allocation grown by this allocator is
+    /// not shared, so growing that allocation through the clone may commit its pages again,
+    /// which is harmless.
+    fn clone(&self) -> Self {
+        VirtualAlloc {
+            max: self.max,
+            prot: self.prot,
+            guard: self.guard,
+            quantum: self.quantum,
+            #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+            commit_on_fault: self.commit_on_fault,
+            huge_pages: self.huge_pages,
+            populate: self.populate,
+            numa_node: self.numa_node,
+            growth: self.growth,
+            label: self.label,
+            #[cfg(feature = "nightly")]
+            committed: HighWaterMark::new()
+        }
+    }
+}
+
+/// Allocators are equal when they have the same configuration, in which case either can
+/// grow and release the allocations of the other.
+impl PartialEq for VirtualAlloc {
+    fn eq(&self, other: &Self) -> bool {
+        self.max == other.max && self.prot == other.prot && self.guard == other.guard &&
+            self.quantum == other.quantum && self.commits_on_fault() == other.commits_on_fault() &&
+            self.huge_pages == other.huge_pages && self.populate == other.populate &&
+            self.numa_node == other.numa_node && self.growth == other.growth &&
+            self.label == other.label
+    }
+}
+
+impl Eq for VirtualAlloc {}
+
+impl fmt::Debug for VirtualAlloc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Demand paging is stored as a protection flag, but shown on its own.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        let prot = self.prot & !PROT_NORESERVE;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        let prot = self.prot;
+
+        f.debug_struct("VirtualAlloc")
+            .field("max", &self.max)
+            .field("protection", &format_args!("{:#x}", prot))
+            .field("guard", &self.guard)
+            .field("reservation_quantum", &self.quantum)
+            .field("demand_paged", &self.is_demand_paged())
+            .field("commit_on_fault", &self.commits_on_fault())
+            .field("huge_pages", &self.huge_pages)
+            .field("populate", &self.populate)
+            .field("numa_node", &self.numa_node)
+            .field("growth", &self.growth)
+            .field("label", &self.label)
+            .finish()
     }
 }
 
 impl VirtualAlloc {
+    #[inline]
+    fn with_raw_protection(max: usize, prot: Protection) -> Self {
+        VirtualAlloc {
+            max, prot,
+            guard: GuardPages::None,
+            quantum: None,
+            #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+            commit_on_fault: false,
+            huge_pages: None,
+            populate: false,
+            numa_node: None,
+            growth: GrowthPolicy::default(),
+            label: None,
+            #[cfg(feature = "nightly")]
+            committed: HighWaterMark::new()
+        }
+    }
+
     /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of read-write memory.
     pub fn new(max: usize) -> Self {
-        VirtualAlloc { max, prot: get_protection(true, true, false) }
+        VirtualAlloc::with_raw_protection(max, get_protection(true, true, false))
+    }
+
+    /// Returns a builder for an allocator, which configures every aspect of the allocator
+    /// in one place.
+    ///
+    /// # Example
+    /// ```
+    /// use virtualalloc::{GrowthPolicy, VirtualAlloc, VirtualVec};
+    ///
+    /// let alloc = VirtualAlloc::options()
+    ///     .max(1 << 30)
+    ///     .huge_pages(true)
+    ///     .growth_policy(GrowthPolicy::Exact)
+    ///     .label("frames")
+    ///     .build();
+    ///
+    /// let mut vec = VirtualVec::<u8>::with_backing(1 << 20, alloc);
+    ///
+    /// vec.push(1);
+    /// ```
+    #[inline]
+    pub fn options() -> VirtualAllocOptions {
+        VirtualAllocOptions::new()
     }
 
     /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of memory.
+    ///
+    /// # Panics
+    /// Panics if memory that is both writable and executable is requested in strict W^X
+    /// mode. See `try_with_protection`.
     pub fn with_protection(max: usize, read: bool, write: bool, exec: bool) -> Self {
-        VirtualAlloc { max, prot: get_protection(read, write, exec) }
+        VirtualAlloc::try_with_protection(max, read, write, exec)
+            .unwrap_or_else(|err| panic!("Could not create allocator: {}.", err))
     }
 
-    /// Returns the absolute maximum capacity of the vector.
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of memory.
+    ///
+    /// Fails with `WxViolation` if memory that is both writable and executable is requested
+    /// in strict W^X mode. Such code must instead be written to writable memory, which is
+    /// then made executable with `make_executable`, or written and executed through the
+    /// two views of a `jit::DualMapJit`.
+    pub fn try_with_protection(max: usize, read: bool, write: bool, exec: bool)
+        -> Result<Self, VirtualMemError> {
+        if is_wx_violation(write, exec) {
+            return Err(VirtualMemError::WxViolation)
+        }
+
+        Ok(VirtualAlloc::with_raw_protection(max, get_protection(read, write, exec)))
+    }
+
+    /// Makes the allocator map its reservations readable and writable upfront, and leave
+    /// committing memory to the kernel as pages are first touched.
+    ///
+    /// This removes the system call otherwise made every time memory is committed, but
+    /// also makes committing memory infallible: when the system runs out of memory, the
+    /// process is killed on first touch instead of seeing `CommitFailed`. Users who need
+    /// commit failures to be reported deterministically should keep the default mode.
+    ///
+    /// # Note
+    /// Demand paging is only supported on Linux, where reservations are mapped with
+    /// `MAP_NORESERVE`. This function returns the allocator unchanged everywhere else.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
     #[inline]
-    pub fn max_capacity(&self) -> usize {
-        self.max
+    pub fn demand_paged(mut self) -> Self {
+        self.prot |= PROT_NORESERVE;
+        self
     }
 
-    /// Sets the protection of an allocated buffer.
-    #[cfg(windows)]
+    /// Makes the allocator map its reservations readable and writable upfront, and leave
+    /// committing memory to the kernel as pages are first touched.
+    ///
+    /// This removes the system call otherwise made every time memory is committed, but
+    /// also makes committing memory infallible: when the system runs out of memory, the
+    /// process is killed on first touch instead of seeing `CommitFailed`. Users who need
+    /// commit failures to be reported deterministically should keep the default mode.
+    ///
+    /// # Note
+    /// Demand paging is only supported on Linux, where reservations are mapped with
+    /// `MAP_NORESERVE`. This function returns the allocator unchanged everywhere else.
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
     #[inline]
-    pub fn set_protection<T: ?Sized>(ptr: NonNull<T>, len: usize,
-                                     read: bool, write: bool, exec: bool) {
-        let prot = get_protection(read, write, exec);
-        let mut old = 0;
+    pub fn demand_paged(self) -> Self {
+        self
+    }
 
-        unsafe {
-            kernel32::VirtualProtect(ptr.as_ptr() as _, len as _, prot as _, &mut old);
-        }
+    /// Returns whether the allocator leaves committing memory to demand paging.
+    #[inline]
+    pub fn is_demand_paged(&self) -> bool {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        return self.prot & PROT_NORESERVE != 0;
+        #[cfg(not(any(target_os = "linux", target_os = "android")))]
+        return false;
     }
 
-    /// Sets the protection of an allocated buffer.
-    #[cfg(not(windows))]
+    /// Makes containers leave their reservations inaccessible, and commit each page when it
+    /// is first accessed, from a fault handler.
+    ///
+    /// Committing memory explicitly then costs nothing, and memory accessed sparsely
+    /// through a raw pointer to the reservation is committed one page at a time without
+    /// any bookkeeping. Unlike `demand_paged`, this works on every platform where faults
+    /// can be handled, and pages decommitted by containers become inaccessible again.
+    /// However, each first access to a page costs a fault and a system call, and failing
+    /// to commit a page terminates the process.
+    ///
+    /// Faults are handled by the handler that also serves `GuestMemory`, and at most a
+    /// few hundred reservations can commit on fault at once; further reservations fail with
+    /// `Unsupported`. This only applies to memory reserved through `VirtualBacking`.
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
     #[inline]
-    pub fn set_protection<T: ?Sized>(ptr: NonNull<T>, len: usize,
-                                     read: bool, write: bool, exec: bool) {
-        let prot = get_protection(read, write, exec);
-        
-        unsafe {
-            libc::mprotect(ptr.as_ptr() as _, len, prot as _);
-        }
+    pub fn commit_on_fault(mut self) -> Self {
+        self.commit_on_fault = true;
+        self
     }
 
-    #[cfg(windows)]
-    fn init(max_size: usize, prot: u8) -> *mut Opaque {
-        unsafe {
-            kernel32::VirtualAlloc(ptr::null_mut(), max_size as _, 0x00002000, prot as _) as _
-        }
+    /// Returns whether containers commit the memory of the allocator when it is first
+    /// accessed.
+    #[inline]
+    pub fn commits_on_fault(&self) -> bool {
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        return self.commit_on_fault;
+        #[cfg(not(all(feature = "std", any(unix, windows), not(target_os = "fuchsia"))))]
+        return false;
     }
-    #[cfg(not(windows))]
-    fn init(max_size: usize, _: u8) -> *mut Opaque {
-        unsafe {
-            libc::mmap(ptr::null_mut(), max_size, 0x0, 0x22, -1, 0) as _
-        }
+
+    /// Specifies whether allocations must be surrounded by inaccessible guard pages, in
+    /// the manner of Electric Fence.
+    ///
+    /// This is a debug mode meant to catch out-of-bounds accesses during testing: since
+    /// the end of each allocation abuts a guard page, allocations can no longer grow in
+    /// place, and every reallocation moves its contents to a new reservation. Each
+    /// allocation also takes at least two pages of memory.
+    ///
+    /// # Note
+    /// Allocations whose size is not a multiple of their alignment cannot abut a guard
+    /// page exactly, and overflows of up to `align - 1` bytes may go unnoticed.
+    ///
+    /// Guard pages only apply to allocations made through the `Allocator` implementation,
+    /// which requires the `nightly` feature.
+    #[inline]
+    pub fn with_guard_pages(mut self, guard: GuardPages) -> Self {
+        self.guard = guard;
+        self
     }
 
-    #[cfg(windows)]
-    fn grow(&self, ptr: *mut Opaque, needed: usize, prot: u8) -> bool {
-        unsafe {
-            kernel32::VirtualAlloc(ptr as _, needed as _, 0x00001000, prot as _) != ptr::null_mut()
-        }
+    /// Makes each allocation reserve address space for its own size, rounded up to a
+    /// multiple of `quantum` bytes, rather than for the maximum size of the allocator.
+    ///
+    /// By default, every allocation reserves `max_capacity()` bytes so that it always grows
+    /// in place, which lets a handful of collections sharing an allocator with a large
+    /// maximum size exhaust the address space. With a quantum, allocations grow in place
+    /// within their reservation, and are moved to a larger one past it, up to the maximum
+    /// size. Larger quanta trade address space for fewer moves.
+    ///
+    /// The quantum is rounded up to whole pages. Like guard pages, it only applies to
+    /// allocations made through the `Allocator` implementation, which requires the
+    /// `nightly` feature.
+    #[inline]
+    pub fn with_reservation_quantum(mut self, quantum: usize) -> Self {
+        self.quantum = Some(round_to_page(quantum.max(1)));
+        self
     }
-    #[cfg(not(windows))]
-    fn grow(&self, ptr: *mut Opaque, needed: usize, prot: u8) -> bool {
-        unsafe {
-            libc::mprotect(ptr as _, needed, prot as _) == 0
-        }
+
+    /// Returns the granularity of the reservations of allocations, or `None` if each of
+    /// them reserves the maximum size of the allocator.
+    #[inline]
+    pub fn reservation_quantum(&self) -> Option<usize> {
+        self.quantum
     }
 
+    /// Enables or disables strict W^X mode for the whole process.
+    ///
+    /// In strict W^X mode, memory can never be writable and executable at the same time:
+    /// `set_protection` and `try_with_protection` refuse such requests up front with
+    /// `WxViolation`, and `with_protection` panics. Code must instead be written to
+    /// writable memory, which is then made executable using `make_executable`.
+    ///
+    /// Strict mode is enabled by default on platforms that enforce W^X themselves
+    /// (OpenBSD and iOS-like targets), where such requests would fail at runtime anyway.
     #[inline]
-    unsafe fn reserve_internal(&self, ptr: *mut Opaque, min: usize) -> bool {
-        intrinsics::likely(min <= self.max) &&
-        intrinsics::likely(self.grow(ptr, min, self.prot as _))
+    pub fn set_strict_wx(enabled: bool) {
+        STRICT_WX.store(enabled, Ordering::Relaxed)
+    }
+
+    /// Sets the size from which a single commit asks the system to back the committed
+    /// memory with transparent huge pages, or disables huge pages if `None` is given.
+    ///
+    /// Huge pages make sequential scans over large buffers cause far fewer TLB misses.
+    /// The threshold is 2MB by default, and is ignored by allocators built with
+    /// `VirtualAllocOptions::huge_pages`.
+    ///
+    /// # Implementation
+    /// - On Linux, `madvise(MADV_HUGEPAGE)` is used.
+    /// - On Windows, large pages can only be requested when memory is reserved, and
+    ///   require the `SeLockMemoryPrivilege` privilege, so commits are left unchanged.
+    /// - Huge pages are not used anywhere else.
+    #[inline]
+    pub fn set_huge_page_threshold(threshold: Option<usize>) {
+        HUGE_PAGE_THRESHOLD.store(threshold.unwrap_or(0), Ordering::Relaxed)
     }
-}
 
-unsafe impl Alloc for VirtualAlloc {
-    unsafe fn alloc(&mut self, _: Layout) -> Result<NonNull<Opaque>, AllocErr> {
-        match NonNull::new(Self::init(self.max, self.prot)) {
-            Some(ptr) => Ok(ptr),
-            None => Err(AllocErr)
+    /// Returns the size from which commits are backed by transparent huge pages, or `None`
+    /// if they never are.
+    #[inline]
+    pub fn huge_page_threshold() -> Option<usize> {
+        match HUGE_PAGE_THRESHOLD.load(Ordering::Relaxed) {
+            0 => None,
+            threshold => Some(threshold)
         }
     }
 
-    unsafe fn alloc_zeroed(&mut self, layout: Layout) -> Result<NonNull<Opaque>, AllocErr> {
-        // VirtualAlloc automatically initializes to zero on Windows.
-        // mmap automatically initializes to zero on Unix with MAP_ANONYMOUS (which we use).
-        self.alloc(layout)
+    /// Sets a function called whenever a container commits memory, with the start and the
+    /// size of the committed memory, or removes it if `None` is given.
+    ///
+    /// This and the other hooks apply to the whole process, and let embedders keep their
+    /// own accounting of memory, enforce their policies, or mirror changes into a garbage
+    /// collector or a profiler. They are only called for memory committed by containers
+    /// through the `VirtualAlloc` backing, after the operation succeeded.
+    #[inline]
+    pub fn on_commit(hook: Option<RangeHook>) {
+        let hook = hook.map_or(ptr::null_mut(), |hook| hook as *mut ());
+
+        hooks::COMMIT.store(hook, Ordering::Release)
+    }
+
+    /// Sets a function called whenever a container decommits memory, with the start and
+    /// the size of the decommitted memory, or removes it if `None` is given.
+    #[inline]
+    pub fn on_decommit(hook: Option<RangeHook>) {
+        let hook = hook.map_or(ptr::null_mut(), |hook| hook as *mut ());
+
+        hooks::DECOMMIT.store(hook, Ordering::Release)
+    }
+
+    /// Sets a function called whenever a container changes the protection of its memory,
+    /// with the start and size of the memory and its new protection, or removes it if
+    /// `None` is given.
+    #[inline]
+    pub fn on_protect(hook: Option<ProtectHook>) {
+        let hook = hook.map_or(ptr::null_mut(), |hook| hook as *mut ());
+
+        hooks::PROTECT.store(hook, Ordering::Release)
+    }
+
+    /// Returns a snapshot of the memory used by all live vectors of the process.
+    #[inline]
+    pub fn stats_snapshot() -> StatsSnapshot {
+        StatsSnapshot::capture()
+    }
+
+    /// Returns a snapshot of the memory used by all live vectors of the process as a JSON
+    /// object, which services can expose directly on their debug endpoints.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn stats_snapshot_json() -> String {
+        StatsSnapshot::capture().to_json()
+    }
+
+    /// Enables or disables the registration of new vectors in the registry of live regions.
+    ///
+    /// Vectors created while the registry is enabled are listed by `live_regions` until
+    /// they are dropped, which helps finding out which part of a program reserved or
+    /// committed memory. Vectors created while it is disabled are never listed.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn set_region_registry(enabled: bool) {
+        registry::set_enabled(enabled)
+    }
+
+    /// Returns the registered vectors that are still alive, in the order they were created.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn live_regions() -> Vec<RegionInfo> {
+        registry::live_regions()
+    }
+
+    /// Prints the registered vectors that are still alive to the standard error, with their
+    /// labels and sizes, and returns how many there are.
+    ///
+    /// Calling this at the end of a test suite catches vectors that were leaked or
+    /// forgotten with `mem::forget`, provided the registry was enabled when they were
+    /// created.
+    #[cfg(feature = "std")]
+    pub fn report_leaks() -> usize {
+        registry::write_leaks(&mut std::io::stderr()).unwrap_or(0)
+    }
+
+    /// Enables the registry of live regions, and prints the registered vectors that are
+    /// still alive when the process exits, like `report_leaks`.
+    ///
+    /// Vectors created before this is first called are not registered, and are therefore
+    /// never reported.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn report_leaks_at_exit() {
+        registry::report_leaks_at_exit()
+    }
+
+    /// Makes registered vectors capture a backtrace whenever they commit at least
+    /// `threshold` bytes at once, which `live_regions` reports along with the backtrace
+    /// of their creation. Only the last such backtrace of each vector is kept.
+    ///
+    /// Capturing backtraces is slow, so this is disabled by default, or if `None` is given.
+    #[cfg(feature = "backtrace")]
+    #[inline]
+    pub fn set_commit_backtrace_threshold(threshold: Option<usize>) {
+        registry::set_commit_backtrace_threshold(threshold)
+    }
+
+    /// Enables or disables the capture of diagnostics when memory cannot be reserved or
+    /// committed.
+    ///
+    /// When enabled, failures to reserve or commit memory through the `VirtualAlloc` backing
+    /// record the available memory, commit charge, address space limit, overcommit mode and
+    /// largest free range of addresses at the time of the failure, which can then be
+    /// retrieved with `VirtualMemError::diagnostics` by the thread that saw the error.
+    /// Capturing them reads system files, so this is disabled by default.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn set_oom_diagnostics(enabled: bool) {
+        oom::set_enabled(enabled)
+    }
+
+    /// Returns whether strict W^X mode is enabled.
+    #[inline]
+    pub fn is_strict_wx() -> bool {
+        STRICT_WX.load(Ordering::Relaxed)
     }
 
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of write-combined memory.
+    ///
+    /// Write-combined memory is not cached, and writes to it are buffered and then
+    /// flushed in bursts, which makes it well-suited for buffers that are only written
+    /// sequentially by the CPU, and then read by another device (such as a GPU).
+    /// Reading from such memory is however extremely slow.
+    ///
+    /// # Note
+    /// Write combining is only supported on Windows, and this function is equivalent to
+    /// `with_protection(max, read, write, false)` everywhere else.
     #[cfg(windows)]
-    unsafe fn dealloc(&mut self, ptr: NonNull<Opaque>, _: Layout) {
-        kernel32::VirtualFree(ptr.as_ptr() as _, self.max as _, 0x8000);
+    pub fn with_write_combining(max: usize, read: bool, write: bool) -> Self {
+        use windows_sys::Win32::System::Memory::PAGE_WRITECOMBINE;
+
+        let prot = get_protection(read, write, false) | PAGE_WRITECOMBINE;
+
+        VirtualAlloc::with_raw_protection(max, prot)
     }
 
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of write-combined memory.
+    ///
+    /// Write-combined memory is not cached, and writes to it are buffered and then
+    /// flushed in bursts, which makes it well-suited for buffers that are only written
+    /// sequentially by the CPU, and then read by another device (such as a GPU).
+    /// Reading from such memory is however extremely slow.
+    ///
+    /// # Note
+    /// Write combining is only supported on Windows, and this function is equivalent to
+    /// `with_protection(max, read, write, false)` everywhere else.
     #[cfg(not(windows))]
-    unsafe fn dealloc(&mut self, ptr: NonNull<Opaque>, _: Layout) {
-        libc::munmap(ptr.as_ptr() as _, self.max);
+    pub fn with_write_combining(max: usize, read: bool, write: bool) -> Self {
+        VirtualAlloc::with_raw_protection(max, get_protection(read, write, false))
     }
 
-    unsafe fn realloc(&mut self, ptr: NonNull<Opaque>, _: Layout, new_size: usize)
-        -> Result<NonNull<Opaque>, AllocErr> {
-        // Grow in place directly
-        if self.reserve_internal(ptr.as_ptr(), new_size) {
-            Ok(ptr)
-        } else {
-            Err(AllocErr)
-        }
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of read-write memory
+    /// with hardware memory tagging enabled.
+    ///
+    /// Tag checking must then be enabled using `mte::enable`, and ranges handed out to
+    /// users must be tagged using `mte::tag_range`.
+    ///
+    /// # Note
+    /// Memory tagging is only supported on AArch64 Linux, and this function is equivalent
+    /// to `new(max)` everywhere else.
+    #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+    pub fn with_memory_tagging(max: usize) -> Self {
+        VirtualAlloc::with_raw_protection(max, get_protection(true, true, false) | mte::PROT_MTE)
     }
 
-    unsafe fn grow_in_place(&mut self, ptr: NonNull<Opaque>, _: Layout, new_size: usize)
-        -> Result<(), CannotReallocInPlace> {
-        if self.reserve_internal(ptr.as_ptr(), new_size) {
-            Ok(())
-        } else {
-            Err(CannotReallocInPlace)
-        }
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of read-write memory
+    /// with hardware memory tagging enabled.
+    ///
+    /// Tag checking must then be enabled using `mte::enable`, and ranges handed out to
+    /// users must be tagged using `mte::tag_range`.
+    ///
+    /// # Note
+    /// Memory tagging is only supported on AArch64 Linux, and this function is equivalent
+    /// to `new(max)` everywhere else.
+    #[cfg(not(all(target_os = "linux", target_arch = "aarch64")))]
+    pub fn with_memory_tagging(max: usize) -> Self {
+        Self::new(max)
     }
-}
 
-#[cfg(test)]
-speculate! {
-    use alloc::raw_vec::RawVec;
-    
-    type VirtualVec<T> = RawVec<T, VirtualAlloc>;
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of purgeable
+    /// read-write memory.
+    ///
+    /// Purgeable memory can be marked volatile using `set_volatile`, in which case the
+    /// kernel may discard its contents under memory pressure instead of swapping them out.
+    /// This makes it well-suited for caches whose contents can be recomputed.
+    ///
+    /// # Note
+    /// Purgeable memory is only supported on macOS, and this function is equivalent to
+    /// `new(max)` everywhere else.
+    #[cfg(target_os = "macos")]
+    pub fn purgeable(max: usize) -> Self {
+        VirtualAlloc::with_raw_protection(max, get_protection(true, true, false) | mach::PROT_PURGEABLE)
+    }
 
-    describe "never-changing pointer" {
-        const INITIAL_CAP: usize = 1_000;
-        const MAX_CAP: usize = 1_000_000;
+    /// Returns a `VirtualAlloc` that can allocate up to `max` bytes of purgeable
+    /// read-write memory.
+    ///
+    /// Purgeable memory can be marked volatile using `set_volatile`, in which case the
+    /// kernel may discard its contents under memory pressure instead of swapping them out.
+    /// This makes it well-suited for caches whose contents can be recomputed.
+    ///
+    /// # Note
+    /// Purgeable memory is only supported on macOS, and this function is equivalent to
+    /// `new(max)` everywhere else.
+    #[cfg(not(target_os = "macos"))]
+    pub fn purgeable(max: usize) -> Self {
+        Self::new(max)
+    }
 
-        before {
-            let allocator = VirtualAlloc::new(MAX_CAP);
-            let mut vec = VirtualVec::<u8>::with_capacity_in(INITIAL_CAP, allocator);
+    /// Sets the tag given to memory reserved from now on, under which it appears in tools
+    /// such as `vmmap` and Instruments.
+    ///
+    /// Tags between 240 and 255 are reserved for applications, and 240 is used by default.
+    ///
+    /// This function does nothing on other platforms than macOS.
+    #[cfg(target_os = "macos")]
+    #[inline]
+    pub fn set_vm_tag(tag: u8) {
+        VM_TAG.store(tag, Ordering::Relaxed)
+    }
 
-            let initial_ptr = vec.ptr();
-        }
+    /// Sets the tag given to memory reserved from now on, under which it appears in tools
+    /// such as `vmmap` and Instruments.
+    ///
+    /// Tags between 240 and 255 are reserved for applications, and 240 is used by default.
+    ///
+    /// This function does nothing on other platforms than macOS.
+    #[cfg(not(target_os = "macos"))]
+    #[inline]
+    pub fn set_vm_tag(_: u8) {}
 
-        it "can double in place implicitely" {
-            vec.double();
-        }
+    /// Returns the absolute maximum capacity of the vector.
+    #[inline]
+    pub fn max_capacity(&self) -> usize {
+        self.max
+    }
 
-        it "can double in place explicitely" {
-            assert!(vec.double_in_place());
-        }
+    /// Returns the size of a page, which is the granularity at which memory can be
+    /// committed and protected.
+    ///
+    /// The page size is only queried from the system once, and then cached.
+    #[inline]
+    pub fn page_size() -> usize {
+        match PAGE_SIZE.load(Ordering::Relaxed) {
+            0 => {
+                let page = Self::query_page_size();
 
-        it "can reserve in place implicitely" {
-            vec.reserve(INITIAL_CAP, 500_000);
+                PAGE_SIZE.store(page, Ordering::Relaxed);
+                page
+            },
+            page => page
         }
+    }
 
-        it "can reserve in place explicitely" {
-            assert!(vec.reserve_in_place(INITIAL_CAP, 500_000));
-        }
+    #[cfg(windows)]
+    #[cold]
+    fn query_page_size() -> usize {
+        #[cfg(feature = "std")]      use std::mem;
+        #[cfg(not(feature = "std"))] use core::mem;
 
-        it "can't reserve values over maximum implicitely" {
-            assert!(vec.try_reserve(INITIAL_CAP, MAX_CAP * 2).is_err());
-        }
+        use windows_sys::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
 
-        it "can't reserve values over maximum explicitely" {
-            assert!(!vec.reserve_in_place(INITIAL_CAP, MAX_CAP * 2));
-        }
+        unsafe {
+            let mut info: SYSTEM_INFO = mem::zeroed();
 
-        after {
-            assert_eq!(vec.ptr(), initial_ptr);
+            GetSystemInfo(&mut info);
+
+            info.dwPageSize as _
+        }
+    }
+
+    #[cfg(not(any(windows, target_arch = "wasm32")))]
+    #[cold]
+    fn query_page_size() -> usize {
+        unsafe {
+            libc::sysconf(libc::_SC_PAGESIZE) as _
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    fn query_page_size() -> usize {
+        WASM_PAGE_SIZE
+    }
+
+    /// Returns the granularity at which memory can be reserved, which is also the
+    /// alignment of all reservations.
+    ///
+    /// This is 64KB on Windows (including on ARM64, where pages are still 4KB), and the
+    /// page size everywhere else.
+    #[cfg(windows)]
+    pub fn allocation_granularity() -> usize {
+        #[cfg(feature = "std")]      use std::mem;
+        #[cfg(not(feature = "std"))] use core::mem;
+
+        use windows_sys::Win32::System::SystemInformation::{GetSystemInfo, SYSTEM_INFO};
+
+        unsafe {
+            let mut info: SYSTEM_INFO = mem::zeroed();
+
+            GetSystemInfo(&mut info);
+
+            info.dwAllocationGranularity as _
+        }
+    }
+
+    /// Returns the granularity at which memory can be reserved, which is also the
+    /// alignment of all reservations.
+    ///
+    /// This is 64KB on Windows (including on ARM64, where pages are still 4KB), and the
+    /// page size everywhere else.
+    #[cfg(not(windows))]
+    #[inline]
+    pub fn allocation_granularity() -> usize {
+        Self::page_size()
+    }
+
+    /// Sets the protection of an allocated buffer.
+    ///
+    /// Fails with `WxViolation` if the buffer was requested to be both writable and
+    /// executable in strict W^X mode. The length is rounded up to whole pages.
+    #[cfg(windows)]
+    #[inline]
+    pub fn set_protection<T: ?Sized>(ptr: NonNull<T>, len: usize,
+                                     read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::Memory::VirtualProtect;
+
+        if is_wx_violation(write, exec) {
+            return Err(VirtualMemError::WxViolation)
+        }
+
+        let prot = get_protection(read, write, exec);
+        let len = round_to_page(len);
+        let mut old = 0;
+
+        unsafe {
+            if VirtualProtect(ptr.as_ptr() as _, len, prot, &mut old) == 0 {
+                return Err(VirtualMemError::ProtectFailed { os_err: last_os_error() })
+            }
+
+            // Instruction caches are not coherent on ARM64.
+            if exec {
+                flush_icache(ptr.as_ptr() as _, len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the protection of an allocated buffer.
+    ///
+    /// Fails with `WxViolation` if the buffer was requested to be both writable and
+    /// executable in strict W^X mode. The length is rounded up to whole pages.
+    #[cfg(not(any(windows, target_arch = "wasm32")))]
+    #[inline]
+    pub fn set_protection<T: ?Sized>(ptr: NonNull<T>, len: usize,
+                                     read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        if is_wx_violation(write, exec) {
+            return Err(VirtualMemError::WxViolation)
+        }
+
+        let prot = get_protection(read, write, exec);
+        let len = round_to_page(len);
+
+        unsafe {
+            if libc::mprotect(ptr.as_ptr() as _, len, prot) != 0 {
+                return Err(VirtualMemError::ProtectFailed { os_err: last_os_error() })
+            }
+
+            // Instruction caches are not coherent on ARM.
+            if exec {
+                flush_icache(ptr.as_ptr() as _, len);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Sets the protection of an allocated buffer.
+    ///
+    /// Fails with `Unsupported` on WebAssembly, where linear memory is always readable
+    /// and writable, and never executable.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub fn set_protection<T: ?Sized>(_: NonNull<T>, _: usize, _: bool, _: bool, _: bool)
+        -> Result<(), VirtualMemError> {
+        Err(VirtualMemError::Unsupported)
+    }
+
+    /// Makes an allocated buffer executable, removing its write permission and flushing
+    /// the instruction cache for the given range.
+    ///
+    /// This is the transition JIT compilers need to perform once code has been written
+    /// to a buffer and before it is executed.
+    #[cfg(windows)]
+    pub fn make_executable<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::Memory::VirtualProtect;
+
+        let prot = get_protection(true, false, true);
+        let mut old = 0;
+
+        unsafe {
+            if VirtualProtect(ptr.as_ptr() as _, len, prot, &mut old) == 0 {
+                return Err(VirtualMemError::ProtectFailed { os_err: last_os_error() })
+            }
+
+            flush_icache(ptr.as_ptr() as _, len);
+        }
+
+        Ok(())
+    }
+
+    /// Makes an allocated buffer executable, removing its write permission and flushing
+    /// the instruction cache for the given range.
+    ///
+    /// This is the transition JIT compilers need to perform once code has been written
+    /// to a buffer and before it is executed.
+    #[cfg(not(any(windows, target_arch = "wasm32")))]
+    pub fn make_executable<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Result<(), VirtualMemError> {
+        let prot = get_protection(true, false, true);
+
+        unsafe {
+            if libc::mprotect(ptr.as_ptr() as _, len, prot) != 0 {
+                return Err(VirtualMemError::ProtectFailed { os_err: last_os_error() })
+            }
+
+            flush_icache(ptr.as_ptr() as _, len);
+        }
+
+        Ok(())
+    }
+
+    /// Makes an allocated buffer executable, removing its write permission and flushing
+    /// the instruction cache for the given range.
+    ///
+    /// Fails with `Unsupported` on WebAssembly, where code cannot be executed from linear
+    /// memory.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub fn make_executable<T: ?Sized>(_: NonNull<T>, _: usize) -> Result<(), VirtualMemError> {
+        Err(VirtualMemError::Unsupported)
+    }
+
+    /// Enables or disables write protection of `MAP_JIT` memory for the current thread.
+    ///
+    /// On macOS on Apple Silicon, executable memory allocated by a `VirtualAlloc` is either
+    /// writable or executable for a given thread, but never both at once. This function must
+    /// therefore be called with `false` before writing code, and with `true` before executing it.
+    ///
+    /// This function does nothing on other platforms, which means it can be called
+    /// unconditionally by portable JIT compilers.
+    #[cfg(target_os = "macos")]
+    #[inline]
+    pub fn set_jit_write_protection(enabled: bool) {
+        extern "C" {
+            fn pthread_jit_write_protect_np(enabled: libc::c_int);
+        }
+
+        unsafe {
+            pthread_jit_write_protect_np(enabled as _);
+        }
+    }
+
+    /// Enables or disables write protection of `MAP_JIT` memory for the current thread.
+    ///
+    /// On macOS on Apple Silicon, executable memory allocated by a `VirtualAlloc` is either
+    /// writable or executable for a given thread, but never both at once. This function must
+    /// therefore be called with `false` before writing code, and with `true` before executing it.
+    ///
+    /// This function does nothing on other platforms, which means it can be called
+    /// unconditionally by portable JIT compilers.
+    #[cfg(not(target_os = "macos"))]
+    #[inline]
+    pub fn set_jit_write_protection(_: bool) {}
+
+    /// Excludes an allocated buffer from core dumps and crash reports, so that they neither
+    /// leak its contents nor grow with its size.
+    ///
+    /// Fails with `Unsupported` if this is not supported on this platform.
+    ///
+    /// # Implementation
+    /// - On Linux, `madvise(MADV_DONTDUMP)` is used.
+    /// - On FreeBSD, `madvise(MADV_NOCORE)` is used.
+    /// - On Windows, `WerRegisterExcludedMemoryBlock` is used, which only applies to
+    ///   Windows Error Reporting dumps.
+    /// - Exclusion is not supported anywhere else.
+    #[inline]
+    pub fn exclude_from_dumps<T: ?Sized>(ptr: NonNull<T>, len: usize)
+        -> Result<(), VirtualMemError> {
+        Self::exclude_range(ptr.as_ptr() as *mut Opaque, len)
+    }
+
+    #[cfg(windows)]
+    fn exclude_range(ptr: *mut Opaque, len: usize) -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::ErrorReporting::WerRegisterExcludedMemoryBlock;
+
+        if len > u32::MAX as usize {
+            return Err(VirtualMemError::ExceedsMax { requested: len, max: u32::MAX as _ })
+        }
+
+        match unsafe { WerRegisterExcludedMemoryBlock(ptr as _, len as _) } {
+            hr if hr >= 0 => Ok(()),
+            hr => Err(VirtualMemError::AdviseFailed { os_err: hr })
+        }
+    }
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    fn exclude_range(ptr: *mut Opaque, len: usize) -> Result<(), VirtualMemError> {
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        const ADVICE: libc::c_int = libc::MADV_DONTDUMP;
+        #[cfg(target_os = "freebsd")]
+        const ADVICE: libc::c_int = libc::MADV_NOCORE;
+
+        unsafe {
+            if libc::madvise(ptr as _, len, ADVICE) != 0 {
+                return Err(VirtualMemError::AdviseFailed { os_err: last_os_error() })
+            }
+        }
+
+        Ok(())
+    }
+    #[cfg(not(any(windows, target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    #[inline]
+    fn exclude_range(_: *mut Opaque, _: usize) -> Result<(), VirtualMemError> {
+        Err(VirtualMemError::Unsupported)
+    }
+
+    /// Asks the operating system to bring the committed pages of an allocated buffer into
+    /// physical memory ahead of time, so that accessing them later does not fault.
+    ///
+    /// This is merely a hint, and returns as soon as the request has been issued.
+    ///
+    /// # Implementation
+    /// - On Windows (8+), `PrefetchVirtualMemory` is used.
+    /// - On Unix, `madvise(MADV_WILLNEED)` is used.
+    #[cfg(windows)]
+    pub fn prefetch<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::Memory::{PrefetchVirtualMemory, WIN32_MEMORY_RANGE_ENTRY};
+        use windows_sys::Win32::System::Threading::GetCurrentProcess;
+
+        let range = WIN32_MEMORY_RANGE_ENTRY { VirtualAddress: ptr.as_ptr() as _, NumberOfBytes: len };
+
+        unsafe {
+            if PrefetchVirtualMemory(GetCurrentProcess(), 1, &range, 0) == 0 {
+                return Err(VirtualMemError::AdviseFailed { os_err: last_os_error() })
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asks the operating system to bring the committed pages of an allocated buffer into
+    /// physical memory ahead of time, so that accessing them later does not fault.
+    ///
+    /// This is merely a hint, and returns as soon as the request has been issued.
+    ///
+    /// # Implementation
+    /// - On Windows (8+), `PrefetchVirtualMemory` is used.
+    /// - On Unix, `madvise(MADV_WILLNEED)` is used.
+    #[cfg(not(any(windows, target_arch = "wasm32")))]
+    pub fn prefetch<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Result<(), VirtualMemError> {
+        unsafe {
+            if libc::madvise(ptr.as_ptr() as _, len, libc::MADV_WILLNEED) != 0 {
+                return Err(VirtualMemError::AdviseFailed { os_err: last_os_error() })
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asks the operating system to bring the committed pages of an allocated buffer into
+    /// physical memory ahead of time, so that accessing them later does not fault.
+    ///
+    /// This does nothing on WebAssembly, where memory is managed by the host.
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    pub fn prefetch<T: ?Sized>(_: NonNull<T>, _: usize) -> Result<(), VirtualMemError> {
+        Ok(())
+    }
+
+    /// Returns how many bytes of the `len` committed bytes starting at `ptr` are currently
+    /// backed by physical memory, or `None` if this cannot be determined.
+    ///
+    /// # Implementation
+    /// - On Unix, `mincore` is used, one batch of pages at a time.
+    /// - `None` is returned everywhere else, including on OpenBSD, which removed `mincore`.
+    #[cfg(not(any(windows, target_os = "openbsd", target_arch = "wasm32")))]
+    pub fn resident_size<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Option<usize> {
+        const BATCH: usize = 256;
+
+        let page = VirtualAlloc::page_size();
+        let start = ptr.as_ptr() as *mut u8;
+        let pages = len.div_ceil(page);
+        let mut resident = 0;
+        let mut vec = [0u8; BATCH];
+
+        for first in (0..pages).step_by(BATCH) {
+            let count = (pages - first).min(BATCH);
+
+            unsafe {
+                let addr = start.add(first * page);
+
+                if libc::mincore(addr as _, count * page, vec.as_mut_ptr() as _) != 0 {
+                    return None
+                }
+            }
+
+            resident += vec[..count].iter().filter(|&&b| b & 1 != 0).count();
+        }
+
+        Some((resident * page).min(len))
+    }
+
+    /// Returns how many bytes of the `len` committed bytes starting at `ptr` are currently
+    /// backed by physical memory, or `None` if this cannot be determined.
+    ///
+    /// This always returns `None` on Windows, OpenBSD and WebAssembly.
+    #[cfg(any(windows, target_os = "openbsd", target_arch = "wasm32"))]
+    #[inline]
+    pub fn resident_size<T: ?Sized>(_: NonNull<T>, _: usize) -> Option<usize> {
+        None
+    }
+
+    /// Transfers `len` bytes from `src` to `dst`, moving whole pages rather than copying
+    /// their bytes when both ranges have the same offset within a page.
+    ///
+    /// This makes multi-gigabyte handoffs between buffers nearly free, but the pages that
+    /// are moved read as zero in `src` afterwards, while the bytes that are copied are left
+    /// intact: `src` must not be used as if it had been copied. Returns the number of bytes
+    /// that were moved, which start at the first page boundary of `src`.
+    ///
+    /// # Safety
+    /// Both ranges must be committed, readable and writable, must not overlap, and must
+    /// lie within reservations made by `VirtualAlloc`.
+    ///
+    /// # Implementation
+    /// - On Linux (5.7+), pages are moved using `mremap(MREMAP_DONTUNMAP)`.
+    /// - Bytes are copied everywhere else, including on Windows, where only memory backed
+    ///   by a section can be aliased using `MapViewOfFile`.
+    pub unsafe fn transfer_pages(src: NonNull<u8>, dst: NonNull<u8>, len: usize) -> usize {
+        let page = Self::page_size();
+        let (src, dst) = (src.as_ptr(), dst.as_ptr());
+        let head = src.align_offset(page).min(len);
+        let body = match src.addr() % page == dst.addr() % page {
+            true => (len - head) / page * page,
+            false => 0
+        };
+
+        if body == 0 || !Self::move_pages(src.add(head), dst.add(head), body) {
+            ptr::copy_nonoverlapping(src, dst, len);
+
+            return 0
+        }
+
+        let tail = head + body;
+
+        ptr::copy_nonoverlapping(src, dst, head);
+        ptr::copy_nonoverlapping(src.add(tail), dst.add(tail), len - tail);
+
+        body
+    }
+
+    /// Moves whole pages from `src` to `dst`, leaving `src` mapped and zeroed, and returns
+    /// whether they could be moved.
+    #[cfg(target_os = "linux")]
+    unsafe fn move_pages(src: *mut u8, dst: *mut u8, len: usize) -> bool {
+        let flags = libc::MREMAP_MAYMOVE | libc::MREMAP_FIXED | libc::MREMAP_DONTUNMAP;
+
+        libc::mremap(src as _, len, len, flags, dst as *mut libc::c_void) != libc::MAP_FAILED
+    }
+    #[cfg(not(target_os = "linux"))]
+    #[inline]
+    unsafe fn move_pages(_: *mut u8, _: *mut u8, _: usize) -> bool {
+        false
+    }
+
+    /// Marks the whole reservation starting at `ptr`, which must have been allocated by an
+    /// allocator created using `purgeable`, as volatile or non-volatile.
+    ///
+    /// The contents of volatile memory may be discarded by the kernel at any time, and
+    /// must not be accessed. When memory is made non-volatile again, `Ok(true)` is returned
+    /// if its contents were discarded in the meantime, in which case it is zero-filled.
+    ///
+    /// Fails with `Unsupported` on other platforms than macOS.
+    #[cfg(target_os = "macos")]
+    pub fn set_volatile<T: ?Sized>(ptr: NonNull<T>, volatile: bool) -> Result<bool, VirtualMemError> {
+        use mach::*;
+
+        let mut state = if volatile { VM_PURGABLE_VOLATILE } else { VM_PURGABLE_NONVOLATILE };
+
+        unsafe {
+            let addr = (ptr.as_ptr() as *mut u8).addr() as mach_vm_address_t;
+            let kr = mach_vm_purgable_control(mach_task_self(), addr, VM_PURGABLE_SET_STATE,
+                                              &mut state);
+
+            if kr != KERN_SUCCESS {
+                return Err(VirtualMemError::AdviseFailed { os_err: kr })
+            }
+        }
+
+        // The previous state is returned in place of the new one.
+        Ok(!volatile && state == VM_PURGABLE_EMPTY)
+    }
+
+    /// Marks the whole reservation starting at `ptr`, which must have been allocated by an
+    /// allocator created using `purgeable`, as volatile or non-volatile.
+    ///
+    /// The contents of volatile memory may be discarded by the kernel at any time, and
+    /// must not be accessed. When memory is made non-volatile again, `Ok(true)` is returned
+    /// if its contents were discarded in the meantime, in which case it is zero-filled.
+    ///
+    /// Fails with `Unsupported` on other platforms than macOS.
+    #[cfg(not(target_os = "macos"))]
+    #[inline]
+    pub fn set_volatile<T: ?Sized>(_: NonNull<T>, _: bool) -> Result<bool, VirtualMemError> {
+        Err(VirtualMemError::Unsupported)
+    }
+
+    /// Permanently seals an allocated buffer, preventing any further change to its
+    /// protection, and forbidding it from being remapped or unmapped.
+    ///
+    /// Fails with `Unsupported` if sealing is not supported on this platform.
+    ///
+    /// # Note
+    /// A sealed buffer can no longer be deallocated; it will live until the process exits.
+    ///
+    /// # Implementation
+    /// - On Linux (6.10+), `mseal` is used.
+    /// - On OpenBSD, `mimmutable` is used.
+    /// - Sealing is not supported anywhere else.
+    #[inline]
+    pub fn seal<T: ?Sized>(ptr: NonNull<T>, len: usize) -> Result<(), VirtualMemError> {
+        unsafe { Self::seal_range(ptr.as_ptr() as *mut Opaque, len) }
+    }
+
+    #[cfg(target_os = "linux")]
+    unsafe fn seal_range(ptr: *mut Opaque, len: usize) -> Result<(), VirtualMemError> {
+        if libc::syscall(libc::SYS_mseal, ptr as *mut libc::c_void, len, 0) != 0 {
+            return Err(VirtualMemError::SealFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+    #[cfg(target_os = "openbsd")]
+    unsafe fn seal_range(ptr: *mut Opaque, len: usize) -> Result<(), VirtualMemError> {
+        if libc::mimmutable(ptr as _, len) != 0 {
+            return Err(VirtualMemError::SealFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "openbsd")))]
+    #[inline]
+    unsafe fn seal_range(_: *mut Opaque, _: usize) -> Result<(), VirtualMemError> {
+        Err(VirtualMemError::Unsupported)
+    }
+
+    /// Reserves memory without any access, so that only committed ranges are given the
+    /// requested protection, and accessing the rest of the reservation always faults.
+    #[cfg(windows)]
+    fn init(addr: *mut Opaque, max_size: usize, _: Protection)
+        -> Result<NonNull<Opaque>, VirtualMemError> {
+        use windows_sys::Win32::System::Memory::*;
+
+        unsafe {
+            let mut ptr = VirtualAlloc(addr as _, max_size, MEM_RESERVE, PAGE_NOACCESS);
+
+            // Unlike mmap, VirtualAlloc fails if the requested address is unavailable.
+            if ptr.is_null() && !addr.is_null() {
+                ptr = VirtualAlloc(ptr::null(), max_size, MEM_RESERVE, PAGE_NOACCESS);
+            }
+
+            NonNull::new(ptr as _).ok_or_else(|| {
+                VirtualMemError::ReservationFailed { os_err: last_os_error() }
+            })
+        }
+    }
+    /// Reserves memory without any access, unless it is demand-paged, so that only
+    /// committed ranges are given the requested protection.
+    #[cfg(not(any(windows, target_os = "fuchsia", target_os = "macos", target_arch = "wasm32")))]
+    fn init(addr: *mut Opaque, max_size: usize, prot: Protection)
+        -> Result<NonNull<Opaque>, VirtualMemError> {
+        let (reserved, flags) = Self::reserved_mapping(prot);
+
+        // Let large reservations be backed by superpages once committed.
+        #[cfg(target_os = "freebsd")]
+        let flags = flags | libc::MAP_ALIGNED_SUPER;
+
+        unsafe {
+            Self::check_mapping(libc::mmap(addr as _, max_size, reserved, flags, -1, 0))
+        }
+    }
+
+    /// Returns the protection and flags of the mapping of reserved memory that is given
+    /// the requested protection once committed, which decommitted memory is mapped with
+    /// again.
+    #[cfg(not(any(windows, target_os = "fuchsia", target_os = "macos", target_arch = "wasm32")))]
+    fn reserved_mapping(prot: Protection) -> (libc::c_int, libc::c_int) {
+        // Under PaX MPROTECT, permissions that were not requested when mapping memory can
+        // never be added later on.
+        #[cfg(target_os = "netbsd")]
+        let reserved = unsafe { libc::PROT_MPROTECT(prot) };
+        #[cfg(not(target_os = "netbsd"))]
+        let reserved = libc::PROT_NONE;
+
+        let flags = libc::MAP_PRIVATE | libc::MAP_ANON;
+
+        // Demand-paged reservations are accessible right away, but only backed by memory
+        // once touched.
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if prot & PROT_NORESERVE != 0 {
+            return (prot & !PROT_NORESERVE, flags | libc::MAP_NORESERVE)
+        }
+        #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "netbsd")))]
+        let _ = prot;
+
+        (reserved, flags)
+    }
+    #[cfg(target_os = "macos")]
+    fn init(addr: *mut Opaque, max_size: usize, prot: Protection)
+        -> Result<NonNull<Opaque>, VirtualMemError> {
+        use mach::*;
+
+        // Under the hardened runtime, executable memory must be mapped with MAP_JIT, and
+        // with its final protection, since exec permissions cannot be added later.
+        if prot & libc::PROT_EXEC != 0 {
+            let flags = libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_JIT;
+
+            return unsafe {
+                Self::check_mapping(libc::mmap(addr as _, max_size, prot, flags, -1, 0))
+            }
+        }
+
+        // Other memory is allocated using Mach, which can tag it and make it purgeable.
+        let flags = VM_FLAGS_ANYWHERE | Self::mach_flags(prot);
+
+        unsafe {
+            let task = mach_task_self();
+            let mut ptr = addr.addr() as mach_vm_address_t;
+            let mut kr = mach_vm_allocate(task, &mut ptr, max_size as _, flags);
+
+            // Memory is allocated read-write, and must be made inaccessible until committed.
+            if kr == KERN_SUCCESS {
+                kr = mach_vm_protect(task, ptr, max_size as _, 0, VM_PROT_NONE);
+
+                if kr != KERN_SUCCESS {
+                    mach_vm_deallocate(task, ptr, max_size as _);
+                }
+            }
+
+            if kr != KERN_SUCCESS {
+                return Err(VirtualMemError::ReservationFailed { os_err: kr })
+            }
+
+            NonNull::new(ptr::with_exposed_provenance_mut(ptr as usize))
+                .ok_or(VirtualMemError::ReservationFailed { os_err: 0 })
+        }
+    }
+
+    /// Reserves memory by mapping a VMO without any permission, so that accessing it
+    /// faults until it is committed.
+    #[cfg(target_os = "fuchsia")]
+    fn init(_: *mut Opaque, max_size: usize, prot: Protection)
+        -> Result<NonNull<Opaque>, VirtualMemError> {
+        use zircon::*;
+
+        unsafe {
+            let mut vmo = ZX_HANDLE_INVALID;
+            let mut status = zx_vmo_create(max_size as u64, 0, &mut vmo);
+
+            // Executable mappings require an executable VMO, which the job policy of the
+            // process may forbid.
+            if status == ZX_OK && prot & libc::PROT_EXEC != 0 {
+                status = zx_vmo_replace_as_executable(vmo, ZX_HANDLE_INVALID, &mut vmo);
+            }
+
+            if status != ZX_OK {
+                return Err(VirtualMemError::ReservationFailed { os_err: status })
+            }
+
+            let mut addr = 0;
+            let status = zx_vmar_map(zx_vmar_root_self(), 0, 0, vmo, 0, max_size, &mut addr);
+
+            // The mapping keeps the VMO alive on its own.
+            zx_handle_close(vmo);
+
+            if status != ZX_OK {
+                return Err(VirtualMemError::ReservationFailed { os_err: status })
+            }
+
+            NonNull::new(ptr::with_exposed_provenance_mut(addr))
+                .ok_or(VirtualMemError::ReservationFailed { os_err: 0 })
+        }
+    }
+
+    /// Reserves memory by growing the linear memory of the module.
+    ///
+    /// Linear memory can neither be reserved without being made accessible nor be shrunk,
+    /// so reservations are made accessible immediately (although hosts typically only
+    /// back them with physical memory once they are touched), and count against the 4GB
+    /// address space of the module until it is destroyed.
+    #[cfg(target_arch = "wasm32")]
+    fn init(_: *mut Opaque, max_size: usize, _: Protection)
+        -> Result<NonNull<Opaque>, VirtualMemError> {
+        #[cfg(feature = "std")]      use std::arch::wasm32;
+        #[cfg(not(feature = "std"))] use core::arch::wasm32;
+
+        let pages = max_size.div_ceil(WASM_PAGE_SIZE);
+
+        match wasm32::memory_grow(0, pages) {
+            usize::MAX => Err(VirtualMemError::ReservationFailed { os_err: last_os_error() }),
+            previous => NonNull::new(ptr::with_exposed_provenance_mut(previous * WASM_PAGE_SIZE))
+                .ok_or(VirtualMemError::ReservationFailed { os_err: 0 })
+        }
+    }
+
+    /// Converts the result of `mmap` into a pointer, noting that failure is signaled by
+    /// `MAP_FAILED` rather than by a null pointer.
+    #[cfg(not(any(windows, target_os = "fuchsia", target_arch = "wasm32")))]
+    #[inline]
+    fn check_mapping(ptr: *mut libc::c_void) -> Result<NonNull<Opaque>, VirtualMemError> {
+        if ptr == libc::MAP_FAILED {
+            return Err(VirtualMemError::ReservationFailed { os_err: last_os_error() })
+        }
+
+        NonNull::new(ptr as _).ok_or(VirtualMemError::ReservationFailed { os_err: 0 })
+    }
+
+    #[cfg(windows)]
+    unsafe fn release(ptr: *mut Opaque, _: usize) {
+        use windows_sys::Win32::System::Memory::{VirtualFree, MEM_RELEASE};
+
+        VirtualFree(ptr as _, 0, MEM_RELEASE);
+    }
+    #[cfg(target_os = "fuchsia")]
+    unsafe fn release(ptr: *mut Opaque, max_size: usize) {
+        zircon::zx_vmar_unmap(zircon::zx_vmar_root_self(), ptr as _, max_size);
+    }
+    #[cfg(not(any(windows, target_os = "fuchsia", target_arch = "wasm32")))]
+    unsafe fn release(ptr: *mut Opaque, max_size: usize) {
+        libc::munmap(ptr as _, max_size);
+    }
+    #[cfg(target_arch = "wasm32")]
+    unsafe fn release(_: *mut Opaque, _: usize) {
+        // Linear memory cannot be shrunk, and is only reclaimed with the whole module.
+    }
+
+    #[cfg(windows)]
+    fn commit(ptr: *mut Opaque, needed: usize, prot: Protection) -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::Memory::{VirtualAlloc, MEM_COMMIT};
+
+        unsafe {
+            if VirtualAlloc(ptr as _, needed, MEM_COMMIT, prot).is_null() {
+                return Err(VirtualMemError::CommitFailed { os_err: last_os_error() })
+            }
+        }
+
+        Ok(())
+    }
+    #[cfg(target_os = "fuchsia")]
+    fn commit(ptr: *mut Opaque, needed: usize, prot: Protection) -> Result<(), VirtualMemError> {
+        use zircon::*;
+
+        unsafe {
+            let vmar = zx_vmar_root_self();
+
+            // Protection flags and ZX_VM_PERM_* options share the same values. Committing pages
+            // upfront ensures that touching them later never fails for lack of memory.
+            let mut status = zx_vmar_protect(vmar, prot as _, ptr as _, needed);
+
+            if status == ZX_OK && prot & libc::PROT_WRITE != 0 {
+                status = zx_vmar_op_range(vmar, ZX_VMAR_OP_COMMIT, ptr as _, needed,
+                                          ptr::null_mut(), 0);
+            }
+
+            if status != ZX_OK {
+                return Err(VirtualMemError::CommitFailed { os_err: status })
+            }
+        }
+
+        Ok(())
+    }
+    #[cfg(not(any(windows, target_os = "fuchsia", target_arch = "wasm32")))]
+    fn commit(ptr: *mut Opaque, needed: usize, prot: Protection) -> Result<(), VirtualMemError> {
+        #[cfg(target_os = "macos")]
+        let prot = prot & !mach::PROT_PURGEABLE;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if prot & PROT_NORESERVE != 0 {
+            return Ok(())
+        }
+
+        unsafe {
+            if libc::mprotect(ptr as _, needed, prot) != 0 {
+                return Err(VirtualMemError::CommitFailed { os_err: last_os_error() })
+            }
+        }
+
+        Ok(())
+    }
+    #[cfg(target_arch = "wasm32")]
+    #[inline]
+    fn commit(_: *mut Opaque, _: usize, _: Protection) -> Result<(), VirtualMemError> {
+        // Reservations are accessible as soon as they are made.
+        Ok(())
+    }
+
+    /// Commits memory, which is the single path through which all containers and
+    /// allocators commit memory from the operating system.
+    ///
+    /// The size is rounded up to whole pages, since the system commits whole pages anyway.
+    /// Fails with `CapacityOverflow` if that does not fit in a `usize`, rather than
+    /// committing less than was needed.
+    #[inline]
+    fn grow(&self, ptr: *mut Opaque, needed: usize, prot: Protection) -> Result<(), VirtualMemError> {
+        let needed = needed.checked_next_multiple_of(Self::page_size())
+            .ok_or(VirtualMemError::CapacityOverflow)?;
+
+        #[cfg(feature = "fault-injection")]
+        fault::check_commit(needed)?;
+
+        VirtualAlloc::commit(ptr, needed, prot)?;
+
+        let huge = self.huge_pages.unwrap_or_else(|| {
+            Self::huge_page_threshold().is_some_and(|threshold| needed >= threshold)
+        });
+
+        if huge {
+            // Huge pages are merely a hint, which the system is free to ignore.
+            unsafe { Self::advise_huge_pages(ptr, needed) };
+        }
+        if self.populate {
+            unsafe { populate(ptr, needed) };
+        }
+
+        Ok(())
+    }
+
+    /// Reserves `size` bytes of address space with the protection of the allocator, and
+    /// binds it to its NUMA node, if any.
+    fn reserve_bound(&self, size: usize) -> Result<NonNull<Opaque>, VirtualMemError> {
+        let ptr = Self::init(ptr::null_mut(), size, self.prot)?;
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        if let Some(node) = self.numa_node {
+            if let Err(err) = unsafe { Self::bind_to_node(ptr.as_ptr(), size, node) } {
+                unsafe { Self::release(ptr.as_ptr(), size) };
+
+                return Err(err)
+            }
+        }
+
+        Ok(ptr)
+    }
+
+    /// Makes the pages of the given range prefer memory of the given NUMA node, which
+    /// applies to pages committed later on.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn bind_to_node(ptr: *mut Opaque, size: usize, node: u32)
+        -> Result<(), VirtualMemError> {
+        #[cfg(feature = "std")]      use std::mem;
+        #[cfg(not(feature = "std"))] use core::mem;
+
+        const MPOL_PREFERRED: libc::c_long = 1;
+        const WORD_BITS: usize = mem::size_of::<libc::c_ulong>() * 8;
+
+        let mut mask = [0 as libc::c_ulong; 16];
+        let node = node as usize;
+
+        // The kernel reads one bit less than the number of nodes it is given.
+        if node + 1 >= mask.len() * WORD_BITS {
+            return Err(VirtualMemError::ReservationFailed { os_err: libc::EINVAL })
+        }
+
+        mask[node / WORD_BITS] |= 1 << (node % WORD_BITS);
+
+        let result = libc::syscall(libc::SYS_mbind, ptr, size, MPOL_PREFERRED, mask.as_ptr(),
+                                   mask.len() * WORD_BITS, 0);
+
+        if result != 0 {
+            return Err(VirtualMemError::ReservationFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[inline]
+    unsafe fn advise_huge_pages(ptr: *mut Opaque, size: usize) {
+        libc::madvise(ptr as _, size, libc::MADV_HUGEPAGE);
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    #[inline]
+    unsafe fn advise_huge_pages(_: *mut Opaque, _: usize) {}
+
+    /// Returns the flags that give memory allocated using Mach its tag, and make it
+    /// purgeable if requested.
+    #[cfg(target_os = "macos")]
+    fn mach_flags(prot: Protection) -> libc::c_int {
+        use mach::*;
+
+        let tag = vm_make_tag(VM_TAG.load(Ordering::Relaxed));
+
+        match prot & PROT_PURGEABLE {
+            0 => tag,
+            _ => tag | VM_FLAGS_PURGABLE
+        }
+    }
+
+    #[cfg(windows)]
+    unsafe fn decommit(ptr: *mut Opaque, size: usize, _: Protection)
+        -> Result<(), VirtualMemError> {
+        use windows_sys::Win32::System::Memory::{VirtualFree, MEM_DECOMMIT};
+
+        if VirtualFree(ptr as _, size, MEM_DECOMMIT) == 0 {
+            return Err(VirtualMemError::DecommitFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+    #[cfg(target_os = "fuchsia")]
+    unsafe fn decommit(ptr: *mut Opaque, size: usize, _: Protection)
+        -> Result<(), VirtualMemError> {
+        use zircon::*;
+
+        let vmar = zx_vmar_root_self();
+        let mut status = zx_vmar_op_range(vmar, ZX_VMAR_OP_DECOMMIT, ptr as _, size,
+                                          ptr::null_mut(), 0);
+
+        if status == ZX_OK {
+            status = zx_vmar_protect(vmar, 0, ptr as _, size);
+        }
+
+        if status != ZX_OK {
+            return Err(VirtualMemError::DecommitFailed { os_err: status })
+        }
+
+        Ok(())
+    }
+    #[cfg(not(any(windows, target_os = "fuchsia", target_os = "macos", target_arch = "wasm32")))]
+    unsafe fn decommit(ptr: *mut Opaque, size: usize, prot: Protection)
+        -> Result<(), VirtualMemError> {
+        // Mapping fresh pages over the range discards its contents and returns the memory
+        // to the system on every Unix, unlike `madvise` whose semantics vary. The pages
+        // are mapped like reserved ones, so that they can be committed again.
+        let (reserved, flags) = Self::reserved_mapping(prot);
+        let flags = flags | libc::MAP_FIXED;
+
+        if libc::mmap(ptr as _, size, reserved, flags, -1, 0) == libc::MAP_FAILED {
+            return Err(VirtualMemError::DecommitFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+    #[cfg(target_os = "macos")]
+    unsafe fn decommit(ptr: *mut Opaque, size: usize, prot: Protection)
+        -> Result<(), VirtualMemError> {
+        use mach::*;
+
+        // MAP_JIT cannot be combined with MAP_FIXED, so executable pages cannot be mapped
+        // again: they are only made inaccessible and handed back to the system, and their
+        // contents are unspecified once committed again.
+        if prot & libc::PROT_EXEC != 0 {
+            if libc::madvise(ptr as _, size, libc::MADV_FREE_REUSABLE) != 0
+                || libc::mprotect(ptr as _, size, libc::PROT_NONE) != 0 {
+                return Err(VirtualMemError::DecommitFailed { os_err: last_os_error() })
+            }
+
+            return Ok(())
+        }
+
+        // Other pages are allocated again in place, with the tag and purgeability of the
+        // reservation.
+        let task = mach_task_self();
+        let addr = ptr.addr() as mach_vm_address_t;
+        let mut ptr = addr;
+        let flags = VM_FLAGS_FIXED | VM_FLAGS_OVERWRITE | Self::mach_flags(prot);
+        let mut kr = mach_vm_allocate(task, &mut ptr, size as _, flags);
+
+        if kr == KERN_SUCCESS {
+            kr = mach_vm_protect(task, addr, size as _, 0, VM_PROT_NONE);
+        }
+        if kr != KERN_SUCCESS {
+            return Err(VirtualMemError::DecommitFailed { os_err: kr })
         }
+
+        Ok(())
+    }
+    /// Returns demand-paged memory to the system, leaving it accessible and zeroed.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn discard(ptr: *mut Opaque, size: usize) -> Result<(), VirtualMemError> {
+        if libc::madvise(ptr as _, size, libc::MADV_DONTNEED) != 0 {
+            return Err(VirtualMemError::DecommitFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    unsafe fn decommit(ptr: *mut Opaque, size: usize, _: Protection)
+        -> Result<(), VirtualMemError> {
+        // Linear memory cannot be returned, but must still read as zero once committed again.
+        ptr::write_bytes(ptr, 0, size);
+
+        Ok(())
+    }
+
+    #[inline]
+    unsafe fn reserve_internal(&self, ptr: *mut Opaque, committed: usize, min: usize)
+        -> Result<(), VirtualMemError> {
+        if unlikely(min > self.max) {
+            return Err(VirtualMemError::ExceedsMax { requested: min, max: self.max })
+        }
+
+        // Only the pages past those already committed are committed.
+        let start = committed / Self::page_size() * Self::page_size();
+
+        if min <= start {
+            return Ok(())
+        }
+
+        self.grow(ptr.add(start), min - start, self.prot)
+    }
+
+    /// Returns the size of the leading guard, of the data pages and of the whole
+    /// reservation of a guarded allocation of the given size.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    fn guarded_sizes(&self, size: usize) -> (usize, usize, usize) {
+        let page = Self::page_size();
+        let lead = if self.guard == GuardPages::Both { page } else { 0 };
+        let data = round_to_page(size.max(1));
+
+        // Layouts never exceed `isize::MAX` bytes, so this cannot overflow.
+        (lead, data, data + lead + page)
+    }
+
+    #[cfg(feature = "nightly")]
+    unsafe fn alloc_guarded(&self, layout: Layout) -> Result<NonNull<Opaque>, AllocError> {
+        if layout.size() > self.max {
+            return Err(AllocError)
+        }
+
+        let (lead, data, total) = self.guarded_sizes(layout.size());
+        let base = self.reserve_bound(total).map_err(|_| AllocError)?.as_ptr();
+
+        if self.grow(base.add(lead), data, self.prot).is_err() {
+            Self::release(base, total);
+
+            return Err(AllocError)
+        }
+
+        // FreeBSD can make guard pages impossible to ever make accessible.
+        #[cfg(target_os = "freebsd")]
+        if Self::map_guard(base, lead).is_err()
+            || Self::map_guard(base.add(lead + data), total - lead - data).is_err() {
+            Self::release(base, total);
+
+            return Err(AllocError)
+        }
+
+        // Place the allocation right before the trailing guard page, but keep empty
+        // allocations in the first data page, which `dealloc_guarded` finds the base from.
+        let offset = (data - layout.size()).min(data - 1) / layout.align() * layout.align();
+
+        Ok(NonNull::new_unchecked(base.add(lead + offset)))
+    }
+
+    /// Maps guard pages over the given reserved range, which `mprotect` then refuses to
+    /// make accessible.
+    #[cfg(all(feature = "nightly", target_os = "freebsd"))]
+    unsafe fn map_guard(ptr: *mut Opaque, size: usize) -> Result<(), VirtualMemError> {
+        let flags = libc::MAP_GUARD | libc::MAP_FIXED;
+
+        if size == 0 {
+            return Ok(())
+        }
+        if libc::mmap(ptr as _, size, libc::PROT_NONE, flags, -1, 0) == libc::MAP_FAILED {
+            return Err(VirtualMemError::ReservationFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "nightly")]
+    unsafe fn dealloc_guarded(&self, ptr: NonNull<Opaque>, layout: Layout) {
+        let page = Self::page_size();
+        let (lead, _, total) = self.guarded_sizes(layout.size());
+        let base = ptr.as_ptr().sub(ptr.as_ptr().addr() % page + lead);
+
+        Self::release(base, total);
+    }
+
+    /// Returns the size of the reservation of an allocation of the given size.
+    #[cfg(feature = "nightly")]
+    #[inline]
+    fn reservation_size(&self, size: usize) -> usize {
+        match self.quantum {
+            Some(quantum) => size.max(1).div_ceil(quantum).saturating_mul(quantum).min(self.max),
+            None => self.max
+        }
+    }
+
+    /// Moves an allocation to a new allocation of the given layout.
+    #[cfg(feature = "nightly")]
+    unsafe fn relocate(&self, ptr: NonNull<Opaque>, old_layout: Layout, new_layout: Layout)
+        -> Result<NonNull<[Opaque]>, AllocError> {
+        let new_ptr = Allocator::allocate(self, new_layout)?.cast::<Opaque>();
+        let len = old_layout.size().min(new_layout.size());
+
+        ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), len);
+
+        Allocator::deallocate(self, ptr, old_layout);
+
+        Ok(NonNull::slice_from_raw_parts(new_ptr, new_layout.size()))
+    }
+}
+
+/// Hints that the given condition is unlikely to be true.
+#[cfg(feature = "nightly")]
+#[inline(always)]
+fn unlikely(b: bool) -> bool {
+    #[cfg(feature = "std")]      use std::intrinsics;
+    #[cfg(not(feature = "std"))] use core::intrinsics;
+
+    intrinsics::unlikely(b)
+}
+
+/// Hints that the given condition is unlikely to be true.
+#[cfg(not(feature = "nightly"))]
+#[inline(always)]
+fn unlikely(b: bool) -> bool {
+    b
+}
+
+/// Only available with the `nightly` feature.
+///
+/// Each allocation reserves `max_capacity()` bytes of address space, and commits memory
+/// as it grows, which is always done in place. With `with_reservation_quantum`, each
+/// allocation only reserves enough address space for its size, and is moved when it grows
+/// past its reservation, or shrinks into a smaller one. Allocations that use guard pages
+/// are always moved when they grow or shrink.
+#[cfg(feature = "nightly")]
+unsafe impl Allocator for VirtualAlloc {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<[Opaque]>, AllocError> {
+        if layout.align() > Self::allocation_granularity() {
+            return Err(AllocError)
+        }
+
+        unsafe {
+            if self.guard != GuardPages::None {
+                let ptr = self.alloc_guarded(layout)?;
+
+                return Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+            }
+
+            let reserved = self.reservation_size(layout.size());
+            let ptr = self.reserve_bound(reserved).map_err(|_| AllocError)?;
+
+            let size = layout.size();
+
+            if size != 0 && self.reserve_internal(ptr.as_ptr(), 0, size).is_err() {
+                Self::release(ptr.as_ptr(), reserved);
+
+                return Err(AllocError)
+            }
+
+            self.committed.set(ptr.as_ptr(), round_to_page(layout.size()));
+
+            Ok(NonNull::slice_from_raw_parts(ptr, layout.size()))
+        }
+    }
+
+    // VirtualAlloc automatically initializes to zero on Windows.
+    // mmap automatically initializes to zero on Unix with MAP_ANONYMOUS (which we use).
+    fn allocate_zeroed(&self, layout: Layout) -> Result<NonNull<[Opaque]>, AllocError> {
+        self.allocate(layout)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<Opaque>, layout: Layout) {
+        if self.guard != GuardPages::None {
+            return self.dealloc_guarded(ptr, layout)
+        }
+
+        self.committed.forget(ptr.as_ptr());
+
+        Self::release(ptr.as_ptr(), self.reservation_size(layout.size()));
+    }
+
+    unsafe fn grow(&self, ptr: NonNull<Opaque>, old_layout: Layout, new_layout: Layout)
+        -> Result<NonNull<[Opaque]>, AllocError> {
+        if new_layout.align() > Self::allocation_granularity() {
+            return Err(AllocError)
+        }
+
+        // Guarded allocations abut a guard page, and must always be moved.
+        if self.guard != GuardPages::None {
+            return self.relocate(ptr, old_layout, new_layout)
+        }
+
+        let reserved = self.reservation_size(old_layout.size());
+
+        if new_layout.size() > reserved {
+            return match new_layout.size() <= self.max {
+                true => self.relocate(ptr, old_layout, new_layout),
+                false => Err(AllocError)
+            }
+        }
+
+        // Pages are committed whole, and never decommitted when shrinking, so growing
+        // within memory that was already committed needs no system call.
+        let committed = self.committed.get(ptr.as_ptr()).max(round_to_page(old_layout.size()));
+
+        if new_layout.size() <= committed {
+            return Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+        }
+
+        // Grow in place directly, committing ahead so that further growth is cheaper.
+        let size = self.growth.commit_size(committed, new_layout.size(), reserved,
+                                           Self::page_size());
+
+        match self.reserve_internal(ptr.as_ptr(), committed, size) {
+            Ok(()) => {
+                self.committed.set(ptr.as_ptr(), size);
+
+                Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+            },
+            Err(_) => Err(AllocError)
+        }
+    }
+
+    unsafe fn grow_zeroed(&self, ptr: NonNull<Opaque>, old_layout: Layout, new_layout: Layout)
+        -> Result<NonNull<[Opaque]>, AllocError> {
+        // Newly committed memory is zeroed, but shrinking in place leaves the bytes past the
+        // new size committed, so growing back over them must clear them.
+        let new_ptr = Allocator::grow(self, ptr, old_layout, new_layout)?;
+        let start = new_ptr.as_ptr() as *mut Opaque;
+
+        ptr::write_bytes(start.add(old_layout.size()), 0, new_layout.size() - old_layout.size());
+
+        Ok(new_ptr)
+    }
+
+    unsafe fn shrink(&self, ptr: NonNull<Opaque>, old_layout: Layout, new_layout: Layout)
+        -> Result<NonNull<[Opaque]>, AllocError> {
+        // Allocations are released with the size of reservation their layout gives.
+        if self.guard != GuardPages::None ||
+           self.reservation_size(new_layout.size()) != self.reservation_size(old_layout.size()) {
+            return self.relocate(ptr, old_layout, new_layout)
+        }
+
+        Ok(NonNull::slice_from_raw_parts(ptr, new_layout.size()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "nightly")]
+    mod never_changing_pointer {
+        #[cfg(feature = "std")] use std::vec::Vec;
+
+        use super::*;
+
+        const INITIAL_CAP: usize = 1_000;
+        const MAX_CAP: usize = 1_000_000;
+
+        fn vec() -> Vec<u8, VirtualAlloc> {
+            Vec::with_capacity_in(INITIAL_CAP, VirtualAlloc::new(MAX_CAP))
+        }
+
+        #[test]
+        fn can_grow_in_place_implicitly() {
+            let mut vec = vec();
+            let initial_ptr = vec.as_ptr();
+
+            vec.extend((0..INITIAL_CAP * 2).map(|i| i as u8));
+
+            assert_eq!(vec.as_ptr(), initial_ptr);
+        }
+
+        #[test]
+        fn can_reserve_in_place() {
+            let mut vec = vec();
+            let initial_ptr = vec.as_ptr();
+
+            vec.reserve(500_000);
+
+            assert_eq!(vec.as_ptr(), initial_ptr);
+        }
+
+        #[test]
+        fn zeroes_bytes_left_over_by_shrinking() {
+            let alloc = VirtualAlloc::new(MAX_CAP);
+            let small = Layout::from_size_align(10, 1).unwrap();
+            let large = Layout::from_size_align(100, 1).unwrap();
+
+            unsafe {
+                let ptr = alloc.allocate(large).unwrap().cast::<u8>();
+
+                ptr::write_bytes(ptr.as_ptr(), 0xAA, 100);
+
+                let ptr = alloc.shrink(ptr, large, small).unwrap().cast::<u8>();
+                let ptr = alloc.grow_zeroed(ptr, small, large).unwrap().cast::<u8>();
+
+                assert_eq!(*ptr.as_ptr().add(9), 0xAA);
+                assert_eq!(*ptr.as_ptr().add(50), 0);
+                assert!((10..100).all(|i| *ptr.as_ptr().add(i) == 0));
+
+                alloc.deallocate(ptr, large);
+            }
+        }
+
+        #[test]
+        fn cant_reserve_values_over_maximum() {
+            let mut vec = vec();
+            let initial_ptr = vec.as_ptr();
+
+            assert!(vec.try_reserve(MAX_CAP * 2).is_err());
+            assert_eq!(vec.as_ptr(), initial_ptr);
+        }
+    }
+
+    #[cfg(all(feature = "nightly", feature = "std", target_pointer_width = "64"))]
+    mod reservation_quantum {
+        use std::vec::Vec;
+
+        use super::*;
+
+        #[test]
+        fn reserves_address_space_for_the_layout() {
+            // Reserving the maximum size for each vector would take far more than the
+            // address space.
+            let allocator = VirtualAlloc::new(1 << 44).with_reservation_quantum(1 << 16);
+            let mut vecs = (0..100u64).map(|i| {
+                let mut vec = Vec::with_capacity_in(10, &allocator);
+
+                vec.push(i);
+                vec
+            }).collect::<Vec<_>>();
+
+            let first = vecs[0].as_ptr();
+
+            vecs[0].extend(1..1000);
+
+            assert_eq!(vecs[0].as_ptr(), first);
+
+            for vec in &mut vecs {
+                vec.extend(0..100_000);
+            }
+
+            assert!(vecs.iter().enumerate().all(|(i, vec)| vec[0] == i as u64));
+            assert_eq!(vecs[0][999], 999);
+
+            vecs[1].truncate(10);
+            vecs[1].shrink_to_fit();
+
+            assert_eq!(&vecs[1][..3], [1, 0, 1]);
+        }
+    }
+
+    #[cfg(feature = "nightly")]
+    mod guard_pages {
+        use super::*;
+
+        const MAX_SIZE: usize = 1_000_000;
+
+        fn allocator() -> VirtualAlloc {
+            VirtualAlloc::new(MAX_SIZE).with_guard_pages(GuardPages::Both)
+        }
+
+        #[test]
+        fn places_allocations_right_before_a_page_boundary() {
+            let allocator = allocator();
+            let layout = Layout::from_size_align(100, 1).unwrap();
+            let ptr = allocator.allocate(layout).unwrap().cast::<u8>();
+
+            assert_eq!((ptr.as_ptr().addr() + 100) % VirtualAlloc::page_size(), 0);
+
+            unsafe { allocator.deallocate(ptr, layout) };
+        }
+
+        #[test]
+        fn moves_allocations_when_growing() {
+            let allocator = allocator();
+            let layout = Layout::from_size_align(100, 1).unwrap();
+            let new_layout = Layout::from_size_align(200, 1).unwrap();
+
+            unsafe {
+                let ptr = allocator.allocate(layout).unwrap().cast::<u8>();
+
+                *ptr.as_ptr() = 42;
+
+                let new_ptr = Allocator::grow(&allocator, ptr, layout, new_layout).unwrap().cast::<u8>();
+
+                assert_ne!(new_ptr, ptr);
+                assert_eq!(*new_ptr.as_ptr(), 42);
+
+                allocator.deallocate(new_ptr, new_layout);
+            }
+        }
+
+        #[test]
+        fn keeps_empty_allocations_in_their_reservation() {
+            let page = VirtualAlloc::page_size();
+
+            for guard in [GuardPages::End, GuardPages::Both] {
+                let allocator = VirtualAlloc::new(MAX_SIZE).with_guard_pages(guard);
+
+                for align in [1, 8, page] {
+                    let layout = Layout::from_size_align(0, align).unwrap();
+                    let ptr = allocator.allocate(layout).unwrap().cast::<u8>();
+
+                    assert_eq!(ptr.as_ptr().addr() % align, 0);
+
+                    // The allocation lies in the accessible data page, from which the
+                    // reservation to release is found.
+                    unsafe {
+                        *ptr.as_ptr() = 1;
+
+                        allocator.deallocate(ptr, layout);
+                    }
+                }
+            }
+        }
+
+        #[cfg(target_os = "freebsd")]
+        #[test]
+        fn maps_guard_pages_that_cannot_be_made_accessible() {
+            let allocator = allocator();
+            let page = VirtualAlloc::page_size();
+            let layout = Layout::from_size_align(page, 1).unwrap();
+            let ptr = allocator.allocate(layout).unwrap().cast::<u8>();
+
+            unsafe {
+                for guard in [ptr.as_ptr().sub(page), ptr.as_ptr().add(page)] {
+                    assert_ne!(libc::mprotect(guard as _, page, libc::PROT_READ), 0);
+                }
+
+                allocator.deallocate(ptr, layout);
+            }
+        }
+    }
+
+    #[test]
+    fn allocation_granularity_is_a_power_of_two() {
+        assert!(VirtualAlloc::allocation_granularity().is_power_of_two());
+    }
+
+    #[test]
+    fn pages_can_be_moved_between_reservations() {
+        let page = VirtualAlloc::page_size();
+        let mut src = VirtualVec::<u8>::with_capacity(page * 4, page * 4);
+        let mut dst = VirtualVec::<u8>::with_capacity(page * 4, page * 4);
+
+        src.extend_from_slice(&(0..page * 4).map(|i| i as u8).collect::<Vec<_>>());
+
+        unsafe {
+            let from = NonNull::new_unchecked(src.as_mut_ptr().add(10));
+            let to = NonNull::new_unchecked(dst.as_mut_ptr().add(10));
+            let moved = VirtualAlloc::transfer_pages(from, to, page * 3);
+
+            #[cfg(target_os = "linux")]
+            assert_eq!(moved, page * 2);
+            #[cfg(not(target_os = "linux"))]
+            assert_eq!(moved, 0);
+
+            dst.set_len(page * 3 + 10);
+
+            // Only the moved pages are zeroed in the source.
+            let expected = (0..page * 4)
+                .map(|i| if i >= page && i < page + moved { 0 } else { i as u8 })
+                .collect::<Vec<_>>();
+
+            assert_eq!(src[..], expected[..]);
+        }
+
+        assert_eq!(dst[10..], (10..page * 3 + 10).map(|i| i as u8).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn sizes_are_rounded_to_whole_pages() {
+        let page = VirtualAlloc::page_size();
+
+        assert_eq!(VirtualAlloc::page_size(), VirtualAlloc::query_page_size());
+        assert_eq!(round_to_page(0), 0);
+        assert_eq!(round_to_page(1), page);
+        assert_eq!(round_to_page(page + 1), page * 2);
+        assert_eq!(round_to_page(usize::MAX), usize::MAX / page * page);
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn allocation_granularity_is_64kb_on_windows() {
+        assert_eq!(VirtualAlloc::allocation_granularity(), 0x10000);
+    }
+
+    #[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn reservations_are_inaccessible_until_committed() {
+        let page = VirtualAlloc::page_size();
+        let alloc = VirtualAlloc::with_protection(page * 4, true, false, true);
+        let ptr = VirtualAlloc::init(ptr::null_mut(), alloc.max, alloc.prot).unwrap();
+
+        unsafe {
+            alloc.reserve_internal(ptr.as_ptr(), 0, 1).unwrap();
+
+            assert_eq!(pagemap::dump(ptr.cast(), page * 4, 0).unwrap(), "x...");
+
+            VirtualAlloc::release(ptr.as_ptr(), alloc.max);
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn reservations_are_inaccessible_until_committed() {
+        use std::mem;
+        use windows_sys::Win32::System::Memory::*;
+
+        let alloc = VirtualAlloc::with_protection(1_000_000, true, true, true);
+        let ptr = VirtualAlloc::init(ptr::null_mut(), alloc.max, alloc.prot).unwrap();
+
+        unsafe {
+            alloc.reserve_internal(ptr.as_ptr(), 0, 1).unwrap();
+
+            let mut info: MEMORY_BASIC_INFORMATION = mem::zeroed();
+            let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+            let uncommitted = ptr.as_ptr().add(VirtualAlloc::page_size());
+
+            assert_ne!(VirtualQuery(uncommitted as _, &mut info, size), 0);
+            assert_eq!((info.State, info.AllocationProtect), (MEM_RESERVE, PAGE_NOACCESS));
+
+            VirtualQuery(ptr.as_ptr() as _, &mut info, size);
+
+            assert_eq!((info.State, info.Protect), (MEM_COMMIT, PAGE_EXECUTE_READWRITE));
+
+            VirtualAlloc::release(ptr.as_ptr(), alloc.max);
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn purgeable_memory_can_be_made_volatile() {
+        let alloc = VirtualAlloc::purgeable(1_000_000);
+        let ptr = VirtualAlloc::init(ptr::null_mut(), alloc.max, alloc.prot).unwrap();
+
+        unsafe {
+            alloc.reserve_internal(ptr.as_ptr(), 0, 1_000).unwrap();
+
+            assert!(VirtualAlloc::set_volatile(ptr, true).is_ok());
+            assert!(VirtualAlloc::set_volatile(ptr, false).is_ok());
+
+            VirtualAlloc::release(ptr.as_ptr(), alloc.max);
+        }
+    }
+
+    #[cfg(not(any(windows, target_arch = "wasm32")))]
+    #[test]
+    fn protection_matches_libc_constants() {
+        assert_eq!(get_protection(false, false, false), libc::PROT_NONE);
+        assert_eq!(get_protection(true, false, false), libc::PROT_READ);
+        assert_eq!(get_protection(true, true, false), libc::PROT_READ | libc::PROT_WRITE);
+        assert_eq!(get_protection(true, false, true), libc::PROT_READ | libc::PROT_EXEC);
+    }
+
+    #[test]
+    fn clones_compare_equal() {
+        let alloc = VirtualAlloc::new(1 << 20).with_guard_pages(GuardPages::End);
+
+        assert_eq!(alloc.clone(), alloc);
+        assert_ne!(alloc.clone().with_reservation_quantum(1), alloc);
+        assert_ne!(VirtualAlloc::with_protection(1 << 20, true, false, false),
+                   VirtualAlloc::new(1 << 20));
+    }
+
+    #[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+    #[test]
+    fn debug_shows_the_configuration() {
+        let alloc = VirtualAlloc::new(4096).demand_paged();
+
+        assert_eq!(format!("{:?}", alloc),
+                   "VirtualAlloc { max: 4096, protection: 0x3, guard: None, \
+                    reservation_quantum: None, demand_paged: true, commit_on_fault: false, \
+                    huge_pages: None, populate: false, numa_node: None, \
+                    growth: Geometric { max_ahead: 67108864 }, label: None }");
+    }
+
+    #[test]
+    fn reservations_can_be_committed_and_written() {
+        let alloc = VirtualAlloc::new(1_000_000);
+        let ptr = VirtualAlloc::init(ptr::null_mut(), alloc.max, alloc.prot).unwrap();
+
+        unsafe {
+            alloc.reserve_internal(ptr.as_ptr(), 0, 1_000).unwrap();
+
+            *ptr.as_ptr().add(999) = 42;
+
+            assert_eq!(*ptr.as_ptr().add(999), 42);
+
+            VirtualAlloc::release(ptr.as_ptr(), alloc.max);
+        }
+    }
+
+    #[test]
+    fn decommitted_memory_can_be_committed_again() {
+        use VirtualBacking;
+
+        let page = VirtualAlloc::page_size();
+
+        // NetBSD and macOS only let executable memory be committed again if decommitting
+        // keeps the protection and flags it was reserved with.
+        let executable = VirtualAlloc::with_protection(1 << 20, true, false, true);
+
+        for alloc in [VirtualAlloc::new(1 << 20), executable] {
+            let ptr = alloc.reserve(1 << 20).unwrap();
+
+            unsafe {
+                alloc.commit(ptr, page).unwrap();
+                alloc.decommit(ptr, page).unwrap();
+                alloc.commit(ptr, page).unwrap();
+                alloc.release(ptr, 1 << 20);
+            }
+        }
+
+        let alloc = VirtualAlloc::new(1 << 20);
+        let ptr = alloc.reserve(1 << 20).unwrap();
+
+        unsafe {
+            alloc.commit(ptr, page).unwrap();
+            *ptr.as_ptr() = 42;
+            alloc.decommit(ptr, page).unwrap();
+            alloc.commit(ptr, page).unwrap();
+
+            assert_eq!(*ptr.as_ptr(), 0);
+
+            alloc.release(ptr, 1 << 20);
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[test]
+    fn page_size_is_64kb_on_wasm() {
+        assert_eq!(VirtualAlloc::page_size(), 0x10000);
+    }
+
+    #[test]
+    fn default_limits_can_be_overridden() {
+        set_default_max_size(Some(1_000_000));
+
+        assert_eq!(VirtualAlloc::default().max_capacity(), 1_000_000);
+
+        set_default_max_size(None);
+    }
+
+    #[test]
+    fn large_commits_can_use_huge_pages() {
+        let size = 8 << 20;
+        let mut vec = VirtualVec::<u8>::with_capacity(size, size);
+
+        assert_eq!(VirtualAlloc::huge_page_threshold(), Some(2 << 20));
+
+        unsafe {
+            ptr::write_bytes(vec.as_mut_ptr(), 1, size);
+            vec.set_len(size);
+        }
+
+        assert!(vec.iter().all(|&b| b == 1));
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[test]
+    fn default_limits_fit_in_the_address_space_of_32_bit_targets() {
+        assert!(default_max_size() <= usize::MAX / 16);
     }
 }
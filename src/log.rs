@@ -0,0 +1,291 @@
+//! A byte log that many producers append to concurrently.
+
+#[cfg(feature = "std")] use std::ops::{Deref, DerefMut};
+#[cfg(feature = "std")] use std::ptr::NonNull;
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::ops::{Deref, DerefMut};
+#[cfg(not(feature = "std"))] use core::ptr::NonNull;
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+use super::sync::{self, AtomicBool, AtomicUsize, Ordering};
+
+
+/// A log of bytes that many producers append to concurrently, with little overhead.
+///
+/// Appending to the log is done in two steps: a producer first reserves a range of bytes
+/// with `reserve`, which only takes an atomic update of the end of the log, then writes
+/// its entry into that range. Entries are published in the order in which they were
+/// reserved once they are written, and readers see all published bytes through
+/// `published`.
+///
+/// Memory is committed on demand as the log grows, and bytes that were published never
+/// move.
+///
+/// # Example
+/// ```
+/// use std::thread;
+/// use virtualalloc::VirtualLog;
+///
+/// let log = VirtualLog::new(1 << 20);
+///
+/// thread::scope(|s| {
+///     for _ in 0..4 {
+///         s.spawn(|| {
+///             for _ in 0..100 {
+///                 log.append(b"event;").unwrap();
+///             }
+///         });
+///     }
+/// });
+///
+/// assert_eq!(log.published(), b"event;".repeat(400).as_slice());
+/// ```
+pub struct VirtualLog<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    max: usize,
+    /// The end of the last reserved entry.
+    reserved: AtomicUsize,
+    /// The end of the last published entry, which never exceeds `reserved`.
+    published: AtomicUsize,
+    /// The number of committed bytes, which never falls below `reserved`.
+    committed: AtomicUsize,
+    committing: AtomicBool
+}
+
+// Producers only write to the ranges they reserved, and committing memory is serialized.
+unsafe impl<B: VirtualBacking + Send + Sync> Send for VirtualLog<B> {}
+unsafe impl<B: VirtualBacking + Send + Sync> Sync for VirtualLog<B> {}
+
+/// An entry reserved in a `VirtualLog`, which dereferences to its bytes.
+///
+/// The entry is published when dropped, once all entries reserved before it are
+/// published as well.
+pub struct LogEntry<'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    log: &'a VirtualLog<B>,
+    start: usize,
+    end: usize
+}
+
+impl VirtualLog {
+    /// Returns a log that can hold up to `max` bytes.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved.
+    pub fn new(max: usize) -> Self {
+        Self::try_new(max).unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a log that can hold up to `max` bytes, or an error if the memory could not
+    /// be reserved.
+    pub fn try_new(max: usize) -> Result<Self, VirtualMemError> {
+        VirtualLog::try_with_backing(max, VirtualAlloc::new(max))
+    }
+}
+
+impl<B: VirtualBacking> VirtualLog<B> {
+    /// Returns a log that can hold up to `max` bytes of memory provided by the given
+    /// backing, or an error if the memory could not be reserved.
+    pub fn try_with_backing(max: usize, backing: B) -> Result<Self, VirtualMemError> {
+        let ptr = backing.reserve(max)?;
+
+        Ok(VirtualLog {
+            backing, ptr, max,
+            reserved: AtomicUsize::new(0),
+            published: AtomicUsize::new(0),
+            committed: AtomicUsize::new(0),
+            committing: AtomicBool::new(false)
+        })
+    }
+
+    /// Commits enough memory for the log to hold `end` bytes.
+    #[cold]
+    fn commit(&self, end: usize) -> Result<(), VirtualMemError> {
+        while self.committing
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err() {
+            sync::spin_loop();
+        }
+
+        let committed = self.committed.load(Ordering::Relaxed);
+        let mut result = Ok(());
+
+        if end > committed {
+            let size = ::commit_size(committed, end, self.max, self.backing.page_size());
+
+            result = unsafe { ::commit_delta(&self.backing, self.ptr, committed, size) };
+
+            if result.is_ok() {
+                self.committed.store(size, Ordering::Release);
+            }
+        }
+
+        self.committing.store(false, Ordering::Release);
+        result
+    }
+
+    /// Reserves an entry of `len` bytes at the end of the log.
+    ///
+    /// The entry must be dropped to be published, and later entries are only published
+    /// once it is, so it should be written to and dropped promptly.
+    pub fn reserve(&self, len: usize) -> Result<LogEntry<'_, B>, VirtualMemError> {
+        let mut start = self.reserved.load(Ordering::Relaxed);
+
+        loop {
+            let end = match start.checked_add(len) {
+                Some(end) if end <= self.max => end,
+                _ => return Err(VirtualMemError::ExceedsMax {
+                    requested: start.saturating_add(len), max: self.max
+                })
+            };
+
+            // Memory is committed before the entry is reserved, so that failing to commit
+            // it never leaves a hole that would block the entries reserved after it.
+            if end > self.committed.load(Ordering::Acquire) {
+                self.commit(end)?;
+            }
+
+            match self.reserved.compare_exchange_weak(start, end, Ordering::Relaxed,
+                                                      Ordering::Relaxed) {
+                Ok(_) => return Ok(LogEntry { log: self, start, end }),
+                Err(current) => start = current
+            }
+        }
+    }
+
+    /// Appends the given bytes to the log, and returns their offset in the log once they
+    /// are published.
+    pub fn append(&self, bytes: &[u8]) -> Result<usize, VirtualMemError> {
+        let mut entry = self.reserve(bytes.len())?;
+
+        entry.copy_from_slice(bytes);
+
+        Ok(entry.offset())
+    }
+
+    /// Returns the number of published bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.published.load(Ordering::Acquire)
+    }
+
+    /// Returns whether no bytes were published.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of bytes the log can hold.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        self.max
+    }
+
+    /// Returns the published bytes, which remain valid while producers keep appending.
+    #[inline]
+    pub fn published(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+}
+
+impl<B: VirtualBacking> Drop for VirtualLog<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.max);
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> LogEntry<'a, B> {
+    /// Returns the offset of the entry in the log.
+    #[inline]
+    pub fn offset(&self) -> usize {
+        self.start
+    }
+}
+
+impl<'a, B: VirtualBacking> Deref for LogEntry<'a, B> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        unsafe {
+            slice::from_raw_parts(self.log.ptr.as_ptr().add(self.start), self.end - self.start)
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> DerefMut for LogEntry<'a, B> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut(self.log.ptr.as_ptr().add(self.start), self.end - self.start)
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> Drop for LogEntry<'a, B> {
+    fn drop(&mut self) {
+        // Entries are published in order, so wait for the entries reserved before this one,
+        // whose producers may have been preempted.
+        while self.log.published.load(Ordering::Acquire) != self.start {
+            sync::yield_now();
+        }
+
+        self.log.published.store(self.end, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn publishes_entries_in_order() {
+        let log = VirtualLog::new(1024);
+        let mut first = log.reserve(3).unwrap();
+        let mut second = log.reserve(3).unwrap();
+
+        first.copy_from_slice(b"abc");
+        second.copy_from_slice(b"def");
+
+        assert_eq!(second.offset(), 3);
+        assert!(log.is_empty());
+
+        drop(first);
+
+        assert_eq!(log.published(), b"abc");
+
+        drop(second);
+
+        assert_eq!(log.published(), b"abcdef");
+        assert!(log.reserve(1019).is_err());
+        assert_eq!(log.append(b"ghi").unwrap(), 6);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn can_be_appended_to_concurrently() {
+        use std::thread;
+
+        let page = VirtualAlloc::page_size();
+        let log = VirtualLog::new(page * 64);
+
+        thread::scope(|s| {
+            for i in 0..8u8 {
+                let log = &log;
+
+                s.spawn(move || {
+                    for _ in 0..page {
+                        log.append(&[i; 4]).unwrap();
+                    }
+                });
+            }
+        });
+
+        assert_eq!(log.len(), page * 32);
+        assert!(log.published().chunks(4).all(|entry| entry.iter().all(|&b| b == entry[0])));
+    }
+}
@@ -0,0 +1,63 @@
+//! Bindings to the Mach virtual memory interface used on macOS.
+//!
+//! Unlike `mmap`, `mach_vm_allocate` can create purgeable memory, whose contents the
+//! kernel may discard under memory pressure while it is marked volatile.
+
+#![allow(non_camel_case_types)]
+
+pub type kern_return_t = libc::c_int;
+pub type mach_port_t = libc::c_uint;
+pub type mach_vm_address_t = u64;
+pub type mach_vm_size_t = u64;
+pub type vm_prot_t = libc::c_int;
+
+pub const KERN_SUCCESS: kern_return_t = 0;
+
+pub const VM_FLAGS_FIXED: libc::c_int = 0x0000;
+pub const VM_FLAGS_ANYWHERE: libc::c_int = 0x0001;
+pub const VM_FLAGS_PURGABLE: libc::c_int = 0x0002;
+pub const VM_FLAGS_OVERWRITE: libc::c_int = 0x4000;
+
+pub const VM_PROT_NONE: vm_prot_t = 0;
+
+pub const VM_PURGABLE_SET_STATE: libc::c_int = 0;
+pub const VM_PURGABLE_NONVOLATILE: libc::c_int = 0;
+pub const VM_PURGABLE_VOLATILE: libc::c_int = 1;
+pub const VM_PURGABLE_EMPTY: libc::c_int = 2;
+
+/// The tag given to memory reserved by this crate, under which it appears in `vmmap`
+/// and Instruments (VM_MEMORY_APPLICATION_SPECIFIC_1).
+pub const DEFAULT_VM_TAG: u8 = 240;
+
+/// A protection flag that is not passed to the kernel, and that marks memory that must
+/// be allocated as purgeable.
+pub const PROT_PURGEABLE: libc::c_int = 1 << 29;
+
+/// Returns the flags that give the given tag to allocated memory.
+#[inline]
+pub fn vm_make_tag(tag: u8) -> libc::c_int {
+    (tag as libc::c_int) << 24
+}
+
+extern "C" {
+    static mach_task_self_: mach_port_t;
+
+    pub fn mach_vm_allocate(task: mach_port_t, addr: *mut mach_vm_address_t,
+                            size: mach_vm_size_t, flags: libc::c_int) -> kern_return_t;
+
+    pub fn mach_vm_protect(task: mach_port_t, addr: mach_vm_address_t, size: mach_vm_size_t,
+                           set_maximum: libc::boolean_t, protection: vm_prot_t) -> kern_return_t;
+
+    pub fn mach_vm_purgable_control(task: mach_port_t, addr: mach_vm_address_t,
+                                    control: libc::c_int, state: *mut libc::c_int)
+                                    -> kern_return_t;
+
+    pub fn mach_vm_deallocate(task: mach_port_t, addr: mach_vm_address_t,
+                              size: mach_vm_size_t) -> kern_return_t;
+}
+
+/// Returns the port of the current task.
+#[inline]
+pub fn mach_task_self() -> mach_port_t {
+    unsafe { mach_task_self_ }
+}
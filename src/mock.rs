@@ -0,0 +1,328 @@
+//! A deterministic backing for unit tests.
+//!
+//! `MockBacking` records every call made to it, and can be scripted to fail specific
+//! calls or to run out of memory after a given amount has been committed. This lets code
+//! built on top of `VirtualVec` and other containers test its handling of failures
+//! without actually exhausting the memory of the machine.
+//!
+//! Memory itself is still reserved from the operating system, so containers built upon
+//! a `MockBacking` behave exactly as they would otherwise. Under Miri, which cannot map
+//! memory, reservations are allocated on the heap instead and are always accessible, so
+//! that the bookkeeping of containers can still be checked for undefined behaviour.
+//! Only tests whose containers are built upon a `MockBacking` can run under Miri:
+//!
+//! ```sh
+//! cargo +nightly miri test --lib -- mock:: slices_outlive_growth
+//! ```
+
+use std::cell::RefCell;
+use std::ptr::NonNull;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// An operation of a `VirtualBacking`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Op {
+    Reserve,
+    Commit,
+    Decommit,
+    Protect,
+    Release
+}
+
+/// A call made to a `MockBacking`.
+///
+/// Addresses are given as offsets from the start of the reservation they fall in, so that
+/// recorded calls do not depend on where the operating system placed reservations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Call {
+    Reserve { size: usize },
+    Commit { offset: usize, size: usize },
+    Decommit { offset: usize, size: usize },
+    Protect { offset: usize, size: usize, read: bool, write: bool, exec: bool },
+    Release { size: usize }
+}
+
+impl Call {
+    /// Returns the operation performed by the call.
+    pub fn op(&self) -> Op {
+        match *self {
+            Call::Reserve { .. } => Op::Reserve,
+            Call::Commit { .. } => Op::Commit,
+            Call::Decommit { .. } => Op::Decommit,
+            Call::Protect { .. } => Op::Protect,
+            Call::Release { .. } => Op::Release
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    calls: Vec<Call>,
+    failures: Vec<(Op, usize, VirtualMemError)>,
+    /// The base address, size and committed prefix of every live reservation.
+    reservations: Vec<(usize, usize, usize)>,
+    commit_limit: Option<usize>
+}
+
+impl State {
+    /// Returns the index of the reservation the given address falls in, and the offset of
+    /// the address within it.
+    fn find(&self, ptr: NonNull<u8>) -> (usize, usize) {
+        let addr = ptr.as_ptr().addr();
+
+        self.reservations.iter()
+            .position(|&(base, size, _)| addr >= base && addr - base <= size)
+            .map(|i| (i, addr - self.reservations[i].0))
+            .expect("Address does not belong to a reservation of this backing.")
+    }
+
+    fn committed(&self) -> usize {
+        self.reservations.iter().map(|&(_, _, committed)| committed).sum()
+    }
+
+    /// Records the given call, and returns the failure it was scripted to cause, if any.
+    fn record(&mut self, call: Call) -> Result<(), VirtualMemError> {
+        let op = call.op();
+        let nth = self.calls.iter().filter(|c| c.op() == op).count();
+
+        self.calls.push(call);
+
+        match self.failures.iter().position(|&(o, n, _)| o == op && n == nth) {
+            Some(i) => Err(self.failures.remove(i).2),
+            None => Ok(())
+        }
+    }
+}
+
+/// A `VirtualBacking` that records its calls and fails when scripted to.
+///
+/// # Example
+/// ```
+/// use virtualalloc::{VirtualMemError, VirtualVec};
+/// use virtualalloc::mock::{Call, MockBacking, Op};
+///
+/// let backing = MockBacking::new();
+///
+/// backing.fail_nth(Op::Commit, 0, VirtualMemError::CommitFailed { os_err: 12 });
+///
+/// let mut vec = VirtualVec::<u8, _>::with_backing(1024, backing);
+///
+/// assert!(vec.reserve(10).is_err());
+/// assert!(vec.reserve(10).is_ok());
+/// assert_eq!(vec.backing().calls()[1], Call::Commit { offset: 0, size: 1024 });
+/// ```
+pub struct MockBacking {
+    os: VirtualAlloc,
+    state: RefCell<State>
+}
+
+impl Default for MockBacking {
+    fn default() -> Self {
+        MockBacking::new()
+    }
+}
+
+impl MockBacking {
+    /// Returns a backing that provides read-write memory, and that does not fail unless
+    /// scripted to.
+    pub fn new() -> Self {
+        MockBacking { os: VirtualAlloc::new(0), state: RefCell::new(State::default()) }
+    }
+
+    /// Makes the `nth` call (counting from zero) of the given operation fail with the
+    /// given error.
+    ///
+    /// Failing calls are still recorded, but have no effect. Releases cannot fail.
+    pub fn fail_nth(&self, op: Op, nth: usize, err: VirtualMemError) {
+        assert!(op != Op::Release, "Releases cannot fail.");
+
+        self.state.borrow_mut().failures.push((op, nth, err));
+    }
+
+    /// Makes commits fail with `CommitFailed` once more than `limit` bytes would be
+    /// committed in total, simulating a machine that runs out of memory.
+    pub fn set_commit_limit(&self, limit: Option<usize>) {
+        self.state.borrow_mut().commit_limit = limit;
+    }
+
+    /// Returns all calls made to the backing so far, in order.
+    pub fn calls(&self) -> Vec<Call> {
+        self.state.borrow().calls.clone()
+    }
+
+    /// Returns the number of calls of the given operation made to the backing so far.
+    pub fn count(&self, op: Op) -> usize {
+        self.state.borrow().calls.iter().filter(|c| c.op() == op).count()
+    }
+
+    /// Returns the number of bytes committed across all live reservations, assuming that
+    /// memory is committed from the start of each reservation, as containers do.
+    pub fn committed(&self) -> usize {
+        self.state.borrow().committed()
+    }
+
+    /// Forgets all recorded calls, keeping scripted failures and live reservations.
+    pub fn clear_calls(&self) {
+        self.state.borrow_mut().calls.clear();
+    }
+}
+
+unsafe impl VirtualBacking for MockBacking {
+    fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+        let mut state = self.state.borrow_mut();
+
+        state.record(Call::Reserve { size })?;
+
+        #[cfg(not(miri))]
+        let ptr = self.os.reserve(size)?;
+        #[cfg(miri)]
+        let ptr = heap::reserve(size, self.page_size())?;
+
+        state.reservations.push((ptr.as_ptr().addr(), size, 0));
+
+        Ok(ptr)
+    }
+
+    unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        let mut state = self.state.borrow_mut();
+        let (i, offset) = state.find(ptr);
+
+        state.record(Call::Commit { offset, size })?;
+
+        let previous = state.reservations[i].2;
+        let end = previous.max(offset.saturating_add(size));
+
+        if state.commit_limit.is_some_and(|limit| state.committed() - previous + end > limit) {
+            return Err(VirtualMemError::CommitFailed { os_err: 0 })
+        }
+
+        #[cfg(not(miri))]
+        self.os.commit(ptr, size)?;
+        state.reservations[i].2 = end;
+
+        Ok(())
+    }
+
+    unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        let mut state = self.state.borrow_mut();
+        let (i, offset) = state.find(ptr);
+
+        state.record(Call::Decommit { offset, size })?;
+
+        #[cfg(not(miri))]
+        self.os.decommit(ptr, size)?;
+        #[cfg(miri)]
+        ptr.as_ptr().write_bytes(0, size);
+
+        if offset.saturating_add(size) >= state.reservations[i].2 {
+            state.reservations[i].2 = state.reservations[i].2.min(offset);
+        }
+
+        Ok(())
+    }
+
+    unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        let mut state = self.state.borrow_mut();
+        let (_, offset) = state.find(ptr);
+
+        state.record(Call::Protect { offset, size, read, write, exec })?;
+
+        #[cfg(not(miri))]
+        return self.os.protect(ptr, size, read, write, exec);
+        #[cfg(miri)]
+        return Ok(());
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, size: usize) {
+        let mut state = self.state.borrow_mut();
+        let addr = ptr.as_ptr().addr();
+
+        let _ = state.record(Call::Release { size });
+
+        state.reservations.retain(|&(base, _, _)| base != addr);
+
+        #[cfg(not(miri))]
+        self.os.release(ptr, size);
+        #[cfg(miri)]
+        heap::release(ptr, size, self.page_size());
+    }
+
+    fn page_size(&self) -> usize {
+        self.os.page_size()
+    }
+}
+
+/// Reservations made on the heap, which Miri can track.
+#[cfg(miri)]
+mod heap {
+    use std::alloc::{self, Layout};
+    use std::ptr::NonNull;
+
+    use super::super::VirtualMemError;
+
+    pub(super) fn reserve(size: usize, page: usize) -> Result<NonNull<u8>, VirtualMemError> {
+        let failed = VirtualMemError::ReservationFailed { os_err: 0 };
+        let layout = Layout::from_size_align(size.max(1), page).map_err(|_| failed)?;
+
+        NonNull::new(unsafe { alloc::alloc_zeroed(layout) }).ok_or(failed)
+    }
+
+    pub(super) unsafe fn release(ptr: NonNull<u8>, size: usize, page: usize) {
+        alloc::dealloc(ptr.as_ptr(), Layout::from_size_align_unchecked(size.max(1), page));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use VirtualVec;
+
+    #[test]
+    fn records_calls_relative_to_reservations() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8, _>::with_backing(page * 4, MockBacking::new());
+
+        vec.extend_from_slice(b"hello");
+        vec.push(b'!');
+        vec.reserve(page + 1).unwrap();
+
+        assert_eq!(vec.backing().calls(), vec![
+            Call::Reserve { size: page * 4 },
+            Call::Commit { offset: 0, size: page },
+            Call::Commit { offset: page, size: page }
+        ]);
+    }
+
+    #[test]
+    fn fails_scripted_calls_once() {
+        let backing = MockBacking::new();
+        let err = VirtualMemError::CommitFailed { os_err: 12 };
+
+        backing.fail_nth(Op::Commit, 0, err);
+
+        let mut vec = VirtualVec::<u8, _>::with_backing(1024, backing);
+
+        assert_eq!(vec.reserve(1), Err(err));
+        assert_eq!(vec.reserve(1), Ok(()));
+        assert_eq!(vec.backing().count(Op::Commit), 2);
+    }
+
+    #[test]
+    fn simulates_running_out_of_memory() {
+        let backing = MockBacking::new();
+
+        let page = VirtualAlloc::page_size();
+
+        backing.set_commit_limit(Some(page * 2));
+
+        let mut vec = VirtualVec::<u8, _>::with_backing(page * 4, backing);
+
+        assert!(vec.reserve(page * 2).is_ok());
+        assert_eq!(vec.reserve(page * 2 + 1), Err(VirtualMemError::CommitFailed { os_err: 0 }));
+        assert_eq!(vec.backing().committed(), page * 2);
+    }
+}
@@ -0,0 +1,129 @@
+//! Hardware memory tagging (MTE) on AArch64 Linux.
+//!
+//! With MTE, each 16-byte granule of memory mapped with `PROT_MTE` carries a 4-bit tag,
+//! and every pointer carries a tag in its top byte. Accessing memory through a pointer
+//! whose tag does not match the tag of the memory faults, which turns overflows and
+//! use-after-free bugs into immediate crashes.
+//!
+//! Memory must be allocated with `VirtualAlloc::with_memory_tagging`, and tag checking
+//! must be enabled for the current thread using `enable`. Ranges can then be tagged
+//! using `tag_range` when they are handed out, and retagged when they are released.
+
+#[cfg(feature = "std")]      use std::arch::asm;
+#[cfg(not(feature = "std"))] use core::arch::asm;
+
+
+/// The protection flag with which tagged memory is mapped.
+pub(crate) const PROT_MTE: libc::c_int = 0x20;
+
+/// The size of a tag granule, to which all tagged ranges must be aligned.
+pub const GRANULE_SIZE: usize = 16;
+
+const HWCAP2_MTE: libc::c_ulong = 1 << 18;
+
+const PR_SET_TAGGED_ADDR_CTRL: libc::c_int = 55;
+const PR_TAGGED_ADDR_ENABLE: libc::c_ulong = 1 << 0;
+const PR_MTE_TCF_SYNC: libc::c_ulong = 1 << 1;
+const PR_MTE_TCF_ASYNC: libc::c_ulong = 1 << 2;
+const PR_MTE_TAG_SHIFT: libc::c_ulong = 3;
+
+/// Returns whether the processor supports MTE.
+#[inline]
+pub fn is_supported() -> bool {
+    unsafe {
+        libc::getauxval(libc::AT_HWCAP2) & HWCAP2_MTE != 0
+    }
+}
+
+/// Enables tag checking for the current thread.
+///
+/// In synchronous mode, faults are reported precisely on the faulting access. In
+/// asynchronous mode, they are reported later, but checking is much cheaper. All
+/// non-zero tags may be generated by `tag_range`.
+///
+/// Returns `false` if MTE is not supported by the processor or the kernel.
+pub fn enable(sync: bool) -> bool {
+    let mode = if sync { PR_MTE_TCF_SYNC } else { PR_MTE_TCF_ASYNC };
+    let tags = 0xFFFE << PR_MTE_TAG_SHIFT;
+
+    unsafe {
+        libc::prctl(PR_SET_TAGGED_ADDR_CTRL, PR_TAGGED_ADDR_ENABLE | mode | tags, 0, 0, 0) == 0
+    }
+}
+
+/// Assigns a new random tag to the given range, returning a pointer to its start with
+/// the new tag.
+///
+/// Only the returned pointer (and pointers derived from it) can access the range
+/// afterwards; in particular, tagging a range again when it is released makes any
+/// remaining pointer to it fault on use.
+///
+/// # Safety
+/// The range must be within memory allocated with memory tagging enabled, and both `ptr`
+/// and `len` must be aligned on `GRANULE_SIZE`.
+pub unsafe fn tag_range(ptr: *mut u8, len: usize) -> *mut u8 {
+    debug_assert_eq!(ptr.addr() % GRANULE_SIZE, 0);
+    debug_assert_eq!(len % GRANULE_SIZE, 0);
+
+    let tagged: *mut u8;
+
+    // Exclude the current tag of the pointer, so that the new tag always differs.
+    asm!(".arch_extension memtag",
+         "gmi {excl}, {ptr}, xzr",
+         "irg {tagged}, {ptr}, {excl}",
+         ptr = in(reg) ptr,
+         excl = out(reg) _,
+         tagged = lateout(reg) tagged,
+         options(nomem, nostack, preserves_flags));
+
+    let mut granule = tagged;
+    let end = tagged.add(len);
+
+    while granule < end {
+        asm!(".arch_extension memtag",
+             "stg {0}, [{0}]",
+             in(reg) granule,
+             options(nostack, preserves_flags));
+
+        granule = granule.add(GRANULE_SIZE);
+    }
+
+    tagged
+}
+
+/// Returns the tag of the given pointer.
+#[inline]
+pub fn pointer_tag(ptr: *const u8) -> u8 {
+    ((ptr.addr() >> 56) & 0xF) as u8
+}
+
+#[cfg(all(test, feature = "nightly"))]
+mod tests {
+    use super::*;
+    use {Allocator, Layout, VirtualAlloc};
+
+    #[test]
+    fn tags_ranges_with_a_new_tag() {
+        if !is_supported() || !enable(true) {
+            return
+        }
+
+        let alloc = VirtualAlloc::with_memory_tagging(1_000_000);
+        let layout = Layout::from_size_align(4096, GRANULE_SIZE).unwrap();
+
+        unsafe {
+            let ptr = alloc.allocate(layout).unwrap().cast::<u8>();
+
+            let first = tag_range(ptr.as_ptr(), 64);
+            let second = tag_range(first, 64);
+
+            assert_ne!(pointer_tag(first), pointer_tag(second));
+
+            *second = 42;
+
+            assert_eq!(*second, 42);
+
+            alloc.deallocate(ptr, layout);
+        }
+    }
+}
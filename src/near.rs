@@ -0,0 +1,264 @@
+//! Allocation of small executable slots near a given address.
+
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ptr::{self, NonNull};
+
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+
+use super::{Opaque, Protection, VirtualAlloc};
+
+
+/// The maximum distance between a slot and the target address, which is the range
+/// of a signed 32-bit displacement.
+const MAX_DISTANCE: usize = i32::MAX as usize;
+
+/// The minimum step by which the address space is scanned.
+const MIN_SCAN_STEP: usize = 0x10000;
+
+/// An allocator that hands out small executable slots, all of which are within
+/// ±2GB of a given target address.
+///
+/// Since every slot can be reached from the target using a 32-bit relative jump (and
+/// vice versa), this is well-suited for allocating trampolines and thunks in hot-patching
+/// and detour libraries.
+///
+/// Slots are readable, writable and executable. In strict W^X mode, they are only
+/// readable and writable, and must be made executable using `VirtualAlloc::make_executable`.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::near::NearAlloc;
+///
+/// fn target() {}
+///
+/// let mut alloc = NearAlloc::new(target as *const u8, 64, 100).unwrap();
+/// let slot = alloc.alloc().unwrap();
+///
+/// assert!(alloc.is_near(slot.as_ptr()));
+/// ```
+pub struct NearAlloc {
+    target: *const u8,
+    ptr: NonNull<Opaque>,
+    size: usize,
+    slot_size: usize,
+    next: usize,
+    free: *mut Opaque
+}
+
+impl NearAlloc {
+    /// Returns a `NearAlloc` that can hand out `slots` slots of `slot_size` bytes each,
+    /// all within ±2GB of `target`.
+    ///
+    /// The address space around `target` is scanned for a free range large enough to
+    /// hold all slots. Returns `None` if no such range could be found, or in strict W^X
+    /// mode, since slots are writable and executable.
+    pub fn new(target: *const u8, slot_size: usize, slots: usize) -> Option<Self> {
+        // Slots must be able to hold a pointer to the next free slot.
+        let slot_size = round_up(slot_size.max(mem::size_of::<usize>()), 16);
+        let size = round_up(slot_size.checked_mul(slots)?, scan_step());
+
+        if size == 0 || size >= MAX_DISTANCE {
+            return None
+        }
+
+        let prot = VirtualAlloc::try_with_protection(size, true, true, true).ok()?.prot;
+        let ptr = scan(target.addr(), size, prot)?;
+
+        Some(NearAlloc { target, ptr, size, slot_size, next: 0, free: ptr::null_mut() })
+    }
+
+    /// Returns the address near which all slots are allocated.
+    #[inline]
+    pub fn target(&self) -> *const u8 {
+        self.target
+    }
+
+    /// Returns the size of a single slot.
+    #[inline]
+    pub fn slot_size(&self) -> usize {
+        self.slot_size
+    }
+
+    /// Returns whether the given pointer is within ±2GB of the target address.
+    #[inline]
+    pub fn is_near(&self, ptr: *const u8) -> bool {
+        is_near(self.target.addr(), ptr.addr(), 1)
+    }
+
+    /// Allocates a slot, returning `None` if all slots are in use.
+    pub fn alloc(&mut self) -> Option<NonNull<u8>> {
+        if let Some(slot) = NonNull::new(self.free) {
+            unsafe {
+                self.free = *(slot.as_ptr() as *mut *mut Opaque);
+            }
+
+            return Some(slot)
+        }
+
+        if self.next + self.slot_size > self.size {
+            return None
+        }
+
+        let slot = unsafe { self.ptr.as_ptr().add(self.next) };
+
+        self.next += self.slot_size;
+
+        NonNull::new(slot)
+    }
+
+    /// Returns a slot to the allocator, so that it can be handed out again.
+    ///
+    /// # Safety
+    /// The slot must have been allocated by this allocator, and must not be used anymore.
+    /// In particular, no thread may be executing code in it.
+    pub unsafe fn dealloc(&mut self, slot: NonNull<u8>) {
+        *(slot.as_ptr() as *mut *mut Opaque) = self.free;
+
+        self.free = slot.as_ptr();
+    }
+}
+
+impl Drop for NearAlloc {
+    fn drop(&mut self) {
+        unsafe {
+            VirtualAlloc::release(self.ptr.as_ptr(), self.size);
+        }
+    }
+}
+
+#[inline]
+fn round_up(value: usize, to: usize) -> usize {
+    value.div_ceil(to) * to
+}
+
+#[inline]
+fn is_near(target: usize, ptr: usize, size: usize) -> bool {
+    let lo = if ptr < target { ptr } else { target };
+    let hi = if ptr + size > target { ptr + size } else { target };
+
+    hi - lo <= MAX_DISTANCE
+}
+
+/// Returns the step by which the address space is scanned, which must be a multiple
+/// of the allocation granularity for reservations at the scanned addresses to succeed.
+#[inline]
+fn scan_step() -> usize {
+    VirtualAlloc::allocation_granularity().max(MIN_SCAN_STEP)
+}
+
+/// Scans the address space around `target`, alternating between addresses above and
+/// below it, until `size` bytes can be mapped.
+fn scan(target: usize, size: usize, prot: Protection) -> Option<NonNull<Opaque>> {
+    let step = scan_step();
+    let base = target / step * step;
+    let mut distance = 0;
+
+    while distance <= MAX_DISTANCE {
+        let above = base.checked_add(distance);
+        let below = base.checked_sub(distance + step);
+
+        let mut any = false;
+
+        for addr in above.into_iter().chain(below) {
+            if addr == 0 || !is_near(target, addr, size) {
+                continue
+            }
+
+            any = true;
+
+            if let Some(ptr) = map_at(addr, size, prot) {
+                return Some(ptr)
+            }
+        }
+
+        if !any && distance > size {
+            break
+        }
+
+        distance += step;
+    }
+
+    None
+}
+
+#[cfg(windows)]
+fn map_at(addr: usize, size: usize, prot: Protection) -> Option<NonNull<Opaque>> {
+    use windows_sys::Win32::System::Memory::{VirtualAlloc, MEM_COMMIT, MEM_RESERVE};
+
+    unsafe {
+        // MEM_RESERVE | MEM_COMMIT fails if the range is not entirely free.
+        let hint = ptr::without_provenance(addr);
+
+        NonNull::new(VirtualAlloc(hint, size, MEM_RESERVE | MEM_COMMIT, prot) as _)
+    }
+}
+
+#[cfg(not(windows))]
+fn map_at(addr: usize, size: usize, prot: Protection) -> Option<NonNull<Opaque>> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const FLAGS: libc::c_int = libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_FIXED_NOREPLACE;
+    #[cfg(target_os = "macos")]
+    const FLAGS: libc::c_int = libc::MAP_PRIVATE | libc::MAP_ANON | libc::MAP_JIT;
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+    const FLAGS: libc::c_int = libc::MAP_PRIVATE | libc::MAP_ANON;
+
+    unsafe {
+        let ptr = libc::mmap(ptr::without_provenance_mut(addr), size, prot, FLAGS, -1, 0);
+
+        if ptr == libc::MAP_FAILED {
+            return None
+        }
+
+        // Without MAP_FIXED_NOREPLACE, the address is only a hint.
+        if ptr.addr() != addr {
+            libc::munmap(ptr, size);
+
+            return None
+        }
+
+        NonNull::new(ptr as _)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn alloc() -> NearAlloc {
+        NearAlloc::new(round_up as *const u8, 32, 100).unwrap()
+    }
+
+    #[test]
+    fn allocates_slots_near_the_target() {
+        let mut alloc = alloc();
+
+        for _ in 0..100 {
+            let slot = alloc.alloc().unwrap();
+
+            assert!(alloc.is_near(slot.as_ptr()));
+        }
+    }
+
+    #[test]
+    fn cant_allocate_more_slots_than_available() {
+        let mut alloc = alloc();
+        let slots = alloc.size / alloc.slot_size();
+
+        for _ in 0..slots {
+            assert!(alloc.alloc().is_some());
+        }
+
+        assert!(alloc.alloc().is_none());
+    }
+
+    #[test]
+    fn reuses_deallocated_slots() {
+        let mut alloc = alloc();
+        let slot = alloc.alloc().unwrap();
+
+        unsafe { alloc.dealloc(slot) };
+
+        assert_eq!(alloc.alloc(), Some(slot));
+    }
+}
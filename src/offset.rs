@@ -0,0 +1,621 @@
+//! Pointers stored as offsets from the start of a region, for shared and persistent data.
+
+#[cfg(feature = "std")] use std::convert::TryFrom;
+#[cfg(feature = "std")] use std::fmt;
+#[cfg(feature = "std")] use std::hash::{Hash, Hasher};
+#[cfg(feature = "std")] use std::marker::PhantomData;
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::convert::TryFrom;
+#[cfg(not(feature = "std"))] use core::fmt;
+#[cfg(not(feature = "std"))] use core::hash::{Hash, Hasher};
+#[cfg(not(feature = "std"))] use core::marker::PhantomData;
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::Pod;
+
+
+/// The integer types an `Offset` can be stored as.
+///
+/// This trait is sealed, and implemented for `u32` and `u64`.
+pub trait OffsetWidth: Pod + Eq + Hash + fmt::Debug + private::Sealed {
+    #[doc(hidden)]
+    fn from_usize(offset: usize) -> Option<Self>;
+
+    #[doc(hidden)]
+    fn to_usize(self) -> Option<usize>;
+}
+
+mod private {
+    pub trait Sealed {}
+
+    impl Sealed for u32 {}
+    impl Sealed for u64 {}
+}
+
+impl OffsetWidth for u32 {
+    #[inline]
+    fn from_usize(offset: usize) -> Option<Self> {
+        if offset as u64 > u32::MAX as u64 { None } else { Some(offset as u32) }
+    }
+
+    #[inline]
+    fn to_usize(self) -> Option<usize> {
+        Some(self as usize)
+    }
+}
+
+impl OffsetWidth for u64 {
+    #[inline]
+    fn from_usize(offset: usize) -> Option<Self> {
+        Some(offset as u64)
+    }
+
+    #[inline]
+    fn to_usize(self) -> Option<usize> {
+        if self > usize::MAX as u64 { None } else { Some(self as usize) }
+    }
+}
+
+/// A pointer to a `T`, stored as an offset in bytes from the start of a region rather
+/// than as an address.
+///
+/// Addresses are only meaningful in the process that mapped a region, and only until it
+/// is mapped again, while offsets stay valid wherever the region is mapped. Data
+/// structures that live inside shared or persistent memory, such as a `SharedChannel` or
+/// a `PersistentVirtualVec<u8>`, can therefore link their nodes with offsets, and be
+/// followed in other processes or after the file is mapped again.
+///
+/// An offset is resolved with `get` or `get_mut`, given the bytes of the region it points
+/// into. Resolving checks that the value is in bounds and aligned, so that it is safe even
+/// if the region holds corrupted data. Offsets are 32 bits by default, which halves the
+/// size of links in regions of up to 4GB, and can be made 64 bits with `Offset<T, u64>`.
+///
+/// Offsets are plain old data, and can themselves be stored in regions.
+///
+/// # Example
+/// ```
+/// use virtualalloc::{Offset, Pod, VirtualVec};
+///
+/// #[derive(Clone, Copy)]
+/// #[repr(C)]
+/// struct Node {
+///     value: u32,
+///     next: Offset<Node>
+/// }
+///
+/// unsafe impl Pod for Node {}
+///
+/// let mut region = VirtualVec::<u8>::new(1 << 20);
+///
+/// region.extend_from_slice(&[0; 64]);
+///
+/// let (first, second) = (Offset::<Node>::new(0).unwrap(), Offset::new(8).unwrap());
+///
+/// *first.get_mut(&mut region).unwrap() = Node { value: 1, next: second };
+/// *second.get_mut(&mut region).unwrap() = Node { value: 2, next: second };
+///
+/// // The bytes can be mapped anywhere else, and the links still resolve.
+/// let mut copy = VirtualVec::<u8>::new(1 << 20);
+///
+/// copy.extend_from_slice(&region);
+///
+/// let next = first.get(&copy).unwrap().next;
+///
+/// assert_eq!(next.get(&copy).unwrap().value, 2);
+/// ```
+#[repr(transparent)]
+pub struct Offset<T, O: OffsetWidth = u32> {
+    offset: O,
+    _marker: PhantomData<fn() -> T>
+}
+
+unsafe impl<T: 'static, O: OffsetWidth> Pod for Offset<T, O> {}
+
+impl<T, O: OffsetWidth> Offset<T, O> {
+    /// Returns an offset of `offset` bytes from the start of a region, or `None` if it
+    /// does not fit in `O`.
+    #[inline]
+    pub fn new(offset: usize) -> Option<Self> {
+        O::from_usize(offset).map(|offset| Offset { offset, _marker: PhantomData })
+    }
+
+    /// Returns the offset of the given pointer from the start of the given region, or
+    /// `None` if the pointer is not inside the region, or its offset does not fit in `O`.
+    #[inline]
+    pub fn from_ptr(region: &[u8], ptr: *const T) -> Option<Self> {
+        let offset = ptr.addr().checked_sub(region.as_ptr().addr())?;
+
+        if offset >= region.len() {
+            return None
+        }
+
+        Offset::new(offset)
+    }
+
+    /// Returns the number of bytes between the start of the region and the value.
+    ///
+    /// Returns `usize::MAX` if a 64-bit offset does not fit in a `usize`, which is never in
+    /// bounds of a region.
+    #[inline]
+    pub fn offset(self) -> usize {
+        self.offset.to_usize().unwrap_or(usize::MAX)
+    }
+
+    /// Returns the offset of the `count`-th `T` after this one, or `None` if it does not fit
+    /// in `O`.
+    #[inline]
+    pub fn nth(self, count: usize) -> Option<Self> {
+        count.checked_mul(mem::size_of::<T>())
+            .and_then(|bytes| self.offset().checked_add(bytes))
+            .and_then(Offset::new)
+    }
+
+    /// Returns the offset of `len` values of `T` at this offset in the region, or `None` if
+    /// they are out of bounds or misaligned.
+    #[inline]
+    fn check(self, region: &[u8], len: usize) -> Option<usize> {
+        let start = self.offset();
+        let end = len.checked_mul(mem::size_of::<T>()).and_then(|size| start.checked_add(size))?;
+
+        if end > region.len() ||
+            !(region.as_ptr().addr() + start).is_multiple_of(mem::align_of::<T>()) {
+            return None
+        }
+
+        Some(start)
+    }
+}
+
+impl<T: Pod, O: OffsetWidth> Offset<T, O> {
+    /// Returns a reference to the value at this offset in the given region, or `None` if
+    /// it is out of bounds or misaligned.
+    #[inline]
+    pub fn get(self, region: &[u8]) -> Option<&T> {
+        self.get_slice(region, 1).map(|values| &values[0])
+    }
+
+    /// Returns a mutable reference to the value at this offset in the given region, or
+    /// `None` if it is out of bounds or misaligned.
+    #[inline]
+    pub fn get_mut(self, region: &mut [u8]) -> Option<&mut T> {
+        self.get_slice_mut(region, 1).map(|values| &mut values[0])
+    }
+
+    /// Returns the `len` values starting at this offset in the given region, or `None` if
+    /// they are out of bounds or misaligned.
+    #[inline]
+    pub fn get_slice(self, region: &[u8], len: usize) -> Option<&[T]> {
+        let start = self.check(region, len)?;
+
+        Some(unsafe { slice::from_raw_parts(region.as_ptr().add(start).cast(), len) })
+    }
+
+    /// Returns the `len` values starting at this offset in the given region, mutably, or
+    /// `None` if they are out of bounds or misaligned.
+    #[inline]
+    pub fn get_slice_mut(self, region: &mut [u8], len: usize) -> Option<&mut [T]> {
+        let start = self.check(region, len)?;
+
+        Some(unsafe { slice::from_raw_parts_mut(region.as_mut_ptr().add(start).cast(), len) })
+    }
+}
+
+impl<T, O: OffsetWidth> Clone for Offset<T, O> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T, O: OffsetWidth> Copy for Offset<T, O> {}
+
+impl<T, O: OffsetWidth> PartialEq for Offset<T, O> {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        self.offset == other.offset
+    }
+}
+
+impl<T, O: OffsetWidth> Eq for Offset<T, O> {}
+
+impl<T, O: OffsetWidth> Hash for Offset<T, O> {
+    #[inline]
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.offset.hash(state)
+    }
+}
+
+impl<T, O: OffsetWidth> fmt::Debug for Offset<T, O> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Offset").field(&self.offset).finish()
+    }
+}
+
+/// A vector of fixed capacity whose elements live in a region, and whose header only
+/// holds offsets, so that the whole vector can be stored in the region and followed
+/// wherever it is mapped.
+///
+/// The header is plain old data: it can live outside of the region, or be stored in it
+/// and read with an `Offset<OffsetVec<T>>`. Operations take the bytes of the region the
+/// elements live in, and treat elements out of bounds of the region as missing, like
+/// `Offset` does.
+///
+/// # Example
+/// ```
+/// use virtualalloc::{Offset, OffsetVec, VirtualVec};
+///
+/// let mut region = VirtualVec::<u8>::new(1 << 20);
+///
+/// region.extend_from_slice(&[0; 4096]);
+///
+/// // The header at the start of the region, and room for 100 elements after it.
+/// let header = Offset::<OffsetVec<u32>>::new(0).unwrap();
+/// let mut vec = OffsetVec::new(Offset::new(64).unwrap(), 100);
+///
+/// vec.push(&mut region, 1).unwrap();
+/// vec.push(&mut region, 2).unwrap();
+///
+/// *header.get_mut(&mut region).unwrap() = vec;
+///
+/// let mut copy = VirtualVec::<u8>::new(1 << 20);
+///
+/// copy.extend_from_slice(&region);
+///
+/// assert_eq!(header.get(&copy).unwrap().as_slice(&copy), Some(&[1, 2][..]));
+/// ```
+#[repr(C)]
+pub struct OffsetVec<T> {
+    data: Offset<T, u64>,
+    len: u64,
+    capacity: u64
+}
+
+unsafe impl<T: Pod> Pod for OffsetVec<T> {}
+
+impl<T: Pod> OffsetVec<T> {
+    /// Returns an empty vector of up to `capacity` elements, stored at the given offset.
+    #[inline]
+    pub fn new(data: Offset<T, u64>, capacity: usize) -> Self {
+        OffsetVec { data, len: 0, capacity: capacity as u64 }
+    }
+
+    /// Returns the number of bytes needed to store `capacity` elements.
+    #[inline]
+    pub fn storage_size(capacity: usize) -> usize {
+        capacity.saturating_mul(mem::size_of::<T>())
+    }
+
+    /// Returns the offset of the elements of the vector.
+    #[inline]
+    pub fn data(&self) -> Offset<T, u64> {
+        self.data
+    }
+
+    /// Returns the number of elements of the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        usize::try_from(self.len).unwrap_or(usize::MAX)
+    }
+
+    /// Returns whether the vector has no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the most elements the vector can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        usize::try_from(self.capacity).unwrap_or(usize::MAX)
+    }
+
+    /// Returns the elements of the vector, or `None` if they are not inside the region.
+    #[inline]
+    pub fn as_slice<'a>(&self, region: &'a [u8]) -> Option<&'a [T]> {
+        self.data.get_slice(region, self.len())
+    }
+
+    /// Returns the elements of the vector, mutably, or `None` if they are not inside the
+    /// region.
+    #[inline]
+    pub fn as_mut_slice<'a>(&self, region: &'a mut [u8]) -> Option<&'a mut [T]> {
+        self.data.get_slice_mut(region, self.len())
+    }
+
+    /// Appends an element to the back of the vector.
+    ///
+    /// Gives the element back if the vector is full, or if its storage is not inside the
+    /// region.
+    pub fn push(&mut self, region: &mut [u8], value: T) -> Result<(), T> {
+        if self.len >= self.capacity {
+            return Err(value)
+        }
+
+        match self.data.get_slice_mut(region, self.len() + 1) {
+            Some(values) => values[values.len() - 1] = value,
+            None => return Err(value)
+        }
+
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Removes the last element of the vector and returns it, or `None` if it is empty or
+    /// its storage is not inside the region.
+    pub fn pop(&mut self, region: &[u8]) -> Option<T> {
+        let last = self.as_slice(region)?.last().copied()?;
+
+        self.len -= 1;
+
+        Some(last)
+    }
+
+    /// Removes all elements from the vector.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+}
+
+impl<T> Clone for OffsetVec<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetVec<T> {}
+
+/// The link of an occupied slot of an `OffsetSlab`.
+const OCCUPIED: u64 = u64::MAX;
+
+/// A slab of fixed capacity whose slots live in a region, and whose header only holds
+/// offsets, so that the whole slab can be stored in the region and followed wherever it
+/// is mapped.
+///
+/// Values are inserted in free slots, and identified by the index of their slot, which
+/// stays the same until they are removed. The free slots are linked into a list by index,
+/// stored in an array of links next to the values, so that inserting and removing never
+/// scan the slab.
+///
+/// Like an `OffsetVec`, the header is plain old data, and operations take the bytes of the
+/// region the slots live in.
+///
+/// # Example
+/// ```
+/// use virtualalloc::{Offset, OffsetSlab, VirtualVec};
+///
+/// let mut region = VirtualVec::<u8>::new(1 << 20);
+///
+/// region.extend_from_slice(&[0; 4096]);
+///
+/// // 100 values, followed by their 100 links.
+/// let (values, links) = (Offset::new(0).unwrap(), Offset::new(800).unwrap());
+/// let mut slab = OffsetSlab::<u64>::new(values, links, 100);
+///
+/// let a = slab.insert(&mut region, 10).unwrap();
+/// let b = slab.insert(&mut region, 20).unwrap();
+///
+/// assert_eq!(slab.remove(&mut region, a), Some(10));
+/// assert_eq!(slab.get(&region, b), Some(&20));
+/// assert_eq!(slab.insert(&mut region, 30), Ok(a));
+/// ```
+#[repr(C)]
+pub struct OffsetSlab<T> {
+    values: Offset<T, u64>,
+    links: Offset<u64, u64>,
+    capacity: u64,
+    len: u64,
+    /// The first free slot that was used before, or `capacity` if there is none.
+    free: u64,
+    /// The number of slots that were ever used, after which all slots are free.
+    used: u64
+}
+
+unsafe impl<T: Pod> Pod for OffsetSlab<T> {}
+
+impl<T: Pod> OffsetSlab<T> {
+    /// Returns an empty slab of up to `capacity` values, stored at the given offset, and
+    /// whose links are stored at the other given offset.
+    #[inline]
+    pub fn new(values: Offset<T, u64>, links: Offset<u64, u64>, capacity: usize) -> Self {
+        let capacity = capacity as u64;
+
+        OffsetSlab { values, links, capacity, len: 0, free: capacity, used: 0 }
+    }
+
+    /// Returns the number of bytes needed to store the values and the links of `capacity`
+    /// slots, respectively.
+    #[inline]
+    pub fn storage_size(capacity: usize) -> (usize, usize) {
+        (capacity.saturating_mul(mem::size_of::<T>()), capacity.saturating_mul(8))
+    }
+
+    /// Returns the number of values in the slab.
+    #[inline]
+    pub fn len(&self) -> usize {
+        usize::try_from(self.len).unwrap_or(usize::MAX)
+    }
+
+    /// Returns whether the slab holds no values.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the most values the slab can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        usize::try_from(self.capacity).unwrap_or(usize::MAX)
+    }
+
+    /// Returns the link of the given slot, or `None` if it is not inside the region.
+    #[inline]
+    fn link(&self, region: &[u8], slot: u64) -> Option<u64> {
+        self.links.nth(usize::try_from(slot).ok()?)?.get(region).copied()
+    }
+
+    /// Inserts a value in a free slot, and returns the index of the slot.
+    ///
+    /// Gives the value back if the slab is full, or if its storage is not inside the region.
+    pub fn insert(&mut self, region: &mut [u8], value: T) -> Result<usize, T> {
+        let (slot, next) = match self.free < self.capacity {
+            true => match self.link(region, self.free) {
+                Some(next) if next != OCCUPIED => (self.free, next),
+                _ => return Err(value)
+            },
+            false if self.used < self.capacity => (self.used, self.capacity),
+            false => return Err(value)
+        };
+
+        let index = slot as usize;
+
+        match (self.values.nth(index), self.links.nth(index)) {
+            (Some(at), Some(link)) if at.get(region).is_some() && link.get(region).is_some() => {
+                *at.get_mut(region).unwrap() = value;
+                *link.get_mut(region).unwrap() = OCCUPIED;
+            },
+            _ => return Err(value)
+        }
+
+        if slot == self.used {
+            self.used += 1;
+        } else {
+            self.free = next;
+        }
+
+        self.len += 1;
+
+        Ok(index)
+    }
+
+    /// Removes the value in the given slot and returns it, or `None` if the slot is free.
+    pub fn remove(&mut self, region: &mut [u8], slot: usize) -> Option<T> {
+        let value = *self.get(region, slot)?;
+
+        *self.links.nth(slot)?.get_mut(region)? = self.free;
+        self.free = slot as u64;
+        self.len -= 1;
+
+        Some(value)
+    }
+
+    /// Returns the value in the given slot, or `None` if the slot is free.
+    pub fn get<'a>(&self, region: &'a [u8], slot: usize) -> Option<&'a T> {
+        if slot as u64 >= self.used || self.link(region, slot as u64)? != OCCUPIED {
+            return None
+        }
+
+        self.values.nth(slot)?.get(region)
+    }
+
+    /// Returns the value in the given slot, mutably, or `None` if the slot is free.
+    pub fn get_mut<'a>(&self, region: &'a mut [u8], slot: usize) -> Option<&'a mut T> {
+        self.get(region, slot)?;
+        self.values.nth(slot)?.get_mut(region)
+    }
+
+    /// Returns whether the given slot holds a value.
+    #[inline]
+    pub fn contains(&self, region: &[u8], slot: usize) -> bool {
+        self.get(region, slot).is_some()
+    }
+}
+
+impl<T> Clone for OffsetSlab<T> {
+    #[inline]
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for OffsetSlab<T> {}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use VirtualVec;
+
+    #[test]
+    fn resolves_in_any_copy_of_the_region() {
+        let mut region = VirtualVec::<u8>::new(1 << 16);
+
+        region.extend_from_slice(&[0; 64]);
+
+        let values = Offset::<u64>::new(16).unwrap();
+
+        values.get_slice_mut(&mut region, 2).unwrap().copy_from_slice(&[7, 9]);
+
+        let mut copy = VirtualVec::<u8>::new(1 << 16);
+
+        copy.extend_from_slice(&region);
+
+        assert_eq!(values.get(&copy), Some(&7));
+        assert_eq!(values.nth(1).unwrap().get(&copy), Some(&9));
+        assert_eq!(Offset::from_ptr(&copy, copy[16..].as_ptr().cast::<u64>()), Some(values));
+
+        // Out of bounds and misaligned offsets do not resolve.
+        assert!(values.get_slice(&copy, 7).is_none());
+        assert!(Offset::<u64>::new(60).unwrap().get(&copy).is_none());
+        assert!(Offset::<u64>::new(4).unwrap().get(&copy).is_none());
+        assert!(Offset::<u64>::from_ptr(&copy, region.as_ptr().cast()).is_none());
+
+        assert!(Offset::<u8>::new(u32::MAX as usize).is_some());
+        assert_eq!(Offset::<u8>::new(usize::MAX).is_none(), usize::BITS > 32);
+        assert_eq!(Offset::<u8, u64>::new(usize::MAX).unwrap().offset(), usize::MAX);
+    }
+
+    #[test]
+    fn collections_survive_relocation() {
+        let mut region = VirtualVec::<u8>::new(1 << 16);
+
+        region.extend_from_slice(&[0; 4096]);
+
+        let mut vec = OffsetVec::<u32>::new(Offset::new(64).unwrap(), 4);
+        let mut slab = OffsetSlab::<u64>::new(Offset::new(128).unwrap(),
+                                              Offset::new(160).unwrap(), 4);
+
+        assert_eq!(OffsetSlab::<u64>::storage_size(4), (32, 32));
+
+        for i in 0..5 {
+            assert_eq!(vec.push(&mut region, i).is_ok(), i < 4);
+        }
+
+        assert_eq!(vec.pop(&region), Some(3));
+
+        let keys = (0..4).map(|i| slab.insert(&mut region, i * 10).unwrap()).collect::<Vec<_>>();
+
+        assert_eq!(keys, [0, 1, 2, 3]);
+        assert_eq!(slab.insert(&mut region, 40), Err(40));
+        assert_eq!(slab.remove(&mut region, 1), Some(10));
+        assert_eq!(slab.remove(&mut region, 1), None);
+        assert_eq!(slab.remove(&mut region, 2), Some(20));
+        assert_eq!(slab.len(), 2);
+
+        // The headers hold no addresses, so the region can be copied as a whole.
+        let mut copy = VirtualVec::<u8>::new(1 << 16);
+
+        copy.extend_from_slice(&region);
+
+        assert_eq!(vec.as_slice(&copy), Some(&[0, 1, 2][..]));
+        assert_eq!(slab.get(&copy, 3), Some(&30));
+        assert!(!slab.contains(&copy, 1));
+
+        // Freed slots are reused, the last freed first.
+        assert_eq!(slab.insert(&mut copy, 50), Ok(2));
+        assert_eq!(slab.insert(&mut copy, 60), Ok(1));
+        *slab.get_mut(&mut copy, 1).unwrap() += 1;
+        assert_eq!(slab.get(&copy, 1), Some(&61));
+
+        // Storage outside of the region is treated as missing.
+        let mut outside = OffsetVec::<u32>::new(Offset::new(4096).unwrap(), 4);
+
+        assert_eq!(outside.push(&mut copy, 1), Err(1));
+        assert!(vec.as_slice(&copy[..64]).is_none());
+    }
+}
@@ -0,0 +1,256 @@
+//! Diagnostics gathered when memory cannot be reserved or committed.
+//!
+//! `VirtualMemError` is a small `Copy` value, so the context of a failure cannot be stored
+//! in it. Instead, once enabled with `VirtualAlloc::set_oom_diagnostics`, the state of
+//! the system is captured as soon as a reservation or commit fails, and kept for the
+//! thread that saw the failure until `VirtualMemError::diagnostics` retrieves it.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use super::VirtualMemError;
+#[cfg(not(any(windows, target_arch = "wasm32")))]
+use super::address_space_limit;
+
+
+/// Whether diagnostics are captured when memory cannot be reserved or committed.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The report of the last failure seen by the current thread.
+    static LAST_REPORT: RefCell<Option<OomReport>> = const { RefCell::new(None) };
+}
+
+/// The state of the system when memory could not be reserved or committed, returned by
+/// `VirtualMemError::diagnostics`.
+///
+/// Every measure is optional, since none of them can be obtained on all platforms, or
+/// under every sandbox.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OomReport {
+    /// The error that was returned.
+    pub error: VirtualMemError,
+    /// The operation that failed, either `"reserve"` or `"commit"`.
+    pub op: &'static str,
+    /// The number of bytes that were requested.
+    pub requested: usize,
+    /// The number of bytes of physical memory available to new allocations.
+    pub available_memory: Option<u64>,
+    /// The number of bytes committed by the whole system.
+    pub commit_charge: Option<u64>,
+    /// The number of bytes the system can commit before commits fail.
+    pub commit_limit: Option<u64>,
+    /// The number of bytes of address space the process can map (`ulimit -v`), if limited.
+    pub address_space_limit: Option<u64>,
+    /// The overcommit policy of Linux (`vm.overcommit_memory`): 0 for heuristic, 1 for
+    /// always, and 2 for never.
+    pub overcommit_mode: Option<u8>,
+    /// The size of the largest range of unmapped addresses of the process.
+    pub largest_free_range: Option<usize>
+}
+
+impl OomReport {
+    /// Captures the state of the system after `op` failed to obtain `requested` bytes.
+    fn capture(error: VirtualMemError, op: &'static str, requested: usize) -> Self {
+        let mut report = OomReport {
+            error,
+            op,
+            requested,
+            available_memory: None,
+            commit_charge: None,
+            commit_limit: None,
+            address_space_limit: None,
+            overcommit_mode: None,
+            largest_free_range: None
+        };
+
+        report.capture_system();
+        report
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn capture_system(&mut self) {
+        use std::fs;
+
+        if let Ok(meminfo) = fs::read_to_string("/proc/meminfo") {
+            // Lines look like "MemAvailable:    1234 kB".
+            for line in meminfo.lines() {
+                let mut fields = line.split_whitespace();
+                let field = match fields.next() {
+                    Some("MemAvailable:") => &mut self.available_memory,
+                    Some("Committed_AS:") => &mut self.commit_charge,
+                    Some("CommitLimit:") => &mut self.commit_limit,
+                    _ => continue
+                };
+
+                *field = fields.next().and_then(|kb| kb.parse::<u64>().ok()).map(|kb| kb * 1024);
+            }
+        }
+
+        self.overcommit_mode = fs::read_to_string("/proc/sys/vm/overcommit_memory")
+            .ok()
+            .and_then(|mode| mode.trim().parse().ok());
+        self.address_space_limit = address_space_limit();
+
+        // Lines look like "7f0000000000-7f0000001000 rw-p 00000000 00:00 0", and are
+        // sorted by address. The vsyscall page lies beyond user space, and is skipped so
+        // that the kernel half of the address space is not counted as free.
+        if let Ok(maps) = fs::read_to_string("/proc/self/maps") {
+            let mut largest = 0;
+            let mut previous_end = None;
+
+            for line in maps.lines().filter(|line| !line.ends_with("[vsyscall]")) {
+                let range = line.split_whitespace().next().and_then(|range| range.split_once('-'));
+                let (lo, hi) = match range {
+                    Some((lo, hi)) => (lo, hi),
+                    None => continue
+                };
+                let (lo, hi) = match (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16)) {
+                    (Ok(lo), Ok(hi)) => (lo, hi),
+                    _ => continue
+                };
+
+                if let Some(end) = previous_end {
+                    largest = largest.max(lo.saturating_sub(end));
+                }
+
+                previous_end = Some(hi);
+            }
+
+            self.largest_free_range = previous_end.map(|_| largest);
+        }
+    }
+
+    #[cfg(windows)]
+    fn capture_system(&mut self) {
+        use std::mem;
+        use windows_sys::Win32::System::Memory::{VirtualQuery, MEMORY_BASIC_INFORMATION, MEM_FREE};
+        use windows_sys::Win32::System::SystemInformation::*;
+
+        unsafe {
+            let mut status: MEMORYSTATUSEX = mem::zeroed();
+
+            status.dwLength = mem::size_of::<MEMORYSTATUSEX>() as _;
+
+            if GlobalMemoryStatusEx(&mut status) != 0 {
+                self.available_memory = Some(status.ullAvailPhys);
+                self.commit_charge = Some(status.ullTotalPageFile - status.ullAvailPageFile);
+                self.commit_limit = Some(status.ullTotalPageFile);
+            }
+
+            let mut info: SYSTEM_INFO = mem::zeroed();
+
+            GetSystemInfo(&mut info);
+
+            // Walk the regions of the address space, looking for the largest free one.
+            let mut address = info.lpMinimumApplicationAddress;
+            let mut largest = 0;
+            let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+
+            while address < info.lpMaximumApplicationAddress {
+                let mut region: MEMORY_BASIC_INFORMATION = mem::zeroed();
+
+                if VirtualQuery(address, &mut region, size) == 0 || region.RegionSize == 0 {
+                    break
+                }
+
+                if region.State == MEM_FREE {
+                    largest = largest.max(region.RegionSize);
+                }
+
+                address = region.BaseAddress.byte_add(region.RegionSize);
+            }
+
+            self.largest_free_range = Some(largest);
+        }
+    }
+
+    #[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+    fn capture_system(&mut self) {
+        #[cfg(not(target_arch = "wasm32"))]
+        {
+            self.address_space_limit = address_space_limit();
+        }
+    }
+}
+
+impl fmt::Display for OomReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fn field(f: &mut fmt::Formatter, name: &str, value: Option<impl fmt::Display>)
+            -> fmt::Result {
+            match value {
+                Some(value) => write!(f, "\n  {}: {}", name, value),
+                None => write!(f, "\n  {}: unknown", name)
+            }
+        }
+
+        write!(f, "{} of {} bytes failed: {}", self.op, self.requested, self.error)?;
+
+        field(f, "available memory", self.available_memory)?;
+        field(f, "commit charge", self.commit_charge)?;
+        field(f, "commit limit", self.commit_limit)?;
+        field(f, "address space limit", self.address_space_limit)?;
+        field(f, "overcommit mode", self.overcommit_mode)?;
+        field(f, "largest free range", self.largest_free_range)
+    }
+}
+
+/// Enables or disables the capture of diagnostics.
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Captures diagnostics for the given result of `op` on `requested` bytes if it is a
+/// failure to reserve or commit memory, and diagnostics are enabled.
+#[inline]
+pub(crate) fn check<R>(op: &'static str, requested: usize, result: Result<R, VirtualMemError>)
+    -> Result<R, VirtualMemError> {
+    if let Err(error) = result {
+        match error {
+            VirtualMemError::ReservationFailed { .. } | VirtualMemError::CommitFailed { .. }
+                if ENABLED.load(Ordering::Relaxed) => {
+                let report = OomReport::capture(error, op, requested);
+
+                LAST_REPORT.with(|last| *last.borrow_mut() = Some(report));
+            },
+            _ => ()
+        }
+    }
+
+    result
+}
+
+/// Returns the report of the last failure of the current thread, if it failed with the
+/// given error.
+pub(crate) fn last_report(error: &VirtualMemError) -> Option<OomReport> {
+    LAST_REPORT.with(|last| last.borrow().as_ref().filter(|report| report.error == *error).cloned())
+}
+
+#[cfg(all(test, any(windows, target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    use {VirtualAlloc, VirtualBacking};
+
+    #[test]
+    fn reports_system_state_on_failure() {
+        let size = isize::MAX as usize / 2 + 1;
+        let alloc = VirtualAlloc::new(size);
+
+        VirtualAlloc::set_oom_diagnostics(true);
+
+        let error = alloc.reserve(size).unwrap_err();
+
+        VirtualAlloc::set_oom_diagnostics(false);
+
+        let report = error.diagnostics().unwrap();
+
+        assert_eq!(report.op, "reserve");
+        assert_eq!(report.requested, size);
+        assert!(report.available_memory.is_some());
+        assert!(report.largest_free_range.unwrap() < size);
+        assert!(report.to_string().starts_with("reserve of"));
+        assert_eq!(VirtualMemError::Unsupported.diagnostics(), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! A builder for allocators, which gathers their configuration in one place.
+
+use super::{default_max_size, GrowthPolicy, GuardPages, VirtualAlloc, VirtualMemError};
+
+
+/// Options to configure a `VirtualAlloc`, returned by `VirtualAlloc::options`.
+///
+/// Each option defaults to the behavior of `VirtualAlloc::new(default_max_size())`, and
+/// is set by a method that consumes and returns the options, like the builder methods of
+/// `VirtualVec`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct VirtualAllocOptions {
+    max: usize,
+    read: bool,
+    write: bool,
+    exec: bool,
+    guard: GuardPages,
+    quantum: Option<usize>,
+    demand_paged: bool,
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    commit_on_fault: bool,
+    huge_pages: Option<bool>,
+    populate: bool,
+    numa_node: Option<u32>,
+    growth: GrowthPolicy,
+    label: Option<&'static str>
+}
+
+impl Default for VirtualAllocOptions {
+    #[inline]
+    fn default() -> Self {
+        VirtualAllocOptions::new()
+    }
+}
+
+impl VirtualAllocOptions {
+    /// Returns the options of an allocator of `default_max_size()` bytes of read-write
+    /// memory.
+    pub fn new() -> Self {
+        VirtualAllocOptions {
+            max: default_max_size(),
+            read: true,
+            write: true,
+            exec: false,
+            guard: GuardPages::None,
+            quantum: None,
+            demand_paged: false,
+            #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+            commit_on_fault: false,
+            huge_pages: None,
+            populate: false,
+            numa_node: None,
+            growth: GrowthPolicy::default(),
+            label: None
+        }
+    }
+
+    /// Sets the maximum size of the allocations, in bytes.
+    #[inline]
+    pub fn max(mut self, max: usize) -> Self {
+        self.max = max;
+        self
+    }
+
+    /// Sets the protection of the allocated memory, which is read-write by default.
+    ///
+    /// Memory that is both writable and executable cannot be requested in strict W^X
+    /// mode, in which case `build` panics and `try_build` fails.
+    #[inline]
+    pub fn protection(mut self, read: bool, write: bool, exec: bool) -> Self {
+        self.read = read;
+        self.write = write;
+        self.exec = exec;
+        self
+    }
+
+    /// Sets where guard pages are placed around allocations.
+    ///
+    /// See `VirtualAlloc::with_guard_pages` for more information.
+    #[inline]
+    pub fn guard_pages(mut self, guard: GuardPages) -> Self {
+        self.guard = guard;
+        self
+    }
+
+    /// Makes allocations reserve address space by multiples of `quantum` bytes.
+    ///
+    /// See `VirtualAlloc::with_reservation_quantum` for more information.
+    #[inline]
+    pub fn reservation_quantum(mut self, quantum: usize) -> Self {
+        self.quantum = Some(quantum);
+        self
+    }
+
+    /// Specifies whether committing memory is left to the kernel.
+    ///
+    /// See `VirtualAlloc::demand_paged` for more information.
+    #[inline]
+    pub fn demand_paged(mut self, enabled: bool) -> Self {
+        self.demand_paged = enabled;
+        self
+    }
+
+    /// Specifies whether containers commit memory when it is first accessed.
+    ///
+    /// See `VirtualAlloc::commit_on_fault` for more information.
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    #[inline]
+    pub fn commit_on_fault(mut self, enabled: bool) -> Self {
+        self.commit_on_fault = enabled;
+        self
+    }
+
+    /// Specifies whether committed memory is backed by transparent huge pages whatever
+    /// the size of the commit, overriding `VirtualAlloc::set_huge_page_threshold` for this
+    /// allocator.
+    ///
+    /// Like the threshold, this is a hint that only has an effect on Linux.
+    #[inline]
+    pub fn huge_pages(mut self, enabled: bool) -> Self {
+        self.huge_pages = Some(enabled);
+        self
+    }
+
+    /// Specifies whether committed memory is faulted in right away, so that first
+    /// touching it does not stall on page faults, at the cost of making commits slower.
+    ///
+    /// On Linux, `madvise(MADV_POPULATE_WRITE)` is used. Everywhere else, this falls back
+    /// to `VirtualAlloc::prefetch`, which does not wait for the pages to be faulted in.
+    #[inline]
+    pub fn populate(mut self, enabled: bool) -> Self {
+        self.populate = enabled;
+        self
+    }
+
+    /// Makes memory committed by the allocator preferably come from the given NUMA node,
+    /// and from other nodes once it runs out of memory.
+    ///
+    /// Reservations fail with `ReservationFailed` if the node does not exist.
+    ///
+    /// # Note
+    /// NUMA nodes are only supported on Linux, where `mbind(MPOL_PREFERRED)` is used. This
+    /// option is ignored everywhere else.
+    #[inline]
+    pub fn numa_node(mut self, node: u32) -> Self {
+        self.numa_node = Some(node);
+        self
+    }
+
+    /// Specifies how memory is committed when allocations grow in place.
+    ///
+    /// This applies to allocations made through the `Allocator` implementation, which
+    /// requires the `nightly` feature; containers such as `VirtualVec` have a growth
+    /// policy of their own.
+    #[inline]
+    pub fn growth_policy(mut self, policy: GrowthPolicy) -> Self {
+        self.growth = policy;
+        self
+    }
+
+    /// Gives a label to the vectors built upon the allocator, under which they are listed
+    /// by `VirtualAlloc::live_regions`, unless they are given one with `VirtualVec::label`.
+    #[inline]
+    pub fn label(mut self, label: &'static str) -> Self {
+        self.label = Some(label);
+        self
+    }
+
+    /// Returns an allocator with the given options.
+    ///
+    /// # Panics
+    /// Panics if memory that is both writable and executable is requested in strict W^X
+    /// mode.
+    pub fn build(self) -> VirtualAlloc {
+        self.try_build().unwrap_or_else(|err| panic!("Could not create allocator: {}.", err))
+    }
+
+    /// Returns an allocator with the given options, or `WxViolation` if memory that is
+    /// both writable and executable is requested in strict W^X mode.
+    pub fn try_build(self) -> Result<VirtualAlloc, VirtualMemError> {
+        let mut alloc = VirtualAlloc::try_with_protection(self.max, self.read, self.write,
+                                                          self.exec)?
+            .with_guard_pages(self.guard);
+
+        if let Some(quantum) = self.quantum {
+            alloc = alloc.with_reservation_quantum(quantum);
+        }
+        if self.demand_paged {
+            alloc = alloc.demand_paged();
+        }
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if self.commit_on_fault {
+            alloc = alloc.commit_on_fault();
+        }
+
+        alloc.huge_pages = self.huge_pages;
+        alloc.populate = self.populate;
+        alloc.numa_node = self.numa_node;
+        alloc.growth = self.growth;
+        alloc.label = self.label;
+        Ok(alloc)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    use VirtualVec;
+
+    #[test]
+    fn builds_the_configured_allocator() {
+        let alloc = VirtualAlloc::options()
+            .max(1 << 20)
+            .protection(true, false, false)
+            .guard_pages(GuardPages::End)
+            .build();
+
+        assert_eq!(alloc, VirtualAlloc::with_protection(1 << 20, true, false, false)
+                              .with_guard_pages(GuardPages::End));
+        assert_eq!(VirtualAlloc::options().build(), VirtualAlloc::default());
+        assert_ne!(VirtualAlloc::options().growth_policy(GrowthPolicy::Exact).build(),
+                   VirtualAlloc::default());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn populates_and_labels_vectors() {
+        let alloc = VirtualAlloc::options()
+            .max(1 << 24)
+            .populate(true)
+            .huge_pages(false)
+            .label("options-test")
+            .build();
+
+        VirtualAlloc::set_region_registry(true);
+
+        let mut vec = VirtualVec::<u8>::with_backing(1 << 20, alloc);
+
+        VirtualAlloc::set_region_registry(false);
+        vec.push(1);
+
+        let regions = VirtualAlloc::live_regions();
+
+        assert!(regions.iter().any(|region| region.label == Some("options-test")));
+
+        #[cfg(any(target_os = "linux", target_os = "android"))]
+        assert_eq!(vec.stats().resident, Some(vec.capacity()));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn binds_reservations_to_a_numa_node() {
+        use VirtualBacking;
+
+        let alloc = VirtualAlloc::options().numa_node(0).build();
+        let ptr = alloc.reserve(1 << 20).unwrap();
+
+        unsafe { alloc.release(ptr, 1 << 20) };
+
+        assert!(VirtualAlloc::options().numa_node(u32::MAX).build().reserve(1 << 20).is_err());
+    }
+}
@@ -0,0 +1,169 @@
+//! Textual maps of the state of each page of a reservation, for debugging.
+
+use std::ptr::NonNull;
+
+use super::{VirtualAlloc, VirtualMemError};
+
+
+/// The number of pages described on each line of a page map.
+const PAGES_PER_LINE: usize = 64;
+
+/// The state of a page of a reservation, as reported by the operating system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(not(any(windows, target_os = "linux", target_os = "android")), allow(dead_code))]
+enum PageState {
+    /// The page is reserved, but not accessible.
+    Reserved,
+    /// The page is committed, but not accessible, which can only be told on Windows.
+    #[cfg_attr(not(windows), allow(dead_code))]
+    NoAccess,
+    ReadOnly,
+    ReadWrite,
+    Executable
+}
+
+/// Returns the state of each page in the given range, whose start must be page-aligned.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn page_states(ptr: NonNull<u8>, len: usize) -> Result<Vec<PageState>, VirtualMemError> {
+    use std::fs;
+
+    let page = VirtualAlloc::page_size();
+    let start = ptr.as_ptr().addr();
+    let end = start + len;
+    let mut states = vec![PageState::Reserved; len.div_ceil(page)];
+
+    let maps = fs::read_to_string("/proc/self/maps")
+        .map_err(|_| VirtualMemError::Unsupported)?;
+
+    // Lines look like "7f0000000000-7f0000001000 rw-p 00000000 00:00 0".
+    for line in maps.lines() {
+        let mut fields = line.split_whitespace();
+        let (range, perms) = match (fields.next(), fields.next()) {
+            (Some(range), Some(perms)) => (range, perms.as_bytes()),
+            _ => continue
+        };
+        let (lo, hi) = match range.split_once('-') {
+            Some((lo, hi)) => (usize::from_str_radix(lo, 16), usize::from_str_radix(hi, 16)),
+            None => continue
+        };
+        let (lo, hi) = match (lo, hi) {
+            (Ok(lo), Ok(hi)) if hi > start && lo < end => (lo.max(start), hi.min(end)),
+            _ => continue
+        };
+
+        // Reserved memory is mapped without access, and is committed by changing its
+        // protection, so the two cannot be told apart here.
+        let state = match (perms.first(), perms.get(1), perms.get(2)) {
+            (_, _, Some(b'x')) => PageState::Executable,
+            (_, Some(b'w'), _) => PageState::ReadWrite,
+            (Some(b'r'), _, _) => PageState::ReadOnly,
+            _ => PageState::Reserved
+        };
+
+        for state_of_page in &mut states[(lo - start) / page..(hi - start).div_ceil(page)] {
+            *state_of_page = state;
+        }
+    }
+
+    Ok(states)
+}
+
+/// Returns the state of each page in the given range, whose start must be page-aligned.
+#[cfg(windows)]
+fn page_states(ptr: NonNull<u8>, len: usize) -> Result<Vec<PageState>, VirtualMemError> {
+    use std::mem;
+    use windows_sys::Win32::System::Memory::*;
+
+    let page = VirtualAlloc::page_size();
+    let mut states = Vec::with_capacity(len.div_ceil(page));
+
+    while states.len() * page < len {
+        let offset = states.len() * page;
+        let mut info: MEMORY_BASIC_INFORMATION = unsafe { mem::zeroed() };
+        let size = mem::size_of::<MEMORY_BASIC_INFORMATION>();
+
+        if unsafe { VirtualQuery(ptr.as_ptr().add(offset) as _, &mut info, size) } == 0 {
+            return Err(VirtualMemError::Unsupported)
+        }
+
+        let prot = info.Protect & !(PAGE_GUARD | PAGE_NOCACHE | PAGE_WRITECOMBINE);
+        let state = if info.State != MEM_COMMIT {
+            PageState::Reserved
+        } else if info.Protect & PAGE_GUARD != 0 || prot == PAGE_NOACCESS {
+            PageState::NoAccess
+        } else if prot & (PAGE_EXECUTE | PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE) != 0 {
+            PageState::Executable
+        } else if prot == PAGE_READONLY {
+            PageState::ReadOnly
+        } else {
+            PageState::ReadWrite
+        };
+
+        // The region starts at the page containing the queried address.
+        let region_end = info.BaseAddress.addr() - ptr.as_ptr().addr() + info.RegionSize;
+        let pages = (region_end.min(len) - offset).div_ceil(page).max(1);
+
+        states.extend((0..pages).map(|_| state));
+    }
+
+    states.truncate(len.div_ceil(page));
+
+    Ok(states)
+}
+
+/// Returns the state of each page in the given range, whose start must be page-aligned.
+#[cfg(not(any(windows, target_os = "linux", target_os = "android")))]
+fn page_states(_: NonNull<u8>, _: usize) -> Result<Vec<PageState>, VirtualMemError> {
+    Err(VirtualMemError::Unsupported)
+}
+
+/// Returns a map with a character per page of `len` bytes starting at `ptr`, of which the
+/// first `committed` bytes are committed.
+pub(crate) fn dump(ptr: NonNull<u8>, len: usize, committed: usize)
+    -> Result<String, VirtualMemError> {
+    let page = VirtualAlloc::page_size();
+    let committed_pages = committed.div_ceil(page);
+    let states = page_states(ptr, len)?;
+    let mut map = String::with_capacity(states.len() + states.len() / PAGES_PER_LINE + 1);
+
+    for (i, state) in states.into_iter().enumerate() {
+        if i > 0 && i % PAGES_PER_LINE == 0 {
+            map.push('\n');
+        }
+
+        map.push(match state {
+            PageState::Reserved if i < committed_pages => '#',
+            PageState::Reserved => '.',
+            PageState::NoAccess => '#',
+            PageState::ReadOnly => 'r',
+            PageState::ReadWrite => 'w',
+            PageState::Executable => 'x'
+        });
+    }
+
+    Ok(map)
+}
+
+#[cfg(all(test, any(windows, target_os = "linux", target_os = "android")))]
+mod tests {
+    use super::*;
+
+    use {GrowthPolicy, VirtualVec};
+
+    #[test]
+    fn describes_each_page() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::with_capacity(page * 3, page * 70)
+            .growth_policy(GrowthPolicy::Exact);
+
+        unsafe {
+            let second = NonNull::new_unchecked(vec.as_mut_ptr().add(page));
+
+            VirtualAlloc::set_protection(second, page, true, false, false).unwrap();
+        }
+
+        let map = vec.dump_page_map().unwrap();
+
+        assert_eq!(map, format!("wrw{}\n{}", ".".repeat(61), ".".repeat(6)));
+    }
+}
@@ -0,0 +1,285 @@
+//! Vectors stored in memory-mapped files.
+
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::slice;
+
+use super::Pod;
+use super::file_mapping::FileMapping;
+use super::header::{self, FileHeader, SIZE as HEADER_SIZE};
+
+
+/// The version of the format of the file of a `PersistentVirtualVec`.
+const VERSION: u32 = 2;
+
+/// A vector of plain old data stored in a file, which is mapped in memory.
+///
+/// The file starts with a small header holding the length of the vector, the layout of
+/// its elements, the byte order of the machine and the version of the format, followed by
+/// the elements themselves. Opening a file whose header does not match fails, instead of
+/// reinterpreting its contents as elements of another type. Pushing
+/// elements writes them to the mapping, and grows the file as needed up to the maximum
+/// length given when the vector is opened. Changes are written back to the file by the
+/// operating system at any time, and are made durable with `flush`.
+///
+/// On Unix, the maximum length is reserved upfront, so that elements never move, like in
+/// a `VirtualVec`. On Windows, the file is mapped again when it grows, and elements may
+/// move.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::PersistentVirtualVec;
+///
+/// let mut samples = PersistentVirtualVec::<f64>::open("samples.bin", 1 << 30).unwrap();
+///
+/// samples.extend_from_slice(&[1.0, 2.0, 3.0]).unwrap();
+/// samples.flush().unwrap();
+///
+/// drop(samples);
+///
+/// let samples = PersistentVirtualVec::<f64>::open("samples.bin", 1 << 30).unwrap();
+///
+/// assert_eq!(samples[..3], [1.0, 2.0, 3.0]);
+/// ```
+pub struct PersistentVirtualVec<T: Pod> {
+    mapping: FileMapping,
+    _marker: PhantomData<T>
+}
+
+// The mapping is only modified through `&mut self`.
+unsafe impl<T: Pod + Send> Send for PersistentVirtualVec<T> {}
+unsafe impl<T: Pod + Sync> Sync for PersistentVirtualVec<T> {}
+
+impl<T: Pod> PersistentVirtualVec<T> {
+    /// Opens the vector stored in the file at the given path, creating it if it does not
+    /// exist, which can then hold up to `max_len` elements.
+    ///
+    /// Fails with `InvalidData` if the file is not a vector of elements of the size and
+    /// alignment of `T`, was written by another version or on a machine of another byte
+    /// order, or is truncated.
+    pub fn open<P: AsRef<Path>>(path: P, max_len: usize) -> io::Result<Self> {
+        if mem::size_of::<T>() == 0 || mem::align_of::<T>() > HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "unsupported element type"))
+        }
+
+        let max = max_len.checked_mul(mem::size_of::<T>())
+            .and_then(|size| size.checked_add(HEADER_SIZE))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many elements"))?;
+        let mapping = FileMapping::open(path, max)?;
+        let mut vec = PersistentVirtualVec { mapping, _marker: PhantomData };
+
+        if vec.mapping.mapped() == 0 {
+            vec.mapping.grow(HEADER_SIZE)?;
+            PersistentVirtualVec::<T>::file_header().write(vec.header_mut());
+        }
+
+        vec.validate()?;
+
+        Ok(vec)
+    }
+
+    /// Returns the number of elements of the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        header::len(self.header()) as usize
+    }
+
+    /// Returns whether the vector has no elements.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the maximum number of elements of the vector.
+    #[inline]
+    pub fn max_len(&self) -> usize {
+        (self.mapping.max() - HEADER_SIZE) / mem::size_of::<T>()
+    }
+
+    /// Returns the elements of the vector as a slice.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.data(), self.len()) }
+    }
+
+    /// Returns the elements of the vector as a mutable slice.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.data(), self.len()) }
+    }
+
+    /// Appends an element to the back of the vector, growing the file as needed.
+    ///
+    /// Fails with `WriteZero` if the vector is full.
+    #[inline]
+    pub fn push(&mut self, value: T) -> io::Result<()> {
+        self.extend_from_slice(&[value])
+    }
+
+    /// Appends all elements of the given slice to the back of the vector, growing the
+    /// file as needed.
+    ///
+    /// Fails with `WriteZero` if the vector cannot hold all elements, in which case none
+    /// are appended.
+    pub fn extend_from_slice(&mut self, values: &[T]) -> io::Result<()> {
+        let len = self.len();
+        let end = (len + values.len()).saturating_mul(mem::size_of::<T>());
+
+        self.mapping.grow(end.saturating_add(HEADER_SIZE))?;
+
+        unsafe {
+            self.data().add(len).copy_from_nonoverlapping(values.as_ptr(), values.len());
+        }
+
+        header::set_len(self.header_mut(), (len + values.len()) as u64);
+
+        Ok(())
+    }
+
+    /// Removes the last element of the vector and returns it, or `None` if it is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        let last = self.as_slice().last().copied()?;
+
+        self.truncate(self.len() - 1);
+
+        Some(last)
+    }
+
+    /// Shortens the vector to `len` elements, if it is longer. The file keeps its size.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        if len < self.len() {
+            header::set_len(self.header_mut(), len as u64);
+        }
+    }
+
+    /// Removes all elements from the vector.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.truncate(0);
+    }
+
+    /// Writes the elements and the length of the vector back to the file, and waits until
+    /// they are durable.
+    pub fn flush(&mut self) -> io::Result<()> {
+        let end = HEADER_SIZE + self.len() * mem::size_of::<T>();
+
+        self.mapping.flush(0, end)
+    }
+
+    /// Returns the header of the file of a vector of `T`.
+    fn file_header() -> FileHeader {
+        FileHeader {
+            kind: "persistent vector",
+            magic: *b"VVECPERS",
+            version: VERSION,
+            elem_size: mem::size_of::<T>(),
+            elem_align: mem::align_of::<T>()
+        }
+    }
+
+    /// Checks that the header describes a vector of `T` that fits in the file.
+    fn validate(&self) -> io::Result<()> {
+        if self.mapping.mapped() < HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a persistent vector"))
+        }
+
+        PersistentVirtualVec::<T>::file_header().validate(self.header())?;
+
+        let available = (self.mapping.mapped() - HEADER_SIZE) / mem::size_of::<T>();
+
+        if header::len(self.header()) > available as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "truncated persistent vector"))
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn header(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.mapping.base(), HEADER_SIZE) }
+    }
+
+    #[inline]
+    fn header_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.mapping.base(), HEADER_SIZE) }
+    }
+
+    #[inline]
+    fn data(&self) -> *mut T {
+        unsafe { self.mapping.base().add(HEADER_SIZE).cast() }
+    }
+}
+
+impl<T: Pod> Deref for PersistentVirtualVec<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Pod> DerefMut for PersistentVirtualVec<T> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn persists_elements() {
+        let path = env::temp_dir().join(format!("virtualalloc-vec-{}.bin", std::process::id()));
+        let mut vec = PersistentVirtualVec::<u32>::open(&path, 100_000).unwrap();
+
+        assert!(vec.is_empty());
+
+        vec.push(1).unwrap();
+        vec.extend_from_slice(&(2..=50_000).collect::<Vec<_>>()).unwrap();
+        vec[0] = 0;
+        vec.push(7).unwrap();
+
+        assert_eq!(vec.pop(), Some(7));
+
+        vec.flush().unwrap();
+        drop(vec);
+
+        let mut vec = PersistentVirtualVec::<u32>::open(&path, 100_000).unwrap();
+
+        assert_eq!(vec.len(), 50_000);
+        assert_eq!(vec[..3], [0, 2, 3]);
+        assert_eq!(vec.last(), Some(&50_000));
+        assert!(vec.max_len() >= 100_000);
+        assert!(vec.extend_from_slice(&vec![0; vec.max_len() - vec.len() + 1]).is_err());
+
+        drop(vec);
+
+        // Elements of another size or alignment are not reinterpreted.
+        for err in [PersistentVirtualVec::<u64>::open(&path, 100_000).err(),
+                    PersistentVirtualVec::<[u8; 4]>::open(&path, 100_000).err()] {
+            assert_eq!(err.map(|err| err.kind()), Some(io::ErrorKind::InvalidData));
+        }
+
+        assert!(PersistentVirtualVec::<i32>::open(&path, 100_000).is_ok());
+
+        // Neither are those of a file whose header was corrupted, here its element size.
+        let mut bytes = fs::read(&path).unwrap();
+
+        bytes[16] ^= 0x80;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(PersistentVirtualVec::<u32>::open(&path, 100_000).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+//! Plain old data, which can be stored as raw bytes in shared or persistent memory.
+
+
+/// Types that are plain old data, which can be stored as raw bytes and read back from
+/// any bytes of the right size.
+///
+/// # Safety
+/// Implementors must not have padding, pointers, or invalid bit patterns, and must have
+/// the same layout in every process that reads the bytes written by another.
+pub unsafe trait Pod: Copy + 'static {}
+
+macro_rules! impl_pod {
+    ($($ty:ty),*) => {
+        $(unsafe impl Pod for $ty {})*
+    }
+}
+
+impl_pod!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64);
+
+unsafe impl<T: Pod, const N: usize> Pod for [T; N] {}
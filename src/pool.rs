@@ -0,0 +1,406 @@
+//! A buffer pool of fixed-size frames, for databases.
+
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// A function called with the page held by an evicted frame, its contents, and whether it
+/// was modified since it was loaded or flushed.
+type EvictHook = Box<dyn FnMut(u64, &[u8], bool) + Send>;
+
+/// A pool of fixed-size frames that hold pages of a database, in a single reservation.
+///
+/// Pages are loaded into frames with `pin`, which returns the index of the frame holding
+/// the page, and keep that frame until they are unpinned as many times as they were
+/// pinned and then evicted to make room for other pages. Since frames live in a single
+/// reservation, a frame never moves: pointers to a pinned page remain valid until it is
+/// unpinned, which is the guarantee that B-trees and other page-based structures need.
+///
+/// Frames are committed the first time they are used, and are chosen for eviction with
+/// the clock algorithm, which skips pinned frames and gives recently pinned frames a
+/// second chance. The hook given to `on_evict` is called with the contents of each
+/// evicted page, and must write it back if it is dirty.
+///
+/// Clean frames are read-only, so that a stray write to a page that was not declared
+/// modified faults instead of being silently lost on eviction. Frames become writable and
+/// dirty when accessed with `frame_mut`, and clean again when flushed with `flush`.
+///
+/// # Example
+/// ```
+/// use std::io;
+/// use virtualalloc::BufferPool;
+///
+/// let mut pool = BufferPool::new(4096, 2).unwrap().on_evict(|page, data, dirty| {
+///     if dirty {
+///         // Write `data` back to page `page` of the database file.
+///     }
+/// });
+///
+/// let frame = pool.pin(7, |data| -> io::Result<()> {
+///     // Read page 7 of the database file into `data`.
+///     data[0] = 7;
+///     Ok(())
+/// }).unwrap();
+///
+/// assert_eq!(pool.frame(frame)[0], 7);
+///
+/// pool.frame_mut(frame).unwrap()[1] = 1;
+/// pool.unpin(frame);
+/// ```
+pub struct BufferPool<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    frame_size: usize,
+    frames: Vec<Frame>,
+    /// The frame holding each resident page.
+    pages: HashMap<u64, usize>,
+    /// The next frame considered for eviction.
+    hand: usize,
+    on_evict: Option<EvictHook>
+}
+
+#[derive(Default)]
+struct Frame {
+    page: Option<u64>,
+    pins: usize,
+    dirty: bool,
+    /// Whether the frame was pinned since the clock hand last passed it.
+    referenced: bool,
+    committed: bool
+}
+
+// Frames are only accessed through the pool, which requires `&mut self` to modify them.
+unsafe impl<B: VirtualBacking + Send> Send for BufferPool<B> {}
+unsafe impl<B: VirtualBacking + Sync> Sync for BufferPool<B> {}
+
+impl BufferPool {
+    /// Returns a pool of `frames` frames of `frame_size` bytes each, rounded up to whole
+    /// pages.
+    pub fn new(frame_size: usize, frames: usize) -> Result<Self, VirtualMemError> {
+        let size = ::round_to_page(frame_size).saturating_mul(frames);
+
+        BufferPool::with_backing(frame_size, frames, VirtualAlloc::new(size))
+    }
+}
+
+impl<B: VirtualBacking> BufferPool<B> {
+    /// Returns a pool of `frames` frames of `frame_size` bytes each, rounded up to whole
+    /// pages, in memory provided by the given backing.
+    pub fn with_backing(frame_size: usize, frames: usize, backing: B)
+        -> Result<Self, VirtualMemError> {
+        let page = backing.page_size();
+        let frame_size = frame_size.max(1).div_ceil(page).saturating_mul(page);
+
+        let size = match frame_size.checked_mul(frames) {
+            Some(size) if size <= isize::MAX as usize => size,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: frame_size.saturating_mul(frames), max: isize::MAX as usize
+            })
+        };
+
+        let ptr = backing.reserve(size)?;
+        let frames = (0..frames).map(|_| Frame::default()).collect();
+
+        Ok(BufferPool {
+            backing,
+            ptr,
+            frame_size,
+            frames,
+            pages: HashMap::new(),
+            hand: 0,
+            on_evict: None
+        })
+    }
+
+    /// Sets the function called with the page held by each evicted frame, its contents,
+    /// and whether it is dirty, in which case it must be written back.
+    ///
+    /// Frames still holding pages when the pool is dropped are not evicted, and dirty
+    /// pages must therefore be flushed with `flush` beforehand.
+    pub fn on_evict<F>(mut self, hook: F) -> Self
+        where F: FnMut(u64, &[u8], bool) + Send + 'static {
+        self.on_evict = Some(Box::new(hook));
+        self
+    }
+
+    /// Returns the size of each frame, in bytes.
+    #[inline]
+    pub fn frame_size(&self) -> usize {
+        self.frame_size
+    }
+
+    /// Returns the number of frames of the pool.
+    #[inline]
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns the frame holding the given page, if it is resident.
+    #[inline]
+    pub fn frame_of(&self, page: u64) -> Option<usize> {
+        self.pages.get(&page).copied()
+    }
+
+    /// Pins the given page in a frame, and returns the index of the frame.
+    ///
+    /// If the page is not resident, a frame is evicted to make room for it, and `load` is
+    /// called to fill the whole frame with its contents, since it still holds the page that
+    /// was evicted.
+    ///
+    /// Fails with `ExceedsMax` if all frames are pinned, and with the error returned by
+    /// `load` if it fails, in which case the frame is left empty.
+    pub fn pin<E, F>(&mut self, page: u64, load: F) -> Result<usize, E>
+        where E: From<VirtualMemError>, F: FnOnce(&mut [u8]) -> Result<(), E> {
+        if let Some(index) = self.frame_of(page) {
+            let frame = &mut self.frames[index];
+
+            frame.pins += 1;
+            frame.referenced = true;
+
+            return Ok(index)
+        }
+
+        let index = self.evict()?;
+
+        unsafe {
+            let ptr = self.frame_ptr(index);
+
+            if !self.frames[index].committed {
+                self.backing.commit(ptr, self.frame_size)?;
+                self.frames[index].committed = true;
+            }
+
+            self.protect(index, true)?;
+            self.frames[index].dirty = false;
+
+            load(slice::from_raw_parts_mut(ptr.as_ptr(), self.frame_size))?;
+
+            self.protect(index, false)?;
+        }
+
+        let frame = &mut self.frames[index];
+
+        frame.page = Some(page);
+        frame.pins = 1;
+        frame.referenced = true;
+        self.pages.insert(page, index);
+
+        Ok(index)
+    }
+
+    /// Unpins the given frame, which can be evicted once it was unpinned as many times as
+    /// it was pinned.
+    ///
+    /// # Panics
+    /// Panics if the frame is not pinned.
+    pub fn unpin(&mut self, frame: usize) {
+        let frame = &mut self.frames[frame];
+
+        assert!(frame.pins > 0, "frame is not pinned");
+
+        frame.pins -= 1;
+    }
+
+    /// Returns a pointer to the start of the given frame, which never changes.
+    #[inline]
+    pub fn frame_ptr(&self, frame: usize) -> NonNull<u8> {
+        assert!(frame < self.frames.len());
+
+        unsafe { NonNull::new_unchecked(self.ptr.as_ptr().add(frame * self.frame_size)) }
+    }
+
+    /// Returns the contents of the given frame.
+    ///
+    /// # Panics
+    /// Panics if the frame is not pinned.
+    pub fn frame(&self, frame: usize) -> &[u8] {
+        assert!(self.frames[frame].pins > 0, "frame is not pinned");
+
+        unsafe { slice::from_raw_parts(self.frame_ptr(frame).as_ptr(), self.frame_size) }
+    }
+
+    /// Makes the given frame writable and marks it dirty, and returns its contents.
+    ///
+    /// # Panics
+    /// Panics if the frame is not pinned.
+    pub fn frame_mut(&mut self, frame: usize) -> Result<&mut [u8], VirtualMemError> {
+        assert!(self.frames[frame].pins > 0, "frame is not pinned");
+
+        if !self.frames[frame].dirty {
+            self.protect(frame, true)?;
+            self.frames[frame].dirty = true;
+        }
+
+        unsafe { Ok(slice::from_raw_parts_mut(self.frame_ptr(frame).as_ptr(), self.frame_size)) }
+    }
+
+    /// Returns whether the given frame was modified since its page was loaded or flushed.
+    #[inline]
+    pub fn is_dirty(&self, frame: usize) -> bool {
+        self.frames[frame].dirty
+    }
+
+    /// Calls `write` with each dirty page and its contents, and marks them clean.
+    ///
+    /// Stops at the first error returned by `write`, leaving the remaining pages dirty.
+    pub fn flush<E, F>(&mut self, mut write: F) -> Result<(), E>
+        where E: From<VirtualMemError>, F: FnMut(u64, &[u8]) -> Result<(), E> {
+        for index in 0..self.frames.len() {
+            let page = match self.frames[index] {
+                Frame { page: Some(page), dirty: true, .. } => page,
+                _ => continue
+            };
+
+            self.protect(index, false)?;
+            self.frames[index].dirty = false;
+
+            unsafe {
+                write(page, slice::from_raw_parts(self.frame_ptr(index).as_ptr(),
+                                                  self.frame_size))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns an empty frame, evicting the page of an unpinned frame if needed.
+    fn evict(&mut self) -> Result<usize, VirtualMemError> {
+        let count = self.frames.len();
+
+        // Two turns give every referenced frame its second chance.
+        for _ in 0..count * 2 {
+            let index = self.hand;
+            let frame = &mut self.frames[index];
+
+            self.hand = (self.hand + 1) % count;
+
+            if frame.pins > 0 {
+                continue
+            }
+            if frame.referenced {
+                frame.referenced = false;
+                continue
+            }
+
+            if let Some(page) = frame.page.take() {
+                let dirty = frame.dirty;
+
+                self.pages.remove(&page);
+
+                if let Some(ref mut hook) = self.on_evict {
+                    unsafe {
+                        let ptr = self.ptr.as_ptr().add(index * self.frame_size);
+
+                        hook(page, slice::from_raw_parts(ptr, self.frame_size), dirty);
+                    }
+                }
+            }
+
+            return Ok(index)
+        }
+
+        let size = self.frame_size * count;
+        let requested = size.saturating_add(self.frame_size);
+
+        Err(VirtualMemError::ExceedsMax { requested, max: size })
+    }
+
+    /// Makes the given frame writable, or read-only.
+    fn protect(&self, frame: usize, write: bool) -> Result<(), VirtualMemError> {
+        let result = unsafe {
+            self.backing.protect(self.frame_ptr(frame), self.frame_size, true, write, false)
+        };
+
+        // Dirty tracking only relies on `frame_mut` where memory cannot be protected.
+        match result {
+            Err(VirtualMemError::Unsupported) => Ok(()),
+            result => result
+        }
+    }
+}
+
+impl<B: VirtualBacking> Drop for BufferPool<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.frame_size * self.frames.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    fn load(page: u64) -> impl FnOnce(&mut [u8]) -> Result<(), VirtualMemError> {
+        move |data| {
+            data[0] = page as u8;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn evicts_unpinned_frames() {
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let mut pool = {
+            let evicted = evicted.clone();
+
+            BufferPool::new(1, 2).unwrap().on_evict(move |page, data, dirty| {
+                evicted.lock().unwrap().push((page, data[0], data[1], dirty));
+            })
+        };
+
+        let first = pool.pin(1, load(1)).unwrap();
+        let second = pool.pin(2, load(2)).unwrap();
+
+        assert_ne!(first, second);
+        assert_eq!(pool.pin(1, load(9)).unwrap(), first);
+        assert!(pool.pin(3, load(3)).is_err());
+
+        pool.frame_mut(second).unwrap()[1] = 5;
+        pool.unpin(second);
+
+        assert!(pool.is_dirty(second));
+        assert_eq!(pool.pin(3, load(3)).unwrap(), second);
+        assert_eq!(pool.frame(second)[0], 3);
+        assert_eq!(pool.frame_of(2), None);
+        assert_eq!(*evicted.lock().unwrap(), [(2, 2, 5, true)]);
+
+        // Pages stay pinned until unpinned as many times as they were pinned.
+        pool.unpin(first);
+        pool.unpin(second);
+
+        assert_eq!(pool.frame_of(1), Some(first));
+        assert_eq!(pool.pin(4, load(4)).unwrap(), second);
+
+        pool.unpin(first);
+
+        assert_eq!(pool.pin(5, load(5)).unwrap(), first);
+        assert_eq!(evicted.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn flushes_dirty_frames() {
+        let mut pool = BufferPool::new(1, 4).unwrap();
+        let frames = (0..3).map(|page| pool.pin(page, load(page)).unwrap()).collect::<Vec<_>>();
+
+        pool.frame_mut(frames[0]).unwrap()[0] = 10;
+        pool.frame_mut(frames[2]).unwrap()[0] = 12;
+
+        let mut written = Vec::new();
+
+        pool.flush(|page, data| -> Result<(), VirtualMemError> {
+            written.push((page, data[0]));
+            Ok(())
+        }).unwrap();
+
+        assert_eq!(written, [(0, 10), (2, 12)]);
+        assert!(frames.iter().all(|&frame| !pool.is_dirty(frame)));
+        assert!(pool.pin(3, |_| Err(VirtualMemError::Unsupported)).is_err());
+        assert_eq!(pool.frame_of(3), None);
+    }
+}
@@ -0,0 +1,132 @@
+//! A backing that delegates platform calls to the `region` crate.
+//!
+//! Enabled by the `portable` feature, for users who prefer relying on widely used
+//! platform code over the raw system calls made by `VirtualAlloc`.
+
+use std::cell::RefCell;
+use std::ptr::{self, NonNull};
+
+use region::{self, Allocation, Protection};
+
+use super::{VirtualBacking, VirtualMemError};
+
+
+/// A `VirtualBacking` built upon the `region` crate, which provides read-write memory.
+///
+/// `region` commits reservations upfront on Windows, where memory reserved through this
+/// backing therefore counts against the commit limit of the system as soon as it is
+/// reserved. `region` also has no way to return memory to the system, so decommitted
+/// memory is zeroed and made inaccessible instead.
+///
+/// # Example
+/// ```
+/// use virtualalloc::{RegionBacking, VirtualVec};
+///
+/// let mut vec = VirtualVec::<u32, _>::with_backing(1_000_000, RegionBacking::new());
+///
+/// vec.push(42);
+///
+/// assert_eq!(vec[0], 42);
+/// ```
+#[derive(Default)]
+pub struct RegionBacking {
+    allocations: RefCell<Vec<Allocation>>
+}
+
+impl RegionBacking {
+    /// Returns a backing that provides read-write memory.
+    pub fn new() -> Self {
+        RegionBacking::default()
+    }
+}
+
+/// Returns the OS error code carried by the given error, or 0 if there is none.
+fn os_err(err: region::Error) -> i32 {
+    match err {
+        region::Error::SystemCall(err) => err.raw_os_error().unwrap_or(0),
+        _ => 0
+    }
+}
+
+fn protection(read: bool, write: bool, exec: bool) -> Protection {
+    let mut prot = Protection::NONE;
+
+    if read { prot |= Protection::READ }
+    if write { prot |= Protection::WRITE }
+    if exec { prot |= Protection::EXECUTE }
+
+    prot
+}
+
+unsafe impl VirtualBacking for RegionBacking {
+    fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+        let mut allocation = region::alloc(size, Protection::NONE)
+            .map_err(|err| VirtualMemError::ReservationFailed { os_err: os_err(err) })?;
+        let ptr = NonNull::new(allocation.as_mut_ptr::<u8>())
+            .ok_or(VirtualMemError::ReservationFailed { os_err: 0 })?;
+
+        self.allocations.borrow_mut().push(allocation);
+
+        Ok(ptr)
+    }
+
+    unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        region::protect(ptr.as_ptr(), size, Protection::READ_WRITE)
+            .map_err(|err| VirtualMemError::CommitFailed { os_err: os_err(err) })
+    }
+
+    unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+        ptr::write_bytes(ptr.as_ptr(), 0, size);
+
+        region::protect(ptr.as_ptr(), size, Protection::NONE)
+            .map_err(|err| VirtualMemError::DecommitFailed { os_err: os_err(err) })
+    }
+
+    unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool, exec: bool)
+        -> Result<(), VirtualMemError> {
+        if ::is_wx_violation(write, exec) {
+            return Err(VirtualMemError::WxViolation)
+        }
+
+        region::protect(ptr.as_ptr(), size, protection(read, write, exec))
+            .map_err(|err| VirtualMemError::ProtectFailed { os_err: os_err(err) })
+    }
+
+    unsafe fn release(&self, ptr: NonNull<u8>, _: usize) {
+        // Dropping the allocation releases it.
+        self.allocations.borrow_mut().retain(|allocation| allocation.as_ptr() != ptr.as_ptr());
+    }
+
+    fn page_size(&self) -> usize {
+        region::page::size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use VirtualVec;
+
+    #[test]
+    fn can_back_vectors() {
+        let mut vec = VirtualVec::<usize, _>::with_backing(1_000_000, RegionBacking::new());
+        let initial_ptr = vec.as_ptr();
+
+        for i in 0..100_000 {
+            vec.push(i);
+        }
+
+        assert_eq!(vec.as_ptr(), initial_ptr);
+        assert_eq!(vec[99_999], 99_999);
+    }
+
+    #[test]
+    fn releases_reservations() {
+        let backing = RegionBacking::new();
+
+        drop(VirtualVec::<u8, _>::with_backing(1024, &backing));
+
+        assert!(backing.allocations.borrow().is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+//! Background prefaulting of the pages that vectors are about to be appended to.
+//!
+//! Committed memory is only backed by physical pages once it is first touched, and that
+//! first touch costs a page fault to the thread that touches it. A `Prefaulter` runs a
+//! helper thread that faults pages in ahead of time instead, so that append-heavy writers
+//! do not stall on first-touch faults in their hot path.
+//!
+//! Faulting pages in never changes their contents, which is why the helper thread can
+//! safely work on memory that is concurrently written to, or even released.
+//!
+//! # Example
+//! ```
+//! use virtualalloc::VirtualVec;
+//! use virtualalloc::prefault::Prefaulter;
+//!
+//! let prefaulter = Prefaulter::new().unwrap();
+//! let mut vec = VirtualVec::<u8>::new(1 << 30);
+//!
+//! vec.prefault_ahead(&prefaulter, 64);
+//!
+//! for i in 0..1_000_000 {
+//!     vec.push(i as u8);
+//! }
+//! ```
+
+use std::io;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+
+/// A range of memory to fault in, sent to the helper thread.
+pub(crate) struct Range(*mut u8, usize);
+
+// Faulting pages in is harmless whatever their owner does with them in the meantime.
+unsafe impl Send for Range {}
+
+/// A helper thread that faults pages in ahead of time.
+///
+/// The helper thread exits once the prefaulter and all vectors using it are dropped.
+pub struct Prefaulter {
+    sender: Sender<Range>
+}
+
+impl Prefaulter {
+    /// Spawns the helper thread.
+    pub fn new() -> io::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<Range>();
+
+        thread::Builder::new()
+            .name("virtualalloc-prefault".into())
+            .spawn(move || {
+                for Range(ptr, len) in receiver {
+                    unsafe { ::populate(ptr, len) };
+                }
+            })?;
+
+        Ok(Prefaulter { sender })
+    }
+
+    /// Asks the helper thread to fault in the committed pages in the given range.
+    ///
+    /// Pages that are not committed are ignored.
+    pub fn prefault(&self, ptr: *mut u8, len: usize) {
+        let _ = self.sender.send(Range(ptr, len));
+    }
+
+    pub(crate) fn sender(&self) -> Sender<Range> {
+        self.sender.clone()
+    }
+}
+
+/// The state of a vector that prefaults the pages ahead of its end.
+pub(crate) struct PrefaultAhead {
+    pub sender: Sender<Range>,
+    /// The number of bytes to fault in ahead of the end of the vector.
+    pub ahead: usize,
+    /// The offset up to which pages were requested to be faulted in.
+    pub until: usize
+}
+
+impl PrefaultAhead {
+    /// Requests the pages between `start` and `end` to be faulted in.
+    #[inline]
+    pub fn request(&mut self, base: *mut u8, start: usize, end: usize) {
+        let _ = self.sender.send(Range(unsafe { base.add(start) }, end - start));
+
+        self.until = end;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use {VirtualAlloc, VirtualVec};
+
+    #[test]
+    fn prefaults_pages_ahead_of_the_end() {
+        let page = VirtualAlloc::page_size();
+        let prefaulter = Prefaulter::new().unwrap();
+        let mut vec = VirtualVec::<u8>::new(page * 64);
+
+        vec.prefault_ahead(&prefaulter, 4);
+        vec.push(1);
+
+        assert!(vec.capacity() >= page * 4);
+
+        vec.extend_from_slice(&vec![2; page * 10]);
+        drop(prefaulter);
+
+        assert_eq!(vec.len(), page * 10 + 1);
+        assert!(vec[1..].iter().all(|&b| b == 2));
+    }
+}
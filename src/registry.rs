@@ -0,0 +1,355 @@
+//! An opt-in registry of the live vectors of the process.
+//!
+//! Vectors created while the registry is enabled with `VirtualAlloc::set_region_registry`
+//! register their reservation, and keep their entry up to date as they commit and
+//! decommit memory until they are dropped. Vectors created while it is disabled are never
+//! registered, and cost nothing.
+//!
+//! With the `backtrace` feature, entries also hold the backtrace of the creation of their
+//! vector, and of its last commit larger than the threshold given to
+//! `VirtualAlloc::set_commit_backtrace_threshold`.
+
+#[cfg(feature = "backtrace")] use std::backtrace::Backtrace;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{self, Write};
+#[cfg(feature = "backtrace")] use std::ops::Deref;
+use std::sync::{Arc, Mutex, MutexGuard, Once};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+
+/// Whether new vectors are registered.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The identifier given to the next registered vector.
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// The registered vectors, by identifier.
+static REGIONS: Mutex<BTreeMap<u64, Arc<Region>>> = Mutex::new(BTreeMap::new());
+
+/// The size from which commits capture a backtrace, or 0 if they never do.
+#[cfg(feature = "backtrace")]
+static COMMIT_BACKTRACE_THRESHOLD: AtomicUsize = AtomicUsize::new(0);
+
+struct Region {
+    label: Mutex<Option<&'static str>>,
+    reserved: usize,
+    committed: AtomicUsize,
+    #[cfg(feature = "backtrace")]
+    backtrace: CapturedBacktrace,
+    #[cfg(feature = "backtrace")]
+    commit_backtrace: Mutex<Option<CapturedBacktrace>>
+}
+
+/// A backtrace captured by the registry, which can be cheaply cloned.
+///
+/// Backtraces are captured regardless of `RUST_BACKTRACE`, but are only symbolized when
+/// they are first displayed. Two backtraces are equal if they are the same capture.
+#[cfg(feature = "backtrace")]
+#[derive(Clone, Debug)]
+pub struct CapturedBacktrace(Arc<Backtrace>);
+
+#[cfg(feature = "backtrace")]
+impl CapturedBacktrace {
+    #[inline]
+    fn capture() -> Self {
+        CapturedBacktrace(Arc::new(Backtrace::force_capture()))
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl Deref for CapturedBacktrace {
+    type Target = Backtrace;
+
+    #[inline]
+    fn deref(&self) -> &Backtrace {
+        &self.0
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl PartialEq for CapturedBacktrace {
+    #[inline]
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+#[cfg(feature = "backtrace")]
+impl Eq for CapturedBacktrace {}
+
+#[cfg(feature = "backtrace")]
+impl fmt::Display for CapturedBacktrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&*self.0, f)
+    }
+}
+
+/// A live vector, as listed by `VirtualAlloc::live_regions`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionInfo {
+    /// The identifier of the vector, which increases with each registered vector.
+    pub id: u64,
+    /// The label given to the vector with `VirtualVec::label`, if any.
+    pub label: Option<&'static str>,
+    /// The number of bytes of address space reserved by the vector.
+    pub reserved: usize,
+    /// The number of bytes currently committed by the vector.
+    pub committed: usize,
+    /// Where the vector was created.
+    #[cfg(feature = "backtrace")]
+    pub backtrace: CapturedBacktrace,
+    /// Where the vector last committed more memory at once than the threshold given to
+    /// `VirtualAlloc::set_commit_backtrace_threshold`, if it ever did.
+    #[cfg(feature = "backtrace")]
+    pub commit_backtrace: Option<CapturedBacktrace>
+}
+
+impl fmt::Display for RegionInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{} {}: {} bytes reserved, {} bytes committed", self.id,
+               self.label.unwrap_or("<unlabeled>"), self.reserved, self.committed)?;
+
+        #[cfg(feature = "backtrace")]
+        write!(f, ", created at:\n{}", self.backtrace)?;
+
+        Ok(())
+    }
+}
+
+/// The entry of a vector in the registry, which is removed when dropped.
+pub(crate) struct Registration {
+    id: u64,
+    region: Arc<Region>
+}
+
+impl Registration {
+    /// Registers a reservation of `reserved` bytes, if the registry is enabled.
+    pub(crate) fn new(reserved: usize) -> Option<Self> {
+        if !ENABLED.load(Ordering::Relaxed) {
+            return None
+        }
+
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let region = Arc::new(Region {
+            label: Mutex::new(None),
+            reserved,
+            committed: AtomicUsize::new(0),
+            #[cfg(feature = "backtrace")]
+            backtrace: CapturedBacktrace::capture(),
+            #[cfg(feature = "backtrace")]
+            commit_backtrace: Mutex::new(None)
+        });
+
+        regions().insert(id, region.clone());
+
+        Some(Registration { id, region })
+    }
+
+    #[inline]
+    pub(crate) fn set_label(&self, label: &'static str) {
+        *lock(&self.region.label) = Some(label);
+    }
+
+    #[cfg(not(feature = "backtrace"))]
+    #[inline]
+    pub(crate) fn set_committed(&self, committed: usize) {
+        self.region.committed.store(committed, Ordering::Relaxed);
+    }
+
+    /// Updates the committed size, capturing a backtrace if it grew by more than the
+    /// threshold.
+    #[cfg(feature = "backtrace")]
+    #[inline]
+    pub(crate) fn set_committed(&self, committed: usize) {
+        let previous = self.region.committed.swap(committed, Ordering::Relaxed);
+
+        match COMMIT_BACKTRACE_THRESHOLD.load(Ordering::Relaxed) {
+            threshold if threshold > 0 && committed >= previous.saturating_add(threshold) => {
+                *lock(&self.region.commit_backtrace) = Some(CapturedBacktrace::capture());
+            },
+            _ => ()
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        regions().remove(&self.id);
+    }
+}
+
+/// Locks the registered vectors, ignoring panics of other threads, which cannot leave the
+/// map in an inconsistent state.
+fn regions() -> MutexGuard<'static, BTreeMap<u64, Arc<Region>>> {
+    lock(&REGIONS)
+}
+
+/// Locks the given mutex, ignoring panics of other threads.
+#[inline]
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+/// Sets the size from which commits capture a backtrace.
+#[cfg(feature = "backtrace")]
+pub(crate) fn set_commit_backtrace_threshold(threshold: Option<usize>) {
+    COMMIT_BACKTRACE_THRESHOLD.store(threshold.map_or(0, |threshold| threshold.max(1)),
+                                     Ordering::Relaxed);
+}
+
+/// Enables or disables the registration of new vectors.
+pub(crate) fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Returns the registered vectors that are still alive, in the order they were created.
+pub(crate) fn live_regions() -> Vec<RegionInfo> {
+    regions()
+        .iter()
+        .map(|(&id, region)| RegionInfo {
+            id,
+            label: *lock(&region.label),
+            reserved: region.reserved,
+            committed: region.committed.load(Ordering::Relaxed),
+            #[cfg(feature = "backtrace")]
+            backtrace: region.backtrace.clone(),
+            #[cfg(feature = "backtrace")]
+            commit_backtrace: lock(&region.commit_backtrace).clone()
+        })
+        .collect()
+}
+
+/// Writes the registered vectors that are still alive to `out`, and returns how many there
+/// are.
+pub(crate) fn write_leaks(out: &mut dyn Write) -> io::Result<usize> {
+    let regions = live_regions();
+
+    if !regions.is_empty() {
+        let reserved = regions.iter().map(|region| region.reserved).sum::<usize>();
+
+        writeln!(out, "virtualalloc: {} vectors still alive, reserving {} bytes:",
+                 regions.len(), reserved)?;
+
+        for region in &regions {
+            writeln!(out, "  {}", region)?;
+        }
+    }
+
+    Ok(regions.len())
+}
+
+/// Enables the registry, and reports the vectors still alive to the standard error when
+/// the process exits.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn report_leaks_at_exit() {
+    static REGISTERED: Once = Once::new();
+
+    extern "C" fn report() {
+        let _ = write_leaks(&mut io::stderr());
+    }
+
+    #[cfg(windows)]
+    extern "C" {
+        fn atexit(callback: extern "C" fn()) -> i32;
+    }
+    #[cfg(not(windows))]
+    use libc::atexit;
+
+    set_enabled(true);
+
+    REGISTERED.call_once(|| unsafe {
+        atexit(report);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use {VirtualAlloc, VirtualVec};
+
+    #[test]
+    fn lists_live_vectors() {
+        let page = VirtualAlloc::page_size();
+
+        VirtualAlloc::set_region_registry(true);
+
+        let mut vec = VirtualVec::<u8>::new(page * 8).label("registry-test");
+        let unlabeled = VirtualVec::<u8>::new(page);
+
+        VirtualAlloc::set_region_registry(false);
+
+        let ignored = VirtualVec::<u8>::new(page).label("registry-ignored");
+
+        vec.reserve(page * 2).unwrap();
+
+        let regions = VirtualAlloc::live_regions();
+        let region = regions.iter().find(|region| region.label == Some("registry-test")).unwrap();
+
+        assert_eq!(region.reserved, page * 8);
+        assert!(region.committed >= page * 2);
+        assert!(regions.iter().any(|other| other.id > region.id && other.label.is_none()));
+        assert!(regions.iter().all(|region| region.label != Some("registry-ignored")));
+
+        drop((vec, unlabeled, ignored));
+
+        assert!(VirtualAlloc::live_regions().iter().all(|r| r.label != Some("registry-test")));
+    }
+
+    #[test]
+    fn reports_leaked_vectors() {
+        VirtualAlloc::set_region_registry(true);
+
+        let vec = VirtualVec::<u8>::new(VirtualAlloc::page_size()).label("leak-test");
+
+        VirtualAlloc::set_region_registry(false);
+
+        let mut report = Vec::new();
+        let leaks = super::write_leaks(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        let line = report.lines().find(|line| line.contains("leak-test")).unwrap();
+
+        assert!(leaks >= 1);
+        assert!(report.starts_with("virtualalloc: "));
+        assert!(line.contains(&format!("leak-test: {} bytes reserved, 0 bytes committed",
+                                       VirtualAlloc::page_size())));
+
+        drop(vec);
+    }
+
+    #[cfg(feature = "backtrace")]
+    #[test]
+    fn captures_backtraces() {
+        use GrowthPolicy;
+
+        let page = VirtualAlloc::page_size();
+
+        VirtualAlloc::set_region_registry(true);
+        VirtualAlloc::set_commit_backtrace_threshold(Some(page * 4));
+
+        let mut vec = VirtualVec::<u8>::new(page * 64).label("backtrace-test")
+            .growth_policy(GrowthPolicy::Exact);
+
+        VirtualAlloc::set_region_registry(false);
+
+        let find = || {
+            VirtualAlloc::live_regions()
+                .into_iter()
+                .find(|region| region.label == Some("backtrace-test"))
+                .unwrap()
+        };
+
+        vec.reserve(page).unwrap();
+
+        assert!(find().commit_backtrace.is_none());
+
+        vec.reserve(page * 8).unwrap();
+
+        VirtualAlloc::set_commit_backtrace_threshold(None);
+
+        let region = find();
+
+        assert!(region.commit_backtrace.is_some());
+        assert_ne!(region.commit_backtrace, Some(region.backtrace.clone()));
+        assert_eq!(region.backtrace, region.backtrace.clone());
+    }
+}
@@ -0,0 +1,291 @@
+//! A single-producer single-consumer ring buffer over a mirrored mapping, on Linux.
+//!
+//! The memory of the ring is mapped twice in a row, so that the bytes at the end of the
+//! ring are immediately followed by the bytes at its start. Free and readable space can
+//! therefore always be handed out as a single contiguous slice, even when it wraps around,
+//! which lets producers and consumers pass it to the operating system or to a codec
+//! without splitting it in two.
+//!
+//! # Example
+//! ```
+//! use virtualalloc::ring::SpscRing;
+//!
+//! let mut ring = SpscRing::new(4096).unwrap();
+//! let (mut producer, mut consumer) = ring.split();
+//!
+//! producer.write()[..5].copy_from_slice(b"hello");
+//! producer.produce(5);
+//!
+//! assert_eq!(&consumer.read()[..5], b"hello");
+//!
+//! consumer.consume(5);
+//! ```
+
+#[cfg(feature = "std")] use std::ptr::{self, NonNull};
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+#[cfg(not(feature = "std"))] use core::slice;
+
+use libc::{self, c_void};
+
+use super::{last_os_error, VirtualMemError};
+use super::sync::{AtomicUsize, Ordering};
+
+
+/// A byte ring buffer with a single producer and a single consumer, which hands out
+/// contiguous slices.
+///
+/// The ring is used through the producer and consumer returned by `split`, which can be
+/// sent to different threads. Positions are exchanged with a single atomic store on each
+/// side, and bytes are never copied by the ring itself.
+pub struct SpscRing {
+    ptr: NonNull<u8>,
+    size: usize,
+    /// The number of bytes consumed so far, wrapping around.
+    head: AtomicUsize,
+    /// The number of bytes produced so far, wrapping around.
+    tail: AtomicUsize
+}
+
+// The producer and the consumer only write to the positions and bytes they own.
+unsafe impl Send for SpscRing {}
+unsafe impl Sync for SpscRing {}
+
+/// The producing half of a `SpscRing`.
+pub struct RingProducer<'a> {
+    ring: &'a SpscRing
+}
+
+/// The consuming half of a `SpscRing`.
+pub struct RingConsumer<'a> {
+    ring: &'a SpscRing
+}
+
+/// Maps `size` bytes of a new memory file twice in a row, and returns the first mapping.
+unsafe fn map_mirrored(size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+    let fail = || VirtualMemError::ReservationFailed { os_err: last_os_error() };
+
+    let fd = libc::memfd_create(b"virtualalloc-ring\0".as_ptr() as _, libc::MFD_CLOEXEC);
+
+    if fd < 0 {
+        return Err(fail())
+    }
+
+    let mapped = match libc::ftruncate(fd, size as libc::off_t) {
+        0 => map_file_mirrored(fd, 0, size)
+            .map_err(|os_err| VirtualMemError::ReservationFailed { os_err }),
+        _ => Err(fail())
+    };
+
+    // The mappings keep the file alive.
+    libc::close(fd);
+
+    mapped
+}
+
+/// Maps the `size` bytes at `offset` in the given file twice in a row, and returns the
+/// first mapping, or the error code of the operating system.
+pub(crate) unsafe fn map_file_mirrored(fd: libc::c_int, offset: usize, size: usize)
+    -> Result<NonNull<u8>, i32> {
+    // Both mappings are placed over a single reservation, so that they are adjacent.
+    let base = libc::mmap(ptr::null_mut(), size * 2, libc::PROT_NONE,
+                          libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+
+    if base == libc::MAP_FAILED {
+        return Err(last_os_error())
+    }
+
+    for half in 0..2 {
+        let addr = (base as *mut u8).add(half * size) as *mut c_void;
+        let mapped = libc::mmap(addr, size, libc::PROT_READ | libc::PROT_WRITE,
+                                libc::MAP_SHARED | libc::MAP_FIXED, fd, offset as libc::off_t);
+
+        if mapped == libc::MAP_FAILED {
+            let os_err = last_os_error();
+
+            libc::munmap(base, size * 2);
+            return Err(os_err)
+        }
+    }
+
+    Ok(NonNull::new_unchecked(base as *mut u8))
+}
+
+impl SpscRing {
+    /// Returns a ring that can hold at least `min_size` bytes, rounded up to whole pages.
+    pub fn new(min_size: usize) -> Result<Self, VirtualMemError> {
+        let size = ::round_to_page(min_size.max(1));
+        let max = isize::MAX as usize / 2;
+
+        if size > max {
+            return Err(VirtualMemError::ExceedsMax { requested: min_size, max })
+        }
+
+        let ptr = unsafe { map_mirrored(size)? };
+
+        Ok(SpscRing { ptr, size, head: AtomicUsize::new(0), tail: AtomicUsize::new(0) })
+    }
+
+    /// Returns the number of bytes the ring can hold.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.size
+    }
+
+    /// Returns the number of bytes that were produced but not consumed yet.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.tail.load(Ordering::Acquire).wrapping_sub(self.head.load(Ordering::Acquire))
+    }
+
+    /// Returns whether all produced bytes were consumed.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Splits the ring into its producer and its consumer.
+    #[inline]
+    pub fn split(&mut self) -> (RingProducer<'_>, RingConsumer<'_>) {
+        (RingProducer { ring: self }, RingConsumer { ring: self })
+    }
+
+    /// Returns a pointer to the byte at the given position in the ring.
+    #[inline]
+    fn at(&self, pos: usize) -> *mut u8 {
+        unsafe { self.ptr.as_ptr().add(pos % self.size) }
+    }
+}
+
+impl Drop for SpscRing {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut c_void, self.size * 2);
+        }
+    }
+}
+
+impl<'a> RingProducer<'a> {
+    /// Returns the free space of the ring, which can be written to before being produced.
+    #[inline]
+    pub fn write(&mut self) -> &mut [u8] {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+        let free = ring.size - tail.wrapping_sub(ring.head.load(Ordering::Acquire));
+
+        unsafe { slice::from_raw_parts_mut(ring.at(tail), free) }
+    }
+
+    /// Makes the first `len` bytes of the free space available to the consumer.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds the free space of the ring.
+    #[inline]
+    pub fn produce(&mut self, len: usize) {
+        let ring = self.ring;
+        let tail = ring.tail.load(Ordering::Relaxed);
+
+        assert!(len <= ring.size - tail.wrapping_sub(ring.head.load(Ordering::Acquire)),
+                "produced more bytes than the ring can hold");
+
+        ring.tail.store(tail.wrapping_add(len), Ordering::Release);
+    }
+}
+
+impl<'a> RingConsumer<'a> {
+    /// Returns the bytes that were produced but not consumed yet.
+    #[inline]
+    pub fn read(&mut self) -> &[u8] {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+        let len = ring.tail.load(Ordering::Acquire).wrapping_sub(head);
+
+        unsafe { slice::from_raw_parts(ring.at(head), len) }
+    }
+
+    /// Gives the first `len` readable bytes back to the producer.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds the number of readable bytes.
+    #[inline]
+    pub fn consume(&mut self, len: usize) {
+        let ring = self.ring;
+        let head = ring.head.load(Ordering::Relaxed);
+
+        assert!(len <= ring.tail.load(Ordering::Acquire).wrapping_sub(head),
+                "consumed more bytes than were produced");
+
+        ring.head.store(head.wrapping_add(len), Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use VirtualAlloc;
+
+    #[test]
+    fn wraps_around_contiguously() {
+        let page = VirtualAlloc::page_size();
+        let mut ring = SpscRing::new(page).unwrap();
+        let (mut producer, mut consumer) = ring.split();
+
+        producer.produce(page - 2);
+        consumer.consume(page - 2);
+
+        let free = producer.write();
+
+        assert_eq!(free.len(), page);
+
+        free[..4].copy_from_slice(b"wrap");
+        producer.produce(4);
+
+        assert_eq!(consumer.read(), b"wrap");
+        assert_eq!(producer.write().len(), page - 4);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn transfers_bytes_between_threads() {
+        use std::thread;
+
+        let mut ring = SpscRing::new(4096).unwrap();
+        let (mut producer, mut consumer) = ring.split();
+        let total = 1_000_000usize;
+
+        thread::scope(|s| {
+            s.spawn(move || {
+                let mut sent = 0;
+
+                while sent < total {
+                    let free = producer.write();
+                    let len = free.len().min(total - sent).min(1000);
+
+                    for (i, b) in free[..len].iter_mut().enumerate() {
+                        *b = (sent + i) as u8;
+                    }
+
+                    producer.produce(len);
+                    sent += len;
+                    thread::yield_now();
+                }
+            });
+
+            let mut received = 0;
+
+            while received < total {
+                let bytes = consumer.read();
+
+                assert!(bytes.iter().enumerate().all(|(i, &b)| b == (received + i) as u8));
+
+                let len = bytes.len();
+
+                consumer.consume(len);
+                received += len;
+                thread::yield_now();
+            }
+        });
+    }
+}
@@ -0,0 +1,283 @@
+//! Storage for secrets such as keys and passwords.
+
+#[cfg(feature = "std")] use std::cell::Cell;
+#[cfg(feature = "std")] use std::marker::PhantomData;
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ptr::{self, NonNull};
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::cell::Cell;
+#[cfg(not(feature = "std"))] use core::marker::PhantomData;
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{get_protection, secure_zero, Opaque, VirtualAlloc};
+
+
+/// A fixed-size buffer meant to hold secrets, such as keys and passwords.
+///
+/// The contents of a `SecretVec` are:
+/// - Locked in physical memory, so that they are never written to swap.
+/// - Excluded from core dumps, where supported.
+/// - Surrounded by inaccessible guard pages, and placed right before the trailing one,
+///   so that overflows fault immediately.
+/// - Inaccessible outside of calls to `read` and `write`, so that stray pointers cannot
+///   read them.
+/// - Zeroed before being unmapped.
+///
+/// This mirrors `sodium_malloc` and friends in libsodium.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::secret::SecretVec;
+///
+/// let key = SecretVec::from_slice(b"hunter2").unwrap();
+///
+/// key.read(|key| assert_eq!(key, b"hunter2"));
+/// ```
+pub struct SecretVec<T: Copy = u8> {
+    base: NonNull<Opaque>,
+    size: usize,
+    data: NonNull<T>,
+    len: usize,
+    /// The number of calls to `read` and `write` in progress, which can be nested.
+    unlocked: Cell<usize>,
+    _marker: PhantomData<T>
+}
+
+unsafe impl<T: Copy + Send> Send for SecretVec<T> {}
+
+impl<T: Copy + Default> SecretVec<T> {
+    /// Returns a `SecretVec` holding `len` default values.
+    ///
+    /// Returns `None` if the memory could not be allocated or locked.
+    pub fn new(len: usize) -> Option<Self> {
+        let mut vec = unsafe { Self::allocate(len)? };
+
+        vec.write(|data| for x in data.iter_mut() { *x = T::default() });
+
+        Some(vec)
+    }
+}
+
+impl<T: Copy> SecretVec<T> {
+    /// Returns a `SecretVec` holding a copy of the given values.
+    ///
+    /// The given values are left untouched, and should be cleared by the caller.
+    ///
+    /// Returns `None` if the memory could not be allocated or locked.
+    pub fn from_slice(values: &[T]) -> Option<Self> {
+        let mut vec = unsafe { Self::allocate(values.len())? };
+
+        vec.write(|data| data.copy_from_slice(values));
+
+        Some(vec)
+    }
+
+    /// Returns the number of values in the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the vector is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Makes the contents of the vector readable, and passes them to the given function.
+    ///
+    /// The contents are made inaccessible again when the function returns (or panics).
+    pub fn read<R, F: FnOnce(&[T]) -> R>(&self, f: F) -> R {
+        let _guard = self.unlock(false);
+
+        f(unsafe { slice::from_raw_parts(self.data.as_ptr(), self.len) })
+    }
+
+    /// Makes the contents of the vector writable, and passes them to the given function.
+    ///
+    /// The contents are made inaccessible again when the function returns (or panics).
+    pub fn write<R, F: FnOnce(&mut [T]) -> R>(&mut self, f: F) -> R {
+        let _guard = self.unlock(true);
+
+        f(unsafe { slice::from_raw_parts_mut(self.data.as_ptr(), self.len) })
+    }
+
+    /// Reserves and locks the memory of a vector of `len` values, leaving it inaccessible.
+    unsafe fn allocate(len: usize) -> Option<Self> {
+        let page = VirtualAlloc::page_size();
+        let bytes = len.checked_mul(mem::size_of::<T>())?;
+        let data_size = bytes.checked_add(page - 1)? / page * page;
+        let data_size = if data_size == 0 { page } else { data_size };
+
+        // Leading guard page, data pages, trailing guard page.
+        let size = data_size.checked_add(2 * page)?;
+        let prot = get_protection(true, true, false);
+        let alloc = VirtualAlloc::with_raw_protection(size, prot);
+        let base = VirtualAlloc::init(ptr::null_mut(), size, prot).ok()?;
+        let pages = base.as_ptr().add(page);
+
+        if alloc.grow(pages, data_size, prot).is_err() || !lock(pages, data_size) {
+            VirtualAlloc::release(base.as_ptr(), size);
+
+            return None
+        }
+
+        // Not every platform supports this, in which case there is nothing else to do.
+        let _ = VirtualAlloc::exclude_from_dumps(NonNull::new_unchecked(pages), data_size);
+
+        // Place the data right before the trailing guard page.
+        let offset = (data_size - bytes) / mem::align_of::<T>() * mem::align_of::<T>();
+        let data = NonNull::new_unchecked(pages.add(offset) as *mut T);
+
+        let vec = SecretVec { base, size, data, len, unlocked: Cell::new(0),
+                              _marker: PhantomData };
+
+        vec.protect(false, false);
+
+        Some(vec)
+    }
+
+    #[inline]
+    fn data_pages(&self) -> (*mut Opaque, usize) {
+        let page = VirtualAlloc::page_size();
+
+        unsafe { (self.base.as_ptr().add(page), self.size - 2 * page) }
+    }
+
+    #[inline]
+    fn protect(&self, read: bool, write: bool) {
+        let (pages, len) = self.data_pages();
+
+        // Failing to change protection leaves the secret either inaccessible (in which case
+        // accessing it will fault), or accessible (which is only less secure).
+        let _ = VirtualAlloc::set_protection(unsafe { NonNull::new_unchecked(pages) }, len,
+                                             read, write, false);
+    }
+
+    /// Makes the contents accessible until the returned guard is dropped.
+    ///
+    /// Only the outermost call changes the protection, so that nested calls to `read`
+    /// leave the contents readable for the calls they are nested in.
+    #[inline]
+    fn unlock(&self, write: bool) -> Relock<'_, T> {
+        if self.unlocked.get() == 0 {
+            self.protect(true, write);
+        }
+
+        self.unlocked.set(self.unlocked.get() + 1);
+
+        Relock { vec: self }
+    }
+}
+
+impl<T: Copy> Drop for SecretVec<T> {
+    fn drop(&mut self) {
+        let (pages, len) = self.data_pages();
+
+        self.protect(true, true);
+
+        unsafe {
+            secure_zero(pages, len);
+            unlock(pages, len);
+
+            VirtualAlloc::release(self.base.as_ptr(), self.size);
+        }
+    }
+}
+
+/// Makes the contents of a `SecretVec` inaccessible again when dropped.
+struct Relock<'a, T: Copy + 'a> {
+    vec: &'a SecretVec<T>
+}
+
+impl<'a, T: Copy> Drop for Relock<'a, T> {
+    fn drop(&mut self) {
+        let unlocked = self.vec.unlocked.get() - 1;
+
+        self.vec.unlocked.set(unlocked);
+
+        if unlocked == 0 {
+            self.vec.protect(false, false);
+        }
+    }
+}
+
+#[cfg(windows)]
+pub(crate) unsafe fn lock(ptr: *mut Opaque, len: usize) -> bool {
+    windows_sys::Win32::System::Memory::VirtualLock(ptr as _, len) != 0
+}
+
+#[cfg(not(windows))]
+pub(crate) unsafe fn lock(ptr: *mut Opaque, len: usize) -> bool {
+    libc::mlock(ptr as _, len) == 0
+}
+
+#[cfg(windows)]
+pub(crate) unsafe fn unlock(ptr: *mut Opaque, len: usize) {
+    windows_sys::Win32::System::Memory::VirtualUnlock(ptr as _, len);
+}
+
+#[cfg(not(windows))]
+pub(crate) unsafe fn unlock(ptr: *mut Opaque, len: usize) {
+    libc::munlock(ptr as _, len);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn can_be_read() {
+        let secret = SecretVec::from_slice(b"hunter2").unwrap();
+
+        assert_eq!(secret.len(), 7);
+
+        secret.read(|data| assert_eq!(data, b"hunter2"));
+    }
+
+    #[test]
+    fn can_be_written() {
+        let mut secret = SecretVec::<u8>::new(4).unwrap();
+
+        secret.read(|data| assert_eq!(data, &[0, 0, 0, 0]));
+        secret.write(|data| data.copy_from_slice(&[1, 2, 3, 4]));
+        secret.read(|data| assert_eq!(data, &[1, 2, 3, 4]));
+    }
+
+    #[test]
+    fn can_be_read_from_nested_reads() {
+        let secret = SecretVec::from_slice(b"hunter2").unwrap();
+
+        let first = secret.read(|outer| {
+            secret.read(|inner| assert_eq!(inner, b"hunter2"));
+
+            // The inner read must not make the contents inaccessible to the outer one.
+            outer[0]
+        });
+
+        assert_eq!(first, b'h');
+        assert_eq!(secret.unlocked.get(), 0);
+    }
+
+    #[test]
+    fn places_data_right_before_the_trailing_guard_page() {
+        let secret = SecretVec::<u64>::new(3).unwrap();
+        let (pages, len) = secret.data_pages();
+
+        secret.read(|data| {
+            assert_eq!(data.as_ptr().addr() + 24, pages.addr() + len);
+        });
+    }
+
+    #[test]
+    fn can_be_empty() {
+        let secret = SecretVec::<u8>::new(0).unwrap();
+
+        assert!(secret.is_empty());
+
+        secret.read(|data| assert!(data.is_empty()));
+    }
+}
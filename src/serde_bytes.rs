@@ -0,0 +1,119 @@
+//! Serialization of byte vectors, enabled by the `serde` feature.
+//!
+//! Like the `serde_bytes` crate, this module can be used with `#[serde(with = "...")]` to
+//! (de)serialize a `VirtualVec<u8>` field as bytes rather than as a sequence of integers.
+//!
+//! Payloads can also be deserialized into an existing vector using `IntoVec`, which commits
+//! memory as bytes are read instead of first loading them in an intermediate `Vec<u8>`,
+//! which halves the peak memory usage of loading large payloads.
+//!
+//! # Example
+//! ```
+//! extern crate serde;
+//! extern crate virtualalloc;
+//!
+//! use serde::de::{DeserializeSeed, IntoDeserializer};
+//! use serde::de::value::{BytesDeserializer, Error};
+//! use virtualalloc::VirtualVec;
+//! use virtualalloc::serde_bytes::IntoVec;
+//!
+//! let mut vec = VirtualVec::<u8>::new(1_000_000);
+//! let payload = BytesDeserializer::<Error>::new(b"large payload");
+//!
+//! IntoVec(&mut vec).deserialize(payload).unwrap();
+//!
+//! assert_eq!(vec.as_slice(), b"large payload");
+//! ```
+
+#[cfg(feature = "std")] use std::fmt;
+
+#[cfg(not(feature = "std"))] use core::fmt;
+
+use serde::{Deserializer, Serializer};
+use serde::de::{DeserializeSeed, Error, SeqAccess, Visitor};
+
+use super::{VirtualBacking, VirtualVec};
+
+
+/// Serializes the given vector as bytes.
+pub fn serialize<B, S>(vec: &VirtualVec<u8, B>, serializer: S) -> Result<S::Ok, S::Error>
+    where B: VirtualBacking, S: Serializer {
+    serializer.serialize_bytes(vec.as_slice())
+}
+
+/// Deserializes bytes into a new vector that can hold up to `default_max_size()` bytes.
+pub fn deserialize<'de, D>(deserializer: D) -> Result<VirtualVec<u8>, D::Error>
+    where D: Deserializer<'de> {
+    let mut vec = VirtualVec::try_new(::default_max_size()).map_err(D::Error::custom)?;
+
+    IntoVec(&mut vec).deserialize(deserializer)?;
+
+    Ok(vec)
+}
+
+/// Deserializes bytes by appending them to the back of an existing vector.
+pub struct IntoVec<'a, B: VirtualBacking + 'a>(pub &'a mut VirtualVec<u8, B>);
+
+impl<'a, 'de, B: VirtualBacking> DeserializeSeed<'de> for IntoVec<'a, B> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_bytes(self)
+    }
+}
+
+impl<'a, 'de, B: VirtualBacking> Visitor<'de> for IntoVec<'a, B> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: Error>(self, bytes: &[u8]) -> Result<(), E> {
+        self.0.reserve(bytes.len()).map_err(E::custom)?;
+        self.0.extend_from_slice(bytes);
+
+        Ok(())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        if let Some(len) = seq.size_hint() {
+            self.0.reserve(len).map_err(A::Error::custom)?;
+        }
+
+        while let Some(byte) = seq.next_element()? {
+            self.0.reserve(1).map_err(A::Error::custom)?;
+            self.0.push(byte);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::de::value::{BytesDeserializer, Error as ValueError, SeqDeserializer};
+
+    #[test]
+    fn appends_to_existing_vectors() {
+        let mut vec = VirtualVec::<u8>::new(1024);
+
+        vec.push(0);
+
+        IntoVec(&mut vec).deserialize(BytesDeserializer::<ValueError>::new(&[1, 2])).unwrap();
+        IntoVec(&mut vec).deserialize(SeqDeserializer::<_, ValueError>::new(3..5u8)).unwrap();
+
+        assert_eq!(vec.as_slice(), &[0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn reports_payloads_that_exceed_the_maximum_capacity() {
+        let mut vec = VirtualVec::<u8>::new(4);
+        let payload = BytesDeserializer::<ValueError>::new(b"too large");
+
+        assert!(IntoVec(&mut vec).deserialize(payload).is_err());
+        assert!(vec.is_empty());
+    }
+}
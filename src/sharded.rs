@@ -0,0 +1,282 @@
+//! An arena split into per-thread shards within a single reservation.
+
+use std::alloc::Layout;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+use super::sync::{AtomicBool, AtomicUsize, Ordering};
+
+
+/// A bump arena whose reservation is split into shards of equal size, which threads
+/// allocate from without contending with each other.
+///
+/// Each shard has its own bump cursor and commits its own memory, and is claimed by a
+/// single thread at a time with `shard`. Since all shards live in the same reservation,
+/// the data they hold remains in a single contiguous range of addresses, which can be
+/// scanned sequentially with `used` once all shards are released.
+///
+/// Memory allocated from the arena is only released when the arena is dropped, and the
+/// values it holds are never dropped.
+///
+/// # Example
+/// ```
+/// use std::thread;
+/// use virtualalloc::ShardedArena;
+///
+/// let mut arena = ShardedArena::new(4, 1 << 20).unwrap();
+///
+/// thread::scope(|s| {
+///     for i in 0..4 {
+///         let mut shard = arena.shard(i).unwrap();
+///
+///         s.spawn(move || {
+///             shard.alloc_slice(&[i as u8; 100]).unwrap();
+///         });
+///     }
+/// });
+///
+/// assert!(arena.used().all(|bytes| bytes.len() == 100));
+/// ```
+pub struct ShardedArena<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    shard_size: usize,
+    shards: Vec<ShardState>
+}
+
+struct ShardState {
+    claimed: AtomicBool,
+    /// The number of bytes allocated from the shard, only updated by its claimant.
+    cursor: AtomicUsize,
+    /// The number of bytes committed in the shard, only updated by its claimant.
+    committed: AtomicUsize
+}
+
+// Shards are only accessed by the thread that claimed them.
+unsafe impl<B: VirtualBacking + Send> Send for ShardedArena<B> {}
+unsafe impl<B: VirtualBacking + Sync> Sync for ShardedArena<B> {}
+
+/// A shard of a `ShardedArena`, claimed by `ShardedArena::shard`.
+///
+/// The shard is released when dropped, and keeps its allocations and cursor for the
+/// next thread that claims it.
+pub struct ArenaShard<'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    arena: &'a ShardedArena<B>,
+    index: usize,
+    _not_sync: PhantomData<*mut u8>
+}
+
+unsafe impl<'a, B: VirtualBacking + Sync> Send for ArenaShard<'a, B> {}
+
+impl ShardedArena {
+    /// Returns an arena with `shards` shards of `shard_size` bytes each, rounded up to
+    /// whole pages.
+    pub fn new(shards: usize, shard_size: usize) -> Result<Self, VirtualMemError> {
+        let size = ::round_to_page(shard_size).saturating_mul(shards);
+
+        ShardedArena::with_backing(shards, shard_size, VirtualAlloc::new(size))
+    }
+}
+
+impl<B: VirtualBacking> ShardedArena<B> {
+    /// Returns an arena with `shards` shards of `shard_size` bytes each, rounded up to
+    /// whole pages, in memory provided by the given backing.
+    pub fn with_backing(shards: usize, shard_size: usize, backing: B)
+        -> Result<Self, VirtualMemError> {
+        let page = backing.page_size();
+        let shard_size = shard_size.div_ceil(page).saturating_mul(page);
+
+        let size = match shard_size.checked_mul(shards) {
+            Some(size) if size <= isize::MAX as usize => size,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: shard_size.saturating_mul(shards), max: isize::MAX as usize
+            })
+        };
+
+        let ptr = backing.reserve(size)?;
+        let shards = (0..shards)
+            .map(|_| ShardState {
+                claimed: AtomicBool::new(false),
+                cursor: AtomicUsize::new(0),
+                committed: AtomicUsize::new(0)
+            })
+            .collect();
+
+        Ok(ShardedArena { backing, ptr, shard_size, shards })
+    }
+
+    /// Returns the number of shards of the arena.
+    #[inline]
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the size of each shard, in bytes.
+    #[inline]
+    pub fn shard_size(&self) -> usize {
+        self.shard_size
+    }
+
+    /// Returns a pointer to the start of the arena, which never changes.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.ptr.as_ptr()
+    }
+
+    /// Claims the shard with the given index, or returns `None` if it is already claimed.
+    ///
+    /// # Panics
+    /// Panics if the index is out of bounds.
+    pub fn shard(&self, index: usize) -> Option<ArenaShard<'_, B>> {
+        self.shards[index].claimed
+            .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .ok()
+            .map(|_| ArenaShard { arena: self, index, _not_sync: PhantomData })
+    }
+
+    /// Returns the bytes allocated from each shard, in the order of the shards.
+    pub fn used(&mut self) -> impl Iterator<Item = &[u8]> {
+        let (ptr, shard_size) = (self.ptr, self.shard_size);
+
+        self.shards.iter().enumerate().map(move |(i, shard)| unsafe {
+            let len = shard.cursor.load(Ordering::Relaxed);
+
+            slice::from_raw_parts(ptr.as_ptr().add(i * shard_size), len)
+        })
+    }
+}
+
+impl<B: VirtualBacking> Drop for ShardedArena<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.shard_size * self.shards.len());
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> ArenaShard<'a, B> {
+    #[inline]
+    fn state(&self) -> &'a ShardState {
+        &self.arena.shards[self.index]
+    }
+
+    /// Returns the index of the shard in its arena.
+    #[inline]
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the number of bytes allocated from the shard.
+    #[inline]
+    pub fn used(&self) -> usize {
+        self.state().cursor.load(Ordering::Relaxed)
+    }
+
+    /// Allocates memory for the given layout from the shard, committing memory as needed.
+    pub fn alloc(&mut self, layout: Layout) -> Result<NonNull<u8>, VirtualMemError> {
+        let arena = self.arena;
+        let state = self.state();
+        let base = unsafe { arena.ptr.as_ptr().add(self.index * arena.shard_size) };
+        let cursor = state.cursor.load(Ordering::Relaxed);
+        let start = cursor + unsafe { base.add(cursor) }.align_offset(layout.align());
+
+        let end = match start.checked_add(layout.size()) {
+            Some(end) if end <= arena.shard_size => end,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: start.saturating_add(layout.size()), max: arena.shard_size
+            })
+        };
+
+        let committed = state.committed.load(Ordering::Relaxed);
+
+        if end > committed {
+            let page = arena.backing.page_size();
+            let size = ::commit_size(committed, end, arena.shard_size, page);
+
+            unsafe {
+                ::commit_delta(&arena.backing, NonNull::new_unchecked(base), committed, size)?;
+            }
+
+            state.committed.store(size, Ordering::Relaxed);
+        }
+
+        state.cursor.store(end, Ordering::Relaxed);
+
+        Ok(unsafe { NonNull::new_unchecked(base.add(start)) })
+    }
+
+    /// Allocates a copy of the given slice from the shard.
+    pub fn alloc_slice<T: Copy>(&mut self, values: &[T]) -> Result<&'a mut [T], VirtualMemError> {
+        let layout = Layout::for_value(values);
+        let ptr = self.alloc(layout)?.cast::<T>().as_ptr();
+
+        unsafe {
+            ptr.copy_from_nonoverlapping(values.as_ptr(), values.len());
+
+            Ok(slice::from_raw_parts_mut(ptr, values.len()))
+        }
+    }
+
+    /// Allocates the given value from the shard. The value is never dropped.
+    pub fn alloc_value<T>(&mut self, value: T) -> Result<&'a mut T, VirtualMemError> {
+        let ptr = self.alloc(Layout::new::<T>())?.cast::<T>().as_ptr();
+
+        unsafe {
+            ptr.write(value);
+
+            Ok(&mut *ptr)
+        }
+    }
+}
+
+impl<'a, B: VirtualBacking> Drop for ArenaShard<'a, B> {
+    fn drop(&mut self) {
+        self.state().claimed.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shards_are_claimed_once() {
+        let arena = ShardedArena::new(2, 1).unwrap();
+        let shard = arena.shard(0);
+
+        assert!(shard.is_some());
+        assert!(arena.shard(0).is_none());
+        assert!(arena.shard(1).is_some());
+
+        drop(shard);
+
+        assert!(arena.shard(0).is_some());
+    }
+
+    #[test]
+    fn allocates_within_shards() {
+        let page = VirtualAlloc::page_size();
+        let mut arena = ShardedArena::new(3, page).unwrap();
+
+        {
+            let mut shard = arena.shard(1).unwrap();
+            let value = shard.alloc_value(7u64).unwrap();
+
+            assert_eq!(*value, 7);
+            assert_eq!(value as *mut u64 as *const u8, arena.as_ptr().wrapping_add(page));
+            assert_eq!(shard.alloc_slice(&[1u8; 3]).unwrap(), &[1, 1, 1]);
+
+            let aligned = shard.alloc(Layout::from_size_align(8, 8).unwrap()).unwrap();
+
+            assert_eq!(aligned.as_ptr().align_offset(8), 0);
+            assert_eq!(shard.used(), 24);
+            assert!(shard.alloc_slice(&vec![0u8; page]).is_err());
+        }
+
+        let used = arena.used().map(|bytes| bytes.len()).collect::<Vec<_>>();
+
+        assert_eq!(used, [0, 24, 0]);
+    }
+}
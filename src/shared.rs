@@ -0,0 +1,339 @@
+//! A vector shared between a single writer and lock-free readers.
+
+#[cfg(feature = "std")] use std::cell::UnsafeCell;
+#[cfg(feature = "std")] use std::ops::Deref;
+#[cfg(feature = "std")] use std::ptr::NonNull;
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::cell::UnsafeCell;
+#[cfg(not(feature = "std"))] use core::ops::Deref;
+#[cfg(not(feature = "std"))] use core::ptr::NonNull;
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError, VirtualVec};
+use super::sync::{AtomicBool, AtomicUsize, Ordering};
+
+
+/// A `VirtualVec` that can be read from many threads while a single writer appends to it.
+///
+/// Elements appended by the writer only become visible to readers once published. Since
+/// elements never move and published elements are never modified, reading them only takes
+/// an atomic load of the published length, and slices handed out to readers remain valid
+/// while the writer keeps appending.
+///
+/// # Example
+/// ```
+/// use std::thread;
+/// use virtualalloc::SharedVirtualVec;
+///
+/// let shared = SharedVirtualVec::<u64>::new(1_000_000);
+///
+/// thread::scope(|s| {
+///     s.spawn(|| {
+///         let mut writer = shared.writer().unwrap();
+///
+///         for i in 0..1000 {
+///             writer.push(i);
+///             writer.publish();
+///         }
+///     });
+///
+///     s.spawn(|| {
+///         let prefix = shared.as_slice();
+///
+///         assert!(prefix.iter().enumerate().all(|(i, &x)| x == i as u64));
+///     });
+/// });
+///
+/// assert_eq!(shared.len(), 1000);
+/// ```
+pub struct SharedVirtualVec<T, B: VirtualBacking = VirtualAlloc> {
+    ptr: NonNull<T>,
+    published: AtomicUsize,
+    writing: AtomicBool,
+    vec: UnsafeCell<VirtualVec<T, B>>
+}
+
+// Readers only access published elements through `ptr`, and the vector itself is only
+// accessed by the single writer, which may live on any thread.
+unsafe impl<T: Send + Sync, B: VirtualBacking + Send> Send for SharedVirtualVec<T, B> {}
+unsafe impl<T: Send + Sync, B: VirtualBacking + Send> Sync for SharedVirtualVec<T, B> {}
+
+/// A view of the elements of a `SharedVirtualVec` that were published when it was taken,
+/// returned by `SharedVirtualVec::snapshot`.
+pub struct Snapshot<'a, T: 'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    shared: &'a SharedVirtualVec<T, B>,
+    slice: &'a [T]
+}
+
+/// The single writer of a `SharedVirtualVec`, returned by `SharedVirtualVec::writer`.
+pub struct SharedWriter<'a, T: 'a, B: VirtualBacking + 'a = VirtualAlloc> {
+    shared: &'a SharedVirtualVec<T, B>
+}
+
+impl<T> SharedVirtualVec<T> {
+    /// Returns a shared vector that can hold up to `max` elements in read-write memory.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved.
+    pub fn new(max: usize) -> Self {
+        SharedVirtualVec::from_vec(VirtualVec::new(max))
+    }
+
+    /// Returns a shared vector that can hold up to `max` elements in read-write memory, or
+    /// an error if the memory could not be reserved.
+    pub fn try_new(max: usize) -> Result<Self, VirtualMemError> {
+        VirtualVec::try_new(max).map(SharedVirtualVec::from_vec)
+    }
+}
+
+impl<T, B: VirtualBacking> SharedVirtualVec<T, B> {
+    /// Returns a shared vector wrapping the given vector, whose elements are published.
+    pub fn from_vec(mut vec: VirtualVec<T, B>) -> Self {
+        let ptr = unsafe { NonNull::new_unchecked(vec.as_mut_ptr()) };
+
+        SharedVirtualVec {
+            ptr,
+            published: AtomicUsize::new(vec.len()),
+            writing: AtomicBool::new(false),
+            vec: UnsafeCell::new(vec)
+        }
+    }
+
+    /// Returns the writer of the vector, or `None` if it is already taken.
+    #[inline]
+    pub fn writer(&self) -> Option<SharedWriter<'_, T, B>> {
+        match self.writing.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed) {
+            Ok(_) => Some(SharedWriter { shared: self }),
+            Err(_) => None
+        }
+    }
+
+    /// Returns the number of published elements.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.published.load(Ordering::Acquire)
+    }
+
+    /// Returns whether no elements were published.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns a slice over the published elements, which remains valid while the writer
+    /// keeps appending.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len()) }
+    }
+
+    /// Returns a consistent view of the elements published so far.
+    ///
+    /// Published elements are never modified, so unlike snapshots of mutable memory, the
+    /// view does not need to copy anything to remain consistent while the writer keeps
+    /// appending and publishing.
+    #[inline]
+    pub fn snapshot(&self) -> Snapshot<'_, T, B> {
+        Snapshot { shared: self, slice: self.as_slice() }
+    }
+
+    /// Returns the underlying vector, including elements that were not published.
+    #[inline]
+    pub fn into_inner(self) -> VirtualVec<T, B> {
+        self.vec.into_inner()
+    }
+}
+
+impl<T, B: VirtualBacking> Deref for SharedVirtualVec<T, B> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<'a, T, B: VirtualBacking> Snapshot<'a, T, B> {
+    /// Returns the elements of the snapshot, which outlive the snapshot itself.
+    #[inline]
+    pub fn as_slice(&self) -> &'a [T] {
+        self.slice
+    }
+
+    /// Returns whether no elements were published since the snapshot was taken.
+    #[inline]
+    pub fn is_current(&self) -> bool {
+        self.shared.len() == self.slice.len()
+    }
+}
+
+impl<'a, T, B: VirtualBacking> Deref for Snapshot<'a, T, B> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.slice
+    }
+}
+
+impl<'a, T, B: VirtualBacking> SharedWriter<'a, T, B> {
+    #[inline]
+    fn vec(&mut self) -> &mut VirtualVec<T, B> {
+        // Only the writer accesses the vector, and it is unique.
+        unsafe { &mut *self.shared.vec.get() }
+    }
+
+    /// Ensures that at least `additional` more elements can be appended without committing
+    /// more memory.
+    #[inline]
+    pub fn reserve(&mut self, additional: usize) -> Result<(), VirtualMemError> {
+        self.vec().reserve(additional)
+    }
+
+    /// Appends an element, which only becomes visible to readers once published.
+    ///
+    /// # Panics
+    /// Panics if the vector is full, or if memory could not be committed.
+    #[inline]
+    pub fn push(&mut self, value: T) {
+        self.vec().push(value)
+    }
+
+    /// Returns the number of elements that were appended but not published yet.
+    #[inline]
+    pub fn pending(&mut self) -> usize {
+        let published = self.shared.published.load(Ordering::Relaxed);
+
+        self.vec().len() - published
+    }
+
+    /// Makes all appended elements visible to readers.
+    #[inline]
+    pub fn publish(&mut self) {
+        let len = self.vec().len();
+
+        self.shared.published.store(len, Ordering::Release);
+    }
+}
+
+impl<'a, T: Clone, B: VirtualBacking> SharedWriter<'a, T, B> {
+    /// Appends all elements of the given slice, which only become visible to readers once
+    /// published.
+    ///
+    /// # Panics
+    /// Panics if the vector cannot hold all elements, or if memory could not be committed.
+    #[inline]
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        self.vec().extend_from_slice(values)
+    }
+}
+
+impl<'a, T, B: VirtualBacking> Drop for SharedWriter<'a, T, B> {
+    fn drop(&mut self) {
+        self.shared.writing.store(false, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, feature = "std"))]
+mod tests {
+    use super::*;
+
+    use std::thread;
+
+    use mock::MockBacking;
+
+    #[test]
+    fn only_has_a_single_writer() {
+        let shared = SharedVirtualVec::<u8>::new(1024);
+        let writer = shared.writer();
+
+        assert!(writer.is_some());
+        assert!(shared.writer().is_none());
+
+        drop(writer);
+
+        assert!(shared.writer().is_some());
+    }
+
+    #[test]
+    fn snapshots_ignore_later_publications() {
+        let shared = SharedVirtualVec::<u8>::new(1024);
+        let mut writer = shared.writer().unwrap();
+
+        writer.extend_from_slice(b"abc");
+        writer.publish();
+
+        let snapshot = shared.snapshot();
+
+        writer.extend_from_slice(b"def");
+
+        assert!(snapshot.is_current());
+
+        writer.publish();
+
+        assert!(!snapshot.is_current());
+        assert_eq!(&*snapshot, b"abc");
+        assert_eq!(shared.as_slice(), b"abcdef");
+    }
+
+    // Built upon a `MockBacking` so that it can run under Miri, which checks that slices
+    // held by readers are not invalidated by the writer committing more memory through its
+    // shared reference.
+    #[test]
+    fn slices_outlive_growth() {
+        let page = VirtualAlloc::page_size();
+        let vec = VirtualVec::<u8, _>::with_backing(page * 4, MockBacking::new());
+        let shared = SharedVirtualVec::from_vec(vec);
+        let mut writer = shared.writer().unwrap();
+
+        writer.extend_from_slice(b"abc");
+        writer.publish();
+
+        let prefix = shared.as_slice();
+
+        writer.reserve(page * 2).unwrap();
+        writer.extend_from_slice(&[7; 100]);
+        writer.publish();
+
+        assert_eq!(prefix, b"abc");
+        assert_eq!(shared.len(), 103);
+        assert_eq!(shared[102], 7);
+    }
+
+    #[test]
+    fn readers_only_see_published_elements() {
+        let shared = SharedVirtualVec::<u32>::new(1_000_000);
+
+        thread::scope(|s| {
+            s.spawn(|| {
+                let mut writer = shared.writer().unwrap();
+
+                for i in 0..100_000 {
+                    writer.push(i);
+
+                    if i % 100 == 99 {
+                        writer.publish();
+                    }
+                }
+
+                writer.push(0);
+                assert_eq!(writer.pending(), 1);
+            });
+
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..100 {
+                        let slice = shared.as_slice();
+
+                        assert_eq!(slice.len() % 100, 0);
+                        assert!(slice.iter().enumerate().all(|(i, &x)| x == i as u32));
+                    }
+                });
+            }
+        });
+
+        assert_eq!(shared.len(), 100_000);
+        assert_eq!(shared.into_inner().len(), 100_001);
+    }
+}
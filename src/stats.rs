@@ -0,0 +1,233 @@
+//! Memory usage statistics of containers.
+
+#[cfg(feature = "std")] use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(not(feature = "std"))] use core::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// A snapshot of the memory used by a container, returned by `VirtualVec::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct VirtualStats {
+    /// The number of bytes of address space reserved.
+    pub reserved: usize,
+    /// The number of bytes currently committed.
+    pub committed: usize,
+    /// The number of committed bytes currently backed by physical memory, if known.
+    pub resident: Option<usize>,
+    /// The largest number of bytes that were committed at once.
+    pub peak_committed: usize,
+    /// The number of times memory was committed.
+    pub commits: usize,
+    /// The number of times memory was decommitted.
+    pub decommits: usize
+}
+
+/// A snapshot of the memory used by all live vectors of the process, returned by
+/// `VirtualAlloc::stats_snapshot`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    /// The number of live vectors.
+    pub live: usize,
+    /// The number of bytes of address space reserved by live vectors.
+    pub reserved: usize,
+    /// The number of bytes currently committed by live vectors.
+    pub committed: usize,
+    /// The largest number of bytes that were committed at once by all vectors together.
+    pub peak_committed: usize,
+    /// The number of times vectors committed memory.
+    pub commits: usize,
+    /// The number of times vectors decommitted memory.
+    pub decommits: usize,
+    /// The number of times vectors failed to commit memory.
+    pub commit_failures: usize
+}
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static RESERVED: AtomicUsize = AtomicUsize::new(0);
+static COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static PEAK_COMMITTED: AtomicUsize = AtomicUsize::new(0);
+static COMMITS: AtomicUsize = AtomicUsize::new(0);
+static DECOMMITS: AtomicUsize = AtomicUsize::new(0);
+static COMMIT_FAILURES: AtomicUsize = AtomicUsize::new(0);
+
+/// Publishes the sizes of live vectors through the `metrics` facade.
+#[cfg(feature = "metrics")]
+fn publish_sizes() {
+    ::metrics::gauge!("virtualalloc_live_vectors").set(LIVE.load(Ordering::Relaxed) as f64);
+    ::metrics::gauge!("virtualalloc_reserved_bytes").set(RESERVED.load(Ordering::Relaxed) as f64);
+    ::metrics::gauge!("virtualalloc_committed_bytes")
+        .set(COMMITTED.load(Ordering::Relaxed) as f64);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+fn publish_sizes() {}
+
+/// Increments the counter with the given name through the `metrics` facade.
+#[cfg(feature = "metrics")]
+fn publish_event(name: &'static str) {
+    ::metrics::counter!(name).increment(1);
+}
+
+#[cfg(not(feature = "metrics"))]
+#[inline(always)]
+fn publish_event(_: &'static str) {}
+
+/// Records that a vector reserved `size` bytes.
+#[inline]
+pub(crate) fn reserved(size: usize) {
+    LIVE.fetch_add(1, Ordering::Relaxed);
+    RESERVED.fetch_add(size, Ordering::Relaxed);
+    publish_sizes();
+}
+
+/// Records that a vector that reserved `size` bytes and committed `committed` bytes was
+/// released.
+#[inline]
+pub(crate) fn released(size: usize, committed: usize) {
+    LIVE.fetch_sub(1, Ordering::Relaxed);
+    RESERVED.fetch_sub(size, Ordering::Relaxed);
+    COMMITTED.fetch_sub(committed, Ordering::Relaxed);
+    publish_sizes();
+}
+
+/// Records that a vector committed `size` more bytes.
+#[inline]
+pub(crate) fn committed(size: usize) {
+    let committed = COMMITTED.fetch_add(size, Ordering::Relaxed) + size;
+
+    COMMITS.fetch_add(1, Ordering::Relaxed);
+    PEAK_COMMITTED.fetch_max(committed, Ordering::Relaxed);
+    publish_sizes();
+    publish_event("virtualalloc_commits_total");
+}
+
+/// Records that a vector failed to commit memory.
+#[inline]
+pub(crate) fn commit_failed() {
+    COMMIT_FAILURES.fetch_add(1, Ordering::Relaxed);
+    publish_event("virtualalloc_commit_failures_total");
+}
+
+/// Records that a vector decommitted `size` bytes.
+#[inline]
+pub(crate) fn decommitted(size: usize) {
+    COMMITTED.fetch_sub(size, Ordering::Relaxed);
+    DECOMMITS.fetch_add(1, Ordering::Relaxed);
+    publish_sizes();
+    publish_event("virtualalloc_decommits_total");
+}
+
+impl StatsSnapshot {
+    /// Captures the memory currently used by all live vectors.
+    ///
+    /// Counters are read one after the other, so a snapshot taken while other threads
+    /// grow or drop vectors may be slightly inconsistent.
+    pub fn capture() -> Self {
+        StatsSnapshot {
+            live: LIVE.load(Ordering::Relaxed),
+            reserved: RESERVED.load(Ordering::Relaxed),
+            committed: COMMITTED.load(Ordering::Relaxed),
+            peak_committed: PEAK_COMMITTED.load(Ordering::Relaxed),
+            commits: COMMITS.load(Ordering::Relaxed),
+            decommits: DECOMMITS.load(Ordering::Relaxed),
+            commit_failures: COMMIT_FAILURES.load(Ordering::Relaxed)
+        }
+    }
+
+    /// Returns the snapshot as a JSON object, whose keys are the names of its fields.
+    #[cfg(feature = "std")]
+    pub fn to_json(&self) -> String {
+        format!("{{\"live\":{},\"reserved\":{},\"committed\":{},\"peak_committed\":{},\
+                 \"commits\":{},\"decommits\":{},\"commit_failures\":{}}}",
+                self.live, self.reserved, self.committed, self.peak_committed, self.commits,
+                self.decommits, self.commit_failures)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn snapshots_are_exported_as_json() {
+        let snapshot = StatsSnapshot {
+            live: 1, reserved: 2, committed: 3, peak_committed: 4, commits: 5, decommits: 6,
+            commit_failures: 7
+        };
+
+        assert_eq!(snapshot.to_json(), concat!(r#"{"live":1,"reserved":2,"committed":3,"#,
+                                               r#""peak_committed":4,"commits":5,"decommits":6,"#,
+                                               r#""commit_failures":7}"#));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn publishes_metrics() {
+        use std::sync::Arc;
+        use std::sync::atomic::AtomicU64;
+
+        use metrics::{Counter, Gauge, Histogram, Key, KeyName, Metadata, Recorder, SharedString,
+                      Unit};
+
+        use {VirtualAlloc, VirtualVec};
+
+        #[derive(Default)]
+        struct Committed {
+            bytes: Arc<AtomicU64>,
+            commits: Arc<AtomicU64>
+        }
+
+        impl Recorder for Committed {
+            fn describe_counter(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_gauge(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+            fn describe_histogram(&self, _: KeyName, _: Option<Unit>, _: SharedString) {}
+
+            fn register_counter(&self, key: &Key, _: &Metadata) -> Counter {
+                match key.name() {
+                    "virtualalloc_commits_total" => Counter::from_arc(self.commits.clone()),
+                    _ => Counter::noop()
+                }
+            }
+
+            fn register_gauge(&self, key: &Key, _: &Metadata) -> Gauge {
+                match key.name() {
+                    "virtualalloc_committed_bytes" => Gauge::from_arc(self.bytes.clone()),
+                    _ => Gauge::noop()
+                }
+            }
+
+            fn register_histogram(&self, _: &Key, _: &Metadata) -> Histogram {
+                Histogram::noop()
+            }
+        }
+
+        let page = VirtualAlloc::page_size();
+        let recorder = Committed::default();
+        let mut vec = VirtualVec::<u8>::new(page * 4);
+
+        metrics::with_local_recorder(&recorder, || vec.reserve(page * 2).unwrap());
+
+        assert_eq!(recorder.commits.load(Ordering::Relaxed), 1);
+        assert!(f64::from_bits(recorder.bytes.load(Ordering::Relaxed)) >= (page * 2) as f64);
+    }
+
+    #[test]
+    fn snapshots_include_live_vectors() {
+        use {VirtualAlloc, VirtualVec};
+
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 1024);
+
+        vec.reserve(page * 512).unwrap();
+
+        // Other tests create vectors concurrently, which prevents exact comparisons.
+        let snapshot = StatsSnapshot::capture();
+
+        assert!(snapshot.live >= 1);
+        assert!(snapshot.reserved >= page * 1024);
+        assert!(snapshot.committed >= page * 512);
+        assert!(snapshot.peak_committed >= snapshot.committed);
+    }
+}
@@ -0,0 +1,271 @@
+//! A buffer of chunks that are decommitted when they go cold, for asset streaming.
+
+use std::collections::BTreeMap;
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// A function called with the index of a chunk that was just committed and its contents,
+/// which are zeroed.
+type LoadHook = Box<dyn FnMut(usize, &mut [u8]) + Send>;
+
+/// A buffer split into chunks of equal size, of which only the most recently used are
+/// committed, within a budget of resident memory.
+///
+/// Chunks are accessed with `touch`, which commits the chunk if needed and makes it the
+/// most recently used. When committing a chunk would exceed the budget, the least
+/// recently used chunks are decommitted to make room for it, and their contents are lost.
+/// The hook given to `on_load` is called whenever a chunk is committed, and can load its
+/// contents again, from disk or from a slower tier of a cache.
+///
+/// Since all chunks live in a single reservation, a chunk always has the same address,
+/// whether it is committed or not.
+///
+/// # Example
+/// ```
+/// use virtualalloc::StreamingBuffer;
+///
+/// // Four chunks of 64KB, of which at most two are resident at once.
+/// let mut textures = StreamingBuffer::new(64 << 10, 4, 128 << 10).unwrap()
+///     .on_load(|chunk, data| data.fill(chunk as u8));
+///
+/// assert_eq!(textures.touch(0).unwrap()[0], 0);
+/// assert_eq!(textures.touch(3).unwrap()[0], 3);
+/// assert_eq!(textures.touch(2).unwrap()[0], 2);
+///
+/// assert!(!textures.is_resident(0));
+/// assert_eq!(textures.resident_size(), 128 << 10);
+/// ```
+pub struct StreamingBuffer<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<u8>,
+    chunk_size: usize,
+    /// The last use of each chunk, or `None` if it is not committed.
+    chunks: Vec<Option<u64>>,
+    /// The committed chunks, by last use.
+    lru: BTreeMap<u64, usize>,
+    /// The number of calls to `touch` so far, which orders uses.
+    clock: u64,
+    budget: usize,
+    on_load: Option<LoadHook>
+}
+
+// Chunks are only accessed through the buffer, which requires `&mut self` to do so.
+unsafe impl<B: VirtualBacking + Send> Send for StreamingBuffer<B> {}
+unsafe impl<B: VirtualBacking + Sync> Sync for StreamingBuffer<B> {}
+
+impl StreamingBuffer {
+    /// Returns a buffer of `chunks` chunks of `chunk_size` bytes each, rounded up to whole
+    /// pages, of which at most `budget` bytes are committed at once.
+    pub fn new(chunk_size: usize, chunks: usize, budget: usize)
+        -> Result<Self, VirtualMemError> {
+        let size = ::round_to_page(chunk_size).saturating_mul(chunks);
+
+        StreamingBuffer::with_backing(chunk_size, chunks, budget, VirtualAlloc::new(size))
+    }
+}
+
+impl<B: VirtualBacking> StreamingBuffer<B> {
+    /// Returns a buffer of `chunks` chunks of `chunk_size` bytes each, rounded up to whole
+    /// pages, of which at most `budget` bytes are committed at once, in memory provided by
+    /// the given backing.
+    pub fn with_backing(chunk_size: usize, chunks: usize, budget: usize, backing: B)
+        -> Result<Self, VirtualMemError> {
+        let page = backing.page_size();
+        let chunk_size = chunk_size.max(1).div_ceil(page).saturating_mul(page);
+
+        let size = match chunk_size.checked_mul(chunks) {
+            Some(size) if size <= isize::MAX as usize => size,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: chunk_size.saturating_mul(chunks), max: isize::MAX as usize
+            })
+        };
+
+        let ptr = backing.reserve(size)?;
+
+        Ok(StreamingBuffer {
+            backing,
+            ptr,
+            chunk_size,
+            chunks: vec![None; chunks],
+            lru: BTreeMap::new(),
+            clock: 0,
+            budget,
+            on_load: None
+        })
+    }
+
+    /// Sets the function called with each chunk that is committed, which can fill it with
+    /// its contents. Chunks are zeroed otherwise.
+    pub fn on_load<F>(mut self, hook: F) -> Self
+        where F: FnMut(usize, &mut [u8]) + Send + 'static {
+        self.on_load = Some(Box::new(hook));
+        self
+    }
+
+    /// Returns the size of each chunk, in bytes.
+    #[inline]
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns the number of chunks of the buffer.
+    #[inline]
+    pub fn chunk_count(&self) -> usize {
+        self.chunks.len()
+    }
+
+    /// Returns the most bytes committed at once.
+    #[inline]
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Sets the most bytes committed at once, decommitting the least recently used chunks
+    /// until the buffer fits in it.
+    pub fn set_budget(&mut self, budget: usize) -> Result<(), VirtualMemError> {
+        self.budget = budget;
+        self.evict(0)
+    }
+
+    /// Returns the number of bytes currently committed.
+    #[inline]
+    pub fn resident_size(&self) -> usize {
+        self.lru.len() * self.chunk_size
+    }
+
+    /// Returns whether the given chunk is committed.
+    #[inline]
+    pub fn is_resident(&self, chunk: usize) -> bool {
+        self.chunks[chunk].is_some()
+    }
+
+    /// Returns a pointer to the start of the given chunk, which never changes.
+    #[inline]
+    pub fn chunk_ptr(&self, chunk: usize) -> NonNull<u8> {
+        assert!(chunk < self.chunks.len());
+
+        unsafe { NonNull::new_unchecked(self.ptr.as_ptr().add(chunk * self.chunk_size)) }
+    }
+
+    /// Makes the given chunk the most recently used, committing and loading it if needed,
+    /// and returns its contents.
+    ///
+    /// Committing a chunk first decommits the least recently used chunks until it fits in
+    /// the budget. A chunk is committed even if it is larger than the budget on its own.
+    ///
+    /// # Panics
+    /// Panics if the chunk is out of bounds.
+    pub fn touch(&mut self, chunk: usize) -> Result<&mut [u8], VirtualMemError> {
+        let ptr = self.chunk_ptr(chunk);
+
+        match self.chunks[chunk] {
+            Some(last_use) => {
+                self.lru.remove(&last_use);
+            },
+            None => {
+                self.evict(self.chunk_size)?;
+
+                unsafe {
+                    self.backing.commit(ptr, self.chunk_size)?;
+                }
+
+                if let Some(ref mut hook) = self.on_load {
+                    unsafe {
+                        hook(chunk, slice::from_raw_parts_mut(ptr.as_ptr(), self.chunk_size));
+                    }
+                }
+            }
+        }
+
+        self.clock += 1;
+        self.chunks[chunk] = Some(self.clock);
+        self.lru.insert(self.clock, chunk);
+
+        Ok(unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), self.chunk_size) })
+    }
+
+    /// Decommits the given chunk, if it is committed.
+    pub fn discard(&mut self, chunk: usize) -> Result<(), VirtualMemError> {
+        let ptr = self.chunk_ptr(chunk);
+
+        if let Some(last_use) = self.chunks[chunk] {
+            unsafe {
+                self.backing.decommit(ptr, self.chunk_size)?;
+            }
+
+            self.chunks[chunk] = None;
+            self.lru.remove(&last_use);
+        }
+
+        Ok(())
+    }
+
+    /// Decommits the least recently used chunks until `additional` more bytes fit in the
+    /// budget, or no chunk is committed.
+    fn evict(&mut self, additional: usize) -> Result<(), VirtualMemError> {
+        while self.resident_size() + additional > self.budget {
+            match self.lru.first_key_value() {
+                Some((_, &chunk)) => self.discard(chunk)?,
+                None => break
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<B: VirtualBacking> Drop for StreamingBuffer<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.ptr, self.chunk_size * self.chunks.len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn decommits_least_recently_used_chunks() {
+        let page = VirtualAlloc::page_size();
+        let loads = Arc::new(Mutex::new(Vec::new()));
+        let mut buffer = {
+            let loads = loads.clone();
+
+            StreamingBuffer::new(page, 4, page * 2).unwrap().on_load(move |chunk, data| {
+                assert!(data.iter().all(|&b| b == 0));
+
+                loads.lock().unwrap().push(chunk);
+                data[0] = chunk as u8 + 1;
+            })
+        };
+
+        buffer.touch(0).unwrap()[1] = 9;
+        buffer.touch(1).unwrap();
+        buffer.touch(0).unwrap();
+        buffer.touch(2).unwrap();
+
+        // Chunk 1 was the least recently used, since chunk 0 was touched again.
+        assert!(buffer.is_resident(0));
+        assert!(!buffer.is_resident(1));
+        assert_eq!(buffer.resident_size(), page * 2);
+        assert_eq!(buffer.touch(0).unwrap()[..2], [1, 9]);
+        assert_eq!(buffer.touch(1).unwrap()[..2], [2, 0]);
+        assert_eq!(*loads.lock().unwrap(), [0, 1, 2, 1]);
+
+        buffer.set_budget(page).unwrap();
+
+        assert!(buffer.is_resident(1));
+        assert_eq!(buffer.resident_size(), page);
+
+        buffer.discard(1).unwrap();
+
+        assert_eq!(buffer.resident_size(), 0);
+    }
+}
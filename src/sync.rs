@@ -0,0 +1,25 @@
+//! Synchronization primitives used by the concurrent containers.
+//!
+//! When building with `--cfg loom`, these are replaced by the primitives of `loom`, which
+//! lets `tests/loom.rs` model-check the protocols of these containers.
+
+#[cfg(all(not(loom), feature = "std"))]
+pub(crate) use std::hint::spin_loop;
+#[cfg(all(not(loom), feature = "std"))]
+pub(crate) use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(all(not(loom), feature = "std"))]
+pub(crate) use std::thread::yield_now;
+
+#[cfg(all(not(loom), not(feature = "std")))]
+pub(crate) use core::hint::spin_loop;
+#[cfg(all(not(loom), not(feature = "std")))]
+pub(crate) use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(all(not(loom), not(feature = "std")))]
+pub(crate) use core::hint::spin_loop as yield_now;
+
+#[cfg(loom)]
+pub(crate) use loom::hint::spin_loop;
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::thread::yield_now;
@@ -0,0 +1,37 @@
+//! Reporting of virtual memory operations to `tracing`, enabled by the `tracing` feature.
+//!
+//! Each operation performed by the `VirtualAlloc` backing runs in a `virtual_memory` span
+//! with the name of the operation and its size, and emits an event once it completes
+//! with its duration, or with its error if it failed. Events are emitted at the `DEBUG`
+//! level on success, and at the `WARN` level on failure.
+
+use super::VirtualMemError;
+
+#[cfg(feature = "tracing")] use std::time::Instant;
+
+
+/// Performs the given operation on `size` bytes of memory, and reports it.
+#[cfg(feature = "tracing")]
+#[inline]
+pub(crate) fn traced<R, F>(op: &'static str, size: usize, f: F) -> Result<R, VirtualMemError>
+    where F: FnOnce() -> Result<R, VirtualMemError> {
+    let _span = tracing::debug_span!("virtual_memory", op, size).entered();
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+
+    match result {
+        Ok(_) => tracing::debug!(?elapsed, "{} of {} bytes completed", op, size),
+        Err(ref error) => tracing::warn!(?elapsed, %error, "{} of {} bytes failed", op, size)
+    }
+
+    result
+}
+
+/// Performs the given operation on `size` bytes of memory.
+#[cfg(not(feature = "tracing"))]
+#[inline(always)]
+pub(crate) fn traced<R, F>(_: &'static str, _: usize, f: F) -> Result<R, VirtualMemError>
+    where F: FnOnce() -> Result<R, VirtualMemError> {
+    f()
+}
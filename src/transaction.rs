@@ -0,0 +1,407 @@
+//! Crash-consistent updates of a memory-mapped file, with shadow paging.
+//!
+//! The file is mapped privately, so that the pages written to during a transaction are
+//! copied by the kernel into shadow pages, while the file itself is left untouched. The
+//! shadow pages are found with a `WriteWatch`. Committing writes them to a journal next to
+//! the file, makes the journal durable, and only then copies them into the file, so that a
+//! crash at any point leaves either the old or the new contents once the journal is
+//! replayed. Aborting drops the shadow pages, which reverts them to the contents of the
+//! file.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr::{self, NonNull};
+use std::slice;
+
+use super::VirtualMemError;
+use super::watch::WriteWatch;
+
+
+/// The magic number at the start of a journal.
+const JOURNAL_MAGIC: [u8; 8] = *b"VVTXJRNL";
+
+/// A region of memory mapped from a file, which is only modified through transactions
+/// that are either applied to the file as a whole or not at all.
+///
+/// Outside of transactions, the region is read-only. `begin` starts a transaction, which
+/// hands out the contents of the region mutably: the first write to each page copies it
+/// into a shadow page, transparently. `Transaction::commit` then writes the shadow pages
+/// to the file, through a journal that makes the update atomic even if the process or
+/// the machine crashes, while `Transaction::abort`, or dropping the transaction, discards
+/// them.
+///
+/// The journal is a file next to the region, with the same name and a `.journal`
+/// extension. Opening a region replays the journal of a transaction that was committed
+/// but not completely applied to the file.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::TransactionalRegion;
+///
+/// let mut region = TransactionalRegion::open("accounts.bin", 1 << 20).unwrap();
+///
+/// let mut tx = region.begin().unwrap();
+///
+/// tx[0] -= 10;
+/// tx[4096] += 10;
+/// tx.commit().unwrap();
+///
+/// let mut tx = region.begin().unwrap();
+///
+/// tx[0] = 0;
+/// tx.abort().unwrap();
+/// ```
+pub struct TransactionalRegion {
+    file: File,
+    journal: PathBuf,
+    ptr: NonNull<u8>,
+    size: usize
+}
+
+// The mapping is only modified through `&mut self`.
+unsafe impl Send for TransactionalRegion {}
+unsafe impl Sync for TransactionalRegion {}
+
+/// A transaction on a `TransactionalRegion`, returned by `TransactionalRegion::begin`,
+/// which dereferences to the contents of the region.
+///
+/// The transaction is aborted if it is dropped without being committed.
+pub struct Transaction<'a> {
+    region: &'a mut TransactionalRegion,
+    watch: Option<WriteWatch>
+}
+
+/// Returns the FNV-1a hash of the given bytes, continuing from the given hash.
+fn checksum(hash: u64, bytes: &[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, &b| (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3))
+}
+
+/// The initial value of a running checksum.
+const CHECKSUM_SEED: u64 = 0xcbf2_9ce4_8422_2325;
+
+impl TransactionalRegion {
+    /// Opens the region stored in the file at the given path, creating it if it does not
+    /// exist, and extending it with zeros to `size` bytes, rounded up to whole pages.
+    ///
+    /// If a transaction was committed but not completely applied to the file, it is
+    /// applied first.
+    pub fn open<P: AsRef<Path>>(path: P, size: usize) -> io::Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false)
+            .open(path)?;
+        let journal = {
+            let mut name = OsString::from(path.as_os_str());
+
+            name.push(".journal");
+            PathBuf::from(name)
+        };
+
+        replay(&file, &journal)?;
+
+        let size = ::round_to_page(size.max(1));
+
+        if file.metadata()?.len() < size as u64 {
+            file.set_len(size as u64)?;
+        }
+
+        let ptr = unsafe {
+            let ptr = libc::mmap(ptr::null_mut(), size, libc::PROT_READ, libc::MAP_PRIVATE,
+                                 file.as_raw_fd(), 0);
+
+            if ptr == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error())
+            }
+
+            NonNull::new_unchecked(ptr.cast())
+        };
+
+        Ok(TransactionalRegion { file, journal, ptr, size })
+    }
+
+    /// Returns the size of the region, in bytes.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Returns whether the region is empty, which it never is.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        false
+    }
+
+    /// Returns the contents of the region.
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.size) }
+    }
+
+    /// Starts a transaction, which tracks the pages written to until it is committed or
+    /// aborted.
+    pub fn begin(&mut self) -> Result<Transaction<'_>, VirtualMemError> {
+        let watch = WriteWatch::new(self.ptr, self.size, self.size)?;
+
+        watch.clear()?;
+
+        Ok(Transaction { region: self, watch: Some(watch) })
+    }
+
+    /// Drops the shadow pages of the given ranges, which reverts them to the contents of
+    /// the file.
+    fn discard(&self, ranges: &[Range<usize>]) -> io::Result<()> {
+        for range in ranges {
+            unsafe {
+                let ptr = self.ptr.as_ptr().add(range.start);
+
+                if libc::madvise(ptr.cast(), range.len(), libc::MADV_DONTNEED) != 0 {
+                    return Err(io::Error::last_os_error())
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the given ranges of the region to the journal, and makes it durable.
+    fn write_journal(&self, ranges: &[Range<usize>]) -> io::Result<()> {
+        let mut journal = io::BufWriter::new(File::create(&self.journal)?);
+        let mut sum = CHECKSUM_SEED;
+        let mut write = |bytes: &[u8]| {
+            sum = checksum(sum, bytes);
+            journal.write_all(bytes)
+        };
+
+        write(&JOURNAL_MAGIC)?;
+        write(&(ranges.len() as u64).to_le_bytes())?;
+
+        for range in ranges {
+            write(&(range.start as u64).to_le_bytes())?;
+            write(&(range.len() as u64).to_le_bytes())?;
+            write(&self.as_slice()[range.clone()])?;
+        }
+
+        journal.write_all(&sum.to_le_bytes())?;
+        journal.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+
+        // The journal must also be found after a crash, before the file is modified.
+        let dir = match self.journal.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new(".")
+        };
+
+        File::open(dir)?.sync_all()
+    }
+}
+
+impl Drop for TransactionalRegion {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr().cast(), self.size);
+        }
+    }
+}
+
+/// Applies the journal at the given path to the file if it is complete, and removes it.
+///
+/// An incomplete journal belongs to a transaction that was not committed, and is ignored.
+fn replay(file: &File, journal: &Path) -> io::Result<()> {
+    let mut bytes = Vec::new();
+
+    match File::open(journal) {
+        Ok(mut journal) => journal.read_to_end(&mut bytes)?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err)
+    };
+
+    if let Some(pages) = parse_journal(&bytes) {
+        for (offset, contents) in pages {
+            file.write_all_at(contents, offset)?;
+        }
+
+        file.sync_data()?;
+    }
+
+    fs::remove_file(journal)
+}
+
+/// Returns the offsets and contents of the pages of the given journal, or `None` if it is
+/// incomplete or corrupted.
+fn parse_journal(bytes: &[u8]) -> Option<Vec<(u64, &[u8])>> {
+    let read_u64 = |bytes: &[u8], at: usize| -> Option<u64> {
+        let mut field = [0; 8];
+
+        field.copy_from_slice(bytes.get(at..at.checked_add(8)?)?);
+
+        Some(u64::from_le_bytes(field))
+    };
+
+    let body = bytes.len().checked_sub(8)?;
+
+    if bytes.get(..8)? != JOURNAL_MAGIC || checksum(CHECKSUM_SEED, &bytes[..body]) !=
+        read_u64(bytes, body)? {
+        return None
+    }
+
+    let count = read_u64(bytes, 8)?;
+    let mut at = 16;
+    let mut pages = Vec::new();
+
+    for _ in 0..count {
+        let offset = read_u64(bytes, at)?;
+        let len = usize::try_from(read_u64(bytes, at + 8)?).ok()?;
+        let end = (at + 16).checked_add(len).filter(|&end| end <= body)?;
+
+        pages.push((offset, &bytes[at + 16..end]));
+        at = end;
+    }
+
+    Some(pages)
+}
+
+impl<'a> Transaction<'a> {
+    /// Returns the ranges of the region written to during the transaction so far.
+    #[inline]
+    pub fn dirty_ranges(&self) -> Vec<Range<usize>> {
+        self.watch.as_ref().map_or_else(Vec::new, |watch| watch.dirty_ranges())
+    }
+
+    /// Applies the writes of the transaction to the file atomically, and waits until they
+    /// are durable.
+    ///
+    /// If committing fails before the journal is durable, the transaction is aborted.
+    /// Otherwise, the journal is replayed the next time the region is opened.
+    pub fn commit(mut self) -> io::Result<()> {
+        let ranges = self.dirty_ranges();
+
+        if ranges.is_empty() {
+            return self.finish(&ranges)
+        }
+
+        self.region.write_journal(&ranges)?;
+
+        for range in &ranges {
+            self.region.file.write_all_at(&self.region.as_slice()[range.clone()],
+                                          range.start as u64)?;
+        }
+
+        self.region.file.sync_data()?;
+        fs::remove_file(&self.region.journal)?;
+
+        // The shadow pages now hold the contents of the file, and can be shared with it
+        // again.
+        self.finish(&ranges)
+    }
+
+    /// Discards the writes of the transaction.
+    pub fn abort(mut self) -> io::Result<()> {
+        let ranges = self.dirty_ranges();
+
+        self.finish(&ranges)
+    }
+
+    /// Drops the shadow pages of the given ranges, and makes the region read-only again.
+    fn finish(&mut self, ranges: &[Range<usize>]) -> io::Result<()> {
+        if let Some(watch) = self.watch.take() {
+            watch.clear()?;
+            self.region.discard(ranges)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a> Deref for Transaction<'a> {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        self.region.as_slice()
+    }
+}
+
+impl<'a> DerefMut for Transaction<'a> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.region.ptr.as_ptr(), self.region.size) }
+    }
+}
+
+impl<'a> Drop for Transaction<'a> {
+    fn drop(&mut self) {
+        let ranges = self.dirty_ranges();
+        let _ = self.finish(&ranges);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+
+    use VirtualAlloc;
+
+    #[test]
+    fn commits_and_aborts_atomically() {
+        let page = VirtualAlloc::page_size();
+        let path = env::temp_dir().join(format!("virtualalloc-tx-{}.bin", std::process::id()));
+        let mut region = TransactionalRegion::open(&path, page * 4).unwrap();
+
+        let mut tx = region.begin().unwrap();
+
+        tx[1] = 1;
+        tx[page * 2] = 2;
+
+        assert_eq!(tx.dirty_ranges(), [(0..page), (page * 2..page * 3)]);
+
+        tx.commit().unwrap();
+
+        let mut tx = region.begin().unwrap();
+
+        tx[1] = 9;
+        tx.abort().unwrap();
+
+        {
+            let mut tx = region.begin().unwrap();
+
+            tx[page * 3] = 9;
+        }
+
+        assert_eq!((region.as_slice()[1], region.as_slice()[page * 3]), (1, 0));
+
+        // A journal that was made durable is applied when the region is opened again, as
+        // if the process crashed before applying it.
+        let mut tx = region.begin().unwrap();
+
+        tx[page] = 3;
+        tx.region.write_journal(&tx.dirty_ranges()).unwrap();
+        drop(tx);
+        drop(region);
+
+        let region = TransactionalRegion::open(&path, page * 4).unwrap();
+
+        assert_eq!(region.len(), page * 4);
+        assert_eq!(region.as_slice()[page], 3);
+        assert!(!region.journal.exists());
+
+        // An incomplete journal is ignored.
+        fs::write(&region.journal, JOURNAL_MAGIC).unwrap();
+        drop(region);
+
+        let region = TransactionalRegion::open(&path, page * 4).unwrap();
+
+        let bytes = fs::read(&path).unwrap();
+
+        assert_eq!(region.as_slice(), &bytes[..]);
+        assert_eq!((bytes[1], bytes[page], bytes[page * 2]), (1, 3, 2));
+
+        drop(region);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,261 @@
+//! Routing of access faults to handlers registered for ranges of memory.
+//!
+//! A single process-wide handler is installed the first time a range is registered: a
+//! `SIGSEGV` (and `SIGBUS`) handler on Unix, and a vectored exception handler on Windows.
+//! When memory faults, the handler looks up the range containing the faulting address,
+//! and calls its handler, which can make the memory accessible and resume execution.
+//! Faults outside registered ranges, or that handlers do not resolve, are forwarded to
+//! the handler that was installed before, or to the default behavior of the system.
+//!
+//! Since handlers run in the context of a fault, ranges are kept in a fixed table of
+//! atomics, which is read without locking or allocating.
+
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
+use super::VirtualMemError;
+
+
+/// A function called with the data of a range and the faulting address, which returns
+/// whether execution can resume.
+pub(crate) type TrapHandler = unsafe fn(*const (), *mut u8) -> bool;
+
+/// The most ranges that can be registered at once.
+const MAX_RANGES: usize = 256;
+
+const FREE: usize = 0;
+const CLAIMED: usize = 1;
+const ACTIVE: usize = 2;
+
+struct Slot {
+    state: AtomicUsize,
+    start: AtomicUsize,
+    end: AtomicUsize,
+    handler: AtomicPtr<()>,
+    data: AtomicPtr<()>
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY: Slot = Slot {
+    state: AtomicUsize::new(FREE),
+    start: AtomicUsize::new(0),
+    end: AtomicUsize::new(0),
+    handler: AtomicPtr::new(ptr::null_mut()),
+    data: AtomicPtr::new(ptr::null_mut())
+};
+
+static SLOTS: [Slot; MAX_RANGES] = [EMPTY; MAX_RANGES];
+
+/// The number of faults being handled, which must drop to zero before the data of an
+/// unregistered range can be released.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+static INSTALL: Once = Once::new();
+
+/// A registered range, which is unregistered when dropped.
+pub(crate) struct Trap {
+    slot: usize
+}
+
+/// Routes faults in `len` bytes starting at `start` to `handler`, which is given `data`.
+///
+/// # Safety
+/// `data` must remain valid until the returned `Trap` is dropped, and the handler must
+/// only perform operations that are safe in a signal handler.
+pub(crate) unsafe fn register(start: *mut u8, len: usize, handler: TrapHandler, data: *const ())
+    -> Result<Trap, VirtualMemError> {
+    INSTALL.call_once(install);
+
+    for (i, slot) in SLOTS.iter().enumerate() {
+        let claimed = slot.state.compare_exchange(FREE, CLAIMED, Ordering::Acquire,
+                                                  Ordering::Relaxed);
+
+        if claimed.is_err() {
+            continue
+        }
+
+        slot.start.store(start.addr(), Ordering::Relaxed);
+        slot.end.store(start.addr() + len, Ordering::Relaxed);
+        slot.handler.store(handler as *mut (), Ordering::Relaxed);
+        slot.data.store(data as *mut (), Ordering::Relaxed);
+        slot.state.store(ACTIVE, Ordering::Release);
+
+        return Ok(Trap { slot: i })
+    }
+
+    Err(VirtualMemError::Unsupported)
+}
+
+/// Stops routing faults in the range starting at `start`, which must have been registered
+/// and then forgotten.
+pub(crate) fn unregister(start: *mut u8) {
+    let slot = SLOTS.iter().position(|slot| {
+        slot.state.load(Ordering::Acquire) == ACTIVE
+            && slot.start.load(Ordering::Relaxed) == start.addr()
+    });
+
+    if let Some(slot) = slot {
+        free(slot);
+    }
+}
+
+/// Deactivates the given slot, and frees it once no handler can be using it.
+fn free(slot: usize) {
+    let slot = &SLOTS[slot];
+
+    slot.state.store(CLAIMED, Ordering::SeqCst);
+
+    // Wait for handlers that may have read the slot before it was deactivated.
+    while IN_FLIGHT.load(Ordering::SeqCst) > 0 {
+        std::thread::yield_now();
+    }
+
+    slot.state.store(FREE, Ordering::Release);
+}
+
+impl Drop for Trap {
+    fn drop(&mut self) {
+        free(self.slot);
+    }
+}
+
+/// Calls the handler of the range containing `addr`, and returns whether it resolved the
+/// fault.
+fn dispatch(addr: *mut u8) -> bool {
+    IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+
+    let resolved = SLOTS.iter().any(|slot| {
+        if slot.state.load(Ordering::SeqCst) != ACTIVE {
+            return false
+        }
+
+        let (start, end) = (slot.start.load(Ordering::Relaxed), slot.end.load(Ordering::Relaxed));
+
+        if addr.addr() < start || addr.addr() >= end {
+            return false
+        }
+
+        unsafe {
+            let handler = slot.handler.load(Ordering::Relaxed);
+            let handler = std::mem::transmute::<*mut (), TrapHandler>(handler);
+
+            handler(slot.data.load(Ordering::Relaxed), addr)
+        }
+    });
+
+    IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+
+    resolved
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::mem;
+    use std::sync::OnceLock;
+
+    use libc::{c_int, c_void, sigaction, siginfo_t};
+
+    static PREVIOUS_SEGV: OnceLock<sigaction> = OnceLock::new();
+    static PREVIOUS_BUS: OnceLock<sigaction> = OnceLock::new();
+
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "illumos",
+              target_os = "solaris"))]
+    unsafe fn fault_address(info: *mut siginfo_t) -> *mut u8 {
+        (*info).si_addr().cast()
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "illumos",
+                  target_os = "solaris")))]
+    unsafe fn fault_address(info: *mut siginfo_t) -> *mut u8 {
+        (*info).si_addr.cast()
+    }
+
+    extern "C" fn handle(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        unsafe {
+            if super::dispatch(fault_address(info)) {
+                return
+            }
+
+            let previous = match signal {
+                libc::SIGBUS => PREVIOUS_BUS.get(),
+                _ => PREVIOUS_SEGV.get()
+            };
+
+            match previous {
+                Some(previous) if previous.sa_sigaction != libc::SIG_DFL
+                               && previous.sa_sigaction != libc::SIG_IGN => {
+                    if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                        let handler = mem::transmute::<
+                            libc::sighandler_t, extern "C" fn(c_int, *mut siginfo_t, *mut c_void)
+                        >(previous.sa_sigaction);
+
+                        handler(signal, info, context)
+                    } else {
+                        let handler = mem::transmute::<libc::sighandler_t, extern "C" fn(c_int)>(
+                            previous.sa_sigaction
+                        );
+
+                        handler(signal)
+                    }
+                },
+                // Restore the default behavior, which kills the process once the faulting
+                // instruction runs again. Ignoring the fault would loop forever instead.
+                _ => {
+                    libc::signal(signal, libc::SIG_DFL);
+                }
+            }
+        }
+    }
+
+    pub(super) fn install() {
+        unsafe {
+            let signals = [(libc::SIGSEGV, &PREVIOUS_SEGV), (libc::SIGBUS, &PREVIOUS_BUS)];
+
+            for (signal, previous) in signals {
+                let mut action: sigaction = mem::zeroed();
+                let mut old: sigaction = mem::zeroed();
+
+                action.sa_sigaction = handle as extern "C" fn(_, _, _) as libc::sighandler_t;
+                action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+                libc::sigemptyset(&mut action.sa_mask);
+
+                if libc::sigaction(signal, &action, &mut old) == 0 {
+                    let _ = previous.set(old);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::ptr;
+
+    use windows_sys::Win32::Foundation::EXCEPTION_ACCESS_VIOLATION;
+    use windows_sys::Win32::System::Diagnostics::Debug::*;
+
+    unsafe extern "system" fn handle(info: *mut EXCEPTION_POINTERS) -> i32 {
+        let record = &*(*info).ExceptionRecord;
+
+        if record.ExceptionCode != EXCEPTION_ACCESS_VIOLATION || record.NumberParameters < 2 {
+            return EXCEPTION_CONTINUE_SEARCH
+        }
+
+        let addr = ptr::with_exposed_provenance_mut(record.ExceptionInformation[1]);
+
+        if super::dispatch(addr) {
+            EXCEPTION_CONTINUE_EXECUTION
+        } else {
+            EXCEPTION_CONTINUE_SEARCH
+        }
+    }
+
+    pub(super) fn install() {
+        unsafe {
+            AddVectoredExceptionHandler(1, Some(handle));
+        }
+    }
+}
+
+use self::imp::install;
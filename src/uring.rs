@@ -0,0 +1,173 @@
+//! Registration of vectors as `io_uring` fixed buffers on Linux.
+//!
+//! Fixed buffers are pinned by the kernel once, which saves mapping them on every
+//! operation. Since a `VirtualVec` never moves, its committed memory can be registered
+//! once and only needs to be registered again when more memory is committed.
+//!
+//! The kernel limits fixed buffers to 1GB each, so the committed memory of a vector is
+//! split into consecutive buffers of `MAX_BUFFER_SIZE` bytes.
+
+use std::{io, mem, ptr};
+use std::os::unix::io::RawFd;
+
+use libc::{self, c_void, iovec};
+
+use super::{VirtualBacking, VirtualVec};
+
+
+/// The maximum size of a single fixed buffer.
+pub const MAX_BUFFER_SIZE: usize = 1 << 30;
+
+const IORING_REGISTER_BUFFERS: libc::c_uint = 0;
+const IORING_UNREGISTER_BUFFERS: libc::c_uint = 1;
+
+unsafe fn register(fd: RawFd, opcode: libc::c_uint, arg: *const c_void, nr_args: libc::c_uint)
+    -> io::Result<()> {
+    if libc::syscall(libc::SYS_io_uring_register, fd, opcode, arg, nr_args) < 0 {
+        return Err(io::Error::last_os_error())
+    }
+
+    Ok(())
+}
+
+/// The committed memory of a vector, registered as the fixed buffers of an `io_uring`.
+///
+/// The buffers must be kept in sync with the vector by calling `sync` after it grows,
+/// and are unregistered when dropped.
+pub struct FixedBuffers {
+    fd: RawFd,
+    base: *mut u8,
+    len: usize
+}
+
+impl FixedBuffers {
+    /// Registers the committed memory of the given vector as the fixed buffers of the
+    /// `io_uring` with the given file descriptor, which must not have any fixed buffers
+    /// registered yet.
+    pub fn register<T, B: VirtualBacking>(fd: RawFd, vec: &VirtualVec<T, B>)
+        -> io::Result<Self> {
+        let mut buffers = FixedBuffers { fd, base: vec.as_ptr() as *mut u8, len: 0 };
+
+        buffers.sync(vec)?;
+
+        Ok(buffers)
+    }
+
+    /// Registers the buffers again if memory has been committed since the last time they
+    /// were registered. Returns whether they were registered again.
+    ///
+    /// # Panics
+    /// Panics if given a different vector than the one the buffers were registered for.
+    pub fn sync<T, B: VirtualBacking>(&mut self, vec: &VirtualVec<T, B>) -> io::Result<bool> {
+        assert_eq!(vec.as_ptr() as *mut u8, self.base, "buffers belong to a different vector");
+
+        let len = vec.capacity() * mem::size_of::<T>();
+
+        if len == self.len {
+            return Ok(false)
+        }
+
+        let iovecs = (0..len.div_ceil(MAX_BUFFER_SIZE))
+            .map(|i| {
+                let offset = i * MAX_BUFFER_SIZE;
+
+                iovec {
+                    iov_base: unsafe { self.base.add(offset) } as *mut c_void,
+                    iov_len: (len - offset).min(MAX_BUFFER_SIZE)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        unsafe {
+            if self.len > 0 {
+                register(self.fd, IORING_UNREGISTER_BUFFERS, ptr::null(), 0)?;
+                self.len = 0;
+            }
+
+            if !iovecs.is_empty() {
+                let (arg, nr_args) = (iovecs.as_ptr() as *const c_void, iovecs.len() as _);
+
+                register(self.fd, IORING_REGISTER_BUFFERS, arg, nr_args)?;
+                self.len = len;
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the index of the fixed buffer that contains the byte at the given offset in
+    /// the vector, and the offset of that byte in the buffer.
+    #[inline]
+    pub fn buffer_index(&self, offset: usize) -> (u16, usize) {
+        ((offset / MAX_BUFFER_SIZE) as u16, offset % MAX_BUFFER_SIZE)
+    }
+
+    /// Returns the number of bytes currently registered.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no memory is currently registered.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Drop for FixedBuffers {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            unsafe {
+                let _ = register(self.fd, IORING_UNREGISTER_BUFFERS, ptr::null(), 0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an `io_uring`, or returns `None` if they are not available (for instance
+    /// because they are disabled by a seccomp filter).
+    fn ring() -> Option<RawFd> {
+        let mut params = [0u8; 120];
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, 4, params.as_mut_ptr()) };
+
+        if fd < 0 { None } else { Some(fd as RawFd) }
+    }
+
+    #[test]
+    fn registers_committed_memory_again_after_growth() {
+        let fd = match ring() {
+            Some(fd) => fd,
+            None => return
+        };
+
+        let mut vec = VirtualVec::<u8>::with_capacity(4096, 1 << 20);
+        let mut buffers = FixedBuffers::register(fd, &vec).unwrap();
+
+        assert_eq!(buffers.len(), 4096);
+        assert!(!buffers.sync(&vec).unwrap());
+
+        vec.reserve(8192).unwrap();
+
+        assert!(buffers.sync(&vec).unwrap());
+        assert_eq!(buffers.len(), 8192);
+
+        drop(buffers);
+
+        unsafe {
+            libc::close(fd);
+        }
+    }
+
+    #[test]
+    fn maps_offsets_to_buffers() {
+        let vec = VirtualVec::<u8>::new(1024);
+        let buffers = FixedBuffers { fd: -1, base: vec.as_ptr() as *mut u8, len: 0 };
+
+        assert_eq!(buffers.buffer_index(MAX_BUFFER_SIZE + 5), (1, 5));
+    }
+}
@@ -0,0 +1,1727 @@
+//! A vector whose elements never move, even as it grows.
+//!
+//! This module only depends on `core` and the platform bindings, and is therefore
+//! available when the `std` feature is disabled. Implementations of `std` traits for
+//! `VirtualVec` (such as `std::io::Write`) are gated behind the `std` feature.
+
+#[cfg(feature = "std")] use std::borrow::{Borrow, BorrowMut};
+#[cfg(feature = "std")] use std::convert::TryFrom;
+#[cfg(feature = "std")] use std::io;
+#[cfg(feature = "std")] use std::mem;
+#[cfg(feature = "std")] use std::ops::{Deref, DerefMut, Range};
+#[cfg(feature = "std")] use std::ptr::{self, NonNull};
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::borrow::{Borrow, BorrowMut};
+#[cfg(not(feature = "std"))] use core::mem;
+#[cfg(not(feature = "std"))] use core::ops::{Deref, DerefMut, Range};
+#[cfg(not(feature = "std"))] use core::ptr::{self, NonNull};
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{secure_zero, Opaque, VirtualAlloc, VirtualBacking, VirtualCursor, VirtualMemError};
+#[cfg(feature = "std")]
+use super::Pod;
+use super::VirtualStats;
+use super::stats;
+#[cfg(not(target_arch = "wasm32"))]
+use super::last_os_error;
+#[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+use super::prefault::{PrefaultAhead, Prefaulter};
+#[cfg(feature = "std")]
+use super::registry::Registration;
+#[cfg(not(target_arch = "wasm32"))]
+use super::secret::{lock, unlock};
+#[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+use super::watch::WriteWatch;
+
+
+/// A contiguous growable array type whose elements are never moved, even as it grows.
+///
+/// A `VirtualVec` reserves enough address space for its maximum capacity when it is
+/// created, and commits memory lazily as elements are added. Pointers to its elements
+/// therefore remain valid for as long as the elements themselves.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::VirtualVec;
+///
+/// let mut vec = VirtualVec::new(1_000_000);
+///
+/// vec.push(1);
+///
+/// let first = &vec[0] as *const i32;
+///
+/// for i in 0..100_000 {
+///     vec.push(i);
+/// }
+///
+/// assert_eq!(&vec[0] as *const i32, first);
+/// ```
+///
+/// The slice a `VirtualVec` dereferences to never moves, even when the vector itself is
+/// moved or grows. With the `stable_deref_trait` feature, `VirtualVec` therefore
+/// implements `StableDeref`, which lets crates such as `owning_ref` or `yoke` build
+/// self-referential structs over it.
+///
+/// Memory is reserved from the operating system by default, but can be provided by any
+/// `VirtualBacking` using `with_backing`.
+pub struct VirtualVec<T, B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    ptr: NonNull<T>,
+    len: usize,
+    cap: usize,
+    max: usize,
+    reservation: (NonNull<u8>, usize),
+    pinned: usize,
+    on_pinned_decommit: Option<fn(*mut u8, usize)>,
+    growth: GrowthPolicy,
+    slack: DecommitSlack,
+    dirty: usize,
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    prefault: Option<PrefaultAhead>,
+    zero_on_drop: bool,
+    peak: usize,
+    commits: usize,
+    decommits: usize,
+    #[cfg(feature = "std")]
+    registration: Option<Registration>,
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    write_watch: Option<WriteWatch>
+}
+
+/// How a `VirtualVec` commits memory when it runs out of capacity.
+///
+/// See `VirtualVec::growth_policy` for more information.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GrowthPolicy {
+    /// Only the pages needed to hold the requested elements are committed, which keeps
+    /// the committed memory as small as possible but makes a system call every time an
+    /// element crosses a page boundary.
+    Exact,
+    /// The committed memory is doubled, but grows by at most `max_ahead` bytes beyond
+    /// what was requested at a time, so that pushing elements one by one only makes a
+    /// logarithmic number of system calls.
+    Geometric {
+        /// The most bytes committed ahead of what was requested.
+        max_ahead: usize
+    }
+}
+
+impl Default for GrowthPolicy {
+    /// Returns a geometric policy that commits at most 64MB ahead at a time.
+    fn default() -> Self {
+        GrowthPolicy::Geometric { max_ahead: ::MAX_COMMIT_AHEAD }
+    }
+}
+
+/// How much unused memory `VirtualVec::truncate` keeps committed beyond the pages that
+/// hold the remaining elements.
+///
+/// Keeping some slack avoids committing and decommitting the same pages over and over
+/// when the length of a vector oscillates, as buffers reused every frame or request do.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecommitSlack {
+    /// Keeps up to the given number of unused pages committed.
+    Pages(usize),
+    /// Keeps up to the given percentage of the memory holding the remaining elements
+    /// committed, in addition to that memory.
+    Percent(usize)
+}
+
+impl Default for DecommitSlack {
+    /// Returns a slack of 25%.
+    fn default() -> Self {
+        DecommitSlack::Percent(25)
+    }
+}
+
+impl GrowthPolicy {
+    /// Returns how many bytes to commit for `requested` bytes when `committed` bytes
+    /// already are.
+    #[inline]
+    pub(crate) fn commit_size(self, committed: usize, requested: usize, max: usize, page: usize)
+        -> usize {
+        let max_ahead = match self {
+            GrowthPolicy::Exact => 0,
+            GrowthPolicy::Geometric { max_ahead } => max_ahead
+        };
+
+        ::commit_size_ahead(committed, requested, max, page, max_ahead)
+    }
+}
+
+/// A range of memory that is committed, locked in physical memory and page-aligned.
+///
+/// See `VirtualVec::pin` for more information.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PinnedRange {
+    /// The start of the range, which is aligned on a page boundary.
+    pub ptr: *mut u8,
+    /// The size of the range in bytes, which is a multiple of the page size.
+    pub len: usize
+}
+
+impl<T> Default for VirtualVec<T> {
+    /// Returns a `VirtualVec` that can hold up to `default_max_size()` bytes of elements
+    /// in read-write memory.
+    fn default() -> Self {
+        VirtualVec::new(::default_max_size() / mem::size_of::<T>().max(1))
+    }
+}
+
+impl<T> VirtualVec<T> {
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved.
+    pub fn new(max: usize) -> Self {
+        Self::try_new(max).unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory,
+    /// or an error if the memory could not be reserved.
+    pub fn try_new(max: usize) -> Result<Self, VirtualMemError> {
+        let size = max.checked_mul(mem::size_of::<T>()).ok_or(VirtualMemError::CapacityOverflow)?;
+
+        VirtualVec::try_with_backing(max, VirtualAlloc::new(size))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory, and
+    /// that can already hold `capacity` elements without committing more memory.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved or committed.
+    pub fn with_capacity(capacity: usize, max: usize) -> Self {
+        Self::try_with_capacity(capacity, max)
+            .unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory, and
+    /// that can already hold `capacity` elements without committing more memory, or an
+    /// error if the memory could not be reserved or committed.
+    pub fn try_with_capacity(capacity: usize, max: usize) -> Result<Self, VirtualMemError> {
+        let mut vec = Self::try_new(max)?;
+
+        vec.reserve(capacity)?;
+
+        Ok(vec)
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in memory with the given
+    /// protection.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved, or if it was requested to be both
+    /// writable and executable in strict W^X mode.
+    pub fn with_protection(max: usize, read: bool, write: bool, exec: bool) -> Self {
+        Self::try_with_protection(max, read, write, exec)
+            .unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in memory with the given
+    /// protection, or an error if the memory could not be reserved.
+    ///
+    /// Fails with `WxViolation` if the memory was requested to be both writable and
+    /// executable in strict W^X mode.
+    pub fn try_with_protection(max: usize, read: bool, write: bool, exec: bool)
+        -> Result<Self, VirtualMemError> {
+        let size = max.checked_mul(mem::size_of::<T>()).ok_or(VirtualMemError::CapacityOverflow)?;
+        let backing = VirtualAlloc::try_with_protection(size, read, write, exec)?;
+
+        VirtualVec::try_with_backing(max, backing)
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory, and
+    /// whose first element is aligned on `align` bytes.
+    ///
+    /// Combined with `pin`, this makes the vector suitable as a staging buffer shared with
+    /// GPU runtimes (through `cudaHostRegister` or Vulkan host memory import).
+    ///
+    /// # Panics
+    /// Panics if `align` is not a power of two, or if the memory could not be reserved.
+    pub fn with_alignment(max: usize, align: usize) -> Self {
+        Self::try_with_alignment(max, align)
+            .unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in read-write memory, and
+    /// whose first element is aligned on `align` bytes, or an error if the memory could
+    /// not be reserved.
+    ///
+    /// Fails with `InvalidAlignment` if `align` is not a power of two.
+    pub fn try_with_alignment(max: usize, align: usize) -> Result<Self, VirtualMemError> {
+        let size = max.checked_mul(mem::size_of::<T>()).ok_or(VirtualMemError::CapacityOverflow)?;
+
+        VirtualVec::try_with_backing_aligned(max, align, VirtualAlloc::new(size))
+    }
+
+    /// Starts tracking the pages of the vector that are written to, which are then
+    /// returned by `dirty_since_checkpoint` until `clear_dirty` is called.
+    ///
+    /// Pages are tracked by making them read-only when they are cleaned, and catching the
+    /// first write to each of them in a fault handler, which makes the page writable again.
+    /// Each page therefore costs one fault per checkpoint when written to, and the vector
+    /// costs one bit per page of its maximum size. Until `clear_dirty` is first called,
+    /// all committed pages are dirty.
+    ///
+    /// Memory written to by the operating system, such as by a `read` system call, is not
+    /// caught by the handler, and the system call fails instead: such buffers must be
+    /// written to beforehand.
+    ///
+    /// Fails with `Unsupported` if the allocator of the vector commits memory on fault,
+    /// or if too many ranges of memory are tracked at once.
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    pub fn watch_writes(&mut self) -> Result<(), VirtualMemError> {
+        if self.write_watch.is_some() {
+            return Ok(())
+        }
+        if self.backing.commits_on_fault() {
+            return Err(VirtualMemError::Unsupported)
+        }
+
+        let committed = self.cap * mem::size_of::<T>();
+
+        self.write_watch = Some(WriteWatch::new(self.ptr.cast(), self.max, committed)?);
+
+        Ok(())
+    }
+
+    /// Returns the ranges of committed pages written to since the last call to
+    /// `clear_dirty`, as byte offsets from the start of the vector.
+    ///
+    /// Adjacent pages are merged into a single range, so that incremental backups or
+    /// replicas of a large vector only copy what changed. If writes are not tracked with
+    /// `watch_writes`, all committed pages are returned.
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    pub fn dirty_since_checkpoint(&self) -> Vec<Range<usize>> {
+        match self.write_watch {
+            Some(ref watch) => watch.dirty_ranges(),
+            None => self.committed_ranges().collect()
+        }
+    }
+
+    /// Marks all pages of the vector clean, so that `dirty_since_checkpoint` only returns
+    /// the pages written to from now on. Does nothing if writes are not tracked.
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    pub fn clear_dirty(&mut self) -> Result<(), VirtualMemError> {
+        match self.write_watch {
+            Some(ref watch) => watch.clear(),
+            None => Ok(())
+        }
+    }
+
+    /// Excludes the whole reservation of the vector from core dumps and crash reports,
+    /// including memory that will only be committed later.
+    ///
+    /// See `VirtualAlloc::exclude_from_dumps` for more information.
+    #[inline]
+    pub fn exclude_from_dumps(&self) -> Result<(), VirtualMemError> {
+        let (base, size) = self.reservation;
+
+        // Vectors of zero-sized elements reserve nothing.
+        if size == 0 {
+            return Ok(())
+        }
+
+        VirtualAlloc::exclude_from_dumps(base, size)
+    }
+}
+
+impl<T, B: VirtualBacking> VirtualVec<T, B> {
+    /// Returns a `VirtualVec` that can hold up to `max` elements in memory provided by
+    /// the given backing.
+    ///
+    /// # Panics
+    /// Panics if the memory could not be reserved.
+    pub fn with_backing(max: usize, backing: B) -> Self {
+        Self::try_with_backing(max, backing)
+            .unwrap_or_else(|err| panic!("Could not reserve memory: {}.", err))
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in memory provided by
+    /// the given backing, or an error if the memory could not be reserved.
+    pub fn try_with_backing(max: usize, backing: B) -> Result<Self, VirtualMemError> {
+        Self::try_with_backing_aligned(max, 1, backing)
+    }
+
+    /// Returns a `VirtualVec` that can hold up to `max` elements in memory provided by
+    /// the given backing, and whose first element is aligned on `align` bytes, or an error
+    /// if the memory could not be reserved.
+    ///
+    /// Reservations are always aligned on pages, but some consumers of host memory require
+    /// larger alignments, such as Vulkan drivers importing host memory with
+    /// `VK_EXT_external_memory_host`.
+    ///
+    /// Zero-sized elements take no memory, so vectors of them reserve nothing and can hold
+    /// `usize::MAX` elements, whatever `max` is.
+    ///
+    /// Fails with `CapacityOverflow` if `max` elements take more than `usize::MAX` bytes,
+    /// and with `InvalidAlignment` if `align` is not a power of two.
+    pub fn try_with_backing_aligned(max: usize, align: usize, backing: B)
+        -> Result<Self, VirtualMemError> {
+        if !align.is_power_of_two() {
+            return Err(VirtualMemError::InvalidAlignment)
+        }
+
+        let (ptr, cap, max, reservation) = match mem::size_of::<T>() {
+            0 => (NonNull::dangling(), usize::MAX, 0, (NonNull::dangling(), 0)),
+            elem_size => {
+                let max = max.checked_mul(elem_size).ok_or(VirtualMemError::CapacityOverflow)?;
+                let slack = if align > backing.page_size() { align } else { 0 };
+                let size = max.checked_add(slack).ok_or(VirtualMemError::CapacityOverflow)?;
+                let base = backing.reserve(size)?;
+
+                stats::reserved(size);
+
+                let offset = base.as_ptr().align_offset(align);
+                let ptr = unsafe { NonNull::new_unchecked(base.as_ptr().add(offset)) }.cast();
+
+                (ptr, 0, max, (base, size))
+            }
+        };
+
+        #[cfg(feature = "std")]
+        let registration = Registration::new(reservation.1);
+
+        #[cfg(feature = "std")]
+        if let (Some(registration), Some(label)) = (&registration, backing.label()) {
+            registration.set_label(label);
+        }
+
+        Ok(VirtualVec {
+            backing, ptr, len: 0, cap, max, reservation, pinned: 0,
+            on_pinned_decommit: None, growth: GrowthPolicy::default(),
+            slack: DecommitSlack::default(), dirty: 0,
+            #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+            prefault: None,
+            zero_on_drop: false, peak: 0, commits: 0, decommits: 0,
+            #[cfg(feature = "std")]
+            registration,
+            #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+            write_watch: None
+        })
+    }
+
+    /// Returns statistics about the memory used by the vector.
+    ///
+    /// Finding out how much of the committed memory is resident takes a system call.
+    pub fn stats(&self) -> VirtualStats {
+        let committed = self.cap * mem::size_of::<T>();
+
+        VirtualStats {
+            reserved: self.reservation.1,
+            committed,
+            resident: VirtualAlloc::resident_size(self.ptr, committed),
+            peak_committed: self.peak,
+            commits: self.commits,
+            decommits: self.decommits
+        }
+    }
+
+    /// Returns the ranges of committed pages of the vector, as byte offsets from its start.
+    ///
+    /// Memory is always committed from the start of a vector, so there is at most one
+    /// range, which spans whole pages and may therefore extend past its capacity. Code that
+    /// must walk the pages that hold data, such as serializers or checkpointers, can use
+    /// it instead of scanning the whole reservation.
+    pub fn committed_ranges(&self) -> impl Iterator<Item = Range<usize>> {
+        let page = self.backing.page_size();
+        let committed = (self.cap * mem::size_of::<T>()).div_ceil(page) * page;
+
+        Some(0..committed).filter(|range| !range.is_empty()).into_iter()
+    }
+
+    /// Returns a map of the reservation of the vector, with one character per page and
+    /// 64 pages per line, which helps finding out why more memory is resident than
+    /// expected, or why a protection change went wrong.
+    ///
+    /// Pages are described by the following characters:
+    ///
+    /// - `.`: reserved, but not committed.
+    /// - `w`: committed, and readable and writable.
+    /// - `r`: committed, and only readable.
+    /// - `x`: committed, and executable.
+    /// - `#`: committed, but inaccessible, such as guard pages.
+    ///
+    /// The state of pages is queried from the operating system, which is only supported
+    /// on Linux and Windows.
+    #[cfg(feature = "std")]
+    pub fn dump_page_map(&self) -> Result<String, VirtualMemError> {
+        let (base, size) = self.reservation;
+        let offset = self.ptr.as_ptr().cast::<u8>().addr() - base.as_ptr().addr();
+
+        super::pagemap::dump(base, size, offset + self.cap * mem::size_of::<T>())
+    }
+
+    /// Records that memory was committed while the capacity of the vector was `old_cap`.
+    #[inline]
+    fn committed(&mut self, old_cap: usize) {
+        let size = self.cap * mem::size_of::<T>();
+
+        self.peak = self.peak.max(size);
+        self.commits += 1;
+
+        stats::committed(size - old_cap * mem::size_of::<T>());
+
+        #[cfg(feature = "std")]
+        if let Some(ref registration) = self.registration {
+            registration.set_committed(size);
+        }
+
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if let Some(ref watch) = self.write_watch {
+            watch.committed(old_cap * mem::size_of::<T>(), size);
+        }
+    }
+
+    /// Returns the backing that provides the memory of the vector.
+    #[inline]
+    pub fn backing(&self) -> &B {
+        &self.backing
+    }
+
+    /// Specifies whether all committed memory must be zeroed before being released
+    /// when the vector is dropped.
+    ///
+    /// Zeroing is performed in a way that cannot be optimized away, which makes this
+    /// well-suited for vectors that transiently hold key material or personal data.
+    #[inline]
+    pub fn zero_on_drop(mut self, enabled: bool) -> Self {
+        self.zero_on_drop = enabled;
+        self
+    }
+
+    /// Specifies how memory is committed when the vector runs out of capacity.
+    ///
+    /// By default, the committed memory doubles every time it runs out, up to 64MB at a
+    /// time, so that pushing a million elements only commits memory about twenty times.
+    /// `GrowthPolicy::Exact` instead commits as little memory as possible.
+    #[inline]
+    pub fn growth_policy(mut self, policy: GrowthPolicy) -> Self {
+        self.growth = policy;
+        self
+    }
+
+    /// Gives a label to the vector, under which it is listed by `VirtualAlloc::live_regions`
+    /// if it was created while the registry was enabled.
+    #[cfg(feature = "std")]
+    #[inline]
+    pub fn label(self, label: &'static str) -> Self {
+        if let Some(ref registration) = self.registration {
+            registration.set_label(label);
+        }
+
+        self
+    }
+
+    /// Specifies how much unused memory `truncate` keeps committed.
+    #[inline]
+    pub fn decommit_slack(mut self, slack: DecommitSlack) -> Self {
+        self.slack = slack;
+        self
+    }
+
+    /// Returns the number of elements in the vector.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the vector is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of elements the vector can hold without committing more memory.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.cap
+    }
+
+    /// Returns the absolute maximum capacity of the vector.
+    #[inline]
+    pub fn max_capacity(&self) -> usize {
+        match mem::size_of::<T>() {
+            0 => usize::MAX,
+            elem_size => self.max / elem_size
+        }
+    }
+
+    /// Returns a pointer to the start of the vector, which never changes.
+    #[inline]
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns a mutable pointer to the start of the vector, which never changes.
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut T {
+        self.ptr.as_ptr()
+    }
+
+    /// Returns a slice over the elements of the vector.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Returns a mutable slice over the elements of the vector.
+    #[inline]
+    pub fn as_mut_slice(&mut self) -> &mut [T] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Sets the length of the vector.
+    ///
+    /// # Safety
+    /// `len` may not exceed the capacity of the vector, and the elements up to `len` must
+    /// be initialized.
+    #[inline]
+    pub unsafe fn set_len(&mut self, len: usize) {
+        debug_assert!(len <= self.cap);
+
+        self.len = len;
+        self.appended();
+    }
+
+    /// Makes the given prefaulter fault in the `pages` pages that follow the end of the
+    /// vector ahead of time, committing them first if needed, whenever the vector grows
+    /// past the pages it already faulted in.
+    ///
+    /// Giving zero pages stops prefaulting. See the `prefault` module for more information.
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    pub fn prefault_ahead(&mut self, prefaulter: &Prefaulter, pages: usize) {
+        if pages == 0 || mem::size_of::<T>() == 0 {
+            self.prefault = None;
+
+            return
+        }
+
+        let ahead = pages.saturating_mul(self.backing.page_size());
+
+        self.prefault = Some(PrefaultAhead { sender: prefaulter.sender(), ahead, until: 0 });
+        self.appended();
+    }
+
+    /// Records that elements were appended, and keeps prefaulting ahead of the end of the
+    /// vector.
+    #[inline(always)]
+    fn appended(&mut self) {
+        let end = self.len * mem::size_of::<T>();
+
+        // Memory past `dirty` has never held elements, and is therefore still zeroed.
+        self.dirty = self.dirty.max(end);
+
+        #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+        {
+            if self.prefault.as_ref().is_some_and(|prefault| end >= prefault.until) {
+                self.prefault_next(end);
+            }
+        }
+    }
+
+    #[cfg(all(feature = "std", not(target_arch = "wasm32")))]
+    #[cold]
+    fn prefault_next(&mut self, end: usize) {
+        let elem_size = mem::size_of::<T>();
+        let page = self.backing.page_size();
+        let start = end / page * page;
+        let ahead = self.prefault.as_ref().map_or(0, |prefault| prefault.ahead);
+
+        // Only committed pages can be faulted in.
+        let _ = self.reserve(start.saturating_add(ahead).saturating_sub(end).div_ceil(elem_size));
+
+        let target = start.saturating_add(ahead).min(self.cap * elem_size);
+        let base = self.ptr.as_ptr() as *mut u8;
+
+        if let Some(ref mut prefault) = self.prefault {
+            match target > end {
+                true => prefault.request(base, start, target),
+                false => prefault.until = end + 1
+            }
+        }
+    }
+
+    /// Ensures that the vector can hold at least `additional` more elements without
+    /// committing more memory.
+    ///
+    /// Fails with `CapacityOverflow` if the vector would hold more than `usize::MAX`
+    /// elements or bytes, with `ExceedsMax` if this would exceed the maximum capacity of the
+    /// vector, and with `CommitFailed` if memory could not be committed.
+    pub fn reserve(&mut self, additional: usize) -> Result<(), VirtualMemError> {
+        let min = self.len.checked_add(additional).ok_or(VirtualMemError::CapacityOverflow)?;
+
+        if min <= self.cap {
+            return Ok(())
+        }
+
+        // Sizes overflow easily on 32-bit targets, and must not wrap around.
+        let size = min.checked_mul(mem::size_of::<T>()).ok_or(VirtualMemError::CapacityOverflow)?;
+
+        if size > self.max {
+            return Err(VirtualMemError::ExceedsMax { requested: size, max: self.max })
+        }
+
+        let committed = self.cap * mem::size_of::<T>();
+        let size = self.growth.commit_size(committed, size, self.max, self.backing.page_size());
+
+        unsafe {
+            if let Err(err) = ::commit_delta(&self.backing, self.ptr.cast(), committed, size) {
+                stats::commit_failed();
+
+                return Err(err)
+            }
+        }
+
+        let old_cap = self.cap;
+
+        self.cap = match mem::size_of::<T>() {
+            0 => min,
+            elem_size => size / elem_size
+        };
+        self.committed(old_cap);
+
+        Ok(())
+    }
+
+    /// Ensures that the vector can hold at least `additional` more elements without
+    /// committing more memory.
+    ///
+    /// # Panics
+    /// Panics if this would exceed the maximum capacity of the vector, or if memory could
+    /// not be committed.
+    #[inline]
+    pub fn reserve_or_panic(&mut self, additional: usize) {
+        if let Err(err) = self.reserve(additional) {
+            panic!("Could not reserve memory: {}.", err)
+        }
+    }
+
+    /// Appends an element to the back of the vector.
+    ///
+    /// # Panics
+    /// Panics if the vector is full, or if memory could not be committed.
+    pub fn push(&mut self, value: T) {
+        if self.len == self.cap {
+            self.reserve_or_panic(1);
+        }
+
+        unsafe {
+            ptr::write(self.ptr.as_ptr().add(self.len), value);
+        }
+
+        self.len += 1;
+        self.appended();
+    }
+
+    /// Removes the last element of the vector and returns it, or returns `None` if the
+    /// vector is empty.
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None
+        }
+
+        self.len -= 1;
+
+        unsafe {
+            Some(ptr::read(self.ptr.as_ptr().add(self.len)))
+        }
+    }
+
+    /// Commits at least the first `size` bytes of the vector, and locks them in physical
+    /// memory so that devices can access them directly.
+    ///
+    /// The returned range is page-aligned and spans whole pages, which makes it suitable for
+    /// registration with RDMA (`ibv_reg_mr`) or GPU (`cudaHostRegister`) runtimes. Pinned
+    /// memory is never decommitted by the vector; the callback given to
+    /// `on_pinned_decommit` is called instead, as well as before the vector is dropped.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn pin(&mut self, size: usize) -> Result<PinnedRange, VirtualMemError> {
+        let page = self.backing.page_size();
+        let len = size.div_ceil(page).checked_mul(page).ok_or(VirtualMemError::CapacityOverflow)?;
+
+        if len > self.max {
+            return Err(VirtualMemError::ExceedsMax { requested: len, max: self.max })
+        }
+
+        unsafe {
+            ::commit_delta(&self.backing, self.ptr.cast(), self.cap * mem::size_of::<T>(), len)?;
+
+            let old_cap = self.cap;
+
+            if mem::size_of::<T>() > 0 {
+                self.cap = self.cap.max(len / mem::size_of::<T>());
+            }
+
+            if self.cap != old_cap {
+                self.committed(old_cap);
+            }
+
+            if !lock(self.ptr.as_ptr() as *mut Opaque, len) {
+                return Err(VirtualMemError::LockFailed { os_err: last_os_error() })
+            }
+        }
+
+        self.pinned = self.pinned.max(len);
+
+        Ok(PinnedRange { ptr: self.ptr.as_ptr() as *mut u8, len: self.pinned })
+    }
+
+    /// Returns the range pinned by `pin`, or `None` if no memory is pinned.
+    #[inline]
+    pub fn pinned_range(&self) -> Option<PinnedRange> {
+        if self.pinned == 0 {
+            return None
+        }
+
+        Some(PinnedRange { ptr: self.ptr.as_ptr() as *mut u8, len: self.pinned })
+    }
+
+    /// Unlocks the memory pinned by `pin`, which can then be decommitted again.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn unpin(&mut self) {
+        if self.pinned > 0 {
+            unsafe {
+                unlock(self.ptr.as_ptr() as *mut Opaque, self.pinned);
+            }
+
+            self.pinned = 0;
+        }
+    }
+
+    /// Sets a function called with the pinned range that would have been decommitted
+    /// whenever the vector keeps pinned memory committed, and with the whole pinned range
+    /// right before the vector is dropped.
+    ///
+    /// Users that registered pinned memory with a device should deregister it then.
+    #[inline]
+    pub fn on_pinned_decommit(&mut self, callback: fn(*mut u8, usize)) {
+        self.on_pinned_decommit = Some(callback);
+    }
+
+    /// Decommits the whole pages that are not needed to hold the elements of the vector,
+    /// unless they are pinned.
+    pub fn shrink_to_fit(&mut self) -> Result<(), VirtualMemError> {
+        let page = self.backing.page_size();
+        let fit = (self.len * mem::size_of::<T>()).div_ceil(page) * page;
+
+        self.decommit_from(fit)
+    }
+
+    /// Shortens the vector to `len` elements, dropping the others.
+    ///
+    /// Whole pages that are no longer needed are decommitted, except for the slack
+    /// configured with `decommit_slack` (25% by default), and unless they are pinned.
+    /// Memory that could not be decommitted simply stays committed.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len {
+            return
+        }
+
+        let old_len = self.len;
+
+        self.len = len;
+
+        unsafe {
+            let tail = self.ptr.as_ptr().add(len);
+
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(tail, old_len - len));
+        }
+
+        let page = self.backing.page_size();
+        let used = (len * mem::size_of::<T>()).div_ceil(page) * page;
+        let slack = match self.slack {
+            DecommitSlack::Pages(pages) => pages.saturating_mul(page),
+            DecommitSlack::Percent(percent) => (used / 100).saturating_mul(percent)
+        };
+        let keep = used.saturating_add(slack).div_ceil(page).saturating_mul(page);
+
+        if keep < self.cap * mem::size_of::<T>() {
+            let _ = self.decommit_from(keep);
+        }
+    }
+
+    /// Decommits the memory past the first `fit` bytes, which must be a multiple of the
+    /// page size, unless it is pinned.
+    fn decommit_from(&mut self, fit: usize) -> Result<(), VirtualMemError> {
+        let page = self.backing.page_size();
+        let size = self.cap * mem::size_of::<T>();
+        let needed = fit.max(self.pinned);
+
+        if fit < self.pinned && fit < size {
+            if let Some(callback) = self.on_pinned_decommit {
+                let kept = self.pinned.min(size.div_ceil(page) * page) - fit;
+
+                callback(unsafe { self.ptr.as_ptr().cast::<u8>().add(fit) }, kept);
+            }
+        }
+
+        if needed >= size {
+            return Ok(())
+        }
+
+        unsafe {
+            let start = NonNull::new_unchecked(self.ptr.as_ptr().cast::<u8>().add(needed));
+
+            self.backing.decommit(start, size.div_ceil(page) * page - needed)?;
+        }
+
+        self.cap = needed / mem::size_of::<T>();
+        self.dirty = self.dirty.min(needed);
+        self.decommits += 1;
+
+        stats::decommitted(size - self.cap * mem::size_of::<T>());
+
+        #[cfg(feature = "std")]
+        if let Some(ref registration) = self.registration {
+            registration.set_committed(self.cap * mem::size_of::<T>());
+        }
+
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if let Some(ref watch) = self.write_watch {
+            watch.decommitted(self.cap * mem::size_of::<T>());
+        }
+
+        Ok(())
+    }
+
+    /// Removes all elements from the vector, keeping its memory committed.
+    pub fn clear(&mut self) {
+        let len = self.len;
+
+        self.len = 0;
+
+        unsafe {
+            ptr::drop_in_place(ptr::slice_from_raw_parts_mut(self.ptr.as_ptr(), len));
+        }
+    }
+}
+
+impl<T: Clone, B: VirtualBacking> VirtualVec<T, B> {
+    /// Appends all elements of the given slice to the back of the vector.
+    ///
+    /// # Panics
+    /// Panics if the vector cannot hold all elements, or if memory could not be committed.
+    pub fn extend_from_slice(&mut self, values: &[T]) {
+        self.reserve_or_panic(values.len());
+
+        for value in values {
+            unsafe {
+                ptr::write(self.ptr.as_ptr().add(self.len), value.clone());
+            }
+
+            self.len += 1;
+        }
+
+        self.appended();
+    }
+}
+
+impl<T: Copy, B: VirtualBacking> VirtualVec<T, B> {
+    /// Appends `n` copies of `value` to the back of the vector.
+    ///
+    /// This is faster than pushing elements one by one, since memory is only reserved
+    /// once and copies are written in a loop the compiler can vectorize.
+    ///
+    /// # Panics
+    /// Panics if the vector cannot hold all elements, or if memory could not be committed.
+    pub fn extend_with(&mut self, n: usize, value: T) {
+        self.reserve_or_panic(n);
+
+        unsafe {
+            let tail = self.ptr.as_ptr().add(self.len);
+
+            for i in 0..n {
+                ptr::write(tail.add(i), value);
+            }
+
+            self.set_len(self.len + n);
+        }
+    }
+}
+
+/// The magic number at the start of checkpoints.
+#[cfg(feature = "std")]
+const CHECKPOINT_MAGIC: [u8; 4] = *b"VVCK";
+
+/// The version of the format of checkpoints.
+#[cfg(feature = "std")]
+const CHECKPOINT_VERSION: u32 = 1;
+
+/// The offset that ends the pages of a checkpoint.
+#[cfg(feature = "std")]
+const CHECKPOINT_END: u64 = u64::MAX;
+
+/// Checkpoints store elements as raw bytes, which are only valid for plain old data.
+#[cfg(feature = "std")]
+impl<T: Pod, B: VirtualBacking> VirtualVec<T, B> {
+    /// Writes the elements of the vector to `writer`, page by page, and returns the number
+    /// of pages written.
+    ///
+    /// Only the committed pages that hold elements are written, each preceded by its
+    /// offset. If `skip_zero_pages` is true, pages that only hold zeroes are skipped as
+    /// well, since restoring them is the same as leaving freshly committed memory alone:
+    /// checkpointing a huge, sparsely written vector then only writes the pages that hold
+    /// non-zero bytes. Pages written to since the last checkpoint are tracked separately,
+    /// by `watch_writes`.
+    pub fn checkpoint<W: io::Write>(&self, mut writer: W, skip_zero_pages: bool)
+        -> io::Result<usize> {
+        let page = self.backing.page_size();
+        let size = self.len * mem::size_of::<T>();
+        let bytes = unsafe { slice::from_raw_parts(self.ptr.as_ptr() as *const u8, size) };
+        let mut written = 0;
+
+        writer.write_all(&CHECKPOINT_MAGIC)?;
+        writer.write_all(&CHECKPOINT_VERSION.to_le_bytes())?;
+
+        for field in [mem::size_of::<T>(), self.len, page] {
+            writer.write_all(&(field as u64).to_le_bytes())?;
+        }
+
+        for (i, chunk) in bytes.chunks(page).enumerate() {
+            if skip_zero_pages && chunk.iter().all(|&b| b == 0) {
+                continue
+            }
+
+            writer.write_all(&((i * page) as u64).to_le_bytes())?;
+            writer.write_all(chunk)?;
+            written += 1;
+        }
+
+        writer.write_all(&CHECKPOINT_END.to_le_bytes())?;
+
+        Ok(written)
+    }
+
+    /// Replaces the elements of the vector with those of a checkpoint written by
+    /// `checkpoint`, committing memory as needed.
+    ///
+    /// The vector keeps its address, which does not need to be the one the checkpoint was
+    /// taken at. Fails with `InvalidData` if the checkpoint is malformed or holds elements
+    /// of another size, in which case the vector is left empty.
+    pub fn restore<R: io::Read>(&mut self, mut reader: R) -> io::Result<()> {
+        fn invalid(msg: &str) -> io::Error {
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        }
+
+        fn read_u64<R: io::Read>(reader: &mut R) -> io::Result<u64> {
+            let mut bytes = [0; 8];
+
+            reader.read_exact(&mut bytes)?;
+
+            Ok(u64::from_le_bytes(bytes))
+        }
+
+        let mut header = [0; 8];
+
+        self.clear();
+
+        reader.read_exact(&mut header)?;
+
+        if header[..4] != CHECKPOINT_MAGIC {
+            return Err(invalid("not a checkpoint"))
+        }
+        if header[4..] != CHECKPOINT_VERSION.to_le_bytes() {
+            return Err(invalid("unsupported checkpoint version"))
+        }
+        if read_u64(&mut reader)? != mem::size_of::<T>() as u64 {
+            return Err(invalid("checkpoint of elements of another size"))
+        }
+
+        let len = usize::try_from(read_u64(&mut reader)?)
+            .map_err(|_| invalid("checkpoint too large"))?;
+        let page = usize::try_from(read_u64(&mut reader)?)
+            .map_err(|_| invalid("invalid checkpoint page size"))?;
+        let size = len.checked_mul(mem::size_of::<T>())
+            .ok_or_else(|| invalid("checkpoint too large"))?;
+
+        if page == 0 {
+            return Err(invalid("invalid checkpoint page size"))
+        }
+
+        self.reserve(len)?;
+
+        unsafe {
+            let bytes = slice::from_raw_parts_mut(self.ptr.as_ptr() as *mut u8, size);
+
+            // Skipped pages must read as zeroes, which memory that never held elements
+            // already does.
+            bytes[..self.dirty.min(size)].fill(0);
+            self.dirty = self.dirty.max(size);
+
+            loop {
+                let offset = match read_u64(&mut reader)? {
+                    CHECKPOINT_END => break,
+                    offset => usize::try_from(offset).unwrap_or(usize::MAX)
+                };
+
+                if offset >= size || !offset.is_multiple_of(page) {
+                    return Err(invalid("invalid checkpoint page offset"))
+                }
+
+                reader.read_exact(&mut bytes[offset..(offset + page).min(size)])?;
+            }
+
+            self.set_len(len);
+        }
+
+        Ok(())
+    }
+}
+
+impl<B: VirtualBacking> VirtualVec<u8, B> {
+    /// Appends `n` copies of the byte `b` to the back of the vector using `memset`.
+    ///
+    /// Backings guarantee that committed memory is zeroed, so appending zeroes over memory
+    /// that never held elements is free. Memory written to through `as_mut_ptr` must
+    /// therefore be covered by `set_len` before calling this function.
+    ///
+    /// # Panics
+    /// Panics if the vector cannot hold all bytes, or if memory could not be committed.
+    pub fn extend_with_byte(&mut self, b: u8, n: usize) {
+        self.reserve_or_panic(n);
+
+        unsafe {
+            let start = self.ptr.as_ptr().add(self.len);
+            let len = match b {
+                0 => self.dirty.saturating_sub(self.len).min(n),
+                _ => n
+            };
+
+            ptr::write_bytes(start, b, len);
+
+            self.set_len(self.len + n);
+        }
+    }
+
+    /// Returns a cursor that reads the bytes of the vector from the start.
+    #[inline]
+    pub fn cursor(&self) -> VirtualCursor<'_, B> {
+        VirtualCursor::new(self)
+    }
+
+    /// Copies the given bytes to the back of the vector, assuming that they fit in its
+    /// capacity.
+    #[cfg(feature = "std")]
+    #[inline]
+    unsafe fn append_unchecked(&mut self, bytes: &[u8]) {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), self.ptr.as_ptr().add(self.len), bytes.len());
+
+        self.len += bytes.len();
+        self.appended();
+    }
+}
+
+/// Appends written bytes to the back of the vector, committing memory as needed.
+#[cfg(feature = "std")]
+impl<B: VirtualBacking> io::Write for VirtualVec<u8, B> {
+    /// Appends as many bytes as fit in the maximum capacity of the vector.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().min(self.max_capacity() - self.len);
+
+        self.reserve(len)?;
+
+        unsafe {
+            self.append_unchecked(&buf[..len]);
+        }
+
+        Ok(len)
+    }
+
+    /// Appends as many bytes as fit in the maximum capacity of the vector, committing
+    /// memory at most once.
+    fn write_vectored(&mut self, bufs: &[io::IoSlice]) -> io::Result<usize> {
+        let total = bufs.iter().fold(0usize, |total, buf| total.saturating_add(buf.len()));
+        let written = total.min(self.max_capacity() - self.len);
+        let mut len = written;
+
+        self.reserve(len)?;
+
+        for buf in bufs {
+            let buf = &buf[..buf.len().min(len)];
+
+            unsafe {
+                self.append_unchecked(buf);
+            }
+
+            len -= buf.len();
+        }
+
+        Ok(written)
+    }
+
+    /// Appends all bytes, or none of them if they do not fit.
+    fn write_all(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.reserve(buf.len())?;
+
+        unsafe {
+            self.append_unchecked(buf);
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<T, B: VirtualBacking> Deref for VirtualVec<T, B> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, B: VirtualBacking> DerefMut for VirtualVec<T, B> {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+// `AsRef<[u8]>` for byte vectors is covered by `AsRef<[T]>`.
+impl<T, B: VirtualBacking> AsRef<[T]> for VirtualVec<T, B> {
+    #[inline]
+    fn as_ref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, B: VirtualBacking> AsMut<[T]> for VirtualVec<T, B> {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+impl<T, B: VirtualBacking> Borrow<[T]> for VirtualVec<T, B> {
+    #[inline]
+    fn borrow(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T, B: VirtualBacking> BorrowMut<[T]> for VirtualVec<T, B> {
+    #[inline]
+    fn borrow_mut(&mut self) -> &mut [T] {
+        self.as_mut_slice()
+    }
+}
+
+// The vector never reallocates, so its elements stay at the same address for as long as
+// the vector lives, no matter how it is moved or grown.
+#[cfg(feature = "stable_deref_trait")]
+unsafe impl<T, B: VirtualBacking> ::stable_deref_trait::StableDeref for VirtualVec<T, B> {}
+
+impl<T, B: VirtualBacking> Drop for VirtualVec<T, B> {
+    fn drop(&mut self) {
+        // Elements may be written to while they are dropped.
+        #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+        if let Some(watch) = self.write_watch.take() {
+            let _ = watch.stop();
+        }
+
+        self.clear();
+
+        if self.pinned > 0 {
+            if let Some(callback) = self.on_pinned_decommit {
+                callback(self.ptr.as_ptr() as *mut u8, self.pinned);
+            }
+        }
+
+        unsafe {
+            if self.zero_on_drop {
+                secure_zero(self.ptr.as_ptr() as *mut Opaque, self.cap * mem::size_of::<T>());
+            }
+
+            if self.reservation.1 > 0 {
+                self.backing.release(self.reservation.0, self.reservation.1);
+            }
+        }
+
+        stats::released(self.reservation.1, self.cap * mem::size_of::<T>());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "std")]      use std::cell::Cell;
+    #[cfg(not(feature = "std"))] use core::cell::Cell;
+
+    const MAX_CAP: usize = 1_000_000;
+
+    fn vec() -> VirtualVec<usize> {
+        VirtualVec::new(MAX_CAP)
+    }
+
+    #[test]
+    fn never_moves_its_elements() {
+        let mut vec = vec();
+
+        vec.push(0);
+
+        let initial_ptr = vec.as_ptr();
+
+        for i in 1..100_000 {
+            vec.push(i);
+        }
+
+        assert_eq!(vec.as_ptr(), initial_ptr);
+        assert_eq!(vec[99_999], 99_999);
+    }
+
+    #[test]
+    fn can_pop_elements() {
+        let mut vec = vec();
+
+        vec.extend_from_slice(&[1, 2, 3]);
+
+        assert_eq!(vec.pop(), Some(3));
+        assert_eq!(vec.as_slice(), &[1, 2]);
+    }
+
+    #[test]
+    fn cant_reserve_values_over_maximum() {
+        let max = MAX_CAP * mem::size_of::<usize>();
+
+        assert_eq!(vec().reserve(MAX_CAP * 2),
+                   Err(VirtualMemError::ExceedsMax { requested: max * 2, max }));
+    }
+
+    #[test]
+    fn can_be_created_with_an_initial_capacity() {
+        let vec = VirtualVec::<usize>::with_capacity(1_000, MAX_CAP);
+
+        assert!(vec.capacity() >= 1_000);
+    }
+
+    #[test]
+    fn reports_reservation_failures() {
+        match VirtualVec::<u8>::try_new(usize::MAX) {
+            Err(VirtualMemError::ReservationFailed { .. }) => (),
+            _ => panic!("Expected reservation to fail.")
+        }
+    }
+
+    #[test]
+    fn doesnt_wrap_around_when_computing_sizes() {
+        let mut vec = vec();
+
+        assert_eq!(vec.reserve(usize::MAX), Err(VirtualMemError::CapacityOverflow));
+        assert_eq!(vec.reserve(usize::MAX / 8),
+                   Err(VirtualMemError::ExceedsMax {
+                       requested: usize::MAX / 8 * 8,
+                       max: MAX_CAP * mem::size_of::<usize>()
+                   }));
+
+        vec.push(1);
+
+        assert_eq!(vec.reserve(usize::MAX), Err(VirtualMemError::CapacityOverflow));
+        assert_eq!(VirtualVec::<u64>::try_new(usize::MAX / 4).err(),
+                   Some(VirtualMemError::CapacityOverflow));
+        assert_eq!(VirtualVec::<u64>::try_with_capacity(1, usize::MAX / 4).err(),
+                   Some(VirtualMemError::CapacityOverflow));
+        #[cfg(not(target_arch = "wasm32"))]
+        assert_eq!(vec.pin(usize::MAX).err(), Some(VirtualMemError::CapacityOverflow));
+    }
+
+    #[test]
+    fn holds_zero_sized_elements_without_memory() {
+        let mut vec = VirtualVec::<()>::new(0);
+
+        assert_eq!((vec.capacity(), vec.max_capacity()), (usize::MAX, usize::MAX));
+
+        for _ in 0..1000 {
+            vec.push(());
+        }
+
+        assert_eq!(vec.len(), 1000);
+        assert_eq!(vec.pop(), Some(()));
+        assert_eq!(vec.reserve(usize::MAX), Err(VirtualMemError::CapacityOverflow));
+        assert_eq!(vec.stats().committed, 0);
+        assert_eq!(vec.exclude_from_dumps(), Ok(()));
+
+        vec.truncate(10);
+        vec.shrink_to_fit().unwrap();
+
+        assert_eq!(VirtualVec::<()>::default().len(), 0);
+    }
+
+    #[test]
+    fn can_be_zeroed_on_drop() {
+        let mut vec = VirtualVec::new(MAX_CAP).zero_on_drop(true);
+
+        vec.extend_from_slice(b"secret");
+
+        drop(vec);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn appends_written_bytes() {
+        use std::io::{IoSlice, Write};
+
+        let mut vec = VirtualVec::<u8>::new(16);
+
+        vec.write_all(b"hello").unwrap();
+        assert_eq!(vec.write_vectored(&[IoSlice::new(b", "), IoSlice::new(b"world")]).unwrap(), 7);
+
+        assert_eq!(vec.as_slice(), b"hello, world");
+        assert_eq!(vec.write(b"!!!!!!!!").unwrap(), 4);
+        assert_eq!(vec.write_all(b"!").unwrap_err().kind(), std::io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn can_be_passed_as_a_slice() {
+        fn sum<S: AsRef<[usize]>>(values: S) -> usize {
+            values.as_ref().iter().sum()
+        }
+
+        let mut vec = vec();
+
+        vec.extend_from_slice(&[1, 2, 3]);
+        vec.as_mut()[0] = 4;
+
+        assert_eq!(sum(&vec), 9);
+        assert_eq!(Borrow::<[usize]>::borrow(&vec), &[4, 2, 3]);
+    }
+
+    #[cfg(feature = "stable_deref_trait")]
+    #[test]
+    fn implements_stable_deref() {
+        fn first<D: ::stable_deref_trait::StableDeref<Target = [usize]>>(values: D) -> usize {
+            values[0]
+        }
+
+        let mut vec = vec();
+
+        vec.push(42);
+
+        assert_eq!(first(vec), 42);
+    }
+
+    #[test]
+    fn decommits_unused_pages_when_shrunk() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::with_capacity(page * 4, page * 8);
+
+        vec.extend_from_slice(&[1, 2, 3]);
+        vec.shrink_to_fit().unwrap();
+
+        assert_eq!(vec.capacity(), page);
+        assert_eq!(vec.as_slice(), &[1, 2, 3]);
+    }
+
+    #[test]
+    fn extends_with_copies() {
+        let mut vec = VirtualVec::<u32>::new(1024);
+
+        vec.push(1);
+        vec.extend_with(3, 7);
+
+        assert_eq!(vec.as_slice(), &[1, 7, 7, 7]);
+    }
+
+    #[test]
+    fn extends_with_bytes_over_dirty_and_fresh_memory() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 4);
+
+        vec.extend_with_byte(0xff, page);
+        vec.truncate(page / 2);
+        vec.extend_with_byte(0, page * 2);
+
+        assert_eq!(vec.len(), page / 2 + page * 2);
+        assert!(vec[..page / 2].iter().all(|&b| b == 0xff));
+        assert!(vec[page / 2..].iter().all(|&b| b == 0));
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn can_rely_on_demand_paging() {
+        let page = VirtualAlloc::page_size();
+        let backing = VirtualAlloc::new(page * 8).demand_paged();
+        let mut vec = VirtualVec::<u8, _>::with_backing(page * 8, backing);
+
+        assert!(vec.backing().is_demand_paged());
+
+        vec.extend_with_byte(1, page * 4);
+        vec.truncate(0);
+        vec.shrink_to_fit().unwrap();
+        vec.reserve(page * 4).unwrap();
+
+        unsafe {
+            assert!(slice::from_raw_parts(vec.as_ptr(), page * 4).iter().all(|&b| b == 0));
+        }
+    }
+
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    #[test]
+    fn can_commit_on_fault() {
+        let page = VirtualAlloc::page_size();
+        let backing = VirtualAlloc::new(page * 8).commit_on_fault();
+        let mut vec = VirtualVec::<u8, _>::with_backing(page * 8, backing);
+
+        assert!(vec.backing().commits_on_fault());
+
+        vec.extend_with_byte(1, page * 4);
+        vec.truncate(0);
+        vec.shrink_to_fit().unwrap();
+
+        unsafe {
+            let ptr = vec.as_mut_ptr();
+
+            ptr.add(page * 6 + 3).write_volatile(5);
+
+            assert_eq!(ptr.add(page * 6 + 3).read_volatile(), 5);
+            assert_eq!(ptr.read_volatile(), 0);
+        }
+
+        #[cfg(not(any(windows, target_os = "openbsd")))]
+        assert_eq!(VirtualAlloc::resident_size(vec.ptr, page * 8), Some(page * 2));
+    }
+
+    #[cfg(all(feature = "std", any(unix, windows), not(target_os = "fuchsia")))]
+    #[test]
+    fn tracks_dirty_pages() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 16).growth_policy(GrowthPolicy::Exact);
+        let dirty = |vec: &VirtualVec<u8>| {
+            vec.dirty_since_checkpoint().into_iter().map(|range| (range.start, range.end))
+                .collect::<Vec<_>>()
+        };
+
+        vec.extend_with_byte(1, page * 4);
+
+        assert_eq!(dirty(&vec), [(0, page * 4)]);
+
+        vec.watch_writes().unwrap();
+
+        assert_eq!(dirty(&vec), [(0, page * 4)]);
+
+        vec.clear_dirty().unwrap();
+
+        assert!(dirty(&vec).is_empty());
+
+        vec[page + 1] = 2;
+        vec[page * 3] = 3;
+        vec[page * 3 + 1] = 4;
+
+        assert_eq!(dirty(&vec), [(page, page * 2), (page * 3, page * 4)]);
+
+        // Newly committed pages are dirty, and adjacent pages are merged.
+        vec.push(5);
+
+        assert_eq!(dirty(&vec), [(page, page * 2), (page * 3, page * 5)]);
+
+        vec.clear_dirty().unwrap();
+        vec[0] = 6;
+        vec.truncate(page);
+        vec.shrink_to_fit().unwrap();
+
+        assert_eq!(dirty(&vec), [(0, page)]);
+        assert_eq!(vec[..2], [6, 1]);
+    }
+
+    #[test]
+    fn keeps_slack_committed_when_truncated() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::with_capacity(page * 8, page * 8)
+            .decommit_slack(DecommitSlack::Pages(2));
+
+        vec.extend_from_slice(&vec![1; page * 8]);
+        vec.truncate(page * 6);
+
+        assert_eq!(vec.capacity(), page * 8);
+
+        vec.truncate(page);
+
+        assert_eq!(vec.len(), page);
+        assert_eq!(vec.capacity(), page * 3);
+    }
+
+    #[test]
+    fn reports_memory_usage() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::new(page * 8).growth_policy(GrowthPolicy::Exact);
+
+        vec.extend_from_slice(&vec![1; page * 4]);
+        vec.truncate(0);
+        vec.shrink_to_fit().unwrap();
+
+        let stats = vec.stats();
+
+        assert_eq!(stats.reserved, page * 8);
+        assert_eq!(stats.committed, 0);
+        assert_eq!(stats.peak_committed, page * 4);
+        assert_eq!((stats.commits, stats.decommits), (1, 1));
+
+        #[cfg(not(any(windows, target_os = "openbsd", target_arch = "wasm32")))]
+        assert_eq!(stats.resident, Some(0));
+    }
+
+    #[test]
+    fn lists_committed_ranges() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<[u8; 3]>::new(page * 8).growth_policy(GrowthPolicy::Exact);
+
+        assert_eq!(vec.committed_ranges().count(), 0);
+
+        vec.push([1; 3]);
+
+        assert_eq!(vec.committed_ranges().next(), Some(0..page));
+
+        vec.reserve(page).unwrap();
+
+        assert_eq!(vec.committed_ranges().next(), Some(0..page * 4));
+        assert_eq!(vec.committed_ranges().count(), 1);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn can_be_checkpointed_and_restored() {
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u32>::new(page * 64);
+
+        vec.extend_with(page * 4, 0);
+        vec[3] = 1;
+        vec[page * 3 + 1] = 2;
+        vec.push(3);
+
+        let mut full = Vec::new();
+        let mut sparse = Vec::new();
+
+        assert_eq!(vec.checkpoint(&mut full, false).unwrap(), 17);
+        assert_eq!(vec.checkpoint(&mut sparse, true).unwrap(), 3);
+        assert!(sparse.len() < full.len());
+
+        // Restoring over memory that held other elements must zero the skipped pages.
+        let mut other = VirtualVec::<u32>::new(page * 64);
+
+        other.extend_with(page * 8, 9);
+        other.restore(&sparse[..]).unwrap();
+
+        assert_eq!(other.as_slice(), vec.as_slice());
+        assert_ne!(other.as_ptr(), vec.as_ptr());
+
+        other.restore(&full[..]).unwrap();
+
+        assert_eq!(other.as_slice(), vec.as_slice());
+        assert!(VirtualVec::<u64>::new(page).restore(&full[..]).is_err());
+        assert!(other.restore(&full[..full.len() - 1]).is_err());
+        assert!(other.is_empty());
+    }
+
+    #[test]
+    fn keeps_pinned_memory_committed() {
+        #[cfg(feature = "std")]      use std::sync::atomic::{AtomicUsize, Ordering};
+        #[cfg(not(feature = "std"))] use core::sync::atomic::{AtomicUsize, Ordering};
+
+        static KEPT: AtomicUsize = AtomicUsize::new(0);
+
+        let page = VirtualAlloc::page_size();
+        let mut vec = VirtualVec::<u8>::with_capacity(page * 4, page * 8);
+        let range = vec.pin(page * 2).unwrap();
+
+        assert_eq!(range, PinnedRange { ptr: vec.as_mut_ptr(), len: page * 2 });
+        assert_eq!(vec.stats().commits, 1);
+
+        vec.on_pinned_decommit(|_, len| { KEPT.store(len, Ordering::Relaxed); });
+        vec.shrink_to_fit().unwrap();
+
+        assert_eq!(vec.capacity(), page * 2);
+        assert_eq!(KEPT.load(Ordering::Relaxed), page * 2);
+
+        vec.unpin();
+        vec.shrink_to_fit().unwrap();
+
+        assert_eq!(vec.capacity(), 0);
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    #[test]
+    fn can_be_aligned_and_pinned_for_gpu_import() {
+        let align = VirtualAlloc::allocation_granularity() * 16;
+        let mut vec = VirtualVec::<u8>::with_alignment(1 << 20, align);
+
+        assert_eq!(vec.as_ptr().addr() % align, 0);
+        assert_eq!(vec.pinned_range(), None);
+
+        let range = vec.pin(1).unwrap();
+
+        vec.extend_from_slice(b"vertices");
+
+        assert_eq!(vec.pinned_range(), Some(range));
+        assert_eq!(range, PinnedRange { ptr: vec.as_mut_ptr(), len: VirtualAlloc::page_size() });
+        assert_eq!(VirtualVec::<u8>::try_with_alignment(1 << 20, 3).err(),
+                   Some(VirtualMemError::InvalidAlignment));
+    }
+
+    struct CountingBacking {
+        os: VirtualAlloc,
+        commits: Cell<usize>
+    }
+
+    unsafe impl VirtualBacking for CountingBacking {
+        fn reserve(&self, size: usize) -> Result<NonNull<u8>, VirtualMemError> {
+            self.os.reserve(size)
+        }
+
+        unsafe fn commit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+            self.commits.set(self.commits.get() + 1);
+            self.os.commit(ptr, size)
+        }
+
+        unsafe fn decommit(&self, ptr: NonNull<u8>, size: usize) -> Result<(), VirtualMemError> {
+            self.os.decommit(ptr, size)
+        }
+
+        unsafe fn protect(&self, ptr: NonNull<u8>, size: usize, read: bool, write: bool,
+                          exec: bool) -> Result<(), VirtualMemError> {
+            self.os.protect(ptr, size, read, write, exec)
+        }
+
+        unsafe fn release(&self, ptr: NonNull<u8>, size: usize) {
+            self.os.release(ptr, size)
+        }
+
+        fn page_size(&self) -> usize {
+            self.os.page_size()
+        }
+    }
+
+    #[test]
+    fn can_use_custom_backings() {
+        let backing = CountingBacking { os: VirtualAlloc::default(), commits: Cell::new(0) };
+        let mut vec = VirtualVec::with_backing(MAX_CAP, backing);
+
+        for i in 0..MAX_CAP {
+            vec.push(i);
+        }
+
+        assert_eq!(vec[MAX_CAP - 1], MAX_CAP - 1);
+        assert!(vec.backing().commits.get() < 20);
+    }
+
+    #[test]
+    fn commits_every_page_with_exact_growth() {
+        let page = VirtualAlloc::page_size();
+        let backing = CountingBacking { os: VirtualAlloc::default(), commits: Cell::new(0) };
+        let mut vec = VirtualVec::<u8, _>::with_backing(page * 8, backing)
+            .growth_policy(GrowthPolicy::Exact);
+
+        for i in 0..page * 4 {
+            vec.push(i as u8);
+        }
+
+        assert_eq!(vec.capacity(), page * 4);
+        assert_eq!(vec.backing().commits.get(), 4);
+    }
+}
@@ -0,0 +1,300 @@
+//! Read-only views of files mapped in memory, for scanning large datasets.
+
+use std::fs::File;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::ptr::NonNull;
+use std::slice;
+
+use super::{Pod, VirtualAlloc, VirtualMemError};
+
+
+/// How the elements of a `VirtualSlice` are going to be accessed, which lets the operating
+/// system tune how it reads the file ahead.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AccessPattern {
+    /// No particular pattern, which is the default.
+    Normal,
+    /// Elements are read in order, so the file is read ahead aggressively, and pages that
+    /// were read can be dropped early.
+    Sequential,
+    /// Elements are read in no particular order, so the file is not read ahead.
+    Random
+}
+
+/// A read-only slice of plain old data stored in a file, which is mapped in memory.
+///
+/// Only the pages that are accessed are read from the file, and the operating system can
+/// drop them again under memory pressure, so that files much larger than physical memory
+/// can be scanned. `advise` and `prefetch` tell the operating system how the elements are
+/// going to be accessed, so that it reads them ahead of time.
+///
+/// The file must not be modified while it is mapped, since the slice would then change
+/// under its users. Files whose length is not a whole number of elements are rejected.
+///
+/// # Example
+/// ```no_run
+/// use virtualalloc::{AccessPattern, VirtualSlice};
+///
+/// let samples = VirtualSlice::<f32>::open("samples.f32").unwrap();
+///
+/// samples.advise(AccessPattern::Sequential).unwrap();
+///
+/// let sum: f64 = samples.iter().map(|&sample| sample as f64).sum();
+/// ```
+pub struct VirtualSlice<T: Pod> {
+    base: NonNull<u8>,
+    /// The number of bytes mapped, which is zero for empty files.
+    mapped: usize,
+    ptr: NonNull<T>,
+    len: usize,
+    _marker: PhantomData<T>
+}
+
+// The mapping is never modified.
+unsafe impl<T: Pod + Send> Send for VirtualSlice<T> {}
+unsafe impl<T: Pod + Sync> Sync for VirtualSlice<T> {}
+
+impl<T: Pod> VirtualSlice<T> {
+    /// Maps the file at the given path as a slice of `T`.
+    ///
+    /// Fails with `InvalidData` if the length of the file is not a multiple of the size of
+    /// `T`.
+    #[inline]
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        VirtualSlice::open_at(path, 0)
+    }
+
+    /// Maps the file at the given path as a slice of `T`, starting `offset` bytes into the
+    /// file, which skips a header.
+    ///
+    /// Fails with `InvalidInput` if the offset is not a multiple of the alignment of `T`,
+    /// and with `InvalidData` if the file is shorter than the offset, or the rest of the
+    /// file is not a multiple of the size of `T`.
+    pub fn open_at<P: AsRef<Path>>(path: P, offset: usize) -> io::Result<Self> {
+        let invalid = |kind, msg| Err(io::Error::new(kind, msg));
+
+        if mem::size_of::<T>() == 0 || mem::align_of::<T>() > VirtualAlloc::page_size() {
+            return invalid(io::ErrorKind::InvalidInput, "unsupported element type")
+        }
+        if !offset.is_multiple_of(mem::align_of::<T>()) {
+            return invalid(io::ErrorKind::InvalidInput, "misaligned offset")
+        }
+
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+
+        if size > isize::MAX as u64 {
+            return Err(VirtualMemError::ExceedsMax {
+                requested: size as usize, max: isize::MAX as usize
+            }.into())
+        }
+
+        let size = size as usize;
+
+        let data = match size.checked_sub(offset) {
+            Some(data) if data.is_multiple_of(mem::size_of::<T>()) => data,
+            Some(_) => return invalid(io::ErrorKind::InvalidData,
+                                      "file length is not a whole number of elements"),
+            None => return invalid(io::ErrorKind::InvalidData, "file shorter than the offset")
+        };
+
+        let base = match size {
+            0 => NonNull::dangling(),
+            _ => unsafe { imp::map(&file, size)? }
+        };
+
+        let ptr = match data {
+            0 => NonNull::dangling(),
+            _ => unsafe { NonNull::new_unchecked(base.as_ptr().add(offset).cast()) }
+        };
+
+        Ok(VirtualSlice { base, mapped: size, ptr, len: data / mem::size_of::<T>(),
+                          _marker: PhantomData })
+    }
+
+    /// Returns the elements of the slice.
+    #[inline]
+    pub fn as_slice(&self) -> &[T] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+
+    /// Tells the operating system how the elements are going to be accessed.
+    ///
+    /// This does nothing on Windows.
+    pub fn advise(&self, pattern: AccessPattern) -> Result<(), VirtualMemError> {
+        if self.mapped == 0 {
+            return Ok(())
+        }
+
+        unsafe { imp::advise(self.base, self.mapped, pattern) }
+    }
+
+    /// Asks the operating system to read the elements in the given range ahead of time,
+    /// so that accessing them later does not block on the file.
+    ///
+    /// # Panics
+    /// Panics if the range is out of bounds.
+    pub fn prefetch(&self, range: Range<usize>) -> Result<(), VirtualMemError> {
+        let elements = &self.as_slice()[range];
+
+        if elements.is_empty() {
+            return Ok(())
+        }
+
+        // Prefetching works on whole pages, and the mapping starts on a page.
+        let offset = elements.as_ptr().addr() - self.base.as_ptr().addr();
+        let start = offset / VirtualAlloc::page_size() * VirtualAlloc::page_size();
+        let len = offset - start + mem::size_of_val(elements);
+
+        unsafe {
+            VirtualAlloc::prefetch(NonNull::new_unchecked(self.base.as_ptr().add(start)), len)
+        }
+    }
+}
+
+impl<T: Pod> Deref for VirtualSlice<T> {
+    type Target = [T];
+
+    #[inline]
+    fn deref(&self) -> &[T] {
+        self.as_slice()
+    }
+}
+
+impl<T: Pod> Drop for VirtualSlice<T> {
+    fn drop(&mut self) {
+        if self.mapped > 0 {
+            unsafe {
+                imp::unmap(self.base, self.mapped);
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::unix::io::AsRawFd;
+    use std::ptr::{self, NonNull};
+
+    use super::AccessPattern;
+    use super::super::{last_os_error, VirtualMemError};
+
+    pub(super) unsafe fn map(file: &File, size: usize) -> io::Result<NonNull<u8>> {
+        let ptr = libc::mmap(ptr::null_mut(), size, libc::PROT_READ, libc::MAP_SHARED,
+                             file.as_raw_fd(), 0);
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error())
+        }
+
+        Ok(NonNull::new_unchecked(ptr.cast()))
+    }
+
+    pub(super) unsafe fn advise(base: NonNull<u8>, size: usize, pattern: AccessPattern)
+        -> Result<(), VirtualMemError> {
+        let advice = match pattern {
+            AccessPattern::Normal => libc::MADV_NORMAL,
+            AccessPattern::Sequential => libc::MADV_SEQUENTIAL,
+            AccessPattern::Random => libc::MADV_RANDOM
+        };
+
+        if libc::madvise(base.as_ptr().cast(), size, advice) != 0 {
+            return Err(VirtualMemError::AdviseFailed { os_err: last_os_error() })
+        }
+
+        Ok(())
+    }
+
+    pub(super) unsafe fn unmap(base: NonNull<u8>, size: usize) {
+        libc::munmap(base.as_ptr().cast(), size);
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    use std::fs::File;
+    use std::io;
+    use std::os::windows::io::AsRawHandle;
+    use std::ptr::{self, NonNull};
+
+    use windows_sys::Win32::Foundation::CloseHandle;
+    use windows_sys::Win32::System::Memory::*;
+
+    use super::AccessPattern;
+    use super::super::VirtualMemError;
+
+    pub(super) unsafe fn map(file: &File, size: usize) -> io::Result<NonNull<u8>> {
+        let mapping = CreateFileMappingW(file.as_raw_handle() as _, ptr::null(), PAGE_READONLY,
+                                         0, 0, ptr::null());
+
+        if mapping.is_null() {
+            return Err(io::Error::last_os_error())
+        }
+
+        let view = MapViewOfFile(mapping, FILE_MAP_READ, 0, 0, size);
+
+        // The view keeps the mapping alive.
+        CloseHandle(mapping);
+
+        match NonNull::new(view.Value.cast()) {
+            Some(view) => Ok(view),
+            None => Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Windows has no equivalent of `madvise` for access patterns.
+    pub(super) unsafe fn advise(_: NonNull<u8>, _: usize, _: AccessPattern)
+        -> Result<(), VirtualMemError> {
+        Ok(())
+    }
+
+    pub(super) unsafe fn unmap(base: NonNull<u8>, _: usize) {
+        UnmapViewOfFile(MEMORY_MAPPED_VIEW_ADDRESS { Value: base.as_ptr().cast() });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn maps_files_as_slices() {
+        let path = env::temp_dir().join(format!("virtualalloc-slice-{}.bin", std::process::id()));
+        let values = (0..100_000u32).collect::<Vec<_>>();
+        let mut bytes = b"HEADER\0\0".to_vec();
+
+        bytes.extend(values.iter().flat_map(|value| value.to_ne_bytes()));
+        fs::write(&path, &bytes).unwrap();
+
+        let slice = VirtualSlice::<u32>::open_at(&path, 8).unwrap();
+
+        assert_eq!(&slice[..], &values[..]);
+
+        slice.advise(AccessPattern::Sequential).unwrap();
+        slice.prefetch(1000..50_000).unwrap();
+        slice.prefetch(5..5).unwrap();
+
+        assert_eq!(VirtualSlice::<u64>::open(&path).unwrap().len(), 50_001);
+        assert_eq!(VirtualSlice::<u32>::open_at(&path, 2).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::InvalidInput));
+        assert_eq!(VirtualSlice::<[u8; 5]>::open(&path).err().map(|err| err.kind()),
+                   Some(io::ErrorKind::InvalidData));
+        assert!(VirtualSlice::<u8>::open_at(&path, bytes.len()).unwrap().is_empty());
+
+        drop(slice);
+        fs::write(&path, b"").unwrap();
+
+        assert!(VirtualSlice::<u16>::open(&path).unwrap().is_empty());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
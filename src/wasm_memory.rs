@@ -0,0 +1,215 @@
+//! Linear memories for WebAssembly engines.
+
+#[cfg(feature = "std")] use std::ptr::NonNull;
+#[cfg(feature = "std")] use std::slice;
+
+#[cfg(not(feature = "std"))] use core::ptr::NonNull;
+#[cfg(not(feature = "std"))] use core::slice;
+
+use super::{VirtualAlloc, VirtualBacking, VirtualMemError};
+
+
+/// The size of a page of WebAssembly linear memory.
+const WASM_PAGE_SIZE: usize = 0x10000;
+
+/// The largest number of pages of a 32-bit linear memory.
+const MAX_PAGES: u32 = 0x10000;
+
+/// The size of the index space of a 32-bit linear memory.
+const INDEX_SPACE: usize = MAX_PAGES as usize * WASM_PAGE_SIZE;
+
+/// The size of the inaccessible region reserved after the 4GB of a linear memory by
+/// default, which lets engines fold constant offsets of up to 2GB into their addressing.
+const DEFAULT_GUARD_SIZE: usize = 2 << 30;
+
+/// The linear memory of a WebAssembly instance, reserved with all of its 32-bit address
+/// space and followed by a guard region.
+///
+/// Since all 4GB that a 32-bit index can address are reserved upfront, followed by a
+/// guard region that is never committed, engines can translate loads and stores into a
+/// single access to `base() + index + offset` without any bounds check: accesses beyond
+/// the current length fault, and so do accesses with an offset that would overflow the
+/// index space. Growing the memory only commits more of the reservation, so its base
+/// never moves.
+///
+/// # Example
+/// ```
+/// use virtualalloc::WasmLinearMemory;
+///
+/// let mut memory = WasmLinearMemory::new(1, Some(16)).unwrap();
+///
+/// assert_eq!(memory.len(), 65536);
+/// assert_eq!(memory.grow(2).unwrap(), 1);
+/// assert_eq!(memory.pages(), 3);
+/// assert!(memory.grow(16).is_err());
+/// ```
+pub struct WasmLinearMemory<B: VirtualBacking = VirtualAlloc> {
+    backing: B,
+    base: NonNull<u8>,
+    pages: u32,
+    maximum: u32,
+    guard_size: usize
+}
+
+// The memory is only accessed through raw pointers, which are synchronized by the engine.
+unsafe impl<B: VirtualBacking + Send> Send for WasmLinearMemory<B> {}
+unsafe impl<B: VirtualBacking + Sync> Sync for WasmLinearMemory<B> {}
+
+impl WasmLinearMemory {
+    /// Returns a linear memory of `minimum` pages that can grow up to `maximum` pages, or
+    /// to 4GB if `None` is given, followed by a guard region of 2GB.
+    pub fn new(minimum: u32, maximum: Option<u32>) -> Result<Self, VirtualMemError> {
+        let backing = VirtualAlloc::new(INDEX_SPACE + DEFAULT_GUARD_SIZE);
+
+        WasmLinearMemory::with_backing(minimum, maximum, DEFAULT_GUARD_SIZE, backing)
+    }
+}
+
+impl<B: VirtualBacking> WasmLinearMemory<B> {
+    /// Returns a linear memory of `minimum` pages that can grow up to `maximum` pages, or
+    /// to 4GB if `None` is given, followed by a guard region of `guard_size` bytes, in
+    /// memory provided by the given backing.
+    ///
+    /// The page size of the backing must divide the size of WebAssembly pages.
+    pub fn with_backing(minimum: u32, maximum: Option<u32>, guard_size: usize, backing: B)
+        -> Result<Self, VirtualMemError> {
+        let maximum = maximum.unwrap_or(MAX_PAGES).min(MAX_PAGES);
+
+        if minimum > maximum {
+            return Err(VirtualMemError::ExceedsMax {
+                requested: minimum as usize * WASM_PAGE_SIZE,
+                max: maximum as usize * WASM_PAGE_SIZE
+            })
+        }
+
+        debug_assert_eq!(WASM_PAGE_SIZE % backing.page_size(), 0);
+
+        let guard_size = guard_size.div_ceil(backing.page_size()) * backing.page_size();
+        let base = backing.reserve(INDEX_SPACE + guard_size)?;
+        let mut memory = WasmLinearMemory { backing, base, pages: 0, maximum, guard_size };
+
+        memory.grow(minimum)?;
+
+        Ok(memory)
+    }
+
+    /// Returns a pointer to the start of the memory, which never changes.
+    #[inline]
+    pub fn base(&self) -> *mut u8 {
+        self.base.as_ptr()
+    }
+
+    /// Returns the number of accessible bytes of the memory.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.pages as usize * WASM_PAGE_SIZE
+    }
+
+    /// Returns whether the memory has no accessible byte.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.pages == 0
+    }
+
+    /// Returns the number of pages of the memory.
+    #[inline]
+    pub fn pages(&self) -> u32 {
+        self.pages
+    }
+
+    /// Returns the number of pages the memory can grow to.
+    #[inline]
+    pub fn maximum_pages(&self) -> u32 {
+        self.maximum
+    }
+
+    /// Returns the size of the guard region that follows the 4GB of the memory.
+    #[inline]
+    pub fn guard_size(&self) -> usize {
+        self.guard_size
+    }
+
+    /// Returns the memory as a slice.
+    ///
+    /// # Safety
+    /// The memory must not be modified while the slice is alive, which includes modifications
+    /// made by WebAssembly code running on other threads.
+    #[inline]
+    pub unsafe fn as_slice(&self) -> &[u8] {
+        slice::from_raw_parts(self.base.as_ptr(), self.len())
+    }
+
+    /// Grows the memory by `pages` pages, and returns its previous number of pages, like
+    /// the `memory.grow` instruction.
+    ///
+    /// New pages are zeroed. The memory is left unchanged if it would exceed its maximum,
+    /// or if committing fails.
+    pub fn grow(&mut self, pages: u32) -> Result<u32, VirtualMemError> {
+        let previous = self.pages;
+        let new = match previous.checked_add(pages) {
+            Some(new) if new <= self.maximum => new,
+            _ => return Err(VirtualMemError::ExceedsMax {
+                requested: (previous as usize + pages as usize) * WASM_PAGE_SIZE,
+                max: self.maximum as usize * WASM_PAGE_SIZE
+            })
+        };
+
+        if pages > 0 {
+            unsafe {
+                let start = NonNull::new_unchecked(self.base.as_ptr().add(self.len()));
+
+                self.backing.commit(start, pages as usize * WASM_PAGE_SIZE)?;
+            }
+        }
+
+        self.pages = new;
+
+        Ok(previous)
+    }
+}
+
+impl<B: VirtualBacking> Drop for WasmLinearMemory<B> {
+    fn drop(&mut self) {
+        unsafe {
+            self.backing.release(self.base, INDEX_SPACE + self.guard_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn grows_in_place() {
+        let mut memory = WasmLinearMemory::new(1, Some(4)).unwrap();
+        let base = memory.base();
+
+        unsafe {
+            *base.add(memory.len() - 1) = 1;
+        }
+
+        assert_eq!(memory.grow(2), Ok(1));
+        assert_eq!(memory.base(), base);
+        assert_eq!(memory.len(), WASM_PAGE_SIZE * 3);
+
+        unsafe {
+            assert_eq!(memory.as_slice()[WASM_PAGE_SIZE - 1], 1);
+            assert_eq!(memory.as_slice()[WASM_PAGE_SIZE * 3 - 1], 0);
+        }
+
+        assert!(memory.grow(2).is_err());
+        assert_eq!(memory.grow(1), Ok(3));
+        assert_eq!(memory.pages(), memory.maximum_pages());
+    }
+
+    #[test]
+    fn reserves_the_whole_index_space() {
+        let memory = WasmLinearMemory::new(0, None).unwrap();
+
+        assert!(memory.is_empty());
+        assert_eq!(memory.maximum_pages(), 0x10000);
+        assert_eq!(memory.guard_size(), 2 << 30);
+        assert!(WasmLinearMemory::new(2, Some(1)).is_err());
+    }
+}
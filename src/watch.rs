@@ -0,0 +1,155 @@
+//! Tracking of the pages written to since a checkpoint, with write protection.
+//!
+//! Clean pages are made read-only, and the first write to each of them faults into a
+//! handler registered with the `trap` module, which marks the page dirty and makes it
+//! writable again before the write resumes. Subsequent writes to the page are free until
+//! the next checkpoint.
+
+use std::ops::Range;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use super::{VirtualAlloc, VirtualMemError};
+use super::trap::{self, Trap};
+
+
+const BITS: usize = usize::BITS as usize;
+
+/// The pages written to in a reservation since they were last cleaned.
+pub(crate) struct WriteWatch {
+    // The trap must be unregistered before its state is freed.
+    _trap: Trap,
+    state: Box<WatchState>
+}
+
+struct WatchState {
+    base: NonNull<u8>,
+    page_size: usize,
+    /// The number of committed bytes at the start of the reservation, past which faults
+    /// are not write faults, and are left to other handlers.
+    committed: AtomicUsize,
+    /// One bit per page of the reservation, set when the page is dirty.
+    dirty: Box<[AtomicUsize]>
+}
+
+impl WriteWatch {
+    /// Starts tracking writes to the reservation of `size` bytes starting at `base`, of
+    /// which the first `committed` bytes are committed and considered dirty.
+    pub(crate) fn new(base: NonNull<u8>, size: usize, committed: usize)
+        -> Result<Self, VirtualMemError> {
+        let page_size = VirtualAlloc::page_size();
+        let words = size.div_ceil(page_size).div_ceil(BITS);
+        let state = Box::new(WatchState {
+            base,
+            page_size,
+            committed: AtomicUsize::new(0),
+            dirty: (0..words).map(|_| AtomicUsize::new(0)).collect()
+        });
+
+        let trap = unsafe {
+            let data = &*state as *const WatchState as *const ();
+
+            trap::register(base.as_ptr(), size, on_write, data)?
+        };
+        let watch = WriteWatch { _trap: trap, state };
+
+        watch.committed(0, committed);
+
+        Ok(watch)
+    }
+
+    /// Records that the bytes in the given range were committed, which makes their pages
+    /// dirty.
+    pub(crate) fn committed(&self, old: usize, new: usize) {
+        let page = self.state.page_size;
+
+        for index in old / page..new.div_ceil(page) {
+            self.state.mark(index);
+        }
+
+        self.state.committed.store(new.div_ceil(page) * page, Ordering::Relaxed);
+    }
+
+    /// Records that only the first `committed` bytes remain committed.
+    #[inline]
+    pub(crate) fn decommitted(&self, committed: usize) {
+        let page = self.state.page_size;
+
+        self.state.committed.store(committed.div_ceil(page) * page, Ordering::Relaxed);
+    }
+
+    /// Returns the ranges of dirty committed pages, as byte offsets from the start of the
+    /// reservation.
+    pub(crate) fn dirty_ranges(&self) -> Vec<Range<usize>> {
+        let page = self.state.page_size;
+        let pages = self.state.committed.load(Ordering::Relaxed) / page;
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+
+        for index in (0..pages).filter(|&index| self.state.is_dirty(index)) {
+            match ranges.last_mut() {
+                Some(range) if range.end == index * page => range.end += page,
+                _ => ranges.push(index * page..(index + 1) * page)
+            }
+        }
+
+        ranges
+    }
+
+    /// Makes all committed pages clean and read-only, so that the next write to each of
+    /// them is tracked.
+    pub(crate) fn clear(&self) -> Result<(), VirtualMemError> {
+        let committed = self.state.committed.load(Ordering::Relaxed);
+
+        if committed > 0 {
+            VirtualAlloc::set_protection(self.state.base, committed, true, false, false)?;
+        }
+
+        for word in self.state.dirty.iter() {
+            word.store(0, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// Stops tracking writes, and makes all committed pages writable again.
+    pub(crate) fn stop(self) -> Result<(), VirtualMemError> {
+        let committed = self.state.committed.load(Ordering::Relaxed);
+
+        // Pages must be writable before faults stop being routed to the handler, which
+        // happens when the watch is dropped.
+        if committed > 0 {
+            VirtualAlloc::set_protection(self.state.base, committed, true, true, false)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl WatchState {
+    #[inline]
+    fn mark(&self, index: usize) {
+        self.dirty[index / BITS].fetch_or(1 << (index % BITS), Ordering::Relaxed);
+    }
+
+    #[inline]
+    fn is_dirty(&self, index: usize) -> bool {
+        self.dirty[index / BITS].load(Ordering::Relaxed) & (1 << (index % BITS)) != 0
+    }
+}
+
+/// Marks the page written to dirty, and makes it writable.
+unsafe fn on_write(data: *const (), addr: *mut u8) -> bool {
+    let state = &*(data as *const WatchState);
+    let offset = addr.addr() - state.base.as_ptr().addr();
+
+    if offset >= state.committed.load(Ordering::Relaxed) {
+        return false
+    }
+
+    let index = offset / state.page_size;
+    let page = NonNull::new_unchecked(state.base.as_ptr().add(index * state.page_size));
+
+    state.mark(index);
+
+    VirtualAlloc::set_protection(page, state.page_size, true, true, false).is_ok()
+}
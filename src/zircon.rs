@@ -0,0 +1,43 @@
+//! Bindings to the Zircon system calls used on Fuchsia.
+//!
+//! On Fuchsia, memory is reserved by mapping a VMO (virtual memory object) without any
+//! permission into the root VMAR (virtual memory address region) of the process. Memory
+//! is then committed by granting the mapping permissions and committing its pages with
+//! `ZX_VMAR_OP_COMMIT`.
+
+#![allow(non_camel_case_types)]
+
+pub type zx_handle_t = u32;
+pub type zx_status_t = i32;
+pub type zx_vaddr_t = usize;
+pub type zx_vm_option_t = u32;
+
+pub const ZX_OK: zx_status_t = 0;
+pub const ZX_HANDLE_INVALID: zx_handle_t = 0;
+
+pub const ZX_VMAR_OP_COMMIT: u32 = 1;
+pub const ZX_VMAR_OP_DECOMMIT: u32 = 2;
+
+#[link(name = "zircon")]
+extern "C" {
+    pub fn zx_vmar_root_self() -> zx_handle_t;
+
+    pub fn zx_vmo_create(size: u64, options: u32, out: *mut zx_handle_t) -> zx_status_t;
+
+    pub fn zx_vmo_replace_as_executable(handle: zx_handle_t, vmex: zx_handle_t,
+                                        out: *mut zx_handle_t) -> zx_status_t;
+
+    pub fn zx_vmar_map(handle: zx_handle_t, options: zx_vm_option_t, vmar_offset: usize,
+                       vmo: zx_handle_t, vmo_offset: u64, len: usize,
+                       mapped_addr: *mut zx_vaddr_t) -> zx_status_t;
+
+    pub fn zx_vmar_unmap(handle: zx_handle_t, addr: zx_vaddr_t, len: usize) -> zx_status_t;
+
+    pub fn zx_vmar_protect(handle: zx_handle_t, options: zx_vm_option_t, addr: zx_vaddr_t,
+                           len: usize) -> zx_status_t;
+
+    pub fn zx_vmar_op_range(handle: zx_handle_t, op: u32, addr: zx_vaddr_t, len: usize,
+                            buffer: *mut u8, buffer_size: usize) -> zx_status_t;
+
+    pub fn zx_handle_close(handle: zx_handle_t) -> zx_status_t;
+}
@@ -0,0 +1,155 @@
+//! Models of the concurrent containers, checked by `loom`.
+//!
+//! These models only build with `--cfg loom`, and are run with:
+//!
+//! ```sh
+//! RUSTFLAGS="--cfg loom" cargo test --release --test loom
+//! ```
+//!
+//! `loom` explores the interleavings of the atomic operations of the containers, and checks
+//! that their publication protocols hold in every one of them. Accesses to the memory of
+//! the containers themselves go through raw pointers, and are not tracked by `loom`.
+#![cfg(loom)]
+
+extern crate loom;
+extern crate virtualalloc;
+
+use loom::sync::Arc;
+use loom::thread;
+
+use virtualalloc::{ShardedArena, SharedVirtualVec, VirtualLog};
+
+fn model<F: Fn() + Sync + Send + 'static>(f: F) {
+    let mut builder = loom::model::Builder::new();
+
+    builder.preemption_bound = Some(3);
+    builder.check(f);
+}
+
+#[test]
+fn shared_vec_readers_see_published_prefixes() {
+    model(|| {
+        let shared = Arc::new(SharedVirtualVec::<usize>::new(16));
+        let writer = {
+            let shared = shared.clone();
+
+            thread::spawn(move || {
+                let mut writer = shared.writer().unwrap();
+
+                for i in 0..2 {
+                    writer.push(i);
+                    writer.publish();
+                }
+            })
+        };
+
+        let slice = shared.as_slice();
+
+        assert!(slice.len() <= 2);
+        assert!(slice.iter().enumerate().all(|(i, &x)| x == i));
+
+        writer.join().unwrap();
+
+        assert_eq!(shared.len(), 2);
+    });
+}
+
+#[test]
+fn shared_vec_has_a_single_writer() {
+    model(|| {
+        let shared = Arc::new(SharedVirtualVec::<usize>::new(16));
+        let other = {
+            let shared = shared.clone();
+
+            thread::spawn(move || shared.writer().map(|mut writer| writer.push(1)).is_some())
+        };
+
+        let mine = shared.writer().map(|mut writer| writer.push(0)).is_some();
+        let theirs = other.join().unwrap();
+
+        assert!(mine || theirs);
+    });
+}
+
+#[test]
+fn log_publishes_entries_in_reservation_order() {
+    model(|| {
+        let log = Arc::new(VirtualLog::new(4096));
+        let producers = (1..3u8)
+            .map(|i| {
+                let log = log.clone();
+
+                thread::spawn(move || { log.append(&[i; 2]).unwrap(); })
+            })
+            .collect::<Vec<_>>();
+
+        let published = log.published();
+
+        assert_eq!(published.len() % 2, 0);
+        assert!(published.iter().all(|&b| b != 0));
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        assert_eq!(log.len(), 4);
+    });
+}
+
+#[test]
+fn arena_shards_have_a_single_claimant() {
+    model(|| {
+        let arena = Arc::new(ShardedArena::new(1, 1).unwrap());
+        let other = {
+            let arena = arena.clone();
+
+            thread::spawn(move || arena.shard(0).is_some())
+        };
+
+        let mine = arena.shard(0).is_some();
+        let theirs = other.join().unwrap();
+
+        assert!(mine || theirs);
+    });
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn ring_transfers_bytes_in_order() {
+    use virtualalloc::ring::SpscRing;
+
+    model(|| {
+        // The halves of the ring borrow it, but loom threads must be 'static.
+        let ring = Box::into_raw(Box::new(SpscRing::new(1).unwrap()));
+        let (mut producer, mut consumer) = unsafe { (*ring).split() };
+
+        let handle = thread::spawn(move || {
+            for i in 1..3u8 {
+                producer.write()[0] = i;
+                producer.produce(1);
+            }
+        });
+
+        let mut expected = 1;
+
+        while expected < 3 {
+            let len = consumer.read().len();
+
+            if len == 0 {
+                thread::yield_now();
+                continue
+            }
+
+            assert_eq!(consumer.read()[0], expected);
+
+            consumer.consume(1);
+            expected += 1;
+        }
+
+        handle.join().unwrap();
+
+        unsafe {
+            drop(Box::from_raw(ring));
+        }
+    });
+}
@@ -0,0 +1,38 @@
+//! Strict W^X mode, which applies to the whole process, and therefore runs in a test
+//! binary of its own.
+
+extern crate virtualalloc;
+
+use std::ptr::NonNull;
+
+use virtualalloc::{VirtualAlloc, VirtualMemError, VirtualVec};
+
+#[test]
+fn refuses_writable_and_executable_memory() {
+    VirtualAlloc::set_strict_wx(true);
+
+    assert_eq!(VirtualAlloc::try_with_protection(1 << 20, true, true, true),
+               Err(VirtualMemError::WxViolation));
+    assert_eq!(VirtualAlloc::options().protection(true, true, true).try_build(),
+               Err(VirtualMemError::WxViolation));
+    assert_eq!(VirtualVec::<u8>::try_with_protection(1 << 20, true, true, true).err(),
+               Some(VirtualMemError::WxViolation));
+    assert!(std::panic::catch_unwind(|| VirtualAlloc::with_protection(1 << 20, true, true, true))
+        .is_err());
+
+    // Code is written to writable memory, and only then made executable.
+    let mut vec = VirtualVec::<u8>::try_with_protection(1 << 20, true, true, false).unwrap();
+
+    vec.extend_from_slice(&[0xC3; 16]);
+
+    let code = NonNull::new(vec.as_mut_ptr()).unwrap();
+
+    assert_eq!(VirtualAlloc::set_protection(code, 16, true, true, true),
+               Err(VirtualMemError::WxViolation));
+    assert_eq!(VirtualAlloc::make_executable(code, 16), Ok(()));
+    assert!(VirtualAlloc::try_with_protection(1 << 20, true, false, true).is_ok());
+
+    VirtualAlloc::set_strict_wx(false);
+
+    assert!(VirtualAlloc::try_with_protection(1 << 20, true, true, true).is_ok());
+}